@@ -0,0 +1,608 @@
+//! Musical analysis over parsed tracks.
+
+#![cfg(feature = "alloc")]
+
+use crate::{
+    event::{MetaMessage, MidiMessage, PitchBend, TrackEvent, TrackEventKind},
+    prelude::*,
+    primitive::Timing,
+};
+
+/// A single note, paired from its note on and note off events.
+///
+/// Created by the [`notes`](fn.notes.html) function.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct Note {
+    /// The MIDI channel that played this note.
+    pub channel: u4,
+    /// The key of the note.
+    pub key: u7,
+    /// The velocity of the note on event.
+    pub vel: u7,
+    /// The absolute tick at which the note starts sounding.
+    pub start: u32,
+    /// The absolute tick at which the note stops sounding.
+    pub end: u32,
+}
+impl Note {
+    /// The duration of the note, in ticks.
+    #[inline]
+    pub fn duration(&self) -> u32 {
+        self.end - self.start
+    }
+}
+
+/// Pair up the note on and note off events of a track into notes, sorted by their start tick.
+///
+/// Note ons with a velocity of zero are considered note offs.
+/// If the same key is pressed several times on the same channel before being released, note offs
+/// are matched with the earliest note on.
+/// Notes that are never released end at the tick of the last event in the track.
+///
+/// If `sustain_aware` is `true`, the sustain pedal (controller 64) is taken into account: notes
+/// released while the pedal is held keep sounding until the pedal is released, or until the same
+/// key is struck again, whichever comes first.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn notes(track: &[TrackEvent], sustain_aware: bool) -> Vec<Note> {
+    let mut notes: Vec<Note> = Vec::new();
+    //Indices into `notes` of the notes that are held down, in order of note on
+    let mut held: Vec<usize> = Vec::new();
+    //Indices into `notes` of released notes still sounding because of the sustain pedal
+    let mut sustained: Vec<usize> = Vec::new();
+    let mut pedal = [false; 16];
+    let mut time = 0u32;
+    for ev in track {
+        time = time.saturating_add(ev.delta.as_int());
+        let (channel, message) = match ev.kind {
+            TrackEventKind::Midi { channel, message } => (channel, message),
+            _ => continue,
+        };
+        match message {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                //Striking a key again cuts off its sustained sound
+                sustained.retain(|&idx| {
+                    let note = &mut notes[idx];
+                    if note.channel == channel && note.key == key {
+                        note.end = time;
+                        false
+                    } else {
+                        true
+                    }
+                });
+                held.push(notes.len());
+                notes.push(Note {
+                    channel,
+                    key,
+                    vel,
+                    start: time,
+                    end: time,
+                });
+            }
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                let pos = held
+                    .iter()
+                    .position(|&idx| notes[idx].channel == channel && notes[idx].key == key);
+                if let Some(pos) = pos {
+                    let idx = held.remove(pos);
+                    notes[idx].end = time;
+                    if sustain_aware && pedal[channel.as_int() as usize] {
+                        sustained.push(idx);
+                    }
+                }
+            }
+            MidiMessage::Controller { controller, value } if controller == 64 && sustain_aware => {
+                let down = value >= 64;
+                pedal[channel.as_int() as usize] = down;
+                if !down {
+                    sustained.retain(|&idx| {
+                        if notes[idx].channel == channel {
+                            notes[idx].end = time;
+                            false
+                        } else {
+                            true
+                        }
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    //Notes that never ended last until the end of the track
+    for idx in held.into_iter().chain(sustained) {
+        notes[idx].end = time;
+    }
+    notes
+}
+
+/// A note that was struck again on the same channel before being released.
+///
+/// Created by [`Smf::overlapping_notes`](struct.Smf.html#method.overlapping_notes).
+///
+/// This type is only available with the `alloc` feature enabled.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct NoteOverlap {
+    /// The index of the track where the overlap occurs.
+    pub track: usize,
+    /// The channel of the overlapping notes.
+    pub channel: u4,
+    /// The key of the overlapping notes.
+    pub key: u7,
+    /// The absolute tick of the first note on.
+    pub first: u32,
+    /// The absolute tick of the second note on, which arrived before the first note was
+    /// released.
+    pub second: u32,
+}
+
+/// Find all note ons that arrive while the same key is still held on the same channel.
+pub(crate) fn overlapping_notes(
+    track_idx: usize,
+    track: &[TrackEvent],
+    out: &mut Vec<NoteOverlap>,
+) {
+    //The onset of the latest held note for each channel and key
+    let mut held: Vec<(u4, u7, u32)> = Vec::new();
+    let mut time = 0u32;
+    for ev in track {
+        time = time.saturating_add(ev.delta.as_int());
+        if let TrackEventKind::Midi { channel, message } = ev.kind {
+            match message {
+                MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                    match held.iter_mut().find(|(c, k, _)| *c == channel && *k == key) {
+                        Some(onset) => {
+                            out.push(NoteOverlap {
+                                track: track_idx,
+                                channel,
+                                key,
+                                first: onset.2,
+                                second: time,
+                            });
+                            onset.2 = time;
+                        }
+                        None => held.push((channel, key, time)),
+                    }
+                }
+                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                    held.retain(|(c, k, _)| !(*c == channel && *k == key));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The state of a MIDI channel, as set up by channel messages.
+///
+/// Obtained from [`Smf::state_at`](struct.Smf.html#method.state_at).
+/// The default state is the General MIDI power-on state: program 0 on bank 0, volume 100, pan
+/// centered, pitch bend centered and the sustain pedal released.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct ChannelState {
+    /// The active program.
+    pub program: u7,
+    /// The selected bank, from the bank select MSB (controller 0) and LSB (controller 32).
+    pub bank: u14,
+    /// The channel volume (controller 7).
+    pub volume: u7,
+    /// The channel pan (controller 10), where 64 is centered.
+    pub pan: u7,
+    /// The current pitch bend.
+    pub pitch_bend: PitchBend,
+    /// Whether the sustain pedal (controller 64) is held down.
+    pub sustain: bool,
+}
+impl Default for ChannelState {
+    #[inline]
+    fn default() -> ChannelState {
+        ChannelState {
+            program: u7::new(0),
+            bank: u14::new(0),
+            volume: u7::new(100),
+            pan: u7::new(64),
+            pitch_bend: PitchBend::mid_raw_value(),
+            sustain: false,
+        }
+    }
+}
+impl ChannelState {
+    /// Update the state with a channel message.
+    pub(crate) fn apply(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::ProgramChange { program } => self.program = program,
+            MidiMessage::PitchBend { bend } => self.pitch_bend = bend,
+            MidiMessage::Controller { controller, value } => match controller.as_int() {
+                0 => {
+                    let lsb = self.bank.as_int() & 0x7F;
+                    self.bank = u14::new((value.as_int() as u16) << 7 | lsb);
+                }
+                32 => {
+                    let msb = self.bank.as_int() & 0x3F80;
+                    self.bank = u14::new(msb | value.as_int() as u16);
+                }
+                7 => self.volume = value,
+                10 => self.pan = value,
+                64 => self.sustain = value >= 64,
+                //Reset All Controllers does not reset volume or pan
+                121 => {
+                    self.pitch_bend = PitchBend::mid_raw_value();
+                    self.sustain = false;
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Find the channel that each SysEx and escape event of a track is scoped to, according to the
+/// `MetaMessage::MidiChannel` (channel prefix) events in the track.
+///
+/// A channel prefix applies to all following SysEx, escape and meta events, until the next MIDI
+/// channel event or the next channel prefix.
+/// For each SysEx or escape event, its index in the track is returned along with the channel
+/// prefix in effect at that point, or `None` if no channel prefix is in effect.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn sysex_channels(track: &[TrackEvent]) -> Vec<(usize, Option<u4>)> {
+    let mut prefix = None;
+    let mut scoped = Vec::new();
+    for (idx, ev) in track.iter().enumerate() {
+        match ev.kind {
+            TrackEventKind::Midi { .. } => prefix = None,
+            TrackEventKind::Meta(MetaMessage::MidiChannel(channel)) => prefix = Some(channel),
+            TrackEventKind::SysEx(_) | TrackEventKind::Escape(_) => scoped.push((idx, prefix)),
+            TrackEventKind::Meta(_) => {}
+        }
+    }
+    scoped
+}
+
+/// A System Exclusive message, reassembled from the packets it was split into.
+///
+/// Created by the [`sysex_messages`](fn.sysex_messages.html) function.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct SysExMessage<'a> {
+    /// The absolute tick of the first packet, where the whole message should be sent.
+    pub time: u32,
+    /// The data of the message, not including the leading `0xF0` byte but including the final
+    /// `0xF7` byte if the message is complete.
+    ///
+    /// Messages that were not split borrow their data from the track.
+    pub data: Cow<'a, [u8]>,
+    /// The index in the track of every packet of the message, starting with the `SysEx` event and
+    /// followed by its `Escape` continuation packets.
+    pub packets: Vec<usize>,
+    /// The index in the track of every event that appears between the packets of the message
+    /// but is not part of it.
+    ///
+    /// The standard forbids any events in between packets, so a non-empty list indicates a
+    /// malformed file.
+    pub interleaved: Vec<usize>,
+    /// Whether the message is terminated by an `0xF7` byte.
+    ///
+    /// Messages are left incomplete if the track ends, or a new SysEx message starts, before their
+    /// final packet is found.
+    pub complete: bool,
+}
+
+/// Collect all of the System Exclusive messages in a track, reassembling messages that are split
+/// into several packets.
+///
+/// A `SysEx` event whose data does not end with `0xF7` starts a split message, which continues
+/// with all following `Escape` events until one of them ends with `0xF7`.
+/// Packets may be separated by any amount of time, and the reassembled message is placed at the
+/// position of the first packet.
+/// Other events found between packets are not part of the message, and are reported in
+/// `SysExMessage::interleaved`.
+/// `Escape` events that are not part of a split message are not SysEx messages, and are ignored.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn sysex_messages<'a>(track: &[TrackEvent<'a>]) -> Vec<SysExMessage<'a>> {
+    let mut messages = Vec::new();
+    let mut open: Option<SysExMessage> = None;
+    let mut time = 0u32;
+    for (idx, ev) in track.iter().enumerate() {
+        time = time.saturating_add(ev.delta.as_int());
+        match (ev.kind, &mut open) {
+            (TrackEventKind::SysEx(data), _) => {
+                messages.extend(open.take());
+                let message = SysExMessage {
+                    time,
+                    data: Cow::Borrowed(data),
+                    packets: vec![idx],
+                    interleaved: Vec::new(),
+                    complete: data.last() == Some(&0xF7),
+                };
+                if message.complete {
+                    messages.push(message);
+                } else {
+                    open = Some(message);
+                }
+            }
+            (TrackEventKind::Escape(data), Some(message)) => {
+                message.data.to_mut().extend_from_slice(data);
+                message.packets.push(idx);
+                if data.last() == Some(&0xF7) {
+                    message.complete = true;
+                    messages.extend(open.take());
+                }
+            }
+            (_, Some(message)) => message.interleaved.push(idx),
+            (_, None) => {}
+        }
+    }
+    messages.extend(open);
+    messages
+}
+
+/// Guess a reasonable amount of ticks per beat for a track with no header, such as a raw track
+/// dump or a live capture.
+///
+/// The guess is the greatest common divisor of all non-zero delta times, which usually recovers
+/// the grid the track was written on.
+/// The result is clamped to the `24..=960` range: a small divisor is scaled up to its smallest
+/// multiple within the range, and a large one is halved until it fits.
+/// If the track has no non-zero delta times, the default of
+/// [`Timing::DEFAULT_TICKS_PER_BEAT`](enum.Timing.html#associatedconstant.DEFAULT_TICKS_PER_BEAT)
+/// is returned.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn guess_ppq(track: &[TrackEvent]) -> u16 {
+    const MIN: u32 = 24;
+    const MAX: u32 = 960;
+    fn gcd(mut a: u32, mut b: u32) -> u32 {
+        while b != 0 {
+            let r = a % b;
+            a = b;
+            b = r;
+        }
+        a
+    }
+
+    let grid = track.iter().map(|ev| ev.delta.as_int()).fold(0, gcd);
+    let mut ppq = match grid {
+        0 => return Timing::DEFAULT_TICKS_PER_BEAT,
+        grid if grid < MIN => grid * MIN.div_ceil(grid),
+        grid => grid,
+    };
+    while ppq > MAX {
+        ppq /= 2;
+    }
+    ppq.max(MIN) as u16
+}
+
+/// A musical key, described the same way as `MetaMessage::KeySignature` events do.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct Key {
+    /// The amount of sharps in the key signature, or the amount of flats if negative.
+    pub sharps: i8,
+    /// Whether the key is minor, as opposed to major.
+    pub minor: bool,
+}
+impl Key {
+    /// C major, with no sharps or flats.
+    pub const C_MAJOR: Key = Key {
+        sharps: 0,
+        minor: false,
+    };
+
+    /// Create a key from its amount of sharps (negative for flats) and its mode.
+    #[inline]
+    pub const fn new(sharps: i8, minor: bool) -> Key {
+        Key { sharps, minor }
+    }
+
+    /// Create a key from the pitch class of its tonic (`0` for C, `1` for C#, up to `11` for B)
+    /// and its mode.
+    ///
+    /// Tonics that can be spelled either way use between 5 flats and 6 sharps, so for example
+    /// C# major becomes Db major.
+    pub fn from_tonic(tonic: u8, minor: bool) -> Key {
+        //Every sharp raises the tonic by a fifth, and a minor tonic is 3 semitones below the
+        //tonic of its relative major
+        let major_tonic = (tonic % 12 + if minor { 3 } else { 0 }) as i32;
+        Key {
+            sharps: normalize_sharps(major_tonic * 7),
+            minor,
+        }
+    }
+
+    /// The pitch class of the tonic of this key, from `0` for C up to `11` for B.
+    pub fn tonic(&self) -> u8 {
+        let major_tonic = self.sharps as i32 * 7;
+        (major_tonic + if self.minor { 9 } else { 0 }).rem_euclid(12) as u8
+    }
+
+    /// The `MetaMessage::KeySignature` event describing this key.
+    #[inline]
+    pub fn to_meta<'a>(&self) -> MetaMessage<'a> {
+        MetaMessage::KeySignature(self.sharps, self.minor)
+    }
+}
+
+/// Bring an amount of sharps into the `-5..=6` range, without changing the key it describes.
+pub(crate) fn normalize_sharps(sharps: i32) -> i8 {
+    ((sharps + 5).rem_euclid(12) - 5) as i8
+}
+
+/// Estimate the key of a piece from its notes, using the Krumhansl-Kessler key profiles.
+///
+/// Every pitch class is weighted by the total duration of its notes, and the key whose profile
+/// correlates best with these weights is chosen.
+/// Notes on channel 9 (the General MIDI percussion channel) are ignored.
+/// Returns `None` if there are no pitched notes to go by.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn estimate_key(notes: &[Note]) -> Option<Key> {
+    const MAJOR: [f64; 12] = [
+        6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+    ];
+    const MINOR: [f64; 12] = [
+        6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+    ];
+    fn centered(values: &[f64; 12]) -> [f64; 12] {
+        let mean = values.iter().sum::<f64>() / 12.;
+        let mut out = [0.; 12];
+        for (out, &v) in out.iter_mut().zip(values.iter()) {
+            *out = v - mean;
+        }
+        out
+    }
+
+    let mut weights = [0.; 12];
+    for note in notes.iter().filter(|note| note.channel != 9) {
+        weights[(note.key.as_int() % 12) as usize] += note.duration().max(1) as f64;
+    }
+    if weights.iter().all(|&w| w == 0.) {
+        return None;
+    }
+    let weights = centered(&weights);
+    let mut best = None;
+    for &(profile, minor) in [(MAJOR, false), (MINOR, true)].iter() {
+        let profile = centered(&profile);
+        let variance = profile.iter().map(|p| p * p).sum::<f64>();
+        for tonic in 0..12 {
+            let cov = (0..12)
+                .map(|pc| weights[pc] * profile[(pc + 12 - tonic) % 12])
+                .sum::<f64>();
+            //The correlation without the terms common to every key, keeping its sign
+            let score = if cov < 0. { -cov * cov } else { cov * cov } / variance;
+            if best.map_or(true, |(best, _)| score > best) {
+                best = Some((score, Key::from_tonic(tonic as u8, minor)));
+            }
+        }
+    }
+    best.map(|(_, key)| key)
+}
+
+/// The quality of a chord recognized by [`chord_at`](fn.chord_at.html).
+///
+/// This type is only available with the `alloc` feature enabled.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum ChordQuality {
+    /// A major triad, such as C-E-G.
+    Major,
+    /// A minor triad, such as C-Eb-G.
+    Minor,
+    /// A diminished triad, such as C-Eb-Gb.
+    Diminished,
+    /// An augmented triad, such as C-E-G#.
+    Augmented,
+    /// A dominant seventh chord, such as C-E-G-Bb.
+    Dominant7,
+    /// A major seventh chord, such as C-E-G-B.
+    Major7,
+    /// A minor seventh chord, such as C-Eb-G-Bb.
+    Minor7,
+    /// A half-diminished seventh chord, such as C-Eb-Gb-Bb.
+    HalfDiminished7,
+    /// A fully diminished seventh chord, such as C-Eb-Gb-A.
+    Diminished7,
+}
+impl ChordQuality {
+    /// The qualities along with the pitch classes of their notes, as a bitmask relative to the
+    /// root.
+    const TEMPLATES: [(u16, ChordQuality); 9] = [
+        (0b000010010001, ChordQuality::Major),
+        (0b000010001001, ChordQuality::Minor),
+        (0b000001001001, ChordQuality::Diminished),
+        (0b000100010001, ChordQuality::Augmented),
+        (0b010010010001, ChordQuality::Dominant7),
+        (0b100010010001, ChordQuality::Major7),
+        (0b010010001001, ChordQuality::Minor7),
+        (0b010001001001, ChordQuality::HalfDiminished7),
+        (0b001001001001, ChordQuality::Diminished7),
+    ];
+
+    /// The usual suffix of chord symbols with this quality, such as `m7` for `Cm7`.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            ChordQuality::Major => "",
+            ChordQuality::Minor => "m",
+            ChordQuality::Diminished => "dim",
+            ChordQuality::Augmented => "aug",
+            ChordQuality::Dominant7 => "7",
+            ChordQuality::Major7 => "maj7",
+            ChordQuality::Minor7 => "m7",
+            ChordQuality::HalfDiminished7 => "m7b5",
+            ChordQuality::Diminished7 => "dim7",
+        }
+    }
+}
+
+/// A chord, given by the pitch class of its root and its quality.
+///
+/// Formats as a chord symbol with the root spelled with sharps, such as `C`, `F#m` or `A#m7`.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct ChordName {
+    /// The pitch class of the root of the chord, from `0` for C up to `11` for B.
+    pub root: u8,
+    /// The quality of the chord.
+    pub quality: ChordQuality,
+}
+impl fmt::Display for ChordName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: [&str; 12] = [
+            "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+        ];
+        write!(
+            f,
+            "{}{}",
+            NAMES[(self.root % 12) as usize],
+            self.quality.suffix()
+        )
+    }
+}
+
+/// Recognize the chord formed by the notes sounding at the given tick of a track.
+///
+/// Notes sound from their note on up to, but not including, their note off, and notes on channel
+/// 9 (the General MIDI percussion channel) are ignored.
+/// Octaves and doublings do not matter, but the sounding pitch classes must exactly form one of
+/// the common triads or seventh chords in [`ChordQuality`](enum.ChordQuality.html).
+/// Symmetric chords (augmented and fully diminished) take their root from the lowest sounding
+/// note.
+///
+/// Returns `None` if there are fewer than 3 distinct pitch classes sounding, if they do not form
+/// a recognized chord, or if the root is ambiguous.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn chord_at(track: &[TrackEvent], tick: u32) -> Option<ChordName> {
+    let mut classes = 0u16;
+    let mut bass: Option<u7> = None;
+    for note in notes(track, false) {
+        if note.channel != 9 && note.start <= tick && tick < note.end {
+            classes |= 1 << (note.key.as_int() % 12);
+            bass = Some(bass.map_or(note.key, |bass| bass.min(note.key)));
+        }
+    }
+    let bass = bass?.as_int() % 12;
+    if classes.count_ones() < 3 {
+        return None;
+    }
+    let mut found = Vec::new();
+    for root in 0..12 {
+        let relative = (classes >> root | classes << (12 - root)) & 0xFFF;
+        for &(template, quality) in ChordQuality::TEMPLATES.iter() {
+            if relative == template {
+                found.push(ChordName { root, quality });
+            }
+        }
+    }
+    match found[..] {
+        [chord] => Some(chord),
+        //Symmetric chords match several roots, pick the bass note
+        _ => found.into_iter().find(|chord| chord.root == bass),
+    }
+}
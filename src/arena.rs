@@ -74,6 +74,7 @@ impl Arena {
     }
 
     /// Get the amount of allocations in the arena.
+    #[allow(clippy::len_without_is_empty)]
     #[inline]
     pub fn len(&self) -> usize {
         // SAFETY:
@@ -86,7 +87,7 @@ impl Arena {
     /// Add a set of bytes to the arena, returning a longer-lived mutable reference to a copy of
     /// these same bytes.
     #[inline]
-    pub fn add<'a, 'b>(&'a self, bytes: &'b [u8]) -> &'a mut [u8] {
+    pub fn add<'a>(&'a self, bytes: &[u8]) -> &'a mut [u8] {
         self.add_boxed(Box::from(bytes))
     }
 
@@ -94,14 +95,15 @@ impl Arena {
     ///
     /// This method is very similar to `add`, but avoids an allocation and a copy.
     #[inline]
-    pub fn add_vec<'a>(&'a self, bytes: Vec<u8>) -> &'a mut [u8] {
+    pub fn add_vec(&self, bytes: Vec<u8>) -> &mut [u8] {
         self.add_boxed(bytes.into_boxed_slice())
     }
 
     /// Add a set of databytes to the arena, returning a longer-lived mutable reference to a copy
     /// of these same databytes.
+    #[allow(clippy::mut_from_ref)]
     #[inline]
-    pub fn add_u7<'a, 'b>(&'a self, databytes: &'b [u7]) -> &'a mut [u7] {
+    pub fn add_u7<'a>(&'a self, databytes: &[u7]) -> &'a mut [u7] {
         // SAFETY:
         // The returned `&mut [u8]` is transformed into a `&mut [u7]` without checking its
         // contents, which is safe because it was originally a `&[u7]`.
@@ -111,8 +113,9 @@ impl Arena {
     /// Add a `Vec<u7>` to the arena, returning a long-lived mutable reference to its contents.
     ///
     /// This method is very similar to `add_u7`, but avoids an allocation and a copy.
+    #[allow(clippy::mut_from_ref)]
     #[inline]
-    pub fn add_u7_vec<'a>(&'a self, databytes: Vec<u7>) -> &'a mut [u7] {
+    pub fn add_u7_vec(&self, databytes: Vec<u7>) -> &mut [u7] {
         // SAFETY:
         // Two unsafe actions are done:
         // First, a `Vec<u7>` is transmuted into a `Vec<u8>`. This is valid because `u7` has the
@@ -127,8 +130,9 @@ impl Arena {
         }
     }
 
+    #[allow(clippy::mut_from_ref)]
     #[inline]
-    fn add_boxed<'a>(&'a self, boxed_bytes: Box<[u8]>) -> &'a mut [u8] {
+    fn add_boxed(&self, boxed_bytes: Box<[u8]>) -> &mut [u8] {
         // SAFETY:
         // This block moves `boxed_bytes` into `self` and returns a mutable reference to its
         // contents.
@@ -0,0 +1,62 @@
+//! A minimal standard base64 codec (RFC 4648, with padding), used to embed small MIDI files in
+//! source code.
+
+#![cfg(feature = "base64")]
+
+use crate::prelude::*;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode bytes as padded base64.
+pub(crate) fn encode(raw: &[u8]) -> String {
+    let mut out = String::with_capacity(raw.len().div_ceil(3) * 4);
+    for group in raw.chunks(3) {
+        let mut bytes = [0; 3];
+        bytes[..group.len()].copy_from_slice(group);
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode padded base64, ignoring any ASCII whitespace in the input.
+pub(crate) fn decode(text: &str) -> Result<Vec<u8>> {
+    fn sextet(c: u8) -> Option<u32> {
+        Some(match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        } as u32)
+    }
+
+    let digits = text
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<Vec<u8>>();
+    ensure!(digits.len() % 4 == 0, err_invalid!("invalid base64 length"));
+    let mut out = Vec::with_capacity(digits.len() / 4 * 3);
+    let groups = digits.len() / 4;
+    for (idx, group) in digits.chunks(4).enumerate() {
+        let padding = group.iter().rev().take_while(|&&c| c == b'=').count();
+        ensure!(
+            padding <= 2 && (padding == 0 || idx + 1 == groups),
+            err_invalid!("invalid base64 padding")
+        );
+        let mut bits = 0;
+        for &c in &group[..4 - padding] {
+            bits = bits << 6 | sextet(c).ok_or(err_invalid!("invalid base64 character"))?;
+        }
+        bits <<= 6 * padding;
+        out.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
@@ -0,0 +1,778 @@
+//! Transformations over the events of a track.
+
+#![cfg(feature = "alloc")]
+
+use crate::{
+    analysis::normalize_sharps,
+    event::{
+        event_priority, sort_timeline, MetaMessage, MidiMessage, PitchBend, TrackEvent,
+        TrackEventKind, ESSENTIAL_PRIORITY,
+    },
+    prelude::*,
+    smf::{end_at, from_absolute, merge_absolute, split_gap, Track},
+};
+use alloc::collections::{BTreeMap, VecDeque};
+
+/// Remove events from a track for which the given closure returns `false`, keeping the timing of
+/// the remaining events intact.
+///
+/// The delta time of every removed event is carried over to the next kept event, splitting up
+/// gaps that grow too long for a single delta.
+fn retain_timed<'a>(track: &mut Track<'a>, mut keep: impl FnMut(usize, &TrackEvent<'a>) -> bool) {
+    let mut out = Vec::with_capacity(track.len());
+    let mut carry = 0u64;
+    for (idx, ev) in track.drain(..).enumerate() {
+        carry += ev.delta.as_int() as u64;
+        if keep(idx, &ev) {
+            let delta = split_gap(&mut out, carry);
+            out.push(TrackEvent { delta, ..ev });
+            carry = 0;
+        }
+    }
+    *track = out;
+}
+
+/// Thin out a track so that no more than `max_events_per_tick` events fall on the same tick,
+/// dropping the least important events first.
+///
+/// Event importance is given by [`event_priority`](fn.event_priority.html).
+/// Controller events that set a controller to the value it already had are considered redundant,
+/// and are dropped before anything else.
+/// Within the same priority, later events in a tick are dropped first.
+///
+/// Events with [`ESSENTIAL_PRIORITY`](constant.ESSENTIAL_PRIORITY.html) are never dropped, so
+/// ticks with too many essential events will still exceed the budget.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn drop_under_budget(track: &mut Track, max_events_per_tick: usize) {
+    //Compute the priority of every event, taking redundant controllers into account
+    let mut controllers = [[None; 128]; 16];
+    let priorities = track
+        .iter()
+        .map(|ev| match ev.kind {
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::Controller { controller, value },
+            } => {
+                let last =
+                    &mut controllers[channel.as_int() as usize][controller.as_int() as usize];
+                if last.replace(value) == Some(value) {
+                    0
+                } else {
+                    event_priority(&ev.kind) + 1
+                }
+            }
+            _ => event_priority(&ev.kind) + 1,
+        })
+        .collect::<Vec<u8>>();
+
+    //Find out which events to drop, tick by tick
+    let mut dropped = vec![false; track.len()];
+    let mut start = 0;
+    while start < track.len() {
+        let mut end = start + 1;
+        while end < track.len() && track[end].delta == 0 {
+            end += 1;
+        }
+        let mut excess = (end - start).saturating_sub(max_events_per_tick);
+        let mut priority = 0;
+        while excess > 0 && priority <= ESSENTIAL_PRIORITY {
+            for idx in (start..end).rev() {
+                if excess > 0 && priorities[idx] == priority {
+                    dropped[idx] = true;
+                    excess -= 1;
+                }
+            }
+            priority += 1;
+        }
+        start = end;
+    }
+    retain_timed(track, |idx, _ev| !dropped[idx]);
+}
+
+/// Replace every event in a track with zero or more events, preserving the timing of the track.
+///
+/// The closure receives each event in order and returns the events that should replace it.
+/// The delta times of the returned events are ignored: the first returned event is given the
+/// delta time of the original event, and the rest are given a delta time of zero, so that they
+/// all fire at the same tick as the original event.
+/// If the closure returns no events, the delta time of the original event is carried over to the
+/// next event.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn flat_map_events<'a, I>(track: Track<'a>, mut f: impl FnMut(TrackEvent<'a>) -> I) -> Track<'a>
+where
+    I: IntoIterator<Item = TrackEvent<'a>>,
+{
+    let mut out = Vec::with_capacity(track.len());
+    let mut carry = 0u64;
+    for ev in track {
+        carry += ev.delta.as_int() as u64;
+        for (i, mut new_ev) in f(ev).into_iter().enumerate() {
+            new_ev.delta = if i == 0 {
+                split_gap(&mut out, carry)
+            } else {
+                u28::new(0)
+            };
+            carry = 0;
+            out.push(new_ev);
+        }
+    }
+    out
+}
+
+/// How to resolve conflicting conductor events when merging two conductor tracks with
+/// [`merge_conductors`](fn.merge_conductors.html).
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum ConflictPolicy {
+    /// Keep the event from the first track.
+    PreferA,
+    /// Keep the event from the second track.
+    PreferB,
+    /// Average conflicting tempos, measured in microseconds per beat.
+    ///
+    /// Other conflicting events (time and key signatures) are taken from the first track.
+    Average,
+}
+
+/// Merge two conductor tracks (tracks holding tempo, time signature and key signature changes)
+/// into a single coherent conductor track.
+///
+/// Events of both tracks are merged by absolute time.
+/// Two events conflict if they are of the same kind (tempo, time signature or key signature) and
+/// fall on the same tick, in which case `policy` decides which one survives.
+/// If a track holds several events of the same kind on a single tick, only the last one takes
+/// effect, so the earlier ones are dropped and only the last one takes part in conflict
+/// resolution.
+/// All other events are kept from both tracks.
+///
+/// The output track is terminated by a single `EndOfTrack` event.
+pub fn merge_conductors<'a>(
+    a: &[TrackEvent<'a>],
+    b: &[TrackEvent<'a>],
+    policy: ConflictPolicy,
+) -> Track<'a> {
+    fn conflict_class(kind: &TrackEventKind) -> Option<u8> {
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(_)) => Some(0),
+            TrackEventKind::Meta(MetaMessage::TimeSignature(..)) => Some(1),
+            TrackEventKind::Meta(MetaMessage::KeySignature(..)) => Some(2),
+            _ => None,
+        }
+    }
+    /// Find the last event of each conflict class at each tick.
+    fn last_of_class(events: &[(u32, TrackEventKind)]) -> BTreeMap<(u32, u8), usize> {
+        let mut last = BTreeMap::new();
+        for (idx, (time, kind)) in events.iter().enumerate() {
+            if let Some(class) = conflict_class(kind) {
+                last.insert((*time, class), idx);
+            }
+        }
+        last
+    }
+
+    let mut a = merge_absolute(core::iter::once(a));
+    let b = merge_absolute(core::iter::once(b));
+    let last_a = last_of_class(&a);
+    let last_b = last_of_class(&b);
+    //Drop events overridden within their own track, so they cannot take effect after the merge
+    let overridden = |events: &[(u32, TrackEventKind)], last: &BTreeMap<(u32, u8), usize>| {
+        events
+            .iter()
+            .enumerate()
+            .map(|(idx, (time, kind))| {
+                matches!(conflict_class(kind), Some(class) if last[&(*time, class)] != idx)
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut drop_a = overridden(&a, &last_a);
+    let mut drop_b = overridden(&b, &last_b);
+    for (key, &idx_a) in last_a.iter() {
+        let idx_b = match last_b.get(key) {
+            Some(&idx_b) => idx_b,
+            None => continue,
+        };
+        match (policy, &mut a[idx_a].1, &b[idx_b].1) {
+            (ConflictPolicy::PreferB, ..) => drop_a[idx_a] = true,
+            (
+                ConflictPolicy::Average,
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo_a)),
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo_b)),
+            ) => {
+                *tempo_a = u24::new((tempo_a.as_int() + tempo_b.as_int()) / 2);
+                drop_b[idx_b] = true;
+            }
+            _ => drop_b[idx_b] = true,
+        }
+    }
+
+    let mut events = a
+        .into_iter()
+        .zip(drop_a)
+        .chain(b.into_iter().zip(drop_b))
+        .filter(|(_ev, dropped)| !dropped)
+        .map(|(ev, _dropped)| ev)
+        .collect::<Vec<_>>();
+    sort_timeline(&mut events);
+    from_absolute(events)
+}
+
+/// The canonical form of note terminations, used by
+/// [`normalize_note_offs`](fn.normalize_note_offs.html).
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum NoteOffStyle {
+    /// Explicit `NoteOff` messages.
+    NoteOff,
+    /// `NoteOn` messages with a velocity of zero.
+    ZeroVelocity,
+}
+
+/// Rewrite all note terminations in a track to a single canonical form, so that later processing
+/// does not have to handle both `NoteOff` messages and `NoteOn` messages with zero velocity.
+///
+/// Converting an explicit `NoteOff` to a zero-velocity `NoteOn` discards its release velocity.
+/// Zero-velocity `NoteOn`s converted into `NoteOff`s get the default release velocity of `64`.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn normalize_note_offs(track: &mut Track, to: NoteOffStyle) {
+    for ev in track.iter_mut() {
+        if let TrackEventKind::Midi { message, .. } = &mut ev.kind {
+            *message = match (*message, to) {
+                (MidiMessage::NoteOff { key, .. }, NoteOffStyle::ZeroVelocity) => {
+                    MidiMessage::NoteOn {
+                        key,
+                        vel: u7::new(0),
+                    }
+                }
+                (MidiMessage::NoteOn { key, vel }, NoteOffStyle::NoteOff) if vel == 0 => {
+                    MidiMessage::NoteOff {
+                        key,
+                        vel: u7::new(64),
+                    }
+                }
+                (message, _) => message,
+            };
+        }
+    }
+}
+
+/// Move every MIDI channel event in a track to the channel given by `f`, which receives the
+/// current channel of each event.
+///
+/// Only `TrackEventKind::Midi` events are touched: SysEx, escape and meta events (including
+/// `MetaMessage::MidiChannel`) are left as they are.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn map_channels(track: &mut [TrackEvent], mut f: impl FnMut(u4) -> u4) {
+    for ev in track.iter_mut() {
+        if let TrackEventKind::Midi { channel, .. } = &mut ev.kind {
+            *channel = f(*channel);
+        }
+    }
+}
+
+/// Transpose all notes in a track by the given amount of semitones.
+///
+/// The keys of note on, note off and polyphonic aftertouch events are shifted, clamping them to
+/// the valid `0..=127` range.
+/// Events on channel 9 (the General MIDI percussion channel) are left alone, since their keys
+/// select instruments rather than pitches.
+/// `MetaMessage::KeySignature` events are shifted to match, using between 5 flats and 6 sharps.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn transpose(track: &mut [TrackEvent], semitones: i8) {
+    if semitones == 0 {
+        return;
+    }
+    let shift = |key: &mut u7| {
+        let shifted = key.as_int() as i16 + semitones as i16;
+        *key = u7::new(shifted.clamp(0, 127) as u8);
+    };
+    for ev in track.iter_mut() {
+        match &mut ev.kind {
+            TrackEventKind::Midi {
+                channel,
+                message:
+                    MidiMessage::NoteOn { key, .. }
+                    | MidiMessage::NoteOff { key, .. }
+                    | MidiMessage::Aftertouch { key, .. },
+            } if *channel != 9 => shift(key),
+            TrackEventKind::Meta(MetaMessage::KeySignature(sharps, _)) => {
+                *sharps = normalize_sharps(*sharps as i32 + semitones as i32 * 7);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The order of note offs and note ons within a single tick, used by
+/// [`order_chords`](fn.order_chords.html).
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum ChordOrder {
+    /// Release notes before striking new ones.
+    ///
+    /// Unlike [`sort_timeline`](fn.sort_timeline.html), this moves the note off of a zero-length
+    /// note before its note on, leaving the note stuck.
+    OffsFirst,
+    /// Strike new notes before releasing old ones.
+    ///
+    /// Note that a key that is released and struck again at the same tick will be cut short with
+    /// this order.
+    OnsFirst,
+}
+
+/// Reorder the note events within every tick of a track, so that all note offs (including note
+/// ons with zero velocity) come before all note ons, or vice versa.
+///
+/// Only note events are moved: other events keep their positions within the tick, and note offs
+/// and note ons keep their relative order among themselves.
+/// The timing of the track is unaffected.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn order_chords(track: &mut Track, order: ChordOrder) {
+    fn note_rank(kind: &TrackEventKind, order: ChordOrder) -> Option<u8> {
+        let on = match kind {
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { vel, .. },
+                ..
+            } => *vel > 0,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOff { .. },
+                ..
+            } => false,
+            _ => return None,
+        };
+        Some((on == (order == ChordOrder::OffsFirst)) as u8)
+    }
+
+    let mut start = 0;
+    while start < track.len() {
+        let end = start
+            + 1
+            + track[start + 1..]
+                .iter()
+                .take_while(|ev| ev.delta == 0)
+                .count();
+        let tick = &mut track[start..end];
+        let delta = tick[0].delta;
+        tick[0].delta = u28::new(0);
+        let slots = (0..tick.len())
+            .filter(|&idx| note_rank(&tick[idx].kind, order).is_some())
+            .collect::<Vec<_>>();
+        let mut notes = slots.iter().map(|&idx| tick[idx]).collect::<Vec<_>>();
+        notes.sort_by_key(|ev| note_rank(&ev.kind, order));
+        for (&idx, note) in slots.iter().zip(notes) {
+            tick[idx] = note;
+        }
+        tick[0].delta = delta;
+        start = end;
+    }
+}
+
+/// Which note to cut off when [`limit_polyphony`](fn.limit_polyphony.html) runs out of voices.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum VoiceSteal {
+    /// Release the note that started playing the earliest.
+    Oldest,
+    /// Release the note with the lowest velocity, or the oldest among the quietest notes.
+    ///
+    /// If the incoming note is quieter than every sounding note, the incoming note is dropped
+    /// instead.
+    Quietest,
+}
+
+/// Limit the amount of notes sounding at once on each channel of a track to `max_voices`, for
+/// synthesizers with a limited amount of voices.
+///
+/// Whenever a note would start while `max_voices` notes are already held on its channel, a note
+/// is stolen according to `steal`: a note off for the stolen note is inserted right before the
+/// new note on, and the original note off of the stolen note is removed.
+/// Striking a key that is already held on the same channel does not take a new voice.
+/// With `max_voices` set to zero, all notes are removed.
+///
+/// Note ons with zero velocity are considered note offs, and the sustain pedal is not taken into
+/// account.
+/// Inserted note offs use a release velocity of `64`.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn limit_polyphony(track: &mut Track, max_voices: usize, steal: VoiceSteal) {
+    #[derive(Copy, Clone)]
+    struct Voice {
+        key: u7,
+        vel: u7,
+    }
+
+    //Sounding notes per channel, oldest first
+    let mut sounding: [Vec<Voice>; 16] = Default::default();
+    //Amount of pending note offs to remove per channel and key
+    let mut orphaned = BTreeMap::<(u4, u7), usize>::new();
+    let mut out = Vec::with_capacity(track.len());
+    let mut carry = 0u64;
+    for ev in track.drain(..) {
+        let delta = split_gap(&mut out, ev.delta.as_int() as u64 + carry);
+        carry = 0;
+        let (channel, message) = match ev.kind {
+            TrackEventKind::Midi { channel, message } => (channel, message),
+            _ => {
+                out.push(TrackEvent { delta, ..ev });
+                continue;
+            }
+        };
+        let voices = &mut sounding[channel.as_int() as usize];
+        match message {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                if let Some(idx) = voices.iter().position(|voice| voice.key == key) {
+                    voices.remove(idx);
+                } else if voices.len() >= max_voices {
+                    let victim = match steal {
+                        _ if max_voices == 0 => None,
+                        VoiceSteal::Oldest => Some(0),
+                        VoiceSteal::Quietest => {
+                            let (idx, quietest) = voices
+                                .iter()
+                                .enumerate()
+                                .min_by_key(|&(idx, voice)| (voice.vel, idx))
+                                .expect("no sounding voices");
+                            if vel < quietest.vel {
+                                None
+                            } else {
+                                Some(idx)
+                            }
+                        }
+                    };
+                    match victim {
+                        Some(idx) => {
+                            let victim = voices.remove(idx);
+                            *orphaned.entry((channel, victim.key)).or_insert(0) += 1;
+                            out.push(TrackEvent {
+                                delta,
+                                kind: TrackEventKind::Midi {
+                                    channel,
+                                    message: MidiMessage::NoteOff {
+                                        key: victim.key,
+                                        vel: u7::new(64),
+                                    },
+                                },
+                            });
+                            out.push(TrackEvent {
+                                delta: u28::new(0),
+                                kind: ev.kind,
+                            });
+                            voices.push(Voice { key, vel });
+                            continue;
+                        }
+                        None => {
+                            //Drop the new note along with its note off
+                            *orphaned.entry((channel, key)).or_insert(0) += 1;
+                            carry = delta.as_int() as u64;
+                            continue;
+                        }
+                    }
+                }
+                voices.push(Voice { key, vel });
+            }
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                if let Some(idx) = voices.iter().position(|voice| voice.key == key) {
+                    voices.remove(idx);
+                } else if let Some(count) = orphaned.get_mut(&(channel, key)).filter(|c| **c > 0) {
+                    *count -= 1;
+                    carry = delta.as_int() as u64;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        out.push(TrackEvent { delta, ..ev });
+    }
+    *track = out;
+}
+
+/// Convert every channel aftertouch event in a track into a controller event on the same channel,
+/// carrying the pressure as the controller value.
+///
+/// Useful for synthesizers that ignore channel aftertouch, but respond to a controller such as
+/// the modulation wheel (controller 1) or expression (controller 11).
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn aftertouch_to_cc(track: &mut Track, controller: u7) {
+    for ev in track.iter_mut() {
+        if let TrackEventKind::Midi { message, .. } = &mut ev.kind {
+            if let MidiMessage::ChannelAftertouch { vel } = *message {
+                *message = MidiMessage::Controller {
+                    controller,
+                    value: vel,
+                };
+            }
+        }
+    }
+}
+
+/// Remove all pitch bend events from a track, keeping the timing of the remaining events intact.
+///
+/// If `reset` is `true`, a pitch bend event with the center value (`0x2000`) is inserted at the
+/// start of the track for every channel that had pitch bend events, in order to reset any pitch
+/// bend state left over by previous playback.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn flatten_pitch_bend(track: &mut Track, reset: bool) {
+    let mut bent = [false; 16];
+    retain_timed(track, |_idx, ev| match ev.kind {
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::PitchBend { .. },
+        } => {
+            bent[channel.as_int() as usize] = true;
+            false
+        }
+        _ => true,
+    });
+    if reset {
+        let resets = (0..16)
+            .filter(|&ch| bent[ch as usize])
+            .map(|ch| TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Midi {
+                    channel: u4::new(ch),
+                    message: MidiMessage::PitchBend {
+                        bend: PitchBend::mid_raw_value(),
+                    },
+                },
+            });
+        track.splice(0..0, resets);
+    }
+}
+
+/// Remove pitch bend events that can be approximated by a straight line between the bend events
+/// around them, keeping the timing of the remaining events intact.
+///
+/// The pitch bend curve of each channel is simplified separately using the
+/// Ramer-Douglas-Peucker algorithm: a bend event is removed if its value lies within `tolerance`
+/// (in raw pitch bend units, out of `0x3FFF`) of the linear interpolation between the kept bend
+/// events before and after it.
+/// The first and last bend events of each channel, as well as sharp changes, are always kept.
+///
+/// A `tolerance` of 0 only removes events that lie exactly on a line.
+/// Negative tolerances are treated as 0.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn thin_pitch_bend(track: &mut Track, tolerance: i16) {
+    let tolerance = tolerance.max(0) as f64;
+    //Gather the pitch bend curve of each channel as (time, value, index) points
+    let mut curves: [Vec<(u64, f64, usize)>; 16] = Default::default();
+    let mut time = 0u64;
+    for (idx, ev) in track.iter().enumerate() {
+        time += ev.delta.as_int() as u64;
+        if let TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::PitchBend { bend },
+        } = ev.kind
+        {
+            curves[channel.as_int() as usize].push((time, bend.as_int() as f64, idx));
+        }
+    }
+
+    let mut keep = vec![true; track.len()];
+    let mut ranges = Vec::new();
+    for curve in curves.iter().filter(|curve| curve.len() > 2) {
+        for &(_, _, idx) in &curve[1..curve.len() - 1] {
+            keep[idx] = false;
+        }
+        ranges.push((0, curve.len() - 1));
+        while let Some((start, end)) = ranges.pop() {
+            let (t0, v0, _) = curve[start];
+            let (t1, v1, _) = curve[end];
+            let mut furthest = None;
+            let mut max_dist = tolerance;
+            for (i, &(t, v, _)) in curve.iter().enumerate().take(end).skip(start + 1) {
+                let expected = if t1 > t0 {
+                    v0 + (v1 - v0) * (t - t0) as f64 / (t1 - t0) as f64
+                } else {
+                    v0
+                };
+                let dist = (v - expected).abs();
+                if dist > max_dist {
+                    max_dist = dist;
+                    furthest = Some(i);
+                }
+            }
+            if let Some(mid) = furthest {
+                keep[curve[mid].2] = true;
+                ranges.push((start, mid));
+                ranges.push((mid, end));
+            }
+        }
+    }
+    retain_timed(track, |idx, _| keep[idx]);
+}
+
+/// Merge the events of `overlay` into `base` by absolute time, so that both play simultaneously
+/// from a single track.
+///
+/// Both tracks are taken to start at tick 0.
+/// Simultaneous events are ordered according to [`sort_timeline`](fn.sort_timeline.html),
+/// with events from `base` placed first among events of the same priority.
+/// The resulting track is terminated by a single `EndOfTrack` event, placed at the latest end of
+/// both tracks.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn overlay_track<'a>(base: &mut Track<'a>, overlay: &[TrackEvent<'a>]) {
+    let events = merge_absolute([&base[..], overlay].iter().copied());
+    let end = events.last().map(|&(time, _)| time).unwrap_or(0);
+    let mut merged = from_absolute(events);
+    let last_time = merged
+        .iter()
+        .fold(0u32, |time, ev| time.saturating_add(ev.delta.as_int()));
+    end_at(&mut merged, last_time, end);
+    *base = merged;
+}
+
+/// Make sure that no note is struck again on the same channel while it is still held, starting
+/// from the absolute tick `from`.
+///
+/// Whenever a held note is struck again, a note off is inserted right before the new note on, and
+/// the next note off for that key is dropped, so that the amount of note ons and note offs stays
+/// balanced and no note is left hanging.
+pub(crate) fn resolve_retriggers(track: &mut Track, from: u32) {
+    let mut held = vec![0u32; 16 * 128];
+    let mut pending_offs = vec![0u32; 16 * 128];
+    let mut time = 0u32;
+    let mut events = Vec::with_capacity(track.len());
+    let mut dropped = Vec::with_capacity(track.len());
+    for &ev in track.iter() {
+        time = time.saturating_add(ev.delta.as_int());
+        let (channel, key, on) = match ev.kind {
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOn { key, vel },
+            } => (channel, key, vel > 0),
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOff { key, .. },
+            } => (channel, key, false),
+            _ => {
+                events.push(ev);
+                dropped.push(false);
+                continue;
+            }
+        };
+        let slot = channel.as_int() as usize * 128 + key.as_int() as usize;
+        if on {
+            if held[slot] > 0 && time >= from {
+                events.push(TrackEvent {
+                    delta: ev.delta,
+                    kind: TrackEventKind::Midi {
+                        channel,
+                        message: MidiMessage::NoteOff {
+                            key,
+                            vel: u7::new(64),
+                        },
+                    },
+                });
+                dropped.push(false);
+                events.push(TrackEvent {
+                    delta: u28::new(0),
+                    kind: ev.kind,
+                });
+                pending_offs[slot] += 1;
+            } else {
+                events.push(ev);
+                held[slot] += 1;
+            }
+            dropped.push(false);
+        } else if pending_offs[slot] > 0 {
+            pending_offs[slot] -= 1;
+            events.push(ev);
+            dropped.push(true);
+        } else {
+            held[slot] = held[slot].saturating_sub(1);
+            events.push(ev);
+            dropped.push(false);
+        }
+    }
+    *track = events;
+    retain_timed(track, |idx, _| !dropped[idx]);
+}
+
+/// Make every note of a track last at least `min_ticks`, delaying note offs that come too soon
+/// after their note on.
+///
+/// Note offs are paired with the earliest unmatched note on of the same channel and key, and note
+/// ons with zero velocity are considered note offs.
+/// A delayed note off is never moved past the next note on of the same channel and key, so that
+/// it does not cut the following note short.
+/// Other events keep their absolute time, and a trailing `EndOfTrack` event is pushed back if
+/// needed so that it stays last.
+///
+/// This function is only available with the `alloc` feature enabled.
+pub fn enforce_min_duration(track: &mut Track, min_ticks: u32) {
+    let note_of = |kind: &TrackEventKind| match *kind {
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOn { key, vel },
+        } => Some((
+            channel.as_int() as usize * 128 + key.as_int() as usize,
+            vel > 0,
+        )),
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOff { key, .. },
+        } => Some((
+            channel.as_int() as usize * 128 + key.as_int() as usize,
+            false,
+        )),
+        _ => None,
+    };
+
+    let mut times = Vec::with_capacity(track.len());
+    let mut time = 0u32;
+    for ev in track.iter() {
+        time = time.saturating_add(ev.delta.as_int());
+        times.push(time);
+    }
+    //Time of the next note on of the same channel and key after each event
+    let mut next_on = vec![u32::MAX; track.len()];
+    let mut upcoming = vec![u32::MAX; 16 * 128];
+    for (idx, ev) in track.iter().enumerate().rev() {
+        if let Some((slot, on)) = note_of(&ev.kind) {
+            next_on[idx] = upcoming[slot];
+            if on {
+                upcoming[slot] = times[idx];
+            }
+        }
+    }
+    //Start times of the held notes, per channel and key, oldest first
+    let mut held: Vec<VecDeque<u32>> = vec![VecDeque::new(); 16 * 128];
+    let mut moved = false;
+    for (idx, ev) in track.iter().enumerate() {
+        match note_of(&ev.kind) {
+            Some((slot, true)) => held[slot].push_back(times[idx]),
+            Some((slot, false)) => {
+                if let Some(start) = held[slot].pop_front() {
+                    let end = start.saturating_add(min_ticks).min(next_on[idx]);
+                    if end > times[idx] {
+                        times[idx] = end;
+                        moved = true;
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+    if !moved {
+        return;
+    }
+    if let Some(TrackEventKind::Meta(MetaMessage::EndOfTrack)) = track.last().map(|ev| ev.kind) {
+        let last = times.len() - 1;
+        times[last] = times.iter().copied().max().unwrap_or(0);
+    }
+
+    //Stable sort, so simultaneous events keep their original order
+    let mut events = track.drain(..).zip(times).collect::<Vec<_>>();
+    events.sort_by_key(|&(_, time)| time);
+    let mut last = 0;
+    for (ev, time) in events {
+        let delta = split_gap(track, (time - last) as u64);
+        last = time;
+        track.push(TrackEvent { delta, ..ev });
+    }
+}
@@ -1,5 +1,7 @@
 //! All sort of events and their parsers.
 
+#[cfg(feature = "alloc")]
+use crate::Arena;
 use crate::{
     live::{LiveEvent, SystemCommon},
     prelude::*,
@@ -55,6 +57,33 @@ impl<'a> TrackEvent<'a> {
         Ok(())
     }
 
+    /// Create an escape event carrying arbitrary raw bytes, intended to be sent as-is to the
+    /// synthesizer.
+    ///
+    /// The bytes are written as an `0xF7` status, followed by the length of the data as a
+    /// variable-length integer and the data itself, so they may contain any byte, including
+    /// status-like bytes such as `0xF0` or `0xF7`.
+    /// Escapes always cancel running status, so the next MIDI message written after an escape
+    /// always includes its status byte.
+    ///
+    /// Note that writing an escape whose data is 256MB or larger will fail.
+    #[inline]
+    pub fn escape(delta: u28, bytes: &'a [u8]) -> TrackEvent<'a> {
+        TrackEvent {
+            delta,
+            kind: TrackEventKind::Escape(bytes),
+        }
+    }
+
+    /// Compare the contents of two events, ignoring their delta times.
+    ///
+    /// The derived `PartialEq` implementation compares delta times too, which is usually not
+    /// desired when comparing the musical meaning of events.
+    #[inline]
+    pub fn kind_eq(&self, other: &TrackEvent) -> bool {
+        self.kind == other.kind
+    }
+
     /// Remove any lifetimed data from this event to create a `TrackEvent` with `'static`
     /// lifetime that can be stored and moved everywhere, solving borrow checker issues.
     ///
@@ -65,6 +94,20 @@ impl<'a> TrackEvent<'a> {
             kind: self.kind.to_static(),
         }
     }
+
+    /// Copy any lifetimed data from this event into the given arena, creating a `TrackEvent` that
+    /// borrows from the arena instead of the original data.
+    ///
+    /// Unlike [`to_static`](#method.to_static), no data is lost.
+    ///
+    /// This function is only available with the `alloc` feature enabled.
+    #[cfg(feature = "alloc")]
+    pub fn to_arena<'b>(&self, arena: &'b Arena) -> TrackEvent<'b> {
+        TrackEvent {
+            delta: self.delta,
+            kind: self.kind.to_arena(arena),
+        }
+    }
 }
 
 /// Represents the different kinds of SMF events and their associated data.
@@ -90,15 +133,24 @@ pub enum TrackEventKind<'a> {
     /// small packets may only contain the `0xF7` byte in the last packet fragment.
     SysEx(&'a [u8]),
     /// An escape sequence, intended to send arbitrary data to the MIDI synthesizer.
+    ///
+    /// Escapes are commonly used to embed system common or realtime messages (such as song
+    /// position pointers or MTC quarter frames) in a file.
+    /// Like SysEx and meta events, escape events always cancel running status when read or
+    /// written, whatever data they carry, so the next channel message always has an explicit
+    /// status byte.
     Escape(&'a [u8]),
     /// A meta-message, giving extra information for correct playback, like tempo, song name,
     /// lyrics, etc...
     Meta(MetaMessage<'a>),
 }
 impl<'a> TrackEventKind<'a> {
-    fn read(raw: &mut &'a [u8], running_status: &mut Option<u8>) -> Result<TrackEventKind<'a>> {
+    pub(crate) fn read(
+        raw: &mut &'a [u8],
+        running_status: &mut Option<u8>,
+    ) -> Result<TrackEventKind<'a>> {
         //Read status
-        let mut status = *raw.get(0).ok_or(err_invalid!("failed to read status"))?;
+        let mut status = *raw.first().ok_or(err_invalid!("failed to read status"))?;
         if status < 0x80 {
             //Running status!
             status = running_status.ok_or(err_invalid!(
@@ -151,7 +203,11 @@ impl<'a> TrackEventKind<'a> {
     /// `running_status` keeps track of the last MIDI status, in order to make proper use of
     /// running status. It should be shared between consecutive calls, and should initially be set
     /// to `None`.
-    fn write<W: Write>(&self, running_status: &mut Option<u8>, out: &mut W) -> WriteResult<W> {
+    pub(crate) fn write<W: Write>(
+        &self,
+        running_status: &mut Option<u8>,
+        out: &mut W,
+    ) -> WriteResult<W> {
         //Running Status rules:
         // - MIDI Messages (0x80 ..= 0xEF) alter and use running status
         // - System Exclusive (0xF0) cancels and cannot use running status
@@ -223,6 +279,204 @@ impl<'a> TrackEventKind<'a> {
             Meta(meta) => Meta(meta.to_static()),
         }
     }
+
+    /// Copy any lifetimed data from this event into the given arena, creating a `TrackEventKind`
+    /// that borrows from the arena instead of the original data.
+    ///
+    /// This function is only available with the `alloc` feature enabled.
+    #[cfg(feature = "alloc")]
+    pub fn to_arena<'b>(&self, arena: &'b Arena) -> TrackEventKind<'b> {
+        use self::TrackEventKind::*;
+        match *self {
+            Midi { channel, message } => Midi { channel, message },
+            SysEx(data) => SysEx(arena.add(data)),
+            Escape(data) => Escape(arena.add(data)),
+            Meta(meta) => Meta(meta.to_arena(arena)),
+        }
+    }
+}
+
+/// Compare two events placed at absolute tick positions, in the order they should appear on a
+/// timeline.
+///
+/// Events are primarily ordered by their absolute tick.
+/// Simultaneous events are ordered by their type, according to the following priorities:
+///
+/// 1. Meta messages (tempo, time signature, track names, etc...), so that they are in effect
+///    before any sound is produced.
+/// 2. System Exclusive messages and escape sequences, which usually set up the synthesizer.
+/// 3. Channel messages other than notes (program changes, controllers, pitch bends and
+///    aftertouch), so that they affect any notes starting at the same tick.
+/// 4. Note ons and note offs (including note ons with a velocity of zero).
+/// 5. `EndOfTrack` meta messages, which must always be the last event of a track.
+///
+/// Events with the same tick and priority compare as equal, so stable sorts keep their original
+/// relative order.
+/// In particular, simultaneous note offs and note ons are never swapped, since moving the note
+/// off of a zero-length note before its note on would leave the note stuck.
+/// Use [`sort_timeline`](fn.sort_timeline.html) to also release notes before striking new ones
+/// whenever it is safe to do so.
+pub fn compare_timeline(
+    a: (u32, &TrackEventKind),
+    b: (u32, &TrackEventKind),
+) -> core::cmp::Ordering {
+    fn priority(kind: &TrackEventKind) -> u8 {
+        match kind {
+            TrackEventKind::Meta(MetaMessage::EndOfTrack) => 4,
+            TrackEventKind::Meta(_) => 0,
+            TrackEventKind::SysEx(_) | TrackEventKind::Escape(_) => 1,
+            TrackEventKind::Midi { message, .. } => match message {
+                MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. } => 3,
+                _ => 2,
+            },
+        }
+    }
+    a.0.cmp(&b.0)
+        .then_with(|| priority(a.1).cmp(&priority(b.1)))
+}
+
+/// Sort events placed at absolute tick positions in the order they should appear on a timeline.
+///
+/// Events are first stably sorted according to
+/// [`compare_timeline`](fn.compare_timeline.html).
+/// Then, within every tick, note offs (including note ons with a velocity of zero) are moved
+/// before the note ons, so that a key that is released and struck again at the same tick is not
+/// cut short.
+/// A note off that ends a note started earlier in the same tick (ie. a zero-length note) is
+/// kept after its note on instead, so that the note is not left stuck.
+/// Note offs and note ons otherwise keep their relative order.
+///
+/// The merging and splitting helpers of this crate that combine several tracks by absolute
+/// time, such as [`Smf::scheduled`](struct.Smf.html#method.scheduled),
+/// [`Smf::split_by_channel`](struct.Smf.html#method.split_by_channel) or
+/// [`overlay_track`](fn.overlay_track.html), use this ordering.
+///
+/// This function is only available with the `alloc` feature enabled.
+#[cfg(feature = "alloc")]
+pub fn sort_timeline(events: &mut [(u32, TrackEventKind)]) {
+    events.sort_by(|a, b| compare_timeline((a.0, &a.1), (b.0, &b.1)));
+    let note_of = |kind: &TrackEventKind| match *kind {
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOn { key, vel },
+        } => Some((channel, key, vel > 0)),
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOff { key, .. },
+        } => Some((channel, key, false)),
+        _ => None,
+    };
+    let mut start = 0;
+    while start < events.len() {
+        let tick = events[start].0;
+        let len = events[start..]
+            .iter()
+            .take_while(|(time, kind)| *time == tick && note_of(kind).is_some())
+            .count();
+        if len == 0 {
+            start += 1;
+            continue;
+        }
+        //Note offs that do not end a note struck within this tick go first
+        let mut struck = Vec::new();
+        let mut notes = events[start..start + len]
+            .iter()
+            .map(|&ev| {
+                let (channel, key, on) = note_of(&ev.1).expect("not a note event");
+                if on {
+                    struck.push((channel, key));
+                    (1, ev)
+                } else if let Some(idx) = struck.iter().position(|&note| note == (channel, key)) {
+                    struck.remove(idx);
+                    (1, ev)
+                } else {
+                    (0, ev)
+                }
+            })
+            .collect::<Vec<_>>();
+        notes.sort_by_key(|&(rank, _)| rank);
+        for (slot, (_, ev)) in events[start..start + len].iter_mut().zip(notes) {
+            *slot = ev;
+        }
+        start += len;
+    }
+}
+
+/// The transmission priority of an event, for schedulers working with limited bandwidth (such
+/// as DIN MIDI outputs).
+///
+/// Higher values are more important.
+/// Events with priority [`ESSENTIAL_PRIORITY`](constant.ESSENTIAL_PRIORITY.html) should never be
+/// dropped: these are note ons, note offs, program changes, SysEx, escapes and meta messages.
+/// The rest of the events are, from most to least important: controllers and pitch bends,
+/// channel aftertouch and polyphonic aftertouch.
+pub fn event_priority(kind: &TrackEventKind) -> u8 {
+    match kind {
+        TrackEventKind::Midi { message, .. } => match message {
+            MidiMessage::NoteOn { .. }
+            | MidiMessage::NoteOff { .. }
+            | MidiMessage::ProgramChange { .. } => ESSENTIAL_PRIORITY,
+            MidiMessage::Controller { .. } | MidiMessage::PitchBend { .. } => 2,
+            MidiMessage::ChannelAftertouch { .. } => 1,
+            MidiMessage::Aftertouch { .. } => 0,
+        },
+        TrackEventKind::SysEx(_) | TrackEventKind::Escape(_) | TrackEventKind::Meta(_) => {
+            ESSENTIAL_PRIORITY
+        }
+    }
+}
+
+/// The priority of events that must never be dropped, as returned by
+/// [`event_priority`](fn.event_priority.html).
+pub const ESSENTIAL_PRIORITY: u8 = 3;
+
+/// A channel mode message, sent as one of the reserved controllers 120 to 127.
+///
+/// Use [`as_channel_mode`](fn.as_channel_mode.html) to decode these from controller events.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum ChannelMode {
+    /// Silence all sounding notes immediately, including release tails (controller 120).
+    AllSoundOff,
+    /// Reset all controllers to their default values (controller 121).
+    ResetAllControllers,
+    /// Connect or disconnect the instrument's keyboard from its sound generator (controller 122).
+    LocalControl(bool),
+    /// Release all notes, as if their note offs were received (controller 123).
+    AllNotesOff,
+    /// Respond only to messages on the basic channel (controller 124).
+    OmniOff,
+    /// Respond to messages on all channels (controller 125).
+    OmniOn,
+    /// Play monophonically (controller 126).
+    MonoOn {
+        /// The amount of channels to use, starting at the basic channel.
+        ///
+        /// A value of 0 means to use as many channels as the receiver has voices.
+        channels: u7,
+    },
+    /// Play polyphonically (controller 127).
+    PolyOn,
+}
+
+/// Decode a channel mode message out of a controller message.
+///
+/// Returns `None` for messages that are not controllers, or for controllers outside the reserved
+/// range 120 to 127.
+pub fn as_channel_mode(message: &MidiMessage) -> Option<ChannelMode> {
+    match *message {
+        MidiMessage::Controller { controller, value } => Some(match controller.as_int() {
+            120 => ChannelMode::AllSoundOff,
+            121 => ChannelMode::ResetAllControllers,
+            122 => ChannelMode::LocalControl(value.as_int() >= 64),
+            123 => ChannelMode::AllNotesOff,
+            124 => ChannelMode::OmniOff,
+            125 => ChannelMode::OmniOn,
+            126 => ChannelMode::MonoOn { channels: value },
+            127 => ChannelMode::PolyOn,
+            _ => return None,
+        }),
+        _ => None,
+    }
 }
 
 /// Represents a MIDI message, usually associated to a MIDI channel.
@@ -236,7 +490,10 @@ pub enum MidiMessage {
     NoteOff {
         /// The MIDI key to stop playing.
         key: u7,
-        /// The velocity with which to stop playing it.
+        /// The velocity with which to stop playing it, also known as release velocity.
+        ///
+        /// This value is kept as-is when reading and writing files, since some synthesizers use it
+        /// to shape the release of the note.
         vel: u7,
     },
     /// Start playing a note.
@@ -282,6 +539,18 @@ pub enum MidiMessage {
     },
 }
 impl MidiMessage {
+    /// Get the release velocity of a `NoteOff` message.
+    ///
+    /// Returns `None` for any other message, including `NoteOn` messages with zero velocity,
+    /// which carry no release velocity.
+    #[inline]
+    pub fn release_velocity(&self) -> Option<u7> {
+        match *self {
+            MidiMessage::NoteOff { vel, .. } => Some(vel),
+            _ => None,
+        }
+    }
+
     /// Midi messages have a known length.
     pub(crate) fn msg_length(status: u8) -> usize {
         const LENGTH_BY_STATUS: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2, 1, 1, 2, 0];
@@ -388,6 +657,7 @@ impl MidiMessage {
 /// A value of `0x3FFF` indicates full bend upwards.
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub struct PitchBend(pub u14);
+#[allow(clippy::manual_clamp)]
 impl PitchBend {
     /// The minimum value of `0x0000`, indicating full bend downwards.
     #[inline]
@@ -474,6 +744,9 @@ pub enum MetaMessage<'a> {
     /// Information about the name of the current program.
     ProgramName(&'a [u8]),
     /// Name of the device that this file was intended to be played with.
+    ///
+    /// Usually placed at the start of a track, to route the track to a specific output device in
+    /// setups with several devices, much like `MidiPort`.
     DeviceName(&'a [u8]),
     /// Number of the MIDI channel that this file was intended to be played with.
     MidiChannel(u4),
@@ -537,6 +810,69 @@ impl<'a> MetaMessage<'a> {
         }
     }
 
+    /// Copy any lifetimed data from this event into the given arena, creating a `MetaMessage`
+    /// that borrows from the arena instead of the original data.
+    ///
+    /// This function is only available with the `alloc` feature enabled.
+    #[cfg(feature = "alloc")]
+    pub fn to_arena<'b>(&self, arena: &'b Arena) -> MetaMessage<'b> {
+        use self::MetaMessage::*;
+        match *self {
+            TrackNumber(v) => TrackNumber(v),
+            Text(v) => Text(arena.add(v)),
+            Copyright(v) => Copyright(arena.add(v)),
+            TrackName(v) => TrackName(arena.add(v)),
+            InstrumentName(v) => InstrumentName(arena.add(v)),
+            Lyric(v) => Lyric(arena.add(v)),
+            Marker(v) => Marker(arena.add(v)),
+            CuePoint(v) => CuePoint(arena.add(v)),
+            ProgramName(v) => ProgramName(arena.add(v)),
+            DeviceName(v) => DeviceName(arena.add(v)),
+            MidiChannel(v) => MidiChannel(v),
+            MidiPort(v) => MidiPort(v),
+            EndOfTrack => EndOfTrack,
+            Tempo(v) => Tempo(v),
+            SmpteOffset(v) => SmpteOffset(v),
+            TimeSignature(v0, v1, v2, v3) => TimeSignature(v0, v1, v2, v3),
+            KeySignature(v0, v1) => KeySignature(v0, v1),
+            SequencerSpecific(v) => SequencerSpecific(arena.add(v)),
+            Unknown(v, data) => Unknown(v, arena.add(data)),
+        }
+    }
+
+    /// Get the raw text bytes of text-like meta messages (`Text`, `Copyright`, `TrackName`,
+    /// `InstrumentName`, `Lyric`, `Marker`, `CuePoint`, `ProgramName` and `DeviceName`).
+    ///
+    /// Returns `None` for any other meta message.
+    pub fn text_bytes(&self) -> Option<&'a [u8]> {
+        use self::MetaMessage::*;
+        match *self {
+            Text(text) | Copyright(text) | TrackName(text) | InstrumentName(text) | Lyric(text)
+            | Marker(text) | CuePoint(text) | ProgramName(text) | DeviceName(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// Decode the text of text-like meta messages as UTF-8, replacing any invalid sequences with
+    /// the replacement character.
+    ///
+    /// If `normalize_newlines` is `true`, any Windows-style `\r\n` line endings are converted into
+    /// plain `\n` line endings.
+    ///
+    /// Returns `None` if this is not a text-like meta message (see
+    /// [`text_bytes`](#method.text_bytes)).
+    ///
+    /// This method is only available with the `alloc` feature enabled.
+    #[cfg(feature = "alloc")]
+    pub fn decode_text(&self, normalize_newlines: bool) -> Option<Cow<'a, str>> {
+        let text = String::from_utf8_lossy(self.text_bytes()?);
+        if normalize_newlines && text.contains("\r\n") {
+            Some(Cow::Owned(text.replace("\r\n", "\n")))
+        } else {
+            Some(text)
+        }
+    }
+
     #[allow(clippy::len_zero)]
     fn read(raw: &mut &'a [u8]) -> Result<MetaMessage<'a>> {
         let type_byte = u8::read(raw).context(err_invalid!("failed to read meta message type"))?;
@@ -572,7 +908,7 @@ impl<'a> MetaMessage<'a> {
                 u8::read(&mut data)?,
                 u8::read(&mut data)?,
             ),
-            0x59 => {
+            0x59 if data.len() >= 2 => {
                 MetaMessage::KeySignature(u8::read(&mut data)? as i8, u8::read(&mut data)? != 0)
             }
             0x7F => MetaMessage::SequencerSpecific(data),
@@ -91,7 +91,7 @@ impl<W: Write> Seek for NotSeekable<W> {
     }
 }
 
-impl<'a, W: Write> Write for &'a mut W {
+impl<W: Write> Write for &mut W {
     type Error = W::Error;
     type Seekable = W::Seekable;
     #[inline]
@@ -153,7 +153,7 @@ pub struct Cursor<'a> {
 impl<'a> Cursor<'a> {
     /// Create a new cursor located at the start of the given buffer.
     #[inline]
-    pub fn new(buffer: &mut [u8]) -> Cursor {
+    pub fn new(buffer: &mut [u8]) -> Cursor<'_> {
         Cursor {
             buf: buffer,
             cur: 0,
@@ -166,7 +166,7 @@ impl<'a> Cursor<'a> {
     ///
     /// Panics if `cursor > buffer.len()`.
     #[inline]
-    pub fn from_parts(buffer: &mut [u8], cursor: usize) -> Cursor {
+    pub fn from_parts(buffer: &mut [u8], cursor: usize) -> Cursor<'_> {
         assert!(
             cursor <= buffer.len(),
             "cursor beyond the end of the buffer"
@@ -292,7 +292,7 @@ pub enum CursorError {
     /// The input SMF was invalid.
     InvalidInput(&'static str),
 }
-impl<'a> Write for &'a mut [u8] {
+impl Write for &mut [u8] {
     type Error = CursorError;
     type Seekable = NotSeekable<Self>;
     #[inline]
@@ -303,7 +303,7 @@ impl<'a> Write for &'a mut [u8] {
             Err(CursorError::OutOfSpace)
         } else {
             self[..buf.len()].copy_from_slice(buf);
-            let slice = mem::replace(self, &mut []);
+            let slice = mem::take(self);
             *self = &mut slice[buf.len()..];
             Ok(())
         }
@@ -314,6 +314,62 @@ impl<'a> Write for &'a mut [u8] {
     }
 }
 
+/// The errors that can arise when writing a MIDI file to a `midly::io::Write` writer, even in
+/// `no_std` environments.
+///
+/// Values that do not fit in the file format, such as more than 65535 tracks or a chunk of 4GB or
+/// larger, are reported separately from the errors of the underlying `E` writer.
+/// If the `std` feature is enabled, `WriteError<std::io::Error>` can be converted into a
+/// `std::io::Error`, so it can be propagated with `?` from functions returning `io::Result`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteError<E> {
+    /// A value, such as the length of a chunk, does not fit in its field.
+    OutOfRange,
+    /// The underlying writer failed.
+    Sink(E),
+}
+impl<E> WriteError<E> {
+    /// Convert the error of the underlying writer with the given function, keeping `OutOfRange`
+    /// as is.
+    #[inline]
+    pub fn map_sink<F>(self, f: impl FnOnce(E) -> F) -> WriteError<F> {
+        match self {
+            WriteError::OutOfRange => WriteError::OutOfRange,
+            WriteError::Sink(err) => WriteError::Sink(f(err)),
+        }
+    }
+}
+impl<E: fmt::Display> fmt::Display for WriteError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::OutOfRange => write!(f, "value out of range for the midi format"),
+            WriteError::Sink(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl<E: std::error::Error + 'static> std::error::Error for WriteError<E> {
+    #[inline]
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WriteError::OutOfRange => None,
+            WriteError::Sink(err) => Some(err),
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl From<WriteError<io::Error>> for io::Error {
+    fn from(err: WriteError<io::Error>) -> io::Error {
+        match err {
+            WriteError::OutOfRange => io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "value out of range for the midi format",
+            ),
+            WriteError::Sink(err) => err,
+        }
+    }
+}
+
 /// Bridge between a `midly::io::Write` type and a `std::io::Write` type.
 ///
 /// Always available, but only implements `midly::io::Write` when the `std` feature is enabled.
@@ -386,3 +442,126 @@ impl Write for WriteCounter {
         msg
     }
 }
+
+/// Forwards all writes to an inner writer, while counting the amount of bytes written.
+///
+/// This is handy to report progress or enforce size limits while writing large files.
+/// Wrapping a writer hides its seekability, so the written bytes are always produced strictly in
+/// order.
+///
+/// Available even when the `std` and `alloc` features are disabled.
+///
+/// ```rust
+/// # #[cfg(feature = "std")] {
+/// use midly::{io::CountingWriter, Smf};
+///
+/// let smf = Smf::parse(include_bytes!("../test-asset/Clementi.mid")).unwrap();
+/// let mut out = CountingWriter::new(Vec::new());
+/// smf.write(&mut out).unwrap();
+/// println!("wrote {} bytes", out.count());
+/// # assert_eq!(out.count(), out.get_ref().len() as u64);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+impl<W> CountingWriter<W> {
+    /// Wrap a writer, starting with a count of zero.
+    #[inline]
+    pub fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter { inner, count: 0 }
+    }
+
+    /// Get the amount of bytes written so far.
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Get a reference to the inner writer.
+    #[inline]
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Yield the inner writer.
+    #[inline]
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+impl<W: Write> Write for CountingWriter<W> {
+    type Error = W::Error;
+    type Seekable = NotSeekable<Self>;
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> WriteResult<Self> {
+        self.inner.write(buf)?;
+        self.count += buf.len() as u64;
+        Ok(())
+    }
+    #[inline]
+    fn invalid_input(msg: &'static str) -> W::Error {
+        W::invalid_input(msg)
+    }
+}
+
+/// Forwards all writes to an inner writer, while computing the CRC-32 checksum of the written
+/// bytes.
+///
+/// The checksum is the common IEEE 802.3 variant, as used by zip, PNG and most command-line
+/// `crc32` tools.
+/// Wrapping a writer hides its seekability, so the written bytes are always produced strictly in
+/// order.
+///
+/// Available even when the `std` and `alloc` features are disabled.
+#[derive(Debug)]
+pub struct Crc32Writer<W> {
+    inner: W,
+    crc: u32,
+}
+impl<W> Crc32Writer<W> {
+    /// Wrap a writer, starting with an empty checksum.
+    #[inline]
+    pub fn new(inner: W) -> Crc32Writer<W> {
+        Crc32Writer { inner, crc: !0 }
+    }
+
+    /// Get the CRC-32 checksum of all bytes written so far.
+    #[inline]
+    pub fn crc(&self) -> u32 {
+        !self.crc
+    }
+
+    /// Get a reference to the inner writer.
+    #[inline]
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Yield the inner writer.
+    #[inline]
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+impl<W: Write> Write for Crc32Writer<W> {
+    type Error = W::Error;
+    type Seekable = NotSeekable<Self>;
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> WriteResult<Self> {
+        self.inner.write(buf)?;
+        for &byte in buf {
+            self.crc ^= byte as u32;
+            for _ in 0..8 {
+                self.crc = (self.crc >> 1) ^ (0xEDB8_8320 & (self.crc & 1).wrapping_neg());
+            }
+        }
+        Ok(())
+    }
+    #[inline]
+    fn invalid_input(msg: &'static str) -> W::Error {
+        W::invalid_input(msg)
+    }
+}
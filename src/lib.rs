@@ -71,6 +71,23 @@
 //! # }
 //! ```
 //!
+//! To keep track of the amount of bytes written, wrap the writer in an
+//! [`io::CountingWriter`](io/struct.CountingWriter.html), or use the `Smf::write_counted`
+//! method:
+//!
+//! ```rust
+//! # #[cfg(feature = "std")] {
+//! # use std::fs;
+//! # use midly::Smf;
+//! # let bytes = fs::read("test-asset/Clementi.mid").unwrap();
+//! # let smf = Smf::parse(&bytes).unwrap();
+//! let mut in_memory = Vec::new();
+//! let written = smf.write_counted(&mut in_memory).unwrap();
+//!
+//! assert_eq!(written, smf.encoded_len().unwrap());
+//! # }
+//! ```
+//!
 //! # Parsing standalone MIDI messages
 //!
 //! When using an OS API such as [`midir`](https://docs.rs/midir),
@@ -162,6 +179,13 @@
 //!   By enabling the `strict` feature the parser will reject uncompliant data and do
 //!   additional checking, throwing errors of the kind
 //!   [`ErrorKind::Malformed`](enum.ErrorKind.html#variant.Malformed) when such a situation arises.
+//!
+//! - `base64`
+//!
+//!   This feature enables encoding and decoding whole files as base64 text through
+//!   [`Smf::to_base64`](struct.Smf.html#method.to_base64) and
+//!   [`Smf::from_base64`](struct.Smf.html#method.from_base64), which is useful to embed small
+//!   files in source code.
 
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![warn(missing_docs)]
@@ -192,11 +216,11 @@ mod prelude {
     pub(crate) use crate::io::IoWrap;
     pub(crate) use crate::{
         error::{ErrorKind, Result, ResultExt, StdResult},
-        io::{Seek, Write, WriteCounter, WriteResult},
+        io::{Seek, Write, WriteCounter, WriteError, WriteResult},
         primitive::{u14, u24, u28, u4, u7, IntRead, IntReadBottom7, SplitChecked},
     };
     #[cfg(feature = "alloc")]
-    pub(crate) use alloc::{boxed::Box, vec, vec::Vec};
+    pub(crate) use alloc::{borrow::Cow, boxed::Box, string::String, sync::Arc, vec, vec::Vec};
     pub(crate) use core::{convert::TryFrom, fmt, marker::PhantomData, mem};
     #[cfg(feature = "std")]
     pub(crate) use std::{fs::File, io, path::Path};
@@ -209,27 +233,55 @@ mod prelude {
     }
 }
 
+mod analysis;
 mod arena;
+mod base64;
+mod edit;
 mod event;
 pub mod io;
 pub mod live;
+mod patch;
 mod primitive;
 mod riff;
 mod smf;
 pub mod stream;
+mod xf;
 
 #[cfg(feature = "std")]
-pub use crate::smf::write_std;
+pub use crate::smf::{write_std, Duplicates, ParseMetrics};
 #[cfg(feature = "alloc")]
 pub use crate::{
+    analysis::{
+        chord_at, estimate_key, guess_ppq, notes, sysex_channels, sysex_messages, ChannelState,
+        ChordName, ChordQuality, Key, Note, NoteOverlap, SysExMessage,
+    },
     arena::Arena,
-    smf::{BytemappedTrack, Smf, SmfBytemap, Track},
+    edit::{
+        aftertouch_to_cc, drop_under_budget, enforce_min_duration, flat_map_events,
+        flatten_pitch_bend, limit_polyphony, map_channels, merge_conductors, normalize_note_offs,
+        order_chords, overlay_track, thin_pitch_bend, transpose, ChordOrder, ConflictPolicy,
+        NoteOffStyle, VoiceSteal,
+    },
+    event::sort_timeline,
+    patch::{PatchOp, SmfPatch},
+    smf::{
+        AnnotatedTrack, BytemappedTrack, FileLayout, FormatWarning, MidiBytes, SharedSmf, Smf,
+        SmfAnnotated, SmfBytemap, Track,
+    },
+    xf::XfInfo,
 };
 pub use crate::{
     error::{Error, ErrorKind, Result},
-    event::{MetaMessage, MidiMessage, PitchBend, TrackEvent, TrackEventKind},
-    primitive::{Format, Fps, SmpteTime, Timing},
-    smf::{parse, write, EventBytemapIter, EventIter, Header, TrackIter},
+    event::{
+        as_channel_mode, compare_timeline, event_priority, ChannelMode, MetaMessage, MidiMessage,
+        PitchBend, TrackEvent, TrackEventKind, ESSENTIAL_PRIORITY,
+    },
+    primitive::{Format, Fps, SmpteConverter, SmpteTime, Timing},
+    smf::{
+        parse, validate, write, write_header_chunk, write_track_chunk,
+        write_track_chunk_unbuffered, write_with_chunks, ChunkOverrun, ChunkPosition,
+        EventBeatIter, EventBytemapIter, EventIter, Header, ParseObserver, ParseOptions, TrackIter,
+    },
 };
 
 /// Exotically-sized integers used by the MIDI standard.
@@ -17,6 +17,14 @@
 use crate::{event::MidiMessage, prelude::*};
 #[cfg(feature = "alloc")]
 use crate::{event::TrackEventKind, Arena};
+#[cfg(feature = "std")]
+use crate::{
+    event::{MetaMessage, TrackEvent},
+    primitive::{u15, Format, Timing},
+    smf::{split_gap, Header, Smf, Track},
+};
+#[cfg(feature = "std")]
+use std::time::Instant;
 
 /// A live event produced by an OS API or generated on-the-fly, in contrast with "dead"
 /// [`TrackEvent`](../struct.TrackEvent.html)s stored in a `.mid` file.
@@ -60,7 +68,7 @@ impl<'a> LiveEvent<'a> {
         Self::read(status, data)
     }
 
-    pub(crate) fn read(status: u8, data: &[u7]) -> Result<LiveEvent> {
+    pub(crate) fn read(status: u8, data: &[u7]) -> Result<LiveEvent<'_>> {
         match status {
             0x80..=0xEF => {
                 // MIDI message
@@ -234,7 +242,7 @@ impl<'a> SystemCommon<'a> {
         let ev = match status {
             0xF0 => {
                 //SysEx
-                SystemCommon::SysEx(&data[..])
+                SystemCommon::SysEx(data)
             }
             0xF1 if data.len() >= 1 => {
                 //MTC Quarter Frame
@@ -259,7 +267,7 @@ impl<'a> SystemCommon<'a> {
             }
             0xF1..=0xF5 => {
                 //Unknown system common event
-                SystemCommon::Undefined(status, &data[..])
+                SystemCommon::Undefined(status, data)
             }
             _ => {
                 //Invalid/Unknown/Unreachable event
@@ -415,3 +423,101 @@ impl SystemRealtime {
         }
     }
 }
+
+/// Records timestamped live MIDI messages into a track, converting wall-clock time into ticks.
+///
+/// The first recorded message is placed at tick 0, and every later message is placed according
+/// to the time elapsed since the first one, at a fixed tempo and amount of ticks per beat.
+/// Tick positions are computed from the total elapsed time rather than from the time between
+/// consecutive messages, so rounding errors do not accumulate over long recordings.
+///
+/// ```rust
+/// use midly::{live::Recorder, MidiMessage};
+/// use std::time::{Duration, Instant};
+///
+/// let start = Instant::now();
+/// //120 BPM at 480 ticks per beat
+/// let mut recorder = Recorder::new(500_000.into(), 480.into());
+/// let note_on = MidiMessage::NoteOn { key: 60.into(), vel: 100.into() };
+/// recorder.record(start, 0.into(), note_on);
+/// recorder.record(start + Duration::from_millis(250), 0.into(), note_on);
+/// let smf = recorder.into_smf();
+/// assert_eq!(smf.tracks[0][2].delta, 240);
+/// ```
+///
+/// This type is only available with the `std` feature enabled.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    tempo: u24,
+    ppq: u15,
+    start: Option<Instant>,
+    last_tick: u64,
+    track: Track<'static>,
+}
+#[cfg(feature = "std")]
+impl Recorder {
+    /// Create a new recorder with the given tempo, in microseconds per beat, and ticks per beat.
+    ///
+    /// A `ppq` of `0` is treated as `1`, and a tempo of `0` is treated as `1` microsecond per
+    /// beat.
+    #[inline]
+    pub fn new(tempo: u24, ppq: u15) -> Recorder {
+        let tempo = tempo.max(u24::new(1));
+        Recorder {
+            tempo,
+            ppq: ppq.max(u15::new(1)),
+            start: None,
+            last_tick: 0,
+            track: vec![TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::Tempo(tempo)),
+            }],
+        }
+    }
+
+    /// Record a channel message received at the given instant.
+    ///
+    /// Messages must be recorded in order.
+    /// Messages timestamped before a previously recorded message are placed at the same tick as
+    /// that message.
+    pub fn record(&mut self, time: Instant, channel: u4, message: MidiMessage) {
+        let start = *self.start.get_or_insert(time);
+        let nanos = time.saturating_duration_since(start).as_nanos();
+        let nanos_per_beat = self.tempo.as_int() as u128 * 1000;
+        //Round to the nearest tick
+        let tick = (nanos * self.ppq.as_int() as u128 + nanos_per_beat / 2) / nanos_per_beat;
+        let tick = (tick.min(u64::MAX as u128) as u64).max(self.last_tick);
+        let delta = split_gap(&mut self.track, tick - self.last_tick);
+        self.last_tick = tick;
+        self.track.push(TrackEvent {
+            delta,
+            kind: TrackEventKind::Midi { channel, message },
+        });
+    }
+
+    /// Get the events recorded so far, preceded by a tempo meta message.
+    #[inline]
+    pub fn events(&self) -> &[TrackEvent<'static>] {
+        &self.track
+    }
+
+    /// Finish recording, yielding the recorded track terminated by an `EndOfTrack` event.
+    ///
+    /// The track starts with a tempo meta message for the tempo of the recorder.
+    pub fn into_track(mut self) -> Track<'static> {
+        self.track.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        self.track
+    }
+
+    /// Finish recording, yielding a `Format::SingleTrack` file holding the recorded track.
+    pub fn into_smf(self) -> Smf<'static> {
+        let header = Header::new(Format::SingleTrack, Timing::Metrical(self.ppq));
+        let mut smf = Smf::new(header);
+        smf.tracks.push(self.into_track());
+        smf
+    }
+}
@@ -0,0 +1,510 @@
+//! Compact, transmittable differences between two files.
+
+#![cfg(feature = "alloc")]
+
+use crate::{
+    event::{TrackEvent, TrackEventKind},
+    prelude::*,
+    primitive::{Format, Timing},
+    smf::{Header, Smf, Track},
+};
+
+/// The magic bytes at the start of an encoded patch.
+const PATCH_MAGIC: &[u8; 4] = b"MPch";
+
+/// The largest amount of event pairs to compare when diffing the changed region of a track.
+///
+/// Beyond this size, the changed region is replaced wholesale instead of searching for common
+/// events, to keep memory usage bounded.
+const DIFF_CELL_LIMIT: usize = 1 << 22;
+
+/// A single edit within an [`SmfPatch`](struct.SmfPatch.html).
+///
+/// Track and event indices refer to the state of the file at the moment the operation is applied,
+/// that is, after all of the previous operations in the patch have been applied.
+/// Ticks are absolute, and serve both to place inserted events and as anchors to check that the
+/// patch is being applied over the file it was made for.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum PatchOp<'a> {
+    /// Insert an empty track at the given track index.
+    InsertTrack {
+        /// The index the new track will have.
+        track: usize,
+    },
+    /// Remove the track at the given track index, along with all of its events.
+    RemoveTrack {
+        /// The index of the removed track.
+        track: usize,
+    },
+    /// Insert an event at the given position of a track.
+    InsertEvent {
+        /// The index of the track to insert into.
+        track: usize,
+        /// The index the new event will have within the track.
+        index: usize,
+        /// The absolute tick of the new event.
+        tick: u64,
+        /// The new event.
+        kind: TrackEventKind<'a>,
+    },
+    /// Remove the event at the given position of a track.
+    RemoveEvent {
+        /// The index of the track to remove from.
+        track: usize,
+        /// The index of the removed event within the track.
+        index: usize,
+        /// The absolute tick the removed event is expected to be at.
+        tick: u64,
+    },
+    /// Replace the contents of an event, keeping it at the same tick.
+    ChangeEvent {
+        /// The index of the track containing the event.
+        track: usize,
+        /// The index of the event within the track.
+        index: usize,
+        /// The absolute tick the event is expected to be at.
+        tick: u64,
+        /// The replacement event.
+        kind: TrackEventKind<'a>,
+    },
+}
+
+/// A set of edits that turns one MIDI file into another, which can be applied, encoded into a
+/// compact binary form and decoded back.
+///
+/// Useful to transmit small edits to a large file, for example when syncing a collaborative
+/// editor.
+/// Like [`Smf`](struct.Smf.html), decoded patches borrow any bytestrings from the encoded data.
+///
+/// ```rust
+/// use midly::{MetaMessage, Smf, SmfPatch, TrackEvent, TrackEventKind};
+///
+/// let base = Smf::parse(include_bytes!("../test-asset/Clementi.mid")).unwrap();
+/// let mut target = base.clone();
+/// target.tracks[1].insert(0, TrackEvent {
+///     delta: 0.into(),
+///     kind: TrackEventKind::Meta(MetaMessage::Marker(b"Intro")),
+/// });
+///
+/// //Encode the edits and send them over
+/// let mut encoded = Vec::new();
+/// SmfPatch::diff(&base, &target).write(&mut encoded).unwrap();
+///
+/// //Decode the edits and apply them on the other side
+/// let mut synced = base.clone();
+/// SmfPatch::parse(&encoded).unwrap().apply(&mut synced).unwrap();
+/// assert_eq!(synced, target);
+/// ```
+///
+/// This type is only available with the `alloc` feature enabled.
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Default)]
+pub struct SmfPatch<'a> {
+    /// The new header of the file, if it changed.
+    pub header: Option<Header>,
+    /// The edits to apply to the tracks of the file, in order.
+    pub ops: Vec<PatchOp<'a>>,
+}
+impl<'a> SmfPatch<'a> {
+    /// Compute the edits that turn `base` into `target`.
+    ///
+    /// Tracks are compared pairwise by index, with extra tracks inserted or removed at the end.
+    /// Within a track, the smallest set of event insertions and removals is searched for, and a
+    /// removal and insertion at the same tick are merged into a single change.
+    /// Events are compared by their absolute tick and contents, so moving an event to another
+    /// tick is recorded as a removal and an insertion.
+    pub fn diff(base: &Smf, target: &Smf<'a>) -> SmfPatch<'a> {
+        let mut ops = Vec::new();
+        let shared = base.tracks.len().min(target.tracks.len());
+        for track in 0..shared {
+            diff_track(
+                track,
+                &absolute(&base.tracks[track]),
+                &absolute(&target.tracks[track]),
+                &mut ops,
+            );
+        }
+        for track in shared..target.tracks.len() {
+            ops.push(PatchOp::InsertTrack { track });
+            for (index, (tick, kind)) in absolute(&target.tracks[track]).into_iter().enumerate() {
+                ops.push(PatchOp::InsertEvent {
+                    track,
+                    index,
+                    tick,
+                    kind,
+                });
+            }
+        }
+        for track in (shared..base.tracks.len()).rev() {
+            ops.push(PatchOp::RemoveTrack { track });
+        }
+        SmfPatch {
+            header: Some(target.header).filter(|header| *header != base.header),
+            ops,
+        }
+    }
+
+    /// Whether this patch leaves any file untouched.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.header.is_none() && self.ops.is_empty()
+    }
+
+    /// Apply the edits in this patch to the given file.
+    ///
+    /// Fails if an operation refers to a track or event that does not exist, if a removed or
+    /// changed event is not at its anchor tick, or if an insertion would place an event out of
+    /// order.
+    /// These usually mean the patch is being applied to a different file than the one it was
+    /// made from.
+    /// On failure, the file is left untouched.
+    pub fn apply(&self, base: &mut Smf<'a>) -> Result<()> {
+        let mut tracks: Vec<Vec<(u64, TrackEventKind<'a>)>> =
+            base.tracks.iter().map(|track| absolute(track)).collect();
+        for op in self.ops.iter() {
+            match *op {
+                PatchOp::InsertTrack { track } => {
+                    ensure!(
+                        track <= tracks.len(),
+                        err_invalid!("patch inserts a track out of range")
+                    );
+                    tracks.insert(track, Vec::new());
+                }
+                PatchOp::RemoveTrack { track } => {
+                    ensure!(
+                        track < tracks.len(),
+                        err_invalid!("patch removes a track out of range")
+                    );
+                    tracks.remove(track);
+                }
+                PatchOp::InsertEvent {
+                    track,
+                    index,
+                    tick,
+                    kind,
+                } => {
+                    let events = tracks
+                        .get_mut(track)
+                        .ok_or(err_invalid!("patch refers to a track out of range"))?;
+                    ensure!(
+                        index <= events.len(),
+                        err_invalid!("patch inserts an event out of range")
+                    );
+                    events.insert(index, (tick, kind));
+                }
+                PatchOp::RemoveEvent { track, index, tick } => {
+                    anchored(&tracks, track, index, tick)?;
+                    tracks[track].remove(index);
+                }
+                PatchOp::ChangeEvent {
+                    track,
+                    index,
+                    tick,
+                    kind,
+                } => {
+                    anchored(&tracks, track, index, tick)?;
+                    tracks[track][index].1 = kind;
+                }
+            }
+        }
+        let tracks = tracks
+            .into_iter()
+            .map(|events| relative(&events))
+            .collect::<Result<Vec<Track<'a>>>>()?;
+        if let Some(header) = self.header {
+            base.header = header;
+        }
+        base.tracks = tracks;
+        Ok(())
+    }
+
+    /// Decode a patch from its binary form, as produced by [`write`](#method.write).
+    ///
+    /// Any bytestrings within the events of the patch borrow from the input.
+    pub fn parse(raw: &'a [u8]) -> Result<SmfPatch<'a>> {
+        let raw = &mut &raw[..];
+        ensure!(
+            raw.split_checked(4) == Some(&PATCH_MAGIC[..]),
+            err_invalid!("not a midi patch")
+        );
+        let header = match raw.split_checked(1) {
+            Some([0]) => None,
+            Some([1]) => Some(Header::new(
+                Format::read(raw).context(err_invalid!("failed to read patch format"))?,
+                Timing::read(raw).context(err_invalid!("failed to read patch timing"))?,
+            )),
+            _ => bail!(err_invalid!("invalid patch header")),
+        };
+        let count = read_varint(raw)?;
+        let mut ops = Vec::new();
+        for _ in 0..count {
+            let tag = *raw
+                .split_checked(1)
+                .ok_or(err_invalid!("failed to read patch operation"))?
+                .first()
+                .unwrap();
+            let track = read_varint(raw)? as usize;
+            ops.push(match tag {
+                0 => PatchOp::InsertTrack { track },
+                1 => PatchOp::RemoveTrack { track },
+                2 => PatchOp::InsertEvent {
+                    track,
+                    index: read_varint(raw)? as usize,
+                    tick: read_varint(raw)?,
+                    kind: TrackEventKind::read(raw, &mut None)
+                        .context(err_invalid!("failed to read patch event"))?,
+                },
+                3 => PatchOp::RemoveEvent {
+                    track,
+                    index: read_varint(raw)? as usize,
+                    tick: read_varint(raw)?,
+                },
+                4 => PatchOp::ChangeEvent {
+                    track,
+                    index: read_varint(raw)? as usize,
+                    tick: read_varint(raw)?,
+                    kind: TrackEventKind::read(raw, &mut None)
+                        .context(err_invalid!("failed to read patch event"))?,
+                },
+                _ => bail!(err_invalid!("unknown patch operation")),
+            });
+        }
+        ensure!(raw.is_empty(), err_invalid!("trailing data after patch"));
+        Ok(SmfPatch { header, ops })
+    }
+
+    /// Encode this patch into its compact binary form.
+    ///
+    /// Indices and ticks are stored as variable-length integers and events are stored as they
+    /// would be in a file, without running status, so small edits to large files produce small
+    /// patches.
+    pub fn write<W: Write>(&self, out: &mut W) -> WriteResult<W> {
+        out.write(PATCH_MAGIC)?;
+        match self.header {
+            None => out.write(&[0])?,
+            Some(header) => {
+                out.write(&[1])?;
+                out.write(&header.format.encode())?;
+                out.write(&header.timing.encode())?;
+            }
+        }
+        write_varint(self.ops.len() as u64, out)?;
+        for op in self.ops.iter() {
+            let (tag, track) = match *op {
+                PatchOp::InsertTrack { track } => (0, track),
+                PatchOp::RemoveTrack { track } => (1, track),
+                PatchOp::InsertEvent { track, .. } => (2, track),
+                PatchOp::RemoveEvent { track, .. } => (3, track),
+                PatchOp::ChangeEvent { track, .. } => (4, track),
+            };
+            out.write(&[tag])?;
+            write_varint(track as u64, out)?;
+            match op {
+                PatchOp::InsertTrack { .. } | PatchOp::RemoveTrack { .. } => {}
+                PatchOp::RemoveEvent { index, tick, .. } => {
+                    write_varint(*index as u64, out)?;
+                    write_varint(*tick, out)?;
+                }
+                PatchOp::InsertEvent {
+                    index, tick, kind, ..
+                }
+                | PatchOp::ChangeEvent {
+                    index, tick, kind, ..
+                } => {
+                    write_varint(*index as u64, out)?;
+                    write_varint(*tick, out)?;
+                    kind.write(&mut None, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert a track into a list of events with absolute ticks.
+fn absolute<'a>(track: &[TrackEvent<'a>]) -> Vec<(u64, TrackEventKind<'a>)> {
+    let mut tick = 0;
+    track
+        .iter()
+        .map(|ev| {
+            tick += u64::from(ev.delta.as_int());
+            (tick, ev.kind)
+        })
+        .collect()
+}
+
+/// Convert a list of events with absolute ticks back into a track, failing if the events are out
+/// of order or too far apart.
+fn relative<'a>(events: &[(u64, TrackEventKind<'a>)]) -> Result<Track<'a>> {
+    let mut last = 0;
+    events
+        .iter()
+        .map(|&(tick, kind)| {
+            let delta = tick
+                .checked_sub(last)
+                .ok_or(err_invalid!("patch places events out of order"))?;
+            let delta = u32::try_from(delta)
+                .ok()
+                .and_then(u28::try_from)
+                .ok_or(err_invalid!("patch places events too far apart"))?;
+            last = tick;
+            Ok(TrackEvent { delta, kind })
+        })
+        .collect()
+}
+
+/// Check that the given event exists and is at the given tick.
+fn anchored(
+    tracks: &[Vec<(u64, TrackEventKind)>],
+    track: usize,
+    index: usize,
+    tick: u64,
+) -> Result<()> {
+    let events = tracks
+        .get(track)
+        .ok_or(err_invalid!("patch refers to a track out of range"))?;
+    let &(actual, _) = events
+        .get(index)
+        .ok_or(err_invalid!("patch refers to an event out of range"))?;
+    ensure!(
+        actual == tick,
+        err_invalid!("patch anchor does not match event tick")
+    );
+    Ok(())
+}
+
+/// Push the edits that turn the absolute events `a` into `b` into `ops`.
+fn diff_track<'a>(
+    track: usize,
+    a: &[(u64, TrackEventKind)],
+    b: &[(u64, TrackEventKind<'a>)],
+    ops: &mut Vec<PatchOp<'a>>,
+) {
+    //Skip the common prefix and suffix, where most of the file usually lies
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+
+    //Longest common subsequence over the changed region
+    let (n, m) = (a.len(), b.len());
+    let mut lcs = Vec::new();
+    if n.saturating_mul(m) <= DIFF_CELL_LIMIT {
+        lcs = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i * (m + 1) + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * (m + 1) + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+                };
+            }
+        }
+    }
+
+    //Walk the alignment, flushing the removals and insertions between common events
+    let at = |i: usize, j: usize| lcs.get(i * (m + 1) + j).copied().unwrap_or(0);
+    let mut index = prefix;
+    let (mut i, mut j) = (0, 0);
+    let (mut removed, mut inserted) = (Vec::new(), Vec::new());
+    loop {
+        let matched = i < n && j < m && !lcs.is_empty() && a[i] == b[j];
+        if matched || (i == n && j == m) {
+            flush_gap(track, &mut index, &removed, &inserted, ops);
+            removed.clear();
+            inserted.clear();
+            if !matched {
+                break;
+            }
+            index += 1;
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || at(i + 1, j) >= at(i, j + 1)) {
+            removed.push(a[i].0);
+            i += 1;
+        } else {
+            inserted.push(b[j]);
+            j += 1;
+        }
+    }
+}
+
+/// Push the edits that replace the removed events (given by their tick) with the inserted events,
+/// at the given index.
+fn flush_gap<'a>(
+    track: usize,
+    index: &mut usize,
+    removed: &[u64],
+    inserted: &[(u64, TrackEventKind<'a>)],
+    ops: &mut Vec<PatchOp<'a>>,
+) {
+    for k in 0..removed.len().max(inserted.len()) {
+        match (removed.get(k), inserted.get(k)) {
+            (Some(&old), Some(&(tick, kind))) if old == tick => {
+                ops.push(PatchOp::ChangeEvent {
+                    track,
+                    index: *index,
+                    tick,
+                    kind,
+                });
+                *index += 1;
+            }
+            (old, new) => {
+                if let Some(&tick) = old {
+                    ops.push(PatchOp::RemoveEvent {
+                        track,
+                        index: *index,
+                        tick,
+                    });
+                }
+                if let Some(&(tick, kind)) = new {
+                    ops.push(PatchOp::InsertEvent {
+                        track,
+                        index: *index,
+                        tick,
+                        kind,
+                    });
+                    *index += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Read a variable-length integer of up to 64 bits, stored big-endian in groups of 7 bits like
+/// the delta times of a file.
+fn read_varint(raw: &mut &[u8]) -> Result<u64> {
+    let mut int = 0u64;
+    for _ in 0..10 {
+        let byte = *raw
+            .split_checked(1)
+            .ok_or(err_invalid!("failed to read patch integer"))?
+            .first()
+            .unwrap();
+        int = (int << 7) | u64::from(byte & 0x7F);
+        if byte & 0x80 == 0 {
+            return Ok(int);
+        }
+    }
+    bail!(err_invalid!("patch integer too long"))
+}
+
+/// Write a variable-length integer in the format read by `read_varint`.
+fn write_varint<W: Write>(int: u64, out: &mut W) -> WriteResult<W> {
+    let mut buf = [0; 10];
+    let mut start = buf.len() - 1;
+    buf[start] = (int & 0x7F) as u8;
+    let mut rest = int >> 7;
+    while rest != 0 {
+        start -= 1;
+        buf[start] = (rest & 0x7F) as u8 | 0x80;
+        rest >>= 7;
+    }
+    out.write(&buf[start..])
+}
@@ -153,8 +153,8 @@ macro_rules! restricted_int {
             /// Get the inner integer out of the wrapper.
             /// The inner integer is guaranteed to be in range of the restricted wrapper.
             #[inline]
-            pub fn as_int(self) -> $inner {
-                Into::into(self)
+            pub const fn as_int(self) -> $inner {
+                self.0
             }
 
             /// Cast a slice of raw integers to a slice of restricted integers, only if there are
@@ -417,7 +417,7 @@ pub(crate) fn read_varlen_slice<'a>(raw: &mut &'a [u8]) -> Result<&'a [u8]> {
             if cfg!(feature = "strict") {
                 bail!(err_malformed!("incomplete varlen slice"))
             } else {
-                mem::replace(raw, &[])
+                mem::take(raw)
             }
         }
     })
@@ -435,6 +435,9 @@ pub(crate) fn write_varlen_slice<W: Write>(slice: &[u8], out: &mut W) -> WriteRe
 }
 
 /// The order in which tracks should be laid out when playing back this SMF file.
+///
+/// Files that declare a format other than 0, 1 or 2 are read as `Format::Parallel`, the most
+/// general of the three, unless the `strict` feature is enabled, in which case they are rejected.
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub enum Format {
     /// This file should have a single track only.
@@ -459,7 +462,8 @@ impl Format {
             0 => Format::SingleTrack,
             1 => Format::Parallel,
             2 => Format::Sequential,
-            _ => bail!(err_invalid!("invalid smf format")),
+            _ if cfg!(feature = "strict") => bail!(err_invalid!("invalid smf format")),
+            _ => Format::Parallel,
         })
     }
 
@@ -487,31 +491,66 @@ pub enum Timing {
     Timecode(Fps, u8),
 }
 impl Timing {
-    pub(crate) fn read(raw: &mut &[u8]) -> Result<Timing> {
-        let raw =
-            u16::read(raw).context(err_invalid!("unexpected eof when reading midi timing"))?;
-        if bit_range!(raw, 15..16) != 0 {
+    /// The ticks per beat used by default constructors, `480`.
+    ///
+    /// This is a common resolution among sequencers, fine enough for most purposes.
+    pub const DEFAULT_TICKS_PER_BEAT: u16 = 480;
+
+    /// Decode the 16-bit division field of an `MThd` header chunk, as a native integer.
+    ///
+    /// If the top bit is clear, the remaining 15 bits hold the amount of ticks per beat.
+    /// If it is set, the top byte holds the negated frames per second as a two's complement
+    /// integer (ie. `-24`, `-25`, `-29` or `-30`), and the bottom byte holds the amount of
+    /// subframes per frame.
+    ///
+    /// Fails if the division is timecode with an invalid frame rate.
+    pub fn from_division_u16(division: u16) -> Result<Timing> {
+        if bit_range!(division, 15..16) != 0 {
             //Timecode
-            let fps = -(bit_range!(raw, 8..16) as i8);
-            let subframe = bit_range!(raw, 0..8) as u8;
+            let fps = -(bit_range!(division, 8..16) as i8);
+            let subframe = bit_range!(division, 0..8) as u8;
             Ok(Timing::Timecode(
                 Fps::from_int(fps as u8).ok_or(err_invalid!("invalid smpte fps"))?,
                 subframe,
             ))
         } else {
             //Metrical
-            Ok(Timing::Metrical(u15::from(raw)))
+            Ok(Timing::Metrical(u15::from(division)))
         }
     }
 
-    pub(crate) fn encode(&self) -> [u8; 2] {
+    /// Encode this timing as the 16-bit division field of an `MThd` header chunk, as a native
+    /// integer.
+    ///
+    /// See [`from_division_u16`](#method.from_division_u16) for details on the encoding.
+    pub fn to_division_u16(&self) -> u16 {
         match self {
-            Timing::Metrical(ticksperbeat) => ticksperbeat.as_int().to_be_bytes(),
+            Timing::Metrical(ticksperbeat) => ticksperbeat.as_int(),
             Timing::Timecode(framespersec, ticksperframe) => {
-                [(-(framespersec.as_int() as i8)) as u8, *ticksperframe]
+                let fps = (-(framespersec.as_int() as i8)) as u8;
+                u16::from_be_bytes([fps, *ticksperframe])
             }
         }
     }
+
+    pub(crate) fn read(raw: &mut &[u8]) -> Result<Timing> {
+        let raw =
+            u16::read(raw).context(err_invalid!("unexpected eof when reading midi timing"))?;
+        Timing::from_division_u16(raw)
+    }
+
+    pub(crate) fn encode(&self) -> [u8; 2] {
+        self.to_division_u16().to_be_bytes()
+    }
+}
+
+/// The default timing is metrical, with
+/// [`Timing::DEFAULT_TICKS_PER_BEAT`](#associatedconstant.DEFAULT_TICKS_PER_BEAT) ticks per beat.
+impl Default for Timing {
+    #[inline]
+    fn default() -> Timing {
+        Timing::Metrical(u15::new(Timing::DEFAULT_TICKS_PER_BEAT))
+    }
 }
 
 /// A timestamp encoding an SMPTE time of the day.
@@ -521,7 +560,7 @@ impl Timing {
 /// - `hour` is inside [0,23]
 /// - `minute` is inside [0,59]
 /// - `second` is inside [0,59]
-/// - `frame` is inside [0,fps[
+/// - `frame` is inside [0,fps[ (where `Fps29` has 30 frame labels per second)
 /// - `subframe` is inside [0,99]
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub struct SmpteTime {
@@ -553,7 +592,7 @@ impl SmpteTime {
         check!(hour < 24);
         check!(minute < 60);
         check!(second < 60);
-        check!(frame < fps.as_int());
+        check!(frame < fps.nominal());
         check!(subframe < 100);
         Some(SmpteTime {
             hour,
@@ -676,7 +715,7 @@ impl Fps {
 
     /// Converts an integer representing the semantic fps to an `Fps` value (ie. `24` -> `Fps24`).
     #[inline]
-    pub fn from_int(raw: u8) -> Option<Fps> {
+    pub const fn from_int(raw: u8) -> Option<Fps> {
         Some(match raw {
             24 => Fps::Fps24,
             25 => Fps::Fps25,
@@ -688,7 +727,7 @@ impl Fps {
 
     /// Get the integral approximate fps out.
     #[inline]
-    pub fn as_int(self) -> u8 {
+    pub const fn as_int(self) -> u8 {
         match self {
             Fps::Fps24 => 24,
             Fps::Fps25 => 25,
@@ -697,6 +736,14 @@ impl Fps {
         }
     }
 
+    /// The amount of frame labels per second, which is `30` for `Fps29`.
+    pub(crate) const fn nominal(self) -> u8 {
+        match self {
+            Fps::Fps29 => 30,
+            fps => fps.as_int(),
+        }
+    }
+
     /// Get the actual `f32` fps out.
     #[inline]
     pub fn as_f32(self) -> f32 {
@@ -719,3 +766,105 @@ impl From<Fps> for u8 {
         x.as_int()
     }
 }
+
+/// Converts between absolute tick counts and SMPTE timecodes, for files using
+/// [`Timing::Timecode`](enum.Timing.html#variant.Timecode).
+///
+/// With timecode timing, a tick is a fixed fraction of a frame, so ticks map directly to frame
+/// counts.
+/// For [`Fps::Fps29`](enum.Fps.html#variant.Fps29), frames are labeled using drop-frame
+/// timecode: frame numbers 0 and 1 are skipped at the start of every minute, except for every
+/// tenth minute, so that the timecode stays in sync with wall-clock time.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct SmpteConverter {
+    fps: Fps,
+    subframes: u8,
+}
+impl SmpteConverter {
+    /// Frames in ten minutes of drop-frame timecode.
+    const DROP_FRAMES_PER_10_MINUTES: u64 = 10 * 60 * 30 - 9 * 2;
+    /// Frames in a minute of drop-frame timecode that is not a multiple of ten.
+    const DROP_FRAMES_PER_MINUTE: u64 = 60 * 30 - 2;
+
+    /// Create a converter for the given frame rate and amount of ticks per frame.
+    ///
+    /// Since a frame cannot be split into zero ticks, a `subframes` value of `0` is treated as `1`.
+    #[inline]
+    pub fn new(fps: Fps, subframes: u8) -> SmpteConverter {
+        SmpteConverter {
+            fps,
+            subframes: subframes.max(1),
+        }
+    }
+
+    /// Create a converter from the timing of a file header.
+    ///
+    /// Returns `None` if the timing is not `Timing::Timecode`.
+    #[inline]
+    pub fn from_timing(timing: Timing) -> Option<SmpteConverter> {
+        match timing {
+            Timing::Timecode(fps, subframes) => Some(SmpteConverter::new(fps, subframes)),
+            Timing::Metrical(_) => None,
+        }
+    }
+
+    /// Convert an absolute tick count into a timecode.
+    ///
+    /// The remainder of the tick within its frame is expressed as the subframe field of the
+    /// timecode, in hundredths of a frame (rounded down).
+    /// Returns `None` if the tick lies 24 hours or more after the start of the file.
+    pub fn to_timecode(&self, tick: u64) -> Option<SmpteTime> {
+        let subframes = self.subframes as u64;
+        let mut frames = tick / subframes;
+        let subframe = (tick % subframes * 100 / subframes) as u8;
+        if self.fps == Fps::Fps29 {
+            //Add back the dropped frame labels
+            let tens = frames / Self::DROP_FRAMES_PER_10_MINUTES;
+            let rem = frames % Self::DROP_FRAMES_PER_10_MINUTES;
+            frames += 18 * tens;
+            if rem >= 2 {
+                frames += 2 * ((rem - 2) / Self::DROP_FRAMES_PER_MINUTE);
+            }
+        }
+        let nominal = self.fps.nominal() as u64;
+        let seconds = frames / nominal;
+        if seconds >= 24 * 60 * 60 {
+            return None;
+        }
+        SmpteTime::new(
+            (seconds / 3600) as u8,
+            (seconds / 60 % 60) as u8,
+            (seconds % 60) as u8,
+            (frames % nominal) as u8,
+            subframe,
+            self.fps,
+        )
+    }
+
+    /// Convert a timecode into an absolute tick count.
+    ///
+    /// The frame rate stored in the timecode is ignored in favor of the converter's own.
+    /// This yields the first tick that [`to_timecode`](#method.to_timecode) maps to the given
+    /// timecode.
+    /// With up to 100 ticks per frame every tick has its own timecode, so this is the exact
+    /// inverse of `to_timecode`.
+    /// With finer resolutions several ticks share a subframe field, so converting a tick into a
+    /// timecode and back may yield an earlier tick within the same hundredth of a frame.
+    /// When using drop-frame timecode, dropped frame labels are treated as the frame that follows
+    /// them.
+    pub fn from_timecode(&self, tc: SmpteTime) -> u64 {
+        let nominal = self.fps.nominal() as u64;
+        let minutes = tc.hour() as u64 * 60 + tc.minute() as u64;
+        let mut frames = (minutes * 60 + tc.second() as u64) * nominal + tc.frame() as u64;
+        if self.fps == Fps::Fps29 {
+            let dropped = 2 * (minutes - minutes / 10);
+            let label_skipped = tc.second() == 0 && tc.frame() < 2 && minutes % 10 != 0;
+            frames -= dropped;
+            if label_skipped {
+                frames += 2 - tc.frame() as u64;
+            }
+        }
+        let subframes = self.subframes as u64;
+        frames * subframes + (tc.subframe() as u64 * subframes).div_ceil(100)
+    }
+}
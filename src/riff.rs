@@ -17,9 +17,11 @@ impl<'a> Iterator for ChunkIter<'a> {
             let len = u32::from_le_bytes(len);
             let data = match self.0.split_checked(len as usize) {
                 Some(data) => data,
-                None => mem::replace(&mut self.0, &[]),
+                None => mem::take(&mut self.0),
             };
-            if len % 2 == 1 {
+            //Some writers leave out the padding byte, in which case the next chunk id starts
+            //right away
+            if len % 2 == 1 && self.0.first() == Some(&0) {
                 let _pad = self.0.split_checked(1);
             }
             Some((id, data))
@@ -29,23 +31,69 @@ impl<'a> Iterator for ChunkIter<'a> {
     }
 }
 
-pub fn unwrap(raw: &[u8]) -> Result<&[u8]> {
-    let (id, mut riff) = ChunkIter(raw)
-        .next()
-        .ok_or(err_invalid!("no main riff chunk"))?;
-    if &id != b"RIFF" {
-        bail!(err_invalid!("invalid main riff chunk"));
+/// Whether the given bytes could be the id of a RIFF chunk.
+fn is_chunk_id(id: &[u8]) -> bool {
+    id.len() >= 4 && id[..4].iter().all(|&b| (0x20..0x7F).contains(&b))
+}
+
+/// Find the length of the SMF at the start of the given bytes, by walking over its `MThd` and
+/// `MTrk` chunks until the first chunk with any other id.
+///
+/// Chunks that are longer than the remaining input extend to the end of the input.
+fn smf_len(data: &[u8]) -> usize {
+    let mut len = 0;
+    while let Some(head) = data.get(len..len + 8) {
+        if &head[..4] != b"MThd" && &head[..4] != b"MTrk" {
+            break;
+        }
+        let chunk_len = u32::from_be_bytes([head[4], head[5], head[6], head[7]]) as usize;
+        len = len.saturating_add(8).saturating_add(chunk_len);
     }
+    len.min(data.len())
+}
+
+/// Strip the RIFF wrapping around an RMID file.
+///
+/// Broken RMID files are common, so this is lenient:
+/// - The size of the main RIFF chunk is ignored, and the rest of the input is used instead.
+/// - Chunks that claim to be longer than the remaining input are cut short.
+/// - Missing padding after odd-sized chunks is tolerated.
+/// - If the `data` chunk is not followed by a valid chunk, its declared size is assumed to be
+///   wrong, and the SMF is taken to span the `MThd` and `MTrk` chunks that follow the start of
+///   the `data` chunk, up to any trailing RIFF chunk.
+/// - If there is no `data` chunk, but an `MThd` chunk shows up in the RIFF body, the SMF is
+///   assumed to start there, and is delimited in the same way.
+pub fn unwrap(raw: &[u8]) -> Result<&[u8]> {
+    let mut riff = match raw.get(..8) {
+        Some(header) if &header[..4] == b"RIFF" => &raw[8..],
+        Some(_) => bail!(err_invalid!("invalid main riff chunk")),
+        None => bail!(err_invalid!("no main riff chunk")),
+    };
     let formtype = riff
         .split_checked(4)
         .ok_or(err_invalid!("failed to read riff formtype"))?;
     if formtype != b"RMID" {
         bail!(err_invalid!("not an rmid riff file"));
     }
-    for (id, chunk) in ChunkIter(riff) {
+    let mut chunks = ChunkIter(riff);
+    while let Some((id, chunk)) = chunks.next() {
         if &id == b"data" {
-            return Ok(chunk);
+            let rest = chunks.0;
+            //A data chunk that is too short may stop right before a track chunk
+            if rest.is_empty() || (is_chunk_id(rest) && !rest.starts_with(b"MTrk")) {
+                return Ok(chunk);
+            } else {
+                let start = chunk.as_ptr() as usize - riff.as_ptr() as usize;
+                let smf = &riff[start..];
+                return Ok(&smf[..smf_len(smf)]);
+            }
+        }
+    }
+    match riff.windows(4).position(|id| id == b"MThd") {
+        Some(start) => {
+            let smf = &riff[start..];
+            Ok(&smf[..smf_len(smf)])
         }
+        None => bail!(err_invalid!("no rmid data chunk")),
     }
-    bail!(err_invalid!("no rmid data chunk"))
 }
@@ -1,11 +1,34 @@
 //! Specific to the SMF packaging of MIDI streams.
 
+#[cfg(feature = "base64")]
+use crate::base64;
+#[cfg(feature = "alloc")]
+use crate::{
+    analysis::{self, ChannelState, Key, NoteOverlap},
+    edit::{self, NoteOffStyle},
+    event::{sort_timeline, MetaMessage, MidiMessage, TrackEventKind},
+    primitive::Fps,
+};
 use crate::{
     event::TrackEvent,
     prelude::*,
-    primitive::{Format, Timing},
+    primitive::{u15, Format, Timing},
     riff,
 };
+#[cfg(feature = "alloc")]
+use crate::{
+    io::{CountingWriter, Crc32Writer},
+    Arena,
+};
+#[cfg(feature = "alloc")]
+use alloc::collections::{BTreeSet, BinaryHeap};
+#[cfg(feature = "alloc")]
+use core::cmp::Reverse;
+#[cfg(feature = "std")]
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
 /// How many events per byte to estimate when allocating memory for events while parsing.
 ///
@@ -41,7 +64,7 @@ const EVENTS_TO_BYTES: f32 = 3.4;
 /// How many bytes must a MIDI body have in order to enable multithreading.
 ///
 /// When writing, the MIDI body size is estimated from the event count using `BYTES_PER_EVENT`.
-#[cfg(feature = "parallel")]
+/// When parsing, this is only the default, see `ParseOptions::parallel_bytes`.
 const PARALLEL_ENABLE_THRESHOLD: usize = 3 * 1024;
 
 /// A single track: simply a list of track events.
@@ -65,8 +88,30 @@ pub struct Smf<'a> {
     /// Each track consists simply of a list of events (ie. there is no track metadata).
     pub tracks: Vec<Track<'a>>,
 }
+/// The default `Smf` has no tracks and a default `Header`.
+#[cfg(feature = "alloc")]
+impl<'a> Default for Smf<'a> {
+    #[inline]
+    fn default() -> Smf<'a> {
+        Smf::new(Header::default())
+    }
+}
+#[cfg(feature = "alloc")]
+impl<'a> TryFrom<&'a [u8]> for Smf<'a> {
+    type Error = crate::Error;
+    /// Equivalent to [`Smf::parse`](#method.parse).
+    #[inline]
+    fn try_from(raw: &'a [u8]) -> Result<Smf<'a>> {
+        Smf::parse(raw)
+    }
+}
 #[cfg(feature = "alloc")]
 impl<'a> Smf<'a> {
+    /// The ticks per beat used by [`retime_to_seconds`](#method.retime_to_seconds), `5000`.
+    ///
+    /// At the default tempo of 120 beats per minute, this makes every tick last 100 microseconds.
+    pub const RETIMED_TICKS_PER_BEAT: u16 = 5000;
+
     /// Create a new empty `Smf` with zero tracks, using the given header.
     #[inline]
     pub fn new(header: Header) -> Smf<'a> {
@@ -76,9 +121,53 @@ impl<'a> Smf<'a> {
         }
     }
 
+    /// Build a file out of a single timeline of events, each paired with its absolute tick.
+    ///
+    /// Events are sorted by tick, keeping the given order for simultaneous events.
+    /// For `Format::Parallel` headers the timeline is split into one track per MIDI channel, as
+    /// done by [`split_by_channel`](#method.split_by_channel).
+    /// Otherwise, the whole timeline is kept as a single track.
+    /// Any `EndOfTrack` events in the timeline are dropped, and every track is terminated by a
+    /// single `EndOfTrack` event right after its last event.
+    pub fn from_timeline(header: Header, mut events: Vec<(u32, TrackEventKind<'a>)>) -> Smf<'a> {
+        events.sort_by_key(|&(time, _)| time);
+        let smf = Smf {
+            header,
+            tracks: vec![from_absolute(events)],
+        };
+        match header.format {
+            Format::Parallel => smf.split_by_channel(None),
+            _ => smf,
+        }
+    }
+
+    /// Set the format of this file, making sure that it agrees with the amount of tracks.
+    ///
+    /// Setting `Format::SingleTrack` on a file with more than one track is rejected with an
+    /// invalid input error, leaving the file untouched.
+    /// See [`Header::set_timing`](struct.Header.html#method.set_timing) to change the timing.
+    pub fn set_format(&mut self, format: Format) -> Result<()> {
+        ensure!(
+            format != Format::SingleTrack || self.tracks.len() <= 1,
+            err_invalid!("singletrack format with multiple tracks")
+        );
+        self.header.format = format;
+        Ok(())
+    }
+
+    /// Use metrical timing with the given amount of ticks per beat (also known as PPQ, pulses
+    /// per quarter note) for this file.
+    ///
+    /// See [`Header::with_ppq`](struct.Header.html#method.with_ppq) for details.
+    #[inline]
+    pub fn with_ppq(mut self, ppq: u15) -> Smf<'a> {
+        self.header = self.header.with_ppq(ppq);
+        self
+    }
+
     /// Parse a `.mid` Standard Midi File from its raw bytes.
     /// If you casually want to parse `.mid` files, this is the function you're looking for.
-    pub fn parse(raw: &[u8]) -> Result<Smf> {
+    pub fn parse(raw: &[u8]) -> Result<Smf<'_>> {
         let (header, tracks) = parse(raw)?;
         let track_count_hint = tracks.track_count_hint;
         let tracks = tracks.collect_tracks()?;
@@ -86,180 +175,2951 @@ impl<'a> Smf<'a> {
         Ok(Smf { header, tracks })
     }
 
-    /// Encodes and writes the file to the given generic writer.
+    /// Parse a `.mid` Standard Midi File from its raw bytes, like [`parse`](#method.parse), but
+    /// using the given options.
+    pub fn parse_with_options(raw: &[u8], options: ParseOptions) -> Result<Smf<'_>> {
+        let (header, tracks) = parse(raw)?;
+        let track_count_hint = tracks.track_count_hint;
+        let tracks = tracks.with_options(options).collect_tracks()?;
+        validate_smf(&header, track_count_hint, tracks.len())?;
+        Ok(Smf { header, tracks })
+    }
+
+    /// Parse a `.mid` Standard Midi File from its raw bytes, like [`parse`](#method.parse), while
+    /// reporting any recoverable issues found in the file to the given observer.
     ///
-    /// Note that this function requires a `midly::io::Write` writer, not a `std::io::Write` writer.
-    /// This makes it possible to support `no_std` environments, as well as custom writer errors.
-    /// If you're looking to write to a `File`, see the [`save`](#method.save) method.
-    /// If you're looking to write to a `std::io::Write` writer, see the
-    /// [`write_std`](#method.write_std) method.
+    /// Problems that are silently skipped over by `parse` (such as unknown chunks or tracks cut
+    /// short by malformed data) are reported through the callbacks of
+    /// [`ParseObserver`](trait.ParseObserver.html), in the order in which they appear in the
+    /// file.
+    /// The resulting file is the same as the one produced by `parse`.
     ///
-    /// This function is always available, even in `no_std` environments.
-    #[inline]
-    pub fn write<W: Write>(&self, out: &mut W) -> WriteResult<W> {
-        write(&self.header, &self.tracks, out)
+    /// With the `strict` feature enabled, most issues are errors, and therefore never reach the
+    /// observer.
+    /// Unknown chunks are always allowed, and are always reported.
+    pub fn parse_observed<'r>(raw: &'r [u8], observer: &mut dyn ParseObserver) -> Result<Smf<'r>> {
+        Smf::parse_chunks(raw, observer, None, ParseOptions::default())
     }
 
-    /// Encodes and writes the file to the given `std::io::Write` writer.
+    /// Parse a `.mid` Standard Midi File from its raw bytes, like
+    /// [`parse_observed`](#method.parse_observed), while also keeping any unknown chunks.
     ///
-    /// This function is similar to the [`write`](#method.write) method, but writes to a
-    /// `std::io::Write` writer instead of a `midly::io::Write` writer.
+    /// The identifier and data of each unknown chunk are returned in file order, ready to be
+    /// written back with [`write_with_chunks`](fn.write_with_chunks.html).
+    /// At most [`ParseOptions::max_unknown_chunk_bytes`](struct.ParseOptions.html) bytes of chunk
+    /// data are kept in total, so that untrusted files cannot make the caller retain arbitrarily
+    /// large amounts of data.
+    /// Unknown chunks that would exceed this cap are dropped and reported through
+    /// [`ParseObserver::on_dropped_chunk`](trait.ParseObserver.html#method.on_dropped_chunk).
+    ///
+    /// Tracks are parsed sequentially, so the parallel parsing options are ignored, but chunks that
+    /// overrun the end of the file are handled according to
+    /// [`ParseOptions::on_chunk_overrun`](struct.ParseOptions.html#structfield.on_chunk_overrun).
+    #[allow(clippy::type_complexity)]
+    pub fn parse_preserving<'r>(
+        raw: &'r [u8],
+        options: ParseOptions,
+        observer: &mut dyn ParseObserver,
+    ) -> Result<(Smf<'r>, Vec<([u8; 4], &'r [u8])>)> {
+        let mut chunks = Vec::new();
+        let smf = Smf::parse_chunks(
+            raw,
+            observer,
+            Some((&mut chunks, options.max_unknown_chunk_bytes)),
+            options,
+        )?;
+        Ok((smf, chunks))
+    }
+
+    /// Parse a file chunk by chunk, reporting issues to the observer and optionally keeping
+    /// unknown chunks up to the given total amount of bytes.
+    #[allow(clippy::type_complexity)]
+    fn parse_chunks<'r>(
+        raw: &'r [u8],
+        observer: &mut dyn ParseObserver,
+        mut preserve: Option<(&mut Vec<([u8; 4], &'r [u8])>, usize)>,
+        options: ParseOptions,
+    ) -> Result<Smf<'r>> {
+        let (header, tracks) = parse(raw)?;
+        let smf_raw = match raw.get(..4) {
+            Some(b"RIFF") => riff::unwrap(raw)?,
+            _ => raw,
+        };
+        if let Some(&[hi, lo]) = smf_raw.get(8..10) {
+            let format = u16::from_be_bytes([hi, lo]);
+            if format > 2 {
+                observer.on_unknown_format(format);
+            }
+        }
+        let track_count_hint = tracks.track_count_hint;
+        let mut rest = tracks.unread();
+        let mut tracks = Vec::new();
+        while !rest.is_empty() {
+            let start = rest.as_ptr() as usize - raw.as_ptr() as usize;
+            let chunk = rest
+                .split_checked(4)
+                .and_then(|id| Some((id, u32::read(&mut rest).ok()?)));
+            let (id, len) = match chunk {
+                Some(chunk) => chunk,
+                None => {
+                    ensure!(!cfg!(feature = "strict"), err_malformed!("invalid chunk"));
+                    observer.on_invalid_chunk(start);
+                    break;
+                }
+            };
+            let (data, cut_short) = match rest.split_checked(len as usize) {
+                Some(data) => (data, false),
+                None => match options.on_chunk_overrun {
+                    ChunkOverrun::UseRemainder => (mem::take(&mut rest), true),
+                    ChunkOverrun::Error => bail!(err_malformed!("reached eof before chunk ended")),
+                    ChunkOverrun::TruncateToDeclared => {
+                        observer.on_invalid_chunk(start);
+                        break;
+                    }
+                },
+            };
+            match id {
+                b"MTrk" => {
+                    let mut events = EventIter::new(data);
+                    events.inner.max_payload_len = options.max_payload_len;
+                    let mut track = Vec::new();
+                    let mut end = 0;
+                    while let Some(ev) = events.next() {
+                        track.push(ev?);
+                        end = events.offset();
+                    }
+                    if cut_short || end < data.len() {
+                        observer.on_truncated_track(tracks.len(), start + 8 + end);
+                    }
+                    tracks.push(track);
+                }
+                b"MThd" => {
+                    ensure!(
+                        !cfg!(feature = "strict"),
+                        err_malformed!("found duplicate header")
+                    );
+                    if Header::read(data).is_err() {
+                        //A malformed header stops parsing altogether, like in `parse`
+                        observer.on_invalid_chunk(start);
+                        break;
+                    }
+                    observer.on_duplicate_header(start);
+                }
+                _ => {
+                    let mut chunk_id = [0; 4];
+                    chunk_id.copy_from_slice(id);
+                    observer.on_skipped_chunk(chunk_id, start);
+                    if let Some((chunks, budget)) = preserve.as_mut() {
+                        if data.len() <= *budget {
+                            *budget -= data.len();
+                            chunks.push((chunk_id, data));
+                        } else {
+                            observer.on_dropped_chunk(chunk_id, start, data.len());
+                        }
+                    }
+                }
+            }
+        }
+        validate_smf(&header, track_count_hint, tracks.len())?;
+        Ok(Smf { header, tracks })
+    }
+
+    /// Parse a `.mid` Standard Midi File from its raw bytes, like [`parse`](#method.parse), while
+    /// also gathering some statistics about the parsing process.
+    ///
+    /// This is useful for profiling, for example to find out whether multithreaded parsing
+    /// kicked in for a given file.
     ///
     /// This function is only available with the `std` feature enabled.
     #[cfg(feature = "std")]
-    #[inline]
-    pub fn write_std<W: io::Write>(&self, out: W) -> io::Result<()> {
-        write_std(&self.header, &self.tracks, out)
+    pub fn parse_with_metrics(raw: &[u8]) -> Result<(Smf<'_>, ParseMetrics)> {
+        let start = Instant::now();
+        let (header, tracks) = parse(raw)?;
+        let track_count_hint = tracks.track_count_hint;
+        let parallel = tracks.use_parallel();
+        let header_time = start.elapsed();
+
+        let start = Instant::now();
+        let tracks = tracks.collect_tracks()?;
+        let track_time = start.elapsed();
+        validate_smf(&header, track_count_hint, tracks.len())?;
+
+        let (parallel_tracks, serial_tracks) = if parallel {
+            (tracks.len(), 0)
+        } else {
+            (0, tracks.len())
+        };
+        let metrics = ParseMetrics {
+            bytes: raw.len(),
+            parallel_tracks,
+            serial_tracks,
+            header_time,
+            track_time,
+        };
+        Ok((Smf { header, tracks }, metrics))
     }
 
-    /// Encodes and writes the file to the given path.
+    /// Parse a single track out of a seekable Standard Midi File source, without decoding any of
+    /// the other tracks.
+    ///
+    /// The header is read first, and then track chunks prior to the requested track are skipped
+    /// by seeking past them using their length fields.
+    /// The bytes of the requested track are stored in the given arena, and the parsed events
+    /// borrow from it.
+    ///
+    /// Unknown chunks are skipped and do not count towards the track index.
+    /// RIFF-wrapped (`.rmi`) files are not supported by this method.
+    ///
+    /// MIDI errors are reported as `std::io::Error`s of kind `InvalidData`.
     ///
     /// This function is only available with the `std` feature enabled.
     #[cfg(feature = "std")]
-    #[inline]
-    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
-        /// A non-generic, non-inline function.
-        /// This means that this function will be compiled and monomorphized once, and reused for
-        /// every call to `save`.
-        fn save_impl(smf: &Smf, path: &Path) -> io::Result<()> {
-            smf.write(&mut IoWrap(File::create(path)?))
+    pub fn parse_track<R: io::Read + io::Seek>(
+        mut reader: R,
+        index: usize,
+        arena: &'a Arena,
+    ) -> io::Result<Track<'a>> {
+        let (id, len) = read_chunk_head(&mut reader)?;
+        if &id != b"MThd" {
+            return Err(invalid_data(err_invalid!("not a midi file")));
+        }
+        Header::read(&read_chunk_data(&mut reader, len)?).map_err(invalid_data)?;
+        let mut track_idx = 0;
+        loop {
+            let (id, len) = match read_chunk_head(&mut reader) {
+                Ok(head) => head,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(invalid_data(err_invalid!("track index out of range")));
+                }
+                Err(err) => return Err(err),
+            };
+            if &id == b"MTrk" {
+                if track_idx == index {
+                    let data = arena.add_vec(read_chunk_data(&mut reader, len)?);
+                    return EventIter::new(data).into_vec().map_err(invalid_data);
+                }
+                track_idx += 1;
+            }
+            reader.seek(io::SeekFrom::Current(len as i64))?;
         }
-        save_impl(self, path.as_ref())
     }
 
-    /// Remove any lifetimed data from this event to create an `Smf` with `'static`
-    /// lifetime that can be stored and moved everywhere, solving borrow checker issues.
+    /// Append events to the tracks of an existing file on disk, patching the track chunks in place
+    /// instead of rewriting the whole file.
     ///
-    /// This method creates a copy of the `Smf` structure. See the `make_static` method for an
-    /// in-place solution.
+    /// The `n`-th element of `new_events` is appended to the `n`-th track of the file, before its
+    /// `EndOfTrack` event.
+    /// The delta time of the first appended event is relative to the last event of the track
+    /// (excluding `EndOfTrack`), and any time between that event and the original `EndOfTrack` is
+    /// kept.
+    /// `EndOfTrack` events within `new_events` are ignored, as a single one is always written at
+    /// the end of each track.
     ///
-    /// WARNING: Any bytestrings, including meta messages, SysEx dumps and escape sequences will be
-    /// replaced by empty bytestrings.
-    pub fn to_static(&self) -> Smf<'static> {
-        self.clone().make_static()
-    }
-
-    /// Remove any lifetimed data from this event to create an `Smf` with `'static`
-    /// lifetime that can be stored and moved everywhere, solving borrow checker issues.
+    /// Appending to the last track of the file only touches the end of the file, which makes this
+    /// method suitable for long-running recorders.
+    /// Appending to any other track requires shifting all of the data that follows it.
     ///
-    /// This method consumes the `Smf` structure, reusing the backing memory.
+    /// Fails with `std::io::ErrorKind::InvalidInput` if `new_events` holds more tracks than the
+    /// file, and with `std::io::ErrorKind::InvalidData` if the file is not a valid MIDI file.
+    /// RIFF-wrapped (`.rmi`) files are not supported by this method.
     ///
-    /// WARNING: Any bytestrings, including meta messages, SysEx dumps and escape sequences will be
-    /// replaced by empty bytestrings.
-    pub fn make_static(mut self) -> Smf<'static> {
-        for track in self.tracks.iter_mut() {
-            for ev in track.iter_mut() {
-                *ev = ev.to_static();
+    /// This function is only available with the `std` feature enabled.
+    #[cfg(feature = "std")]
+    pub fn append_to_file<P: AsRef<Path>>(new_events: &[Track], path: P) -> io::Result<()> {
+        /// A non-generic, non-inline function.
+        fn append_impl(new_events: &[Track], path: &Path) -> io::Result<()> {
+            use std::io::{Read, Seek, SeekFrom, Write};
+
+            let mut file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)?;
+            let (id, len) = read_chunk_head(&mut file)?;
+            if &id != b"MThd" {
+                return Err(invalid_data(err_invalid!("not a midi file")));
+            }
+            Header::read(&read_chunk_data(&mut file, len)?).map_err(invalid_data)?;
+
+            //Locate the data of every track chunk that will be appended to
+            let mut chunks = Vec::with_capacity(new_events.len());
+            while chunks.len() < new_events.len() {
+                let (id, len) = match read_chunk_head(&mut file) {
+                    Ok(head) => head,
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "file has less tracks than the tracks to append",
+                        ));
+                    }
+                    Err(err) => return Err(err),
+                };
+                let start = file.stream_position()?;
+                if &id == b"MTrk" {
+                    chunks.push((start, len));
+                }
+                file.seek(SeekFrom::Start(start + len as u64))?;
+            }
+
+            //Patch tracks from back to front, so that shifting the data after a chunk does not
+            //move chunks that have not been patched yet
+            let file_len = file.seek(SeekFrom::End(0))?;
+            for (&(start, len), events) in chunks.iter().zip(new_events).rev() {
+                if events.is_empty() {
+                    continue;
+                }
+                file.seek(SeekFrom::Start(start))?;
+                let mut data = read_chunk_data(&mut file, len)?;
+                let mut tail = Vec::new();
+                file.read_to_end(&mut tail)?;
+
+                //Strip the trailing `EndOfTrack`, keeping its delta time
+                let mut end = 0;
+                let mut carry = 0;
+                let mut iter = EventIter::new(&data);
+                loop {
+                    match iter.next() {
+                        Some(Ok(ev)) => {
+                            if let TrackEventKind::Meta(MetaMessage::EndOfTrack) = ev.kind {
+                                carry = ev.delta.as_int();
+                                break;
+                            }
+                            end = iter.offset();
+                        }
+                        Some(Err(err)) => return Err(invalid_data(err)),
+                        None => break,
+                    }
+                }
+                data.truncate(end);
+
+                let mut running_status = None;
+                let mut write_ev = |delta: u32, kind| {
+                    let ev = TrackEvent {
+                        delta: u28::new(delta.min(u28::max_value().as_int())),
+                        kind,
+                    };
+                    ev.write(&mut running_status, &mut data)
+                        .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))
+                };
+                for ev in events.iter() {
+                    carry = carry.saturating_add(ev.delta.as_int());
+                    if let TrackEventKind::Meta(MetaMessage::EndOfTrack) = ev.kind {
+                        continue;
+                    }
+                    write_ev(carry, ev.kind)?;
+                    carry = 0;
+                }
+                write_ev(carry, TrackEventKind::Meta(MetaMessage::EndOfTrack))?;
+                let new_len = u32::try_from(data.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "track too long"))?;
+
+                file.seek(SeekFrom::Start(start - 4))?;
+                file.write_all(&new_len.to_be_bytes())?;
+                file.write_all(&data)?;
+                file.write_all(&tail)?;
+            }
+            let new_file_len = file.stream_position()?;
+            if new_file_len < file_len {
+                file.set_len(new_file_len)?;
             }
+            Ok(())
         }
-        unsafe { mem::transmute::<Smf<'a>, Smf<'static>>(self) }
+        append_impl(new_events, path.as_ref())
     }
-}
 
-/// A track, represented as a `Vec` of events along with their originating bytes.
-///
-/// This type alias is only available with the `alloc` feature enabled.
-#[cfg(feature = "alloc")]
-pub type BytemappedTrack<'a> = Vec<(&'a [u8], TrackEvent<'a>)>;
+    /// Find out where the header chunk and each track chunk are located within the raw bytes of
+    /// the file.
+    ///
+    /// `original` should be the same buffer that this `Smf` was parsed from.
+    /// See [`FileLayout`](struct.FileLayout.html) for more details.
+    #[inline]
+    pub fn layout(&self, original: &[u8]) -> Result<FileLayout> {
+        FileLayout::read(original)
+    }
 
-/// A `.mid` Standard Midi File, but keeps a mapping to the raw bytes that make up each event.
-///
-/// This type is only available with the `alloc` feature enabled.
-#[cfg(feature = "alloc")]
-#[derive(Clone, PartialEq, Eq, Debug, Hash)]
-pub struct SmfBytemap<'a> {
-    /// The header of this file.
-    pub header: Header,
-    /// A list of tracks, along with the bytemap of their events.
-    pub tracks: Vec<BytemappedTrack<'a>>,
-}
-#[cfg(feature = "alloc")]
-impl<'a> SmfBytemap<'a> {
-    /// Create a new empty `SmfBytemap` with zero tracks, using the given header.
+    /// Get the amount of tracks in this file.
     #[inline]
-    pub fn new(header: Header) -> SmfBytemap<'a> {
-        SmfBytemap {
-            header,
-            tracks: vec![],
-        }
+    pub fn len(&self) -> usize {
+        self.tracks.len()
     }
 
-    /// Parse a Standard Midi File from its raw bytes, keeping a map to the original bytes that
-    /// make up each event.
-    pub fn parse(raw: &[u8]) -> Result<SmfBytemap> {
-        let (header, tracks) = parse(raw)?;
-        let track_count_hint = tracks.track_count_hint;
-        let tracks = tracks.collect_bytemapped()?;
-        validate_smf(&header, track_count_hint, tracks.len())?;
-        Ok(SmfBytemap { header, tracks })
+    /// Whether this file has no tracks.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
     }
 
-    /// Encodes and writes the *events* (not the bytemap) to the given generic writer.
+    /// Count the events across all tracks, including `EndOfTrack` markers.
+    pub fn total_event_count(&self) -> usize {
+        self.tracks.iter().map(|track| track.len()).sum()
+    }
+
+    /// Whether any track has an event other than `EndOfTrack`.
+    ///
+    /// Unlike [`is_empty`](#method.is_empty), which only checks for the presence of tracks, this
+    /// is `false` for a file whose tracks contain nothing but their end markers.
+    pub fn has_events(&self) -> bool {
+        self.tracks
+            .iter()
+            .flatten()
+            .any(|ev| !matches!(ev.kind, TrackEventKind::Meta(MetaMessage::EndOfTrack)))
+    }
+
+    /// Iterate over the tracks of this file.
     #[inline]
-    pub fn write<W: Write>(&self, out: &mut W) -> WriteResult<W> {
-        write(
-            &self.header,
-            self.tracks
-                .iter()
-                .map(|bytemapped| bytemapped.iter().map(|(_b, ev)| ev)),
-            out,
-        )
+    pub fn iter(&self) -> core::slice::Iter<'_, Track<'a>> {
+        self.tracks.iter()
     }
 
-    /// Encodes and writes the *events* (not the bytemap) to the given `std::io::Write` writer.
+    /// Iterate mutably over the tracks of this file.
+    #[inline]
+    pub fn iter_mut(&mut self) -> core::slice::IterMut<'_, Track<'a>> {
+        self.tracks.iter_mut()
+    }
+
+    /// Sort the tracks of this file by a key extracted from each track.
     ///
-    /// This function is only available with the `std` feature enabled.
-    #[cfg(feature = "std")]
+    /// The sort is stable, so tracks with equal keys keep their relative order.
+    /// Note that track order is meaningful: for example, `Format::Parallel` files conventionally
+    /// hold tempo information in the first track.
     #[inline]
-    pub fn write_std<W: io::Write>(&self, out: W) -> io::Result<()> {
-        write_std(
-            &self.header,
-            self.tracks
-                .iter()
-                .map(|bytemapped| bytemapped.iter().map(|(_b, ev)| ev)),
-            out,
-        )
+    pub fn sort_tracks_by<K: Ord>(&mut self, key: impl FnMut(&Track<'a>) -> K) {
+        self.tracks.sort_by_key(key);
+    }
+
+    /// Remove the track at the given index, returning it.
+    ///
+    /// Every file must have at least one track, so removing the last remaining track is an
+    /// error.
+    /// Removing tracks from a `Format::Parallel` or `Format::Sequential` file never changes its
+    /// format, since a single track is valid in every format.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn remove_track(&mut self, index: usize) -> Result<Track<'a>> {
+        assert!(index < self.tracks.len(), "track index out of bounds");
+        ensure!(
+            self.tracks.len() > 1,
+            err_invalid!("cannot remove the only track of a file")
+        );
+        Ok(self.tracks.remove(index))
+    }
+
+    /// Insert a track at the given index, shifting all tracks after it.
+    ///
+    /// If the file is `Format::SingleTrack` and ends up with more than one track, its format is
+    /// switched to `Format::Parallel` to keep the header consistent.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`.
+    pub fn insert_track(&mut self, index: usize, track: Track<'a>) {
+        self.tracks.insert(index, track);
+        if self.header.format == Format::SingleTrack && self.tracks.len() > 1 {
+            self.header.format = Format::Parallel;
+        }
+    }
+
+    /// Insert a General MIDI setup preamble at the very start of the file, so that players start
+    /// from a known state.
+    ///
+    /// The preamble consists of a GM System On SysEx message (`F0 7E 7F 09 01 F7`), followed by a
+    /// Reset All Controllers message and default volume (100) and pan (center) controllers on
+    /// every channel.
+    /// All of these events are inserted with a delta of 0 before any existing event, so the
+    /// timing of the rest of the file is unaffected.
+    ///
+    /// The preamble is inserted in the first track, except in `Format::Sequential` files, where
+    /// every track is an independent sequence and therefore receives its own preamble.
+    /// If the file has no tracks, a new track is created to hold it.
+    pub fn prepend_gm_reset(&mut self) {
+        const GM_SYSTEM_ON: &[u8] = &[0x7E, 0x7F, 0x09, 0x01, 0xF7];
+        let mut preamble = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::SysEx(GM_SYSTEM_ON),
+        }];
+        for channel in 0..16 {
+            for &(controller, value) in &[(121, 0), (7, 100), (10, 64)] {
+                preamble.push(TrackEvent {
+                    delta: u28::new(0),
+                    kind: TrackEventKind::Midi {
+                        channel: u4::new(channel),
+                        message: MidiMessage::Controller {
+                            controller: u7::new(controller),
+                            value: u7::new(value),
+                        },
+                    },
+                });
+            }
+        }
+
+        if self.tracks.is_empty() {
+            self.tracks.push(Vec::new());
+        }
+        let count = match self.header.format {
+            Format::Sequential => self.tracks.len(),
+            _ => 1,
+        };
+        for track in self.tracks.iter_mut().take(count) {
+            track.splice(0..0, preamble.iter().copied());
+        }
+    }
+
+    /// Remove the tracks that hold no meaningful events, such as tracks left with only an
+    /// `EndOfTrack` event after filtering.
+    ///
+    /// A track is considered empty if it only holds `EndOfTrack` and `TrackNumber` events.
+    /// `TrackNumber` events that held the old position of a kept track are updated to its new
+    /// position.
+    /// At least one track is always kept, and if a single track remains the file is converted to
+    /// `Format::SingleTrack`.
+    pub fn compact(&mut self) {
+        let is_empty = |track: &Track| {
+            track.iter().all(|ev| {
+                matches!(
+                    ev.kind,
+                    TrackEventKind::Meta(MetaMessage::EndOfTrack)
+                        | TrackEventKind::Meta(MetaMessage::TrackNumber(_))
+                )
+            })
+        };
+        let keep_first = self.tracks.iter().all(is_empty);
+        let mut old_idx = 0;
+        let mut new_idx = 0;
+        self.tracks.retain_mut(|track| {
+            let keep = !is_empty(track) || (keep_first && old_idx == 0);
+            if keep {
+                for ev in track.iter_mut() {
+                    if let TrackEventKind::Meta(MetaMessage::TrackNumber(Some(num))) = &mut ev.kind
+                    {
+                        if *num as usize == old_idx {
+                            *num = new_idx as u16;
+                        }
+                    }
+                }
+                new_idx += 1;
+            }
+            old_idx += 1;
+            keep
+        });
+        if self.tracks.len() == 1 {
+            self.header.format = Format::SingleTrack;
+        }
+    }
+
+    /// Number the tracks of this file by inserting a `MetaMessage::TrackNumber` at the start of
+    /// each track.
+    ///
+    /// Tracks are numbered sequentially starting from 0, so a `Format::SingleTrack` file receives
+    /// a single sequence number of 0.
+    /// Any `TrackNumber` events already present at tick 0 are replaced.
+    pub fn number_tracks(&mut self) {
+        for (idx, track) in self.tracks.iter_mut().enumerate() {
+            let leading = track.iter().take_while(|ev| ev.delta == 0).count();
+            let mut i = 0;
+            track.retain(|ev| {
+                i += 1;
+                i > leading || !matches!(ev.kind, TrackEventKind::Meta(MetaMessage::TrackNumber(_)))
+            });
+            track.insert(
+                0,
+                TrackEvent {
+                    delta: u28::new(0),
+                    kind: TrackEventKind::Meta(MetaMessage::TrackNumber(Some(idx as u16))),
+                },
+            );
+        }
+    }
+
+    /// Insert a tempo of 120 beats per minute at tick 0 of the first track, unless the file
+    /// already sets a tempo before its first note.
+    ///
+    /// 120 beats per minute is the tempo that the standard assumes when none is given, so this
+    /// does not change how the file sounds, but it helps players that do not follow that default.
+    /// The tempo event is inserted with a delta of 0 before all other events, so no event moves.
+    /// In `Format::Sequential` files every track has its own tempo map, so every track gets a
+    /// tempo if it needs one.
+    /// Files with no tracks or with timecode timing are left untouched.
+    ///
+    /// Files are written exactly as they are by default, so this has to be called explicitly
+    /// before writing.
+    /// Returns whether any tempo event was inserted.
+    pub fn ensure_initial_tempo(&mut self) -> bool {
+        fn needs_tempo(tracks: &[Track]) -> bool {
+            let mut first_note = None;
+            let mut first_tempo = None;
+            for track in tracks {
+                let mut time = 0u32;
+                for ev in track {
+                    time = time.saturating_add(ev.delta.as_int());
+                    let first = match ev.kind {
+                        TrackEventKind::Meta(MetaMessage::Tempo(_)) => &mut first_tempo,
+                        TrackEventKind::Midi {
+                            message: MidiMessage::NoteOn { .. },
+                            ..
+                        } => &mut first_note,
+                        _ => continue,
+                    };
+                    *first = Some(first.map_or(time, |first: u32| first.min(time)));
+                }
+            }
+            match (first_tempo, first_note) {
+                (None, _) => true,
+                (Some(tempo), Some(note)) => tempo > note,
+                (Some(_), None) => false,
+            }
+        }
+
+        if let Timing::Timecode(..) = self.header.timing {
+            return false;
+        }
+        let tempo = TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(500_000))),
+        };
+        let mut inserted = false;
+        if self.header.format == Format::Sequential {
+            for track in self.tracks.iter_mut() {
+                if needs_tempo(core::slice::from_ref(track)) {
+                    track.insert(0, tempo);
+                    inserted = true;
+                }
+            }
+        } else if !self.tracks.is_empty() && needs_tempo(&self.tracks) {
+            self.tracks[0].insert(0, tempo);
+            inserted = true;
+        }
+        inserted
+    }
+
+    /// Get a bitmask of the MIDI channels used anywhere in the file.
+    ///
+    /// Bit `n` is set if channel `n` has at least one channel message in any track.
+    pub fn channels_used(&self) -> u16 {
+        self.tracks
+            .iter()
+            .fold(0, |mask, track| mask | channel_mask(track))
+    }
+
+    /// Get a bitmask of the MIDI channels used by each track, in track order.
+    ///
+    /// Bit `n` of each mask is set if channel `n` has at least one channel message in that track.
+    pub fn channels_per_track(&self) -> Vec<u16> {
+        self.tracks
+            .iter()
+            .map(|track| channel_mask(track))
+            .collect()
+    }
+
+    /// Get the name of the output device that each track targets, in track order, as given by the
+    /// first `MetaMessage::DeviceName` event of the track.
+    ///
+    /// Names are decoded as with
+    /// [`MetaMessage::decode_text`](enum.MetaMessage.html#method.decode_text).
+    /// Tracks without a device name yield `None`.
+    pub fn device_names(&self) -> Vec<Option<Cow<'a, str>>> {
+        self.tracks
+            .iter()
+            .map(|track| {
+                track.iter().find_map(|ev| match ev.kind {
+                    TrackEventKind::Meta(meta @ MetaMessage::DeviceName(_)) => {
+                        meta.decode_text(false)
+                    }
+                    _ => None,
+                })
+            })
+            .collect()
+    }
+
+    /// Move the channels of several files out of each other's way, so that they can be merged
+    /// into a single file without colliding.
+    ///
+    /// Files are handled in order, and every channel used by a file is moved to the lowest channel
+    /// not taken by any earlier file.
+    /// Channel 9 (the General MIDI percussion channel) is never handed out to other channels, and
+    /// percussion on channel 9 stays there, shared by all files.
+    ///
+    /// Returns the mapping chosen for each file, where index `n` holds the new channel for
+    /// channel `n` (unused channels map to themselves).
+    /// Fails without touching any file if the files use more than 15 melodic channels in total.
+    pub fn assign_channels(files: &mut [Smf<'a>]) -> Result<Vec<[u4; 16]>> {
+        let mut taken = 1u16 << 9;
+        let mut mappings = Vec::with_capacity(files.len());
+        for smf in files.iter() {
+            let mut mapping = [u4::new(0); 16];
+            let used = smf.channels_used();
+            for (channel, to) in mapping.iter_mut().enumerate() {
+                *to = u4::new(channel as u8);
+                if channel == 9 || used & (1 << channel) == 0 {
+                    continue;
+                }
+                let free = (0..16)
+                    .find(|&free| taken & (1 << free) == 0)
+                    .ok_or(err_invalid!("not enough free midi channels"))?;
+                taken |= 1 << free;
+                *to = u4::new(free);
+            }
+            mappings.push(mapping);
+        }
+        for (smf, mapping) in files.iter_mut().zip(mappings.iter()) {
+            for track in smf.tracks.iter_mut() {
+                edit::map_channels(track, |channel| mapping[channel.as_int() as usize]);
+            }
+        }
+        Ok(mappings)
+    }
+
+    /// Append the tracks of `other` to the end of this file, so that they play right after the
+    /// current contents.
+    ///
+    /// Track `n` of `other` is merged into track `n` of this file, and tracks are added if
+    /// `other` has more tracks.
+    /// Both files should share the same format and timing.
+    ///
+    /// The end of this file is given by its longest track, including the delta of the final
+    /// `EndOfTrack` events.
+    /// If `overlap` is non-zero, `other` starts `overlap` ticks before the end of this file, and
+    /// events in the overlapping region are merged (this is handy for seamless loops).
+    /// Notes that would be struck again on the same channel while still held, such as a note
+    /// sustained across the join, are retriggered cleanly: the held note is released right before
+    /// the new note starts, so that nothing double-triggers or hangs.
+    /// Meta events (such as tempo changes) in the overlapping region are kept from both files.
+    ///
+    /// In `Format::Sequential` files every track is an independent sequence, so the tracks of
+    /// `other` are added after the tracks of this file instead, and `overlap` is ignored.
+    pub fn append(&mut self, other: &Smf<'a>, overlap: u32) {
+        if self.header.format == Format::Sequential {
+            self.tracks.extend(other.tracks.iter().cloned());
+            return;
+        }
+        let end = self
+            .tracks
+            .iter()
+            .map(|track| {
+                track
+                    .iter()
+                    .fold(0u32, |time, ev| time.saturating_add(ev.delta.as_int()))
+            })
+            .max()
+            .unwrap_or(0);
+        let offset = end.saturating_sub(overlap);
+        for (idx, track) in other.tracks.iter().enumerate() {
+            if idx >= self.tracks.len() {
+                self.tracks.push(Vec::new());
+            }
+            let mut shifted = Vec::with_capacity(track.len() + 1);
+            if let Some((first, rest)) = track.split_first() {
+                let delta = split_gap(&mut shifted, first.delta.as_int() as u64 + offset as u64);
+                shifted.push(TrackEvent { delta, ..*first });
+                shifted.extend_from_slice(rest);
+            }
+            edit::overlay_track(&mut self.tracks[idx], &shifted);
+            edit::resolve_retriggers(&mut self.tracks[idx], offset);
+        }
+    }
+
+    /// Bake the tempo map into the position of every event, so that ticks map to a fixed amount
+    /// of wall-clock time.
+    ///
+    /// After this transformation the file uses metrical timing with
+    /// [`RETIMED_TICKS_PER_BEAT`](#associatedconstant.RETIMED_TICKS_PER_BEAT) ticks per beat and
+    /// has no tempo events, so it plays at the default tempo of 120 beats per minute, where each
+    /// tick lasts exactly 100 microseconds.
+    /// Event positions are rounded to the nearest tick.
+    ///
+    /// Tempo changes in any track affect all tracks, except in `Format::Sequential` files, where
+    /// every track has its own tempo map.
+    /// Files with timecode timing have no tempo map, and are simply converted to the new grid.
+    pub fn retime_to_seconds(&mut self) {
+        let shared = match self.header.format {
+            Format::Sequential => None,
+            _ => Some(TickClock::new(self.header.timing, &self.tracks)),
+        };
+        let clocks = (0..self.tracks.len())
+            .map(|idx| match &shared {
+                Some(clock) => clock.clone(),
+                None => TickClock::new(self.header.timing, &self.tracks[idx..=idx]),
+            })
+            .collect::<Vec<_>>();
+
+        for (track, mut clock) in self.tracks.iter_mut().zip(clocks) {
+            let div = clock.div;
+            let mut time = 0u32;
+            let mut last_grid = 0u64;
+            let mut retimed = Vec::with_capacity(track.len());
+            for ev in track.iter() {
+                time = time.saturating_add(ev.delta.as_int());
+                let scaled = clock.advance(time);
+                if let TrackEventKind::Meta(MetaMessage::Tempo(_)) = ev.kind {
+                    continue;
+                }
+                let grid = (scaled + div * 50) / (div * 100);
+                let delta = split_gap(&mut retimed, grid - last_grid);
+                retimed.push(TrackEvent {
+                    delta,
+                    kind: ev.kind,
+                });
+                last_grid = grid;
+            }
+            *track = retimed;
+        }
+        self.header.timing = Timing::Metrical(u15::new(Self::RETIMED_TICKS_PER_BEAT));
+    }
+
+    /// Iterate over the events of all tracks in order of absolute tick, without merging them into
+    /// a single list first.
+    ///
+    /// Each event is yielded along with the index of its track and its absolute tick.
+    /// Events within a track keep their order, and simultaneous events from different tracks are
+    /// yielded in track order.
+    /// Tracks are merged with a binary heap, so every step takes `O(log n)` time for `n` tracks.
+    ///
+    /// Note that the tick of events in `Format::Sequential` files restarts at 0 for every track.
+    pub fn iter_timeline(&self) -> impl Iterator<Item = (usize, u32, &TrackEvent<'a>)> + '_ {
+        merge_timeline(&self.tracks)
+    }
+
+    /// Iterate over the raw bytes that should be sent to a MIDI port to play this file, in order
+    /// of absolute tick.
+    ///
+    /// Each item holds the absolute tick of an event and the bytes to send for it, without running
+    /// status, ready to be handed to OS APIs or hardware.
+    /// Channel messages yield their usual wire form, sysex events yield their data prefixed with
+    /// `0xF0`, and escape events yield their data verbatim, since they hold raw bytes such as
+    /// sysex continuations or realtime messages.
+    /// Meta events never go on the wire, so they are skipped along with empty escapes.
+    /// Simultaneous events are ordered like in [`scheduled`](#method.scheduled), so for example a
+    /// note ending on the same tick as another one starts is released first.
+    /// In `Format::Sequential` files tracks play one after the other, so the ticks of every track
+    /// continue from the last tick of the previous one.
+    ///
+    /// Tracks are merged lazily as the iterator advances, and only sysex events need to allocate
+    /// to produce their bytes.
+    pub fn midi_bytes(&self) -> impl Iterator<Item = (u32, MidiBytes<'a>)> + '_ {
+        let mut sequences = self.sequences();
+        let mut timeline = merge_timeline(sequences.next().unwrap_or(&[])).peekable();
+        let mut offset = 0u32;
+        let mut last = 0;
+        //The simultaneous events being yielded, sorted with `sort_timeline`
+        let mut group = Vec::new();
+        let mut pos = 0;
+        core::iter::from_fn(move || loop {
+            if let Some(&ev) = group.get(pos) {
+                pos += 1;
+                return Some(ev);
+            }
+            group.clear();
+            pos = 0;
+            let (_, tick, ev) = match timeline.next() {
+                Some(ev) => ev,
+                None => {
+                    timeline = merge_timeline(sequences.next()?).peekable();
+                    offset = offset.saturating_add(last);
+                    last = 0;
+                    continue;
+                }
+            };
+            last = tick;
+            let time = offset.saturating_add(tick);
+            group.push((time, ev.kind));
+            while let Some((_, _, ev)) = timeline.next_if(|&(_, next, _)| next == tick) {
+                group.push((time, ev.kind));
+            }
+            sort_timeline(&mut group);
+        })
+        .filter_map(|(tick, kind)| Some((tick, MidiBytes::new(kind)?)))
+    }
+
+    /// Iterate over all events in the file in playback order, each paired with the real-time
+    /// delay since the previous event.
+    ///
+    /// Delays are computed by following the tempo map, so a player can simply sleep for the given
+    /// duration before sending each event.
+    /// The first delay is measured from the start of the file.
+    /// Tempo changes in any track affect all tracks, except in `Format::Sequential` files, where
+    /// tracks are played one after the other and every track has its own tempo map.
+    ///
+    /// The delays always add up to the [`duration`](#method.duration) of the file.
+    #[cfg(feature = "std")]
+    pub fn scheduled(&self) -> impl Iterator<Item = (Duration, TrackEventKind<'a>)> {
+        //Compute absolute times in nanoseconds first, so rounding errors do not accumulate
+        let mut timed = Vec::with_capacity(self.total_event_count());
+        match self.header.format {
+            Format::Sequential => {
+                let mut offset = 0;
+                for track in self.tracks.iter() {
+                    let mut clock =
+                        TickClock::new(self.header.timing, core::slice::from_ref(track));
+                    let mut time = 0u32;
+                    for ev in track.iter() {
+                        time = time.saturating_add(ev.delta.as_int());
+                        timed.push((offset + clock.nanos(time), ev.kind));
+                    }
+                    offset += clock.nanos(time);
+                }
+            }
+            _ => {
+                let mut clock = TickClock::new(self.header.timing, &self.tracks);
+                let tracks = self.tracks.iter().map(|track| &track[..]);
+                for (time, kind) in merge_absolute(tracks) {
+                    timed.push((clock.nanos(time), kind));
+                }
+            }
+        }
+        let mut last = 0;
+        timed.into_iter().map(move |(nanos, kind)| {
+            let delay = nanos_to_duration(nanos - last);
+            last = nanos;
+            (delay, kind)
+        })
+    }
+
+    /// Iterate over the independent sequences (ie. songs) within the file, each as a slice of
+    /// tracks that play simultaneously and share a tempo map.
+    ///
+    /// In `Format::Sequential` files every track is a standalone sequence, with its own tempo map
+    /// and its ticks starting at 0.
+    /// Otherwise, all tracks make up a single sequence.
+    /// Files without tracks have no sequences.
+    pub fn sequences(&self) -> impl Iterator<Item = &[Track<'a>]> + '_ {
+        let step = match self.header.format {
+            Format::Sequential => 1,
+            _ => self.tracks.len().max(1),
+        };
+        self.tracks.chunks(step)
+    }
+
+    /// Compute the real-time length of every sequence in the file, following their tempo maps.
+    ///
+    /// There is one entry per track in `Format::Sequential` files, and a single entry otherwise.
+    /// See [`sequences`](#method.sequences) and [`duration`](#method.duration).
+    #[cfg(feature = "std")]
+    pub fn sequence_durations(&self) -> Vec<Duration> {
+        self.sequence_nanos().map(nanos_to_duration).collect()
+    }
+
+    /// Compute the real-time length of the file, following the tempo map.
+    ///
+    /// The length of a track is measured up to its last event, usually its `EndOfTrack` event.
+    /// In `Format::Sequential` files the lengths of all tracks add up, otherwise the file lasts as
+    /// long as its longest track.
+    #[cfg(feature = "std")]
+    pub fn duration(&self) -> Duration {
+        nanos_to_duration(self.sequence_nanos().sum())
+    }
+
+    #[cfg(feature = "std")]
+    fn sequence_nanos(&self) -> impl Iterator<Item = u128> + '_ {
+        let timing = self.header.timing;
+        self.sequences().map(move |tracks| {
+            let end = tracks
+                .iter()
+                .map(|track| {
+                    track
+                        .iter()
+                        .fold(0u32, |t, ev| t.saturating_add(ev.delta.as_int()))
+                })
+                .max()
+                .unwrap_or(0);
+            TickClock::new(timing, tracks).nanos(end)
+        })
+    }
+
+    /// Collect the distinct programs selected on each MIDI channel anywhere in the file.
+    ///
+    /// Index `n` of the output holds the programs set by `ProgramChange` events on channel `n`.
+    pub fn programs_used(&self) -> [BTreeSet<u7>; 16] {
+        let mut programs: [BTreeSet<u7>; 16] = Default::default();
+        for ev in self.tracks.iter().flat_map(|track| track.iter()) {
+            if let TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::ProgramChange { program },
+            } = ev.kind
+            {
+                programs[channel.as_int() as usize].insert(program);
+            }
+        }
+        programs
+    }
+
+    /// Collect the distinct controller numbers used on each MIDI channel anywhere in the file.
+    ///
+    /// Index `n` of the output holds the controllers changed by `Controller` events on channel
+    /// `n`, such as 7 for volume or 10 for pan.
+    pub fn controllers_used(&self) -> [BTreeSet<u7>; 16] {
+        let mut controllers: [BTreeSet<u7>; 16] = Default::default();
+        for ev in self.tracks.iter().flat_map(|track| track.iter()) {
+            if let TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::Controller { controller, .. },
+            } = ev.kind
+            {
+                controllers[channel.as_int() as usize].insert(controller);
+            }
+        }
+        controllers
+    }
+
+    /// Find the key of this file.
+    ///
+    /// The earliest `MetaMessage::KeySignature` event in the file is used if there is one.
+    /// Otherwise, the key is estimated from the notes of all tracks, as done by
+    /// [`estimate_key`](fn.estimate_key.html).
+    /// Returns `None` if the file has neither key signatures nor pitched notes.
+    pub fn detect_key(&self) -> Option<Key> {
+        let tracks = self.tracks.iter().map(|track| &track[..]);
+        let declared = merge_absolute(tracks)
+            .into_iter()
+            .find_map(|(_, kind)| match kind {
+                TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) => {
+                    Some(Key::new(sharps, minor))
+                }
+                _ => None,
+            });
+        declared.or_else(|| {
+            let notes = self
+                .tracks
+                .iter()
+                .flat_map(|track| analysis::notes(track, false))
+                .collect::<Vec<_>>();
+            analysis::estimate_key(&notes)
+        })
+    }
+
+    /// Transpose the whole file into the given key, by the smallest amount of semitones that
+    /// reaches it.
+    ///
+    /// The current key is found using [`detect_key`](#method.detect_key), and all tracks are
+    /// shifted using [`transpose`](fn.transpose.html), so percussion is left alone and notes that
+    /// would fall outside of the MIDI range are clamped.
+    /// Transposing cannot change the mode of a piece: if the file is in a minor key and a major
+    /// key is requested (or vice versa), the file is moved into the relative key of the target,
+    /// which has the same key signature.
+    /// Shifts of a tritone go downwards.
+    ///
+    /// Returns the amount of semitones the file was shifted by, or `None` if the current key
+    /// could not be detected, in which case the file is left untouched.
+    pub fn transpose_to_key(&mut self, target: Key) -> Option<i8> {
+        let current = self.detect_key()?;
+        let target = Key::new(target.sharps, current.minor);
+        let semitones = match (target.tonic() as i8 - current.tonic() as i8).rem_euclid(12) {
+            up if up < 6 => up,
+            up => up - 12,
+        };
+        for track in self.tracks.iter_mut() {
+            edit::transpose(track, semitones);
+            //Spell key signatures in the current key the way the caller asked for
+            for ev in track.iter_mut() {
+                if let TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) = &mut ev.kind
+                {
+                    if *minor == target.minor && Key::new(*sharps, *minor).tonic() == target.tonic()
+                    {
+                        *sharps = target.sharps;
+                    }
+                }
+            }
+        }
+        Some(semitones)
+    }
+
+    /// Generate a metronome track that clicks along with the time signatures of this file, until
+    /// the last event of the file.
+    ///
+    /// Clicks are note events on channel 9 (the General MIDI percussion channel), using a high
+    /// wood block (key 76) on the first click of every bar and a low wood block (key 77) on the
+    /// rest.
+    /// If the file has no time signature at tick 0, a time signature of 4/4 is assumed until the
+    /// first one.
+    ///
+    /// By default one click is produced on every beat, as given by the time signature
+    /// denominator.
+    /// If `follow_clocks_per_click` is `true`, the MIDI clocks per metronome click field of the
+    /// time signature is used instead (24 MIDI clocks make up a quarter note), which is more
+    /// faithful for compound meters (eg. clicking on every dotted quarter in 6/8).
+    ///
+    /// Every click is released halfway through, or at the end of its bar if the bar ends earlier
+    /// (eg. in 7/8 with a click every dotted quarter, or when a time signature change cuts a bar
+    /// short).
+    ///
+    /// Returns `None` if the file uses timecode timing, since it has no concept of beats, or if
+    /// any time signature has a numerator of 0.
+    pub fn click_track(&self, follow_clocks_per_click: bool) -> Option<Track<'static>> {
+        let ticks_per_beat = match self.header.timing {
+            Timing::Metrical(tpb) => tpb.as_int() as u32,
+            Timing::Timecode(..) => return None,
+        };
+        let events = merge_absolute(self.tracks.iter().map(|track| &track[..]));
+        let end = events.last().map(|&(time, _)| time).unwrap_or(0);
+        //Time signatures as (tick, numerator, denominator exponent, clocks per click)
+        let mut signatures = vec![(0, 4, 2, 24)];
+        for &(time, kind) in events.iter() {
+            if let TrackEventKind::Meta(MetaMessage::TimeSignature(num, den, cpc, _)) = kind {
+                if num == 0 {
+                    return None;
+                }
+                if time == 0 {
+                    signatures.clear();
+                }
+                signatures.push((time, num as u32, den as u32, cpc as u32));
+            }
+        }
+
+        let mut clicks = Vec::new();
+        let mut click = |time: u32, release: u32, accent: bool| {
+            let (key, vel) = if accent { (76, 127) } else { (77, 100) };
+            let midi = |vel: u8| TrackEventKind::Midi {
+                channel: u4::new(9),
+                message: MidiMessage::NoteOn {
+                    key: u7::new(key),
+                    vel: u7::new(vel),
+                },
+            };
+            clicks.push((time, midi(vel)));
+            clicks.push((release, midi(0)));
+        };
+        for (i, &(start, num, den, cpc)) in signatures.iter().enumerate() {
+            let seg_end = signatures.get(i + 1).map(|sig| sig.0).unwrap_or(end);
+            let beat_len = (ticks_per_beat * 4).checked_shr(den).unwrap_or(0).max(1);
+            let bar_len = num * beat_len;
+            let click_len = if follow_clocks_per_click && cpc > 0 {
+                (ticks_per_beat * cpc / 24).max(1)
+            } else {
+                beat_len
+            };
+            let mut bar_start = start;
+            while bar_start < seg_end {
+                let bar_end = bar_start.saturating_add(bar_len).min(seg_end);
+                let mut time = bar_start;
+                while time < bar_end {
+                    let release = time.saturating_add((click_len / 2).max(1)).min(bar_end);
+                    click(time, release, time == bar_start);
+                    time = time.saturating_add(click_len);
+                }
+                bar_start = bar_start.saturating_add(bar_len);
+            }
+        }
+        Some(from_absolute(clicks))
+    }
+
+    /// Extract the events of a single MIDI channel into a new `Format::SingleTrack` file, along
+    /// with the conductor events needed to play it back.
+    ///
+    /// Events from all tracks are merged by absolute time, so timing is preserved.
+    /// Apart from the channel messages of the given channel, only tempo, time signature, key
+    /// signature and SMPTE offset meta messages are kept.
+    /// The output track is terminated by a single `EndOfTrack` event.
+    pub fn extract_channel(&self, channel: u4) -> Smf<'a> {
+        let events = merge_absolute(self.tracks.iter().map(|track| &track[..]))
+            .into_iter()
+            .filter(|(_time, kind)| match kind {
+                TrackEventKind::Midi { channel: ch, .. } => *ch == channel,
+                TrackEventKind::Meta(meta) => matches!(
+                    meta,
+                    MetaMessage::Tempo(_)
+                        | MetaMessage::TimeSignature(..)
+                        | MetaMessage::KeySignature(..)
+                        | MetaMessage::SmpteOffset(_)
+                ),
+                _ => false,
+            });
+        Smf {
+            header: Header::new(Format::SingleTrack, self.header.timing),
+            tracks: vec![from_absolute(events)],
+        }
+    }
+
+    /// Look for unconventional uses of the file format, which are legal but may confuse other
+    /// tools.
+    ///
+    /// See [`FormatWarning`](enum.FormatWarning.html) for the situations that are reported.
+    /// Warnings are reported in track order.
+    pub fn check_format_consistency(&self) -> Vec<FormatWarning> {
+        let mut warnings = Vec::new();
+        match (self.header.format, self.tracks.len()) {
+            (_, 0) => warnings.push(FormatWarning::NoTracks),
+            (Format::SingleTrack, 1) => {}
+            (Format::SingleTrack, _) => warnings.push(FormatWarning::SingleTrackMultipleTracks),
+            (Format::Parallel, 1) => warnings.push(FormatWarning::ParallelSingleTrack),
+            _ => {}
+        }
+        if self.header.format == Format::Parallel {
+            for (idx, track) in self.tracks.iter().enumerate().skip(1) {
+                let has_tempo = track
+                    .iter()
+                    .any(|ev| matches!(ev.kind, TrackEventKind::Meta(MetaMessage::Tempo(_))));
+                if has_tempo {
+                    warnings.push(FormatWarning::TempoOutsideFirstTrack { track: idx });
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Reconstruct the state of a MIDI channel right before the given absolute tick, by replaying
+    /// all of the channel messages that come before it.
+    ///
+    /// This is what a player needs to set up a synthesizer when seeking: after applying the
+    /// returned state, playback can resume from the events at `tick`.
+    /// Events exactly at `tick` are not taken into account.
+    /// Channels without any messages are in the default [`ChannelState`](struct.ChannelState.html).
+    pub fn state_at(&self, channel: u4, tick: u32) -> ChannelState {
+        let mut state = ChannelState::default();
+        let events = merge_absolute(self.tracks.iter().map(|track| &track[..]));
+        for (_time, kind) in events.into_iter().take_while(|&(time, _)| time < tick) {
+            if let TrackEventKind::Midi {
+                channel: ch,
+                message,
+            } = kind
+            {
+                if ch == channel {
+                    state.apply(message);
+                }
+            }
+        }
+        state
+    }
+
+    /// Suggest ticks at which this file could be split into independent songs, such as in
+    /// soundtrack rips that pack several songs into a single track.
+    ///
+    /// A boundary is reported at the first event after every silent gap of at least `min_gap`
+    /// ticks, where no notes are held across the gap.
+    /// A boundary is also reported at the first event after any `EndOfTrack` event that is not at
+    /// the end of its track.
+    /// The returned ticks are sorted and deduplicated, and can be passed to
+    /// [`split_at_ticks`](#method.split_at_ticks).
+    pub fn detect_song_boundaries(&self, min_gap: u32) -> Vec<u32> {
+        let mut boundaries = Vec::new();
+        for track in self.tracks.iter() {
+            let mut time = 0u32;
+            let mut ended = false;
+            for ev in track.iter() {
+                time = time.saturating_add(ev.delta.as_int());
+                match ev.kind {
+                    TrackEventKind::Meta(MetaMessage::EndOfTrack) => ended = true,
+                    _ if ended => {
+                        boundaries.push(time);
+                        ended = false;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut held = vec![0u32; 16 * 128];
+        let mut sounding = 0u32;
+        let mut last = None;
+        for (time, kind) in merge_absolute(self.tracks.iter().map(|track| &track[..])) {
+            if let TrackEventKind::Meta(MetaMessage::EndOfTrack) = kind {
+                continue;
+            }
+            if let Some(last) = last {
+                if sounding == 0 && time - last >= min_gap {
+                    boundaries.push(time);
+                }
+            }
+            last = Some(time);
+            if let TrackEventKind::Midi { channel, message } = kind {
+                let (key, on) = match message {
+                    MidiMessage::NoteOn { key, vel } => (key, vel > 0),
+                    MidiMessage::NoteOff { key, .. } => (key, false),
+                    _ => continue,
+                };
+                let count = &mut held[channel.as_int() as usize * 128 + key.as_int() as usize];
+                if on {
+                    *count += 1;
+                    sounding += 1;
+                } else if *count > 0 {
+                    *count -= 1;
+                    sounding -= 1;
+                }
+            }
+        }
+        boundaries.retain(|&tick| tick > 0);
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        boundaries
+    }
+
+    /// Split this file at the given absolute ticks, turning it into a `Format::Sequential` file
+    /// with one independent sequence per section.
+    ///
+    /// All tracks are merged, and each section is shifted so that it starts at tick 0.
+    /// The tempo and time signature in effect at the start of each section are copied over to the
+    /// start of the section, so that every sequence plays back correctly by itself.
+    /// Sections without any events are dropped, and points at tick 0 are ignored.
+    pub fn split_at_ticks(&mut self, points: &[u32]) {
+        let mut points = points.to_vec();
+        points.sort_unstable();
+        points.dedup();
+        let mut points = points.into_iter().filter(|&tick| tick > 0).peekable();
+
+        let mut sequences = Vec::new();
+        let mut current = Vec::new();
+        let mut has_events = false;
+        let mut start = 0;
+        let mut tempo = None;
+        let mut time_sig = None;
+        for (time, kind) in merge_absolute(self.tracks.iter().map(|track| &track[..])) {
+            if let TrackEventKind::Meta(MetaMessage::EndOfTrack) = kind {
+                continue;
+            }
+            if points.peek().is_some_and(|&tick| time >= tick) {
+                while let Some(tick) = points.next_if(|&tick| time >= tick) {
+                    start = tick;
+                }
+                if has_events {
+                    sequences.push(from_absolute(current.drain(..)));
+                }
+                current.clear();
+                current.extend(tempo.iter().chain(time_sig.iter()).map(|&kind| (0, kind)));
+            }
+            match kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(_)) => tempo = Some(kind),
+                TrackEventKind::Meta(MetaMessage::TimeSignature(..)) => time_sig = Some(kind),
+                _ => {}
+            }
+            current.push((time - start, kind));
+            has_events = true;
+        }
+        if has_events || sequences.is_empty() {
+            sequences.push(from_absolute(current));
+        }
+        self.header.format = Format::Sequential;
+        self.tracks = sequences;
+    }
+
+    /// Split this file at the given absolute tick into two files with the same header and the same
+    /// amount of tracks, one with the events before `tick` and another one with the events at or
+    /// after `tick`, shifted so that they start at tick 0.
+    ///
+    /// Every track of the first half ends at `tick`, and any notes still held at that point are
+    /// released there with a release velocity of 64.
+    /// Every track of the second half starts with the state the track had built up before `tick`:
+    /// the last tempo, time signature and key signature, any channel settings that differ from the
+    /// default [`ChannelState`](struct.ChannelState.html), and the notes held across the split,
+    /// which are struck again (unless they are released exactly at `tick`).
+    /// This way both halves play back correctly by themselves.
+    pub fn split_at_tick(&self, tick: u32) -> (Smf<'a>, Smf<'a>) {
+        let mut before = Smf::new(self.header);
+        let mut after = Smf::new(self.header);
+        for track in self.tracks.iter() {
+            let (head, tail) = split_track(track, tick);
+            before.tracks.push(head);
+            after.tracks.push(tail);
+        }
+        (before, after)
+    }
+
+    /// Find notes that are struck again on the same channel before being released (ie.
+    /// double-triggers).
+    ///
+    /// Double-triggered notes are a common artifact of merging tracks or bad quantization:
+    /// since a single note off releases the key, one of the note offs is effectively lost.
+    /// Each overlap is reported along with the onsets of both notes, in order of occurrence within
+    /// each track.
+    pub fn overlapping_notes(&self) -> Vec<NoteOverlap> {
+        let mut overlaps = Vec::new();
+        for (idx, track) in self.tracks.iter().enumerate() {
+            analysis::overlapping_notes(idx, track, &mut overlaps);
+        }
+        overlaps
+    }
+
+    /// Count how often each pitch class (C, C#, D, ..., B) is played throughout the file.
+    ///
+    /// Index `0` of the output corresponds to C, index `1` to C#, and so on.
+    /// If `weight_by_duration` is `false` every note counts once, otherwise every note counts as
+    /// many times as its duration in ticks (see [`notes`](fn.notes.html)).
+    ///
+    /// Notes on the General MIDI percussion channel (channel 9) are ignored, since they carry no
+    /// pitch.
+    pub fn pitch_class_histogram(&self, weight_by_duration: bool) -> [u32; 12] {
+        let mut histogram = [0u32; 12];
+        for track in self.tracks.iter() {
+            for note in analysis::notes(track, false) {
+                if note.channel == 9 {
+                    continue;
+                }
+                let weight = if weight_by_duration {
+                    note.duration()
+                } else {
+                    1
+                };
+                let class = &mut histogram[note.key.as_int() as usize % 12];
+                *class = class.saturating_add(weight);
+            }
+        }
+        histogram
+    }
+
+    /// Count the melodic intervals between consecutive notes throughout the file.
+    ///
+    /// Intervals are measured between each note and the previous note that started on the same
+    /// track and channel, and are folded into a single octave: index `n` of the output counts
+    /// intervals of `n` semitones, `n + 12` semitones, etc... regardless of their direction.
+    /// Notes that start at the same tick (eg. chords) are visited from the lowest to the highest
+    /// key.
+    ///
+    /// Notes on the General MIDI percussion channel (channel 9) are ignored, since they carry no
+    /// pitch.
+    pub fn interval_histogram(&self) -> [u32; 12] {
+        let mut histogram = [0u32; 12];
+        for track in self.tracks.iter() {
+            let mut notes = analysis::notes(track, false);
+            notes.sort_by_key(|note| (note.start, note.key));
+            let mut last_key = [None; 16];
+            for note in notes {
+                if note.channel == 9 {
+                    continue;
+                }
+                let key = note.key.as_int();
+                let last = &mut last_key[note.channel.as_int() as usize];
+                if let Some(last) = last.replace(key) {
+                    let interval = (key as i32 - last as i32).unsigned_abs() % 12;
+                    histogram[interval as usize] += 1;
+                }
+            }
+        }
+        histogram
+    }
+
+    /// Encodes and writes the file to the given generic writer.
+    ///
+    /// Note that this function requires a `midly::io::Write` writer, not a `std::io::Write` writer.
+    /// This makes it possible to support `no_std` environments, as well as custom writer errors.
+    /// If you're looking to write to a `File`, see the [`save`](#method.save) method.
+    /// If you're looking to write to a `std::io::Write` writer, see the
+    /// [`write_std`](#method.write_std) method.
+    ///
+    /// This function is always available, even in `no_std` environments.
+    #[inline]
+    pub fn write<W: Write>(&self, out: &mut W) -> StdResult<(), WriteError<W::Error>> {
+        write(&self.header, &self.tracks, out)
+    }
+
+    /// Find duplicates within a collection of files, such as a music library.
+    ///
+    /// Files are reported by their index in `files`, in two kinds of groups:
+    ///
+    /// - [`identical`](struct.Duplicates.html#structfield.identical) groups hold files that encode
+    ///   to exactly the same bytes.
+    /// - [`equivalent`](struct.Duplicates.html#structfield.equivalent) groups hold files that
+    ///   encode differently but sound the same, that is, files that become identical after being
+    ///   [`canonicalize`](#method.canonicalize)d.
+    ///   Every equivalent group contains at least two different encodings.
+    ///
+    /// Encodings are bucketed by their CRC-32 checksum and then compared byte by byte, so
+    /// checksum collisions never merge different files.
+    /// Only groups of two or more files are reported, sorted by their first index.
+    /// Files that cannot be encoded (for example because a SysEx message is too long) are never
+    /// reported as duplicates.
+    ///
+    /// This function is only available with the `std` feature enabled.
+    #[cfg(feature = "std")]
+    pub fn dedupe(files: &[Smf]) -> Duplicates {
+        /// Group equal encodings, returning the group of each file.
+        fn group(encoded: &[Option<(u32, Vec<u8>)>]) -> Vec<Option<usize>> {
+            let mut buckets: HashMap<u32, Vec<usize>> = HashMap::new();
+            let mut groups = vec![None; encoded.len()];
+            for (idx, file) in encoded.iter().enumerate() {
+                let (crc, raw) = match file {
+                    Some(file) => file,
+                    None => continue,
+                };
+                let bucket = buckets.entry(*crc).or_default();
+                let same = bucket
+                    .iter()
+                    .find(|&&other| encoded[other].as_ref().map(|(_, other)| other) == Some(raw));
+                groups[idx] = Some(same.map_or(idx, |&other| groups[other].unwrap_or(other)));
+                bucket.push(idx);
+            }
+            groups
+        }
+        /// Collect the indices of each group with at least two members.
+        fn collect(groups: &[Option<usize>], keep: impl Fn(&[usize]) -> bool) -> Vec<Vec<usize>> {
+            let mut members: Vec<Vec<usize>> = vec![Vec::new(); groups.len()];
+            for (idx, group) in groups.iter().enumerate() {
+                if let Some(group) = *group {
+                    members[group].push(idx);
+                }
+            }
+            members
+                .into_iter()
+                .filter(|group| group.len() >= 2 && keep(group))
+                .collect()
+        }
+        let encode = |smf: &Smf| {
+            let mut raw = Vec::new();
+            smf.write_crc32(&mut raw).ok().map(|crc| (crc, raw))
+        };
+
+        let raw = files.iter().map(encode).collect::<Vec<_>>();
+        let canonical = files
+            .iter()
+            .map(|smf| {
+                let mut smf = smf.clone();
+                smf.canonicalize();
+                encode(&smf)
+            })
+            .collect::<Vec<_>>();
+        let identical = group(&raw);
+        let equivalent = group(&canonical);
+        Duplicates {
+            identical: collect(&identical, |_| true),
+            equivalent: collect(&equivalent, |group| {
+                group
+                    .iter()
+                    .any(|&idx| identical[idx] != identical[group[0]])
+            }),
+        }
+    }
+
+    /// Encodes and writes the file to the given generic writer, returning the CRC-32 checksum of
+    /// the written bytes.
+    ///
+    /// This computes the checksum on the fly, avoiding a second pass over the output.
+    /// See [`Crc32Writer`](../io/struct.Crc32Writer.html) for details on the checksum used.
+    ///
+    /// This function is always available, even in `no_std` environments.
+    pub fn write_crc32<W: Write>(&self, out: &mut W) -> StdResult<u32, WriteError<W::Error>> {
+        let mut out = Crc32Writer::new(out);
+        self.write(&mut out)?;
+        Ok(out.crc())
+    }
+
+    /// Encodes and writes the file to the given generic writer, returning the amount of bytes
+    /// written.
+    ///
+    /// See [`CountingWriter`](../io/struct.CountingWriter.html) to keep track of the byte count
+    /// while writing.
+    ///
+    /// This function is always available, even in `no_std` environments.
+    pub fn write_counted<W: Write>(&self, out: &mut W) -> StdResult<u64, WriteError<W::Error>> {
+        let mut out = CountingWriter::new(out);
+        self.write(&mut out)?;
+        Ok(out.count())
+    }
+
+    /// Compute the amount of bytes that writing this file would produce, without actually
+    /// writing it.
+    ///
+    /// Fails with the same error that [`write`](#method.write) would report if the file cannot
+    /// be encoded (eg. if it has too many tracks).
+    ///
+    /// This function is always available, even in `no_std` environments.
+    pub fn encoded_len(&self) -> StdResult<u64, WriteError<&'static str>> {
+        let mut counter = WriteCounter(0);
+        self.write(&mut counter)?;
+        Ok(counter.0)
+    }
+
+    /// Encodes and writes the file to the given `std::io::Write` writer.
+    ///
+    /// This function is similar to the [`write`](#method.write) method, but writes to a
+    /// `std::io::Write` writer instead of a `midly::io::Write` writer.
+    ///
+    /// The file is written in several small pieces, so unbuffered writers such as `File` should be
+    /// wrapped in a `std::io::BufWriter`, like [`save`](#method.save) does.
+    ///
+    /// This function is only available with the `std` feature enabled.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn write_std<W: io::Write>(&self, out: W) -> io::Result<()> {
+        write_std(&self.header, &self.tracks, out)
+    }
+
+    /// Encodes and writes the file to the given path.
+    ///
+    /// The file is written through a `std::io::BufWriter`, which is flushed before returning.
+    ///
+    /// This function is only available with the `std` feature enabled.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        /// A non-generic, non-inline function.
+        /// This means that this function will be compiled and monomorphized once, and reused for
+        /// every call to `save`.
+        fn save_impl(smf: &Smf, path: &Path) -> io::Result<()> {
+            save_buffered(path, &|out| Ok(smf.write(out)?))
+        }
+        save_impl(self, path.as_ref())
+    }
+
+    /// Encode this file as a base64 string, which is handy to embed small files in source code
+    /// (eg. as test fixtures).
+    ///
+    /// The output uses the standard base64 alphabet with padding, and can be decoded back with
+    /// [`from_base64`](#method.from_base64).
+    ///
+    /// Returns an error if the file cannot be encoded (eg. if it has too many tracks).
+    ///
+    /// This function is only available with the `base64` feature enabled.
+    #[cfg(feature = "base64")]
+    pub fn to_base64(&self) -> StdResult<String, WriteError<&'static str>> {
+        let mut raw = Vec::new();
+        self.write(&mut raw)?;
+        Ok(base64::encode(&raw))
+    }
+
+    /// Decode and parse a base64-encoded file, as produced by
+    /// [`to_base64`](#method.to_base64).
+    ///
+    /// Since parsed events borrow from the raw file bytes, the decoded bytes are stored in the
+    /// given arena.
+    /// Note that [`to_static`](#method.to_static) cannot be used to get rid of the arena, since it
+    /// replaces all bytestrings (track names, SysEx dumps, etc...) with empty ones.
+    /// Whitespace within the base64 text is ignored, so it can be split across several lines.
+    ///
+    /// This function is only available with the `base64` feature enabled.
+    #[cfg(feature = "base64")]
+    pub fn from_base64(text: &str, arena: &'a Arena) -> Result<Smf<'a>> {
+        let raw = arena.add_vec(base64::decode(text)?);
+        Smf::parse(raw)
+    }
+
+    /// Remove any lifetimed data from this event to create an `Smf` with `'static`
+    /// lifetime that can be stored and moved everywhere, solving borrow checker issues.
+    ///
+    /// This method creates a copy of the `Smf` structure. See the `make_static` method for an
+    /// in-place solution.
+    ///
+    /// WARNING: Any bytestrings, including meta messages, SysEx dumps and escape sequences will be
+    /// replaced by empty bytestrings.
+    pub fn to_static(&self) -> Smf<'static> {
+        self.clone().make_static()
+    }
+
+    /// Remove any lifetimed data from this event to create an `Smf` with `'static`
+    /// lifetime that can be stored and moved everywhere, solving borrow checker issues.
+    ///
+    /// This method consumes the `Smf` structure, reusing the backing memory.
+    ///
+    /// WARNING: Any bytestrings, including meta messages, SysEx dumps and escape sequences will be
+    /// replaced by empty bytestrings.
+    pub fn make_static(mut self) -> Smf<'static> {
+        for track in self.tracks.iter_mut() {
+            for ev in track.iter_mut() {
+                *ev = ev.to_static();
+            }
+        }
+        unsafe { mem::transmute::<Smf<'a>, Smf<'static>>(self) }
+    }
+
+    /// Create an independent copy of this file, copying any bytestrings (meta messages, SysEx
+    /// dumps and escape sequences) into the given arena.
+    ///
+    /// Unlike [`clone`](#method.clone), the copy does not borrow from the buffer this file was
+    /// parsed from, so that buffer may be dropped while the copy is kept around.
+    /// Unlike [`to_static`](#method.to_static), no data is lost.
+    pub fn deep_clone<'b>(&self, arena: &'b Arena) -> Smf<'b> {
+        Smf {
+            header: self.header,
+            tracks: self
+                .tracks
+                .iter()
+                .map(|track| track.iter().map(|ev| ev.to_arena(arena)).collect())
+                .collect(),
+        }
+    }
+
+    /// Rewrite this file into a canonical form, so that files that sound the same and only differ
+    /// in how they are encoded end up byte-identical when written.
+    ///
+    /// The following normalizations are applied:
+    /// - All note offs are written as `NoteOn` messages with zero velocity, which discards
+    ///   release velocities (see [`normalize_note_offs`](fn.normalize_note_offs.html)).
+    /// - Simultaneous events within a track are sorted according to
+    ///   [`sort_timeline`](fn.sort_timeline.html).
+    /// - Tempo events that are overridden by another tempo event at the same tick, or that set the
+    ///   tempo already in effect (starting from the default of 120 beats per minute), are
+    ///   removed.
+    /// - Every track ends with a single `EndOfTrack` event, placed right at its last event, so
+    ///   trailing silence is dropped.
+    /// - In `Format::Parallel` files, all tracks but the first are sorted by their contents.
+    ///   The first track conventionally holds the tempo map, and the tracks of
+    ///   `Format::Sequential` files play in order, so these are never moved.
+    ///
+    /// Variable-length quantities and running status are always encoded the same way by the
+    /// writer, so they need no special treatment.
+    pub fn canonicalize(&mut self) {
+        let mut timelines = self
+            .tracks
+            .iter_mut()
+            .map(|track| {
+                edit::normalize_note_offs(track, NoteOffStyle::ZeroVelocity);
+                merge_absolute(core::iter::once(&track[..]))
+            })
+            .collect::<Vec<_>>();
+        if self.header.format == Format::Sequential {
+            for timeline in timelines.iter_mut() {
+                drop_redundant_tempos(core::slice::from_mut(timeline));
+            }
+        } else {
+            drop_redundant_tempos(&mut timelines);
+        }
+        for (track, timeline) in self.tracks.iter_mut().zip(timelines) {
+            *track = from_absolute(timeline);
+        }
+        if self.header.format == Format::Parallel && self.tracks.len() > 2 {
+            self.tracks[1..].sort_by_cached_key(|track| {
+                let mut encoded = Vec::new();
+                let _ = Chunk::write_raw(track.iter(), &mut encoded);
+                encoded
+            });
+        }
+    }
+
+    /// Split the events of this file into one track per MIDI channel, producing a
+    /// `Format::Parallel` file.
+    ///
+    /// Events from all tracks are merged by absolute time, so this is mostly useful to convert
+    /// `Format::SingleTrack` files.
+    /// The first track of the output holds all non-channel events (meta messages, SysEx and
+    /// escapes), and is followed by the channel tracks in ascending channel order.
+    /// Each output track is terminated by a single `EndOfTrack` event.
+    ///
+    /// If `max_polyphony` is `Some(n)`, any channel that plays more than `n` simultaneous notes is
+    /// further split into the minimum amount of monophonic tracks, through a voice-allocation
+    /// pass that assigns each note to the lowest free voice.
+    /// In this case non-note channel events (controllers, program changes, etc...) are kept in
+    /// the first voice track of the channel.
+    pub fn split_by_channel(&self, max_polyphony: Option<usize>) -> Smf<'a> {
+        let events = merge_absolute(self.tracks.iter().map(|track| &track[..]));
+        let mut conductor = Vec::new();
+        let mut channels: [Vec<(u32, TrackEventKind<'a>)>; 16] = Default::default();
+        for (time, kind) in events {
+            match kind {
+                TrackEventKind::Midi { channel, .. } => {
+                    channels[channel.as_int() as usize].push((time, kind))
+                }
+                TrackEventKind::Meta(MetaMessage::EndOfTrack) => {}
+                _ => conductor.push((time, kind)),
+            }
+        }
+        let mut tracks = vec![from_absolute(conductor)];
+        for events in channels.iter().filter(|evs| !evs.is_empty()) {
+            let voices = allocate_voices(events);
+            let voice_count = voices.iter().map(|&v| v + 1).max().unwrap_or(1);
+            if max_polyphony.map(|max| voice_count > max).unwrap_or(false) {
+                for voice in 0..voice_count {
+                    tracks.push(from_absolute(
+                        events
+                            .iter()
+                            .zip(voices.iter())
+                            .filter(|(_, &v)| v == voice)
+                            .map(|(&ev, _)| ev),
+                    ));
+                }
+            } else {
+                tracks.push(from_absolute(events.iter().copied()));
+            }
+        }
+        Smf {
+            header: Header::new(Format::Parallel, self.header.timing),
+            tracks,
+        }
+    }
+}
+
+/// Converts absolute tick positions into elapsed wall-clock time by following a tempo map.
+///
+/// Positions must be fed in nondecreasing order.
+/// Files with timecode timing have no tempo map, and ticks are converted at a fixed rate.
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+struct TickClock {
+    /// Tempo changes, as absolute tick and microseconds per beat, sorted by time.
+    tempos: Vec<(u32, u64)>,
+    next: usize,
+    /// Ticks are converted to `scaled / div` microseconds, where `scaled` accumulates ticks
+    /// multiplied by a rate.
+    div: u64,
+    rate: u64,
+    segment_time: u32,
+    segment_scaled: u64,
+}
+#[cfg(feature = "alloc")]
+impl TickClock {
+    /// Build a clock using the tempo events within all of the given tracks.
+    fn new(timing: Timing, tracks: &[Track]) -> TickClock {
+        let (rate, div, tempo_aware) = match timing {
+            Timing::Metrical(ppq) => (500_000, ppq.as_int().max(1) as u64, true),
+            Timing::Timecode(fps, subframes) => {
+                let micros_per_second = if fps == Fps::Fps29 {
+                    1_001_000
+                } else {
+                    1_000_000
+                };
+                let ticks_per_second = fps.nominal() as u64 * subframes.max(1) as u64;
+                (micros_per_second, ticks_per_second, false)
+            }
+        };
+        let mut tempos = Vec::new();
+        if tempo_aware {
+            for track in tracks {
+                let mut time = 0u32;
+                for ev in track {
+                    time = time.saturating_add(ev.delta.as_int());
+                    if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = ev.kind {
+                        tempos.push((time, tempo.as_int() as u64));
+                    }
+                }
+            }
+            tempos.sort_by_key(|&(time, _)| time);
+        }
+        TickClock {
+            tempos,
+            next: 0,
+            div,
+            rate,
+            segment_time: 0,
+            segment_scaled: 0,
+        }
+    }
+
+    /// Advance the clock to the given absolute tick, returning the elapsed time in units of
+    /// `1 / div` microseconds.
+    ///
+    /// Tempo changes at exactly this tick take effect immediately.
+    fn advance(&mut self, time: u32) -> u64 {
+        while let Some(&(tempo_time, tempo)) = self.tempos.get(self.next) {
+            if tempo_time > time {
+                break;
+            }
+            self.segment_scaled += (tempo_time - self.segment_time) as u64 * self.rate;
+            self.segment_time = tempo_time;
+            self.rate = tempo;
+            self.next += 1;
+        }
+        self.segment_scaled + (time - self.segment_time) as u64 * self.rate
+    }
+
+    /// Advance the clock to the given absolute tick, returning the elapsed time in nanoseconds.
+    #[cfg(feature = "std")]
+    fn nanos(&mut self, time: u32) -> u128 {
+        self.advance(time) as u128 * 1000 / self.div as u128
+    }
+}
+
+#[cfg(feature = "std")]
+fn nanos_to_duration(nanos: u128) -> Duration {
+    Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+/// Remove tempo events that do not change the tempo from a set of tracks that share a tempo map,
+/// given as absolute-timed events.
+///
+/// A tempo event is redundant if another tempo event follows it at the same tick, or if it sets
+/// the tempo that is already in effect.
+#[cfg(feature = "alloc")]
+fn drop_redundant_tempos(timelines: &mut [Vec<(u32, TrackEventKind)>]) {
+    let mut tempos = Vec::new();
+    for (track, timeline) in timelines.iter().enumerate() {
+        for (idx, &(time, kind)) in timeline.iter().enumerate() {
+            if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = kind {
+                tempos.push((time, track, idx, tempo));
+            }
+        }
+    }
+    tempos.sort_by_key(|&(time, ..)| time);
+    let mut current = u24::new(500_000);
+    let mut redundant = BTreeSet::new();
+    for (i, &(time, track, idx, tempo)) in tempos.iter().enumerate() {
+        let overridden = tempos.get(i + 1).is_some_and(|next| next.0 == time);
+        if overridden || tempo == current {
+            redundant.insert((track, idx));
+        } else {
+            current = tempo;
+        }
+    }
+    for (track, timeline) in timelines.iter_mut().enumerate() {
+        let mut idx = 0;
+        timeline.retain(|_| {
+            idx += 1;
+            !redundant.contains(&(track, idx - 1))
+        });
+    }
+}
+
+/// Get a bitmask of the MIDI channels that have at least one channel message in a track.
+#[cfg(feature = "alloc")]
+fn channel_mask(track: &[TrackEvent]) -> u16 {
+    let mut mask = 0;
+    for ev in track {
+        if let TrackEventKind::Midi { channel, .. } = ev.kind {
+            mask |= 1 << channel.as_int();
+        }
+    }
+    mask
+}
+
+/// Iterate over the events of several tracks in order of absolute tick, merging them lazily with
+/// a binary heap.
+///
+/// Each event is yielded along with the index of its track and its absolute tick, and
+/// simultaneous events are yielded in track order.
+#[cfg(feature = "alloc")]
+pub(crate) fn merge_timeline<'a, 'b>(
+    tracks: &'b [Track<'a>],
+) -> impl Iterator<Item = (usize, u32, &'b TrackEvent<'a>)> {
+    let mut heap = BinaryHeap::with_capacity(tracks.len());
+    for (idx, track) in tracks.iter().enumerate() {
+        if let Some(first) = track.first() {
+            heap.push(Reverse((first.delta.as_int(), idx, 0)));
+        }
+    }
+    core::iter::from_fn(move || {
+        let Reverse((tick, track, pos)) = heap.pop()?;
+        let events = &tracks[track];
+        if let Some(next) = events.get(pos + 1) {
+            heap.push(Reverse((
+                tick.saturating_add(next.delta.as_int()),
+                track,
+                pos + 1,
+            )));
+        }
+        Some((track, tick, &events[pos]))
+    })
+}
+
+/// Merge several tracks into a single list of events with absolute times, sorted according to
+/// [`sort_timeline`](fn.sort_timeline.html).
+///
+/// Simultaneous events of the same priority keep their relative order, with events from earlier
+/// tracks placed first.
+#[cfg(feature = "alloc")]
+pub(crate) fn merge_absolute<'a, 'b>(
+    tracks: impl Iterator<Item = &'b [TrackEvent<'a>]>,
+) -> Vec<(u32, TrackEventKind<'a>)>
+where
+    'a: 'b,
+{
+    let mut events = Vec::new();
+    for track in tracks {
+        let mut time = 0u32;
+        for ev in track {
+            time = time.saturating_add(ev.delta.as_int());
+            events.push((time, ev.kind));
+        }
+    }
+    //Stable sort, so simultaneous events keep their track order
+    sort_timeline(&mut events);
+    events
+}
+
+/// Build a track out of absolute-timed events, terminating it with an `EndOfTrack` event.
+///
+/// Any `EndOfTrack` events within the input are dropped.
+/// Gaps that do not fit in a 28-bit delta are split up with `split_gap`, so that every event keeps
+/// its absolute time.
+#[cfg(feature = "alloc")]
+pub(crate) fn from_absolute<'a>(
+    events: impl IntoIterator<Item = (u32, TrackEventKind<'a>)>,
+) -> Track<'a> {
+    let mut track = Vec::new();
+    let mut last = 0;
+    for (time, kind) in events {
+        if let TrackEventKind::Meta(MetaMessage::EndOfTrack) = kind {
+            continue;
+        }
+        let delta = split_gap(&mut track, time.saturating_sub(last) as u64);
+        track.push(TrackEvent { delta, kind });
+        last = time;
+    }
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    track
+}
+
+/// Prepare a track to receive an event `delta` ticks after its last event, returning the delta
+/// to give to that event.
+///
+/// Gaps that do not fit in a 28-bit delta are split up by pushing empty `Text` meta events, which
+/// have no effect on playback.
+#[cfg(feature = "alloc")]
+pub(crate) fn split_gap(track: &mut Track, mut delta: u64) -> u28 {
+    let max_delta = u28::max_value().as_int() as u64;
+    while delta > max_delta {
+        track.push(TrackEvent {
+            delta: u28::max_value(),
+            kind: TrackEventKind::Meta(MetaMessage::Text(b"")),
+        });
+        delta -= max_delta;
+    }
+    u28::new(delta as u32)
+}
+
+/// Split a single track at an absolute tick, as described in `Smf::split_at_tick`.
+#[cfg(feature = "alloc")]
+fn split_track<'a>(track: &[TrackEvent<'a>], tick: u32) -> (Track<'a>, Track<'a>) {
+    let midi = |channel: u4, message: MidiMessage| TrackEventKind::Midi { channel, message };
+    let is_eot =
+        |kind: &TrackEventKind| matches!(kind, TrackEventKind::Meta(MetaMessage::EndOfTrack));
+    let release = |kind: TrackEventKind| match kind {
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOn { key, vel },
+        } if vel == 0 => Some((channel, key)),
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOff { key, .. },
+        } => Some((channel, key)),
+        _ => None,
+    };
+    let last_time = |events: &[(u32, TrackEventKind)]| {
+        events
+            .iter()
+            .rev()
+            .find(|(_, kind)| !is_eot(kind))
+            .map_or(0, |&(time, _)| time)
+    };
+    let mut time = 0u32;
+    let events = track
+        .iter()
+        .map(|ev| {
+            time = time.saturating_add(ev.delta.as_int());
+            (time, ev.kind)
+        })
+        .collect::<Vec<_>>();
+    let end = time;
+    let split = events.partition_point(|&(time, _)| time < tick);
+
+    //Replay the first half, keeping track of the state at the split
+    let mut metas = [None; 3];
+    let mut channels = [ChannelState::default(); 16];
+    let mut held = Vec::new();
+    for &(_, kind) in &events[..split] {
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(_)) => metas[0] = Some(kind),
+            TrackEventKind::Meta(MetaMessage::TimeSignature(..)) => metas[1] = Some(kind),
+            TrackEventKind::Meta(MetaMessage::KeySignature(..)) => metas[2] = Some(kind),
+            TrackEventKind::Midi { channel, message } => {
+                channels[channel.as_int() as usize].apply(message);
+                if let MidiMessage::NoteOn { key, vel } = message {
+                    if vel > 0 {
+                        held.push((channel, key, vel));
+                    }
+                }
+                if let Some(note) = release(kind) {
+                    if let Some(idx) = held.iter().position(|&(c, k, _)| (c, k) == note) {
+                        held.remove(idx);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    //Release held notes at the end of the first half
+    let mut head = events[..split].to_vec();
+    for &(channel, key, _) in held.iter() {
+        let vel = u7::new(64);
+        head.push((tick, midi(channel, MidiMessage::NoteOff { key, vel })));
+    }
+    let head_last = last_time(&head);
+    let mut head = from_absolute(head);
+    end_at(&mut head, head_last, tick);
+
+    //Start the second half with the state at the split
+    let mut tail = metas
+        .iter()
+        .flatten()
+        .map(|&kind| (0, kind))
+        .collect::<Vec<_>>();
+    let default = ChannelState::default();
+    for (channel, state) in channels.iter().enumerate() {
+        let channel = u4::new(channel as u8);
+        let mut controller = |controller: u8, value: u7| {
+            let controller = u7::new(controller);
+            tail.push((
+                0,
+                midi(channel, MidiMessage::Controller { controller, value }),
+            ));
+        };
+        if state.bank != default.bank {
+            controller(0, u7::new((state.bank.as_int() >> 7) as u8));
+            controller(32, u7::new((state.bank.as_int() & 0x7F) as u8));
+        }
+        if state.volume != default.volume {
+            controller(7, state.volume);
+        }
+        if state.pan != default.pan {
+            controller(10, state.pan);
+        }
+        if state.sustain {
+            controller(64, u7::new(127));
+        }
+        if state.program != default.program {
+            let program = state.program;
+            tail.push((0, midi(channel, MidiMessage::ProgramChange { program })));
+        }
+        if state.pitch_bend != default.pitch_bend {
+            let bend = state.pitch_bend;
+            tail.push((0, midi(channel, MidiMessage::PitchBend { bend })));
+        }
+    }
+    //Strike held notes again, unless they are released right at the split, in which case the
+    //release is dropped too
+    let at_split = events[split..]
+        .iter()
+        .take_while(|&&(time, _)| time == tick)
+        .count();
+    let mut skipped = Vec::new();
+    for &(channel, key, vel) in held.iter() {
+        let released = (split..split + at_split)
+            .find(|idx| !skipped.contains(idx) && release(events[*idx].1) == Some((channel, key)));
+        match released {
+            Some(idx) => skipped.push(idx),
+            None => tail.push((0, midi(channel, MidiMessage::NoteOn { key, vel }))),
+        }
+    }
+    for (idx, &(time, kind)) in events.iter().enumerate().skip(split) {
+        if !skipped.contains(&idx) {
+            tail.push((time - tick, kind));
+        }
+    }
+    let tail_last = last_time(&tail);
+    let mut tail = from_absolute(tail);
+    end_at(&mut tail, tail_last, end.saturating_sub(tick));
+    (head, tail)
+}
+
+/// Move the `EndOfTrack` event of a track built by `from_absolute`, whose last other event is at
+/// absolute time `last`, to the absolute time `end` (if it is later).
+#[cfg(feature = "alloc")]
+pub(crate) fn end_at(track: &mut Track, last: u32, end: u32) {
+    if let Some(eot) = track.pop() {
+        let delta = split_gap(track, end.saturating_sub(last) as u64);
+        track.push(TrackEvent { delta, ..eot });
+    }
+}
+
+/// Assign each event in a single-channel event list to a monophonic voice.
+///
+/// Notes are given the lowest voice that is not currently playing, and note-offs are routed to
+/// the voice that is playing that key.
+/// Any other event is assigned to voice 0.
+/// The amount of voices used is the maximum polyphony of the channel.
+#[cfg(feature = "alloc")]
+fn allocate_voices(events: &[(u32, TrackEventKind)]) -> Vec<usize> {
+    //The key that each voice is currently playing, if any
+    let mut playing: Vec<Option<u7>> = Vec::new();
+    events
+        .iter()
+        .map(|(_, kind)| match kind {
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, vel },
+                ..
+            } if *vel > 0 => match playing.iter().position(Option::is_none) {
+                Some(voice) => {
+                    playing[voice] = Some(*key);
+                    voice
+                }
+                None => {
+                    playing.push(Some(*key));
+                    playing.len() - 1
+                }
+            },
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, .. },
+                ..
+            }
+            | TrackEventKind::Midi {
+                message: MidiMessage::NoteOff { key, .. },
+                ..
+            } => match playing.iter().position(|k| *k == Some(*key)) {
+                Some(voice) => {
+                    playing[voice] = None;
+                    voice
+                }
+                None => 0,
+            },
+            _ => 0,
+        })
+        .collect()
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> core::ops::Index<usize> for Smf<'a> {
+    type Output = Track<'a>;
+    #[inline]
+    fn index(&self, idx: usize) -> &Track<'a> {
+        &self.tracks[idx]
+    }
+}
+#[cfg(feature = "alloc")]
+impl<'a> core::ops::IndexMut<usize> for Smf<'a> {
+    #[inline]
+    fn index_mut(&mut self, idx: usize) -> &mut Track<'a> {
+        &mut self.tracks[idx]
+    }
+}
+#[cfg(feature = "alloc")]
+impl<'a> IntoIterator for Smf<'a> {
+    type Item = Track<'a>;
+    type IntoIter = alloc::vec::IntoIter<Track<'a>>;
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.tracks.into_iter()
+    }
+}
+#[cfg(feature = "alloc")]
+impl<'a, 'b> IntoIterator for &'b Smf<'a> {
+    type Item = &'b Track<'a>;
+    type IntoIter = core::slice::Iter<'b, Track<'a>>;
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.tracks.iter()
+    }
+}
+#[cfg(feature = "alloc")]
+impl<'a, 'b> IntoIterator for &'b mut Smf<'a> {
+    type Item = &'b mut Track<'a>;
+    type IntoIter = core::slice::IterMut<'b, Track<'a>>;
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.tracks.iter_mut()
+    }
+}
+
+/// An unconventional use of the file format, reported by
+/// [`Smf::check_format_consistency`](struct.Smf.html#method.check_format_consistency).
+///
+/// This type is only available with the `alloc` feature enabled.
+#[cfg(feature = "alloc")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum FormatWarning {
+    /// The file has no tracks at all.
+    NoTracks,
+    /// The file is `Format::SingleTrack`, but it has more than one track.
+    SingleTrackMultipleTracks,
+    /// The file is `Format::Parallel`, but it has a single track, so it should probably be
+    /// `Format::SingleTrack`.
+    ParallelSingleTrack,
+    /// The file is `Format::Parallel`, and a track other than the first one has tempo changes.
+    ///
+    /// Conventionally, all tempo changes of a parallel file are stored in the first track, and
+    /// some tools ignore tempo changes anywhere else.
+    TempoOutsideFirstTrack {
+        /// The index of the offending track.
+        track: usize,
+    },
+}
+
+/// A parsed `.mid` file that owns the raw bytes its events borrow from, through an `Arc`.
+///
+/// Cloning a `SharedSmf` is cheap, since it only bumps two reference counts: neither the raw
+/// bytes nor the parsed events are copied.
+/// Because it has no lifetime parameter and is `Send + Sync`, it can be easily stored and shared
+/// across threads, for example by a server that plays back the same file to many clients.
+///
+/// The file is accessed through the [`smf`](#method.smf) method, which borrows the events for as
+/// long as the `SharedSmf` is borrowed.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug)]
+pub struct SharedSmf {
+    /// Borrows from `raw`.
+    /// This `'static` lifetime is a lie, and must never be exposed outside of this type.
+    smf: Arc<Smf<'static>>,
+    raw: Arc<[u8]>,
+}
+#[cfg(feature = "alloc")]
+impl SharedSmf {
+    /// Parse a `.mid` file out of a shared byte buffer, like
+    /// [`Smf::parse`](struct.Smf.html#method.parse).
+    pub fn parse(raw: impl Into<Arc<[u8]>>) -> Result<SharedSmf> {
+        let raw = raw.into();
+        let smf = Smf::parse(&raw)?;
+        //SAFETY: The parsed events borrow from the heap allocation behind `raw`, which never moves
+        //and is kept alive for as long as `smf` is, since both are stored together.
+        //The `'static` lifetime is shortened back to the lifetime of `&self` before being handed
+        //out, so no event can outlive the buffer.
+        let smf = unsafe { mem::transmute::<Smf<'_>, Smf<'static>>(smf) };
+        Ok(SharedSmf {
+            smf: Arc::new(smf),
+            raw,
+        })
+    }
+
+    /// Get the parsed file, borrowing from the shared buffer.
+    #[inline]
+    pub fn smf(&self) -> &Smf<'_> {
+        &self.smf
+    }
+
+    /// Get the raw bytes the file was parsed from.
+    #[inline]
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+}
+
+/// A track, represented as a `Vec` of events along with their originating bytes.
+///
+/// This type alias is only available with the `alloc` feature enabled.
+#[cfg(feature = "alloc")]
+pub type BytemappedTrack<'a> = Vec<(&'a [u8], TrackEvent<'a>)>;
+
+/// A `.mid` Standard Midi File, but keeps a mapping to the raw bytes that make up each event.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[cfg(feature = "alloc")]
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct SmfBytemap<'a> {
+    /// The header of this file.
+    pub header: Header,
+    /// A list of tracks, along with the bytemap of their events.
+    pub tracks: Vec<BytemappedTrack<'a>>,
+}
+#[cfg(feature = "alloc")]
+impl<'a> TryFrom<&'a [u8]> for SmfBytemap<'a> {
+    type Error = crate::Error;
+    /// Equivalent to [`SmfBytemap::parse`](#method.parse).
+    #[inline]
+    fn try_from(raw: &'a [u8]) -> Result<SmfBytemap<'a>> {
+        SmfBytemap::parse(raw)
+    }
+}
+#[cfg(feature = "alloc")]
+impl<'a> SmfBytemap<'a> {
+    /// Create a new empty `SmfBytemap` with zero tracks, using the given header.
+    #[inline]
+    pub fn new(header: Header) -> SmfBytemap<'a> {
+        SmfBytemap {
+            header,
+            tracks: vec![],
+        }
+    }
+
+    /// Parse a Standard Midi File from its raw bytes, keeping a map to the original bytes that
+    /// make up each event.
+    pub fn parse(raw: &[u8]) -> Result<SmfBytemap<'_>> {
+        let (header, tracks) = parse(raw)?;
+        let track_count_hint = tracks.track_count_hint;
+        let tracks = tracks.collect_bytemapped()?;
+        validate_smf(&header, track_count_hint, tracks.len())?;
+        Ok(SmfBytemap { header, tracks })
+    }
+
+    /// Encodes and writes the *events* (not the bytemap) to the given generic writer.
+    #[inline]
+    pub fn write<W: Write>(&self, out: &mut W) -> StdResult<(), WriteError<W::Error>> {
+        write(
+            &self.header,
+            self.tracks
+                .iter()
+                .map(|bytemapped| bytemapped.iter().map(|(_b, ev)| ev)),
+            out,
+        )
+    }
+
+    /// Encodes and writes the *events* (not the bytemap) to the given `std::io::Write` writer.
+    ///
+    /// This function is only available with the `std` feature enabled.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn write_std<W: io::Write>(&self, out: W) -> io::Result<()> {
+        write_std(
+            &self.header,
+            self.tracks
+                .iter()
+                .map(|bytemapped| bytemapped.iter().map(|(_b, ev)| ev)),
+            out,
+        )
+    }
+
+    /// Creates/overwrites the file at the given path and writes the *events* (not the bytemap) to
+    /// it.
+    ///
+    /// The file is written through a `std::io::BufWriter`, which is flushed before returning.
+    ///
+    /// This function is only available with the `std` feature enabled.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        /// A non-generic, non-inline function.
+        /// This means that this function will be compiled and monomorphized once, and reused for
+        /// every call to `save`.
+        fn save_impl(smf: &SmfBytemap, path: &Path) -> io::Result<()> {
+            save_buffered(path, &|out| Ok(smf.write(out)?))
+        }
+        save_impl(self, path.as_ref())
+    }
+}
+
+/// Receives notifications about recoverable issues found while parsing a file with
+/// [`Smf::parse_observed`](struct.Smf.html#method.parse_observed).
+///
+/// All offsets are byte offsets into the raw file bytes given to the parser.
+/// Every method does nothing by default, so implementors only need to override the callbacks they
+/// are interested in.
+///
+/// This trait is always available, even in `no_std` environments.
+pub trait ParseObserver {
+    /// A chunk with an unknown identifier was skipped.
+    ///
+    /// `offset` points to the start of the chunk header.
+    fn on_skipped_chunk(&mut self, id: [u8; 4], offset: usize) {
+        let _ = (id, offset);
+    }
+
+    /// The track with the given index was cut short, either because its chunk extends past the end
+    /// of the file or because it contains a malformed event.
+    ///
+    /// `offset` points to the first byte of the track that could not be parsed.
+    fn on_truncated_track(&mut self, index: usize, offset: usize) {
+        let _ = (index, offset);
+    }
+
+    /// An extra `MThd` header chunk was found and ignored.
+    ///
+    /// `offset` points to the start of the chunk header.
+    fn on_duplicate_header(&mut self, offset: usize) {
+        let _ = offset;
+    }
+
+    /// A chunk could not be read, and parsing stopped at this point.
+    ///
+    /// `offset` points to the start of the chunk.
+    fn on_invalid_chunk(&mut self, offset: usize) {
+        let _ = offset;
+    }
+
+    /// The header declares a format other than 0, 1 or 2, so the file was read as
+    /// `Format::Parallel`.
+    ///
+    /// `format` is the raw format value found in the header.
+    fn on_unknown_format(&mut self, format: u16) {
+        let _ = format;
+    }
+
+    /// An unknown chunk was not kept by
+    /// [`Smf::parse_preserving`](struct.Smf.html#method.parse_preserving), because it would
+    /// exceed the [`max_unknown_chunk_bytes`](struct.ParseOptions.html) cap.
+    ///
+    /// `offset` points to the start of the chunk header, and `len` is the length of the chunk data
+    /// present in the file.
+    fn on_dropped_chunk(&mut self, id: [u8; 4], offset: usize, len: usize) {
+        let _ = (id, offset, len);
+    }
+}
+
+/// The location of every chunk within the raw bytes of a Standard Midi File.
+///
+/// All ranges are byte ranges into the original buffer, and include the 8-byte chunk header
+/// (chunk id and chunk length) along with the chunk data.
+/// Chunks that are cut short by the end of the file end at the end of the file.
+///
+/// For RIFF-wrapped (`.rmi`) files, the ranges point into the embedded SMF data, and the RIFF
+/// wrapper itself is not covered by any range.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[cfg(feature = "alloc")]
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct FileLayout {
+    /// The range of the `MThd` header chunk.
+    pub header: core::ops::Range<usize>,
+    /// The ranges of the `MTrk` track chunks, in order.
+    pub tracks: Vec<core::ops::Range<usize>>,
+    /// The ranges of any unknown chunks, in order.
+    pub unknown: Vec<core::ops::Range<usize>>,
+}
+#[cfg(feature = "alloc")]
+impl FileLayout {
+    /// Scan the chunks of a raw Standard Midi File, without parsing any events.
+    pub fn read(raw: &[u8]) -> Result<FileLayout> {
+        let smf = match raw.get(..4) {
+            Some(b"RIFF") => riff::unwrap(raw)?,
+            Some(b"MThd") => raw,
+            _ => bail!(err_invalid!("not a midi file")),
+        };
+        let base = smf.as_ptr() as usize - raw.as_ptr() as usize;
+        let mut header = None;
+        let mut tracks = Vec::new();
+        let mut unknown = Vec::new();
+        let mut rest = smf;
+        while !rest.is_empty() {
+            let start = base + smf.len() - rest.len();
+            let id = rest
+                .split_checked(4)
+                .ok_or(err_invalid!("failed to read chunkid"))?;
+            let len = u32::read(&mut rest).context(err_invalid!("failed to read chunklen"))?;
+            if rest.split_checked(len as usize).is_none() {
+                if cfg!(feature = "strict") {
+                    bail!(err_malformed!("reached eof before chunk ended"));
+                }
+                rest = &[];
+            }
+            let range = start..base + smf.len() - rest.len();
+            match id {
+                b"MThd" if header.is_none() => header = Some(range),
+                b"MTrk" => tracks.push(range),
+                _ => unknown.push(range),
+            }
+        }
+        Ok(FileLayout {
+            header: header.ok_or(err_invalid!("no midi header chunk"))?,
+            tracks,
+            unknown,
+        })
+    }
+}
+
+/// A track, represented as a `Vec` of events along with a user-defined annotation for each event.
+///
+/// This type alias is only available with the `alloc` feature enabled.
+#[cfg(feature = "alloc")]
+pub type AnnotatedTrack<'a, T> = Vec<(TrackEvent<'a>, T)>;
+
+/// A `.mid` Standard Midi File that pairs each event with an arbitrary annotation of type `T`.
+///
+/// This is useful to attach editor-side metadata to events (eg. selection state or color),
+/// while keeping it out of the serialized file: writing an `SmfAnnotated` ignores the
+/// annotations altogether.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[cfg(feature = "alloc")]
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct SmfAnnotated<'a, T> {
+    /// The header of this file.
+    pub header: Header,
+    /// A list of tracks, along with the annotations of their events.
+    pub tracks: Vec<AnnotatedTrack<'a, T>>,
+}
+#[cfg(feature = "alloc")]
+impl<'a, T> SmfAnnotated<'a, T> {
+    /// Create a new empty `SmfAnnotated` with zero tracks, using the given header.
+    #[inline]
+    pub fn new(header: Header) -> SmfAnnotated<'a, T> {
+        SmfAnnotated {
+            header,
+            tracks: vec![],
+        }
+    }
+
+    /// Annotate every event in the given file with `T::default()`.
+    pub fn from_smf(smf: Smf<'a>) -> SmfAnnotated<'a, T>
+    where
+        T: Default,
+    {
+        SmfAnnotated {
+            header: smf.header,
+            tracks: smf
+                .tracks
+                .into_iter()
+                .map(|track| track.into_iter().map(|ev| (ev, T::default())).collect())
+                .collect(),
+        }
+    }
+
+    /// Drop all annotations, producing a plain `Smf`.
+    pub fn into_smf(self) -> Smf<'a> {
+        Smf {
+            header: self.header,
+            tracks: self
+                .tracks
+                .into_iter()
+                .map(|track| track.into_iter().map(|(ev, _a)| ev).collect())
+                .collect(),
+        }
+    }
+
+    /// Encodes and writes the *events* (not the annotations) to the given generic writer.
+    ///
+    /// Annotations must be `Sync`, since tracks may be written from multiple threads.
+    #[inline]
+    pub fn write<W: Write>(&self, out: &mut W) -> StdResult<(), WriteError<W::Error>>
+    where
+        T: Sync,
+    {
+        write(
+            &self.header,
+            self.tracks
+                .iter()
+                .map(|annotated| annotated.iter().map(|(ev, _a)| ev)),
+            out,
+        )
+    }
+
+    /// Encodes and writes the *events* (not the annotations) to the given `std::io::Write`
+    /// writer.
+    ///
+    /// This function is only available with the `std` feature enabled.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn write_std<W: io::Write>(&self, out: W) -> io::Result<()>
+    where
+        T: Sync,
+    {
+        write_std(
+            &self.header,
+            self.tracks
+                .iter()
+                .map(|annotated| annotated.iter().map(|(ev, _a)| ev)),
+            out,
+        )
+    }
+
+    /// Creates/overwrites the file at the given path and writes the *events* (not the
+    /// annotations) to it.
+    ///
+    /// The file is written through a `std::io::BufWriter`, which is flushed before returning.
+    ///
+    /// This function is only available with the `std` feature enabled.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()>
+    where
+        T: Sync,
+    {
+        save_buffered(path.as_ref(), &|out| Ok(self.write(out)?))
+    }
+}
+#[cfg(feature = "alloc")]
+impl<'a, T: Default> From<Smf<'a>> for SmfAnnotated<'a, T> {
+    #[inline]
+    fn from(smf: Smf<'a>) -> SmfAnnotated<'a, T> {
+        SmfAnnotated::from_smf(smf)
+    }
+}
+
+/// Wrap a MIDI error into an `std::io::Error` of kind `InvalidData`.
+#[cfg(feature = "std")]
+fn invalid_data(err: impl Into<crate::Error>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.into())
+}
+
+/// Create or overwrite the file at the given path, writing to it through a buffer that is flushed
+/// at the end, so that write errors are not lost when the buffer is dropped.
+#[cfg(feature = "std")]
+fn save_buffered(
+    path: &Path,
+    write: &dyn Fn(&mut IoWrap<&mut io::BufWriter<File>>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut out = io::BufWriter::new(File::create(path)?);
+    write(&mut IoWrap(&mut out))?;
+    io::Write::flush(&mut out)
+}
+
+/// Read the identifier and length of a chunk.
+#[cfg(feature = "std")]
+fn read_chunk_head<R: io::Read>(reader: &mut R) -> io::Result<([u8; 4], u32)> {
+    let mut head = [0; 8];
+    reader.read_exact(&mut head)?;
+    let mut id = [0; 4];
+    id.copy_from_slice(&head[..4]);
+    let mut len = &head[4..];
+    Ok((id, u32::read(&mut len).map_err(invalid_data)?))
+}
+
+/// Read the data of a chunk, given its length.
+#[cfg(feature = "std")]
+fn read_chunk_data<R: io::Read>(reader: &mut R, len: u32) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    io::Read::read_to_end(&mut io::Read::take(reader, len as u64), &mut data)?;
+    if cfg!(feature = "strict") && data.len() < len as usize {
+        return Err(invalid_data(err_malformed!(
+            "reached eof before chunk ended"
+        )));
+    }
+    Ok(data)
+}
+
+#[cfg(feature = "alloc")]
+fn validate_smf(header: &Header, track_count_hint: u16, track_count: usize) -> Result<()> {
+    if cfg!(feature = "strict") {
+        ensure!(
+            track_count_hint as usize == track_count,
+            err_malformed!("file has a different amount of tracks than declared")
+        );
+        ensure!(
+            header.format != Format::SingleTrack || track_count == 1,
+            err_malformed!("singletrack format file has multiple tracks")
+        );
+    }
+    Ok(())
+}
+
+/// The raw bytes of a single MIDI message, as yielded by
+/// [`Smf::midi_bytes`](struct.Smf.html#method.midi_bytes).
+///
+/// Channel messages are stored inline and escape events borrow their data from the file, so only
+/// sysex messages, which need an extra `0xF0` prefix, allocate.
+/// The bytes are accessed by dereferencing into a byte slice.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MidiBytes<'a> {
+    inner: MidiBytesInner<'a>,
+}
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum MidiBytesInner<'a> {
+    Inline([u8; 3], u8),
+    Borrowed(&'a [u8]),
+    Owned(Vec<u8>),
+}
+#[cfg(feature = "alloc")]
+impl<'a> MidiBytes<'a> {
+    /// Get the bytes to send for an event, or `None` if the event never goes on the wire.
+    fn new(kind: TrackEventKind<'a>) -> Option<MidiBytes<'a>> {
+        let inner = match kind {
+            TrackEventKind::Midi { .. } => {
+                let mut buf = [0; 3];
+                let mut rest = &mut buf[..];
+                kind.as_live_event()?
+                    .write(&mut rest)
+                    .expect("channel messages fit in 3 bytes");
+                let len = 3 - rest.len() as u8;
+                MidiBytesInner::Inline(buf, len)
+            }
+            TrackEventKind::SysEx(data) => {
+                let mut bytes = Vec::with_capacity(data.len() + 1);
+                bytes.push(0xF0);
+                bytes.extend_from_slice(data);
+                MidiBytesInner::Owned(bytes)
+            }
+            TrackEventKind::Escape(data) if !data.is_empty() => MidiBytesInner::Borrowed(data),
+            TrackEventKind::Escape(_) | TrackEventKind::Meta(_) => return None,
+        };
+        Some(MidiBytes { inner })
+    }
+}
+#[cfg(feature = "alloc")]
+impl core::ops::Deref for MidiBytes<'_> {
+    type Target = [u8];
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match &self.inner {
+            MidiBytesInner::Inline(buf, len) => &buf[..*len as usize],
+            MidiBytesInner::Borrowed(data) => data,
+            MidiBytesInner::Owned(data) => data,
+        }
+    }
+}
+#[cfg(feature = "alloc")]
+impl AsRef<[u8]> for MidiBytes<'_> {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Groups of duplicate files found by [`Smf::dedupe`](struct.Smf.html#method.dedupe).
+///
+/// Each group is a list of indices into the files given to `dedupe`, in ascending order.
+///
+/// This type is only available with the `std` feature enabled.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Duplicates {
+    /// Groups of files that encode to exactly the same bytes.
+    pub identical: Vec<Vec<usize>>,
+    /// Groups of files that encode to different bytes, but are the same once canonicalized.
+    pub equivalent: Vec<Vec<usize>>,
+}
+
+/// Statistics gathered while parsing a file with
+/// [`Smf::parse_with_metrics`](struct.Smf.html#method.parse_with_metrics).
+///
+/// This type is only available with the `std` feature enabled.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseMetrics {
+    /// The amount of raw bytes consumed, including the header and any RIFF wrapping.
+    pub bytes: usize,
+    /// How many tracks were parsed using multiple threads.
+    ///
+    /// Always zero if the `parallel` feature is disabled.
+    pub parallel_tracks: usize,
+    /// How many tracks were parsed in the calling thread.
+    pub serial_tracks: usize,
+    /// Time spent unwrapping and parsing the file header.
+    pub header_time: Duration,
+    /// Time spent splitting the file into tracks and parsing their events.
+    pub track_time: Duration,
+}
+
+#[cfg(feature = "std")]
+impl ParseMetrics {
+    /// Whether the tracks of the file were parsed using multiple threads.
+    ///
+    /// This is useful to check whether a given file crosses the thresholds set in
+    /// [`ParseOptions`](struct.ParseOptions.html).
+    /// Always `false` if the `parallel` feature is disabled.
+    #[inline]
+    pub fn was_parallel(&self) -> bool {
+        self.parallel_tracks > 0
+    }
+}
+
+/// Check whether a raw MIDI file is structurally valid, without parsing it into events.
+///
+/// The whole file is walked through, skipping over events instead of building them, so this is
+/// much cheaper than a full parse and makes no allocations.
+/// All checks are as strict as the MIDI spec, regardless of whether the `strict` feature is
+/// enabled.
+/// The file is considered valid if:
+/// - It starts with an `MThd` header chunk declaring a known format and a valid timing, possibly
+///   within an RMID wrapper.
+/// - No chunk extends past the end of the file, and there is no other header chunk.
+/// - Every track consists of well-formed events, without system common or realtime messages,
+///   and ends with an `EndOfTrack` event, right at the end of its chunk.
+/// - The amount of tracks matches the header, and `Format::SingleTrack` files have exactly one
+///   track.
+///
+/// Chunks with unknown ids are allowed, as required by the spec.
+/// Returns the first problem found.
+///
+/// This function is always available, even in `no_std` environments.
+pub fn validate(raw: &[u8]) -> Result<()> {
+    /// Skip over a varlen integer.
+    fn skip_varlen(raw: &mut &[u8]) -> Result<u32> {
+        let mut int = 0;
+        for _ in 0..4 {
+            let byte = raw
+                .split_checked(1)
+                .ok_or(err_malformed!("unexpected eof while reading varlen int"))?[0];
+            int = (int << 7) | (byte & 0x7F) as u32;
+            if byte < 0x80 {
+                return Ok(int);
+            }
+        }
+        bail!(err_malformed!("varlen integer larger than 4 bytes"))
     }
-
-    /// Creates/overwrites the file at the given path and writes the *events* (not the bytemap) to
-    /// it.
-    ///
-    /// This function is only available with the `std` feature enabled.
-    #[cfg(feature = "std")]
-    #[inline]
-    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
-        /// A non-generic, non-inline function.
-        /// This means that this function will be compiled and monomorphized once, and reused for
-        /// every call to `save`.
-        fn save_impl(smf: &SmfBytemap, path: &Path) -> io::Result<()> {
-            smf.write(&mut IoWrap(File::create(path)?))
+    /// Skip over a varlen-prefixed slice.
+    fn skip_slice<'a>(raw: &mut &'a [u8]) -> Result<&'a [u8]> {
+        let len = skip_varlen(raw)?;
+        Ok(raw
+            .split_checked(len as usize)
+            .ok_or(err_malformed!("incomplete varlen slice"))?)
+    }
+    /// Skip over all events of a track chunk.
+    fn skip_track(mut raw: &[u8]) -> Result<()> {
+        let mut running_status = None;
+        let mut ended = false;
+        while !raw.is_empty() {
+            ensure!(!ended, err_malformed!("events after end of track"));
+            skip_varlen(&mut raw)?;
+            let mut status = *raw.first().ok_or(err_invalid!("failed to read status"))?;
+            if status < 0x80 {
+                status = running_status.ok_or(err_invalid!(
+                    "event missing status with no running status active"
+                ))?;
+            } else {
+                raw = &raw[1..];
+            }
+            match status {
+                0x80..=0xEF => {
+                    running_status = Some(status);
+                    let len = if let 0xC0..=0xDF = status { 1 } else { 2 };
+                    let data = raw
+                        .split_checked(len)
+                        .ok_or(err_invalid!("failed to read midi message data"))?;
+                    ensure!(
+                        data.iter().all(|&byte| byte < 0x80),
+                        err_malformed!("invalid byte with top bit set")
+                    );
+                }
+                0xFF => {
+                    running_status = None;
+                    let kind = raw
+                        .split_checked(1)
+                        .ok_or(err_invalid!("failed to read meta message type"))?[0];
+                    let data = skip_slice(&mut raw)?;
+                    ended = kind == 0x2F;
+                    ensure!(
+                        !ended || data.is_empty(),
+                        err_malformed!("end of track with data")
+                    );
+                }
+                0xF0 | 0xF7 => {
+                    running_status = None;
+                    skip_slice(&mut raw)?;
+                }
+                0xF1..=0xF6 => bail!(err_invalid!(
+                    "standard midi files cannot contain system common events"
+                )),
+                _ => bail!(err_invalid!(
+                    "standard midi files cannot contain system realtime events"
+                )),
+            }
         }
-        save_impl(self, path.as_ref())
+        ensure!(ended, err_malformed!("track has no end of track event"));
+        Ok(())
     }
-}
 
-#[cfg(feature = "alloc")]
-fn validate_smf(header: &Header, track_count_hint: u16, track_count: usize) -> Result<()> {
-    if cfg!(feature = "strict") {
-        ensure!(
-            track_count_hint as usize == track_count,
-            err_malformed!("file has a different amount of tracks than declared")
-        );
-        ensure!(
-            header.format != Format::SingleTrack || track_count == 1,
-            err_malformed!("singletrack format file has multiple tracks")
-        );
+    let mut raw = match raw.get(..4) {
+        Some(b"RIFF") => riff::unwrap(raw)?,
+        Some(b"MThd") => raw,
+        _ => bail!(err_invalid!("not a midi file")),
+    };
+    let mut header = None;
+    let mut track_count = 0usize;
+    while !raw.is_empty() {
+        let id = raw
+            .split_checked(4)
+            .ok_or(err_invalid!("failed to read chunkid"))?;
+        let len = u32::read(&mut raw).context(err_invalid!("failed to read chunklen"))?;
+        let mut data = raw
+            .split_checked(len as usize)
+            .ok_or(err_malformed!("reached eof before chunk ended"))?;
+        match id {
+            b"MThd" => {
+                ensure!(header.is_none(), err_malformed!("found duplicate header"));
+                let format = u16::read(&mut data)?;
+                let declared = u16::read(&mut data)?;
+                let division = u16::read(&mut data)?;
+                ensure!(format <= 2, err_invalid!("invalid smf format"));
+                ensure!(division != 0, err_malformed!("zero ticks per beat"));
+                Timing::from_division_u16(division)?;
+                header = Some((format, declared));
+            }
+            _ if header.is_none() => bail!(err_invalid!("expected header, found track")),
+            b"MTrk" => {
+                skip_track(data).context(err_malformed!("malformed event"))?;
+                track_count += 1;
+            }
+            _ => {}
+        }
     }
+    let (format, declared) = header.ok_or(err_invalid!("no midi header chunk"))?;
+    ensure!(
+        declared as usize == track_count,
+        err_malformed!("file has a different amount of tracks than declared")
+    );
+    ensure!(
+        format != 0 || track_count == 1,
+        err_malformed!("singletrack format file has multiple tracks")
+    );
     Ok(())
 }
 
 /// Parse a raw MIDI file lazily, yielding its header and a lazy track iterator.
 /// No allocations are made.
 ///
+/// Only the header chunk is parsed eagerly, so this function doubles as a fast way to peek at the
+/// header of a file: tracks are not touched until the track iterator is advanced, and dropping
+/// the iterator without using it costs nothing.
+///
 /// The track iterator that is returned yields event iterators, which in turn yield concrete events.
+/// The amount of tracks declared by the header is available through
+/// [`TrackIter::declared_count`](struct.TrackIter.html#method.declared_count), although files
+/// may contain a different amount of tracks.
+///
+/// Track chunks with a length of zero are valid, and yield event iterators with no events.
 ///
 /// This function is always available, even in `no_std` environments.
-pub fn parse(raw: &[u8]) -> Result<(Header, TrackIter)> {
+pub fn parse(raw: &[u8]) -> Result<(Header, TrackIter<'_>)> {
     let raw = match raw.get(..4) {
         Some(b"RIFF") => riff::unwrap(raw)?,
         Some(b"MThd") => raw,
@@ -273,13 +3133,16 @@ pub fn parse(raw: &[u8]) -> Result<(Header, TrackIter)> {
         },
         None => Err(err_invalid!("no midi header chunk")),
     }?;
-    let tracks = chunks.as_tracks(track_count);
+    let tracks = chunks.into_tracks(track_count);
     Ok((header, tracks))
 }
 
 /// Encode and write a generic MIDI file into the given generic writer.
 /// The MIDI file is represented by a header and a list of tracks.
 ///
+/// Tracks are written exactly as given: in particular, no `EndOfTrack` event is added to tracks
+/// that lack one, so an empty track is written as an `MTrk` chunk with a length of zero.
+///
 /// # Errors
 ///
 /// The MIDI writer raises almost no errors by itself, it only bubbles errors from the underlying
@@ -300,7 +3163,11 @@ pub fn parse(raw: &[u8]) -> Result<(Header, TrackIter)> {
 ///
 /// Otherwise, encoding will happen twice: once to determine the size of the chunks and once again
 /// to actually write down the file.
-pub fn write<'a, T, E, W>(header: &Header, tracks: T, out: &mut W) -> WriteResult<W>
+pub fn write<'a, T, E, W>(
+    header: &Header,
+    tracks: T,
+    out: &mut W,
+) -> StdResult<(), WriteError<W::Error>>
 where
     T: IntoIterator<Item = E>,
     T::IntoIter: ExactSizeIterator + Clone + Send,
@@ -308,9 +3175,162 @@ where
     E::IntoIter: Clone + Send,
     W: Write,
 {
-    let tracks = tracks.into_iter().map(|events| events.into_iter());
+    let tracks = tracks.into_iter();
     //Write the header first
     Chunk::write_header(header, tracks.len(), out)?;
+    write_tracks(tracks, out)
+}
+
+/// Write a single `MThd` header chunk, declaring the given amount of tracks.
+///
+/// Together with [`write_track_chunk`](fn.write_track_chunk.html), this allows assembling MIDI
+/// data piece by piece, or embedding it in custom containers.
+/// Writing a header chunk followed by `track_count` track chunks produces the same bytes as
+/// [`write`](fn.write.html).
+///
+/// Errors with `WriteError::OutOfRange` if `track_count` does not fit in 16 bits.
+///
+/// This function is always available, even in `no_std` environments.
+#[inline]
+pub fn write_header_chunk<W: Write>(
+    header: &Header,
+    track_count: usize,
+    out: &mut W,
+) -> StdResult<(), WriteError<W::Error>> {
+    Chunk::write_header(header, track_count, out)
+}
+
+/// Write a single `MTrk` track chunk holding the given events.
+///
+/// Events are written exactly as given, making use of running status, and no `EndOfTrack` event
+/// is added.
+/// See [`write_header_chunk`](fn.write_header_chunk.html) for assembling whole files.
+///
+/// Errors with `WriteError::OutOfRange` if the track is 4GB or larger, and with an invalid input
+/// error of the writer if the data of an event is 256MB or larger.
+///
+/// This function is always available, even in `no_std` environments.
+pub fn write_track_chunk<'a, E, W>(events: E, out: &mut W) -> StdResult<(), WriteError<W::Error>>
+where
+    E: IntoIterator<Item = &'a TrackEvent<'a>>,
+    E::IntoIter: Clone,
+    W: Write,
+{
+    let track = events.into_iter();
+    #[cfg(feature = "alloc")]
+    {
+        let mut buf = Vec::new();
+        Chunk::write_to_vec(track, &mut buf).map_err(|err| err.map_sink(W::invalid_input))?;
+        return out.write(&buf).map_err(WriteError::Sink);
+    }
+
+    #[allow(unreachable_code)]
+    {
+        write_track_chunk_unbuffered(track, out)
+    }
+}
+
+/// Similar to [`write_track_chunk`](fn.write_track_chunk.html), but never buffers the chunk in
+/// memory, even if the `alloc` feature is enabled.
+///
+/// Seekable writers are written once and then seeked back to fill in the chunk length, while other
+/// writers are written twice, once to find out the length of the chunk and again to actually
+/// write it.
+///
+/// Errors in the same way as `write_track_chunk`.
+///
+/// This function is always available, even in `no_std` environments.
+pub fn write_track_chunk_unbuffered<'a, E, W>(
+    events: E,
+    out: &mut W,
+) -> StdResult<(), WriteError<W::Error>>
+where
+    E: IntoIterator<Item = &'a TrackEvent<'a>>,
+    E::IntoIter: Clone,
+    W: Write,
+{
+    let track = events.into_iter();
+    if let Some(out) = out.make_seekable() {
+        return Chunk::write_seek(track, out);
+    }
+    Chunk::write_probe(track, out)
+}
+
+/// Where to place extra chunks written by [`write_with_chunks`](fn.write_with_chunks.html).
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum ChunkPosition {
+    /// Right after the header chunk, before any track chunk.
+    BeforeTracks,
+    /// After all track chunks, at the end of the file.
+    AfterTracks,
+}
+
+/// Similar to [`write`](fn.write.html), but also writes the given extra chunks, for example
+/// proprietary chunks that should be preserved.
+///
+/// Each extra chunk is given as its 4-byte identifier and its data.
+/// Readers that follow the spec ignore chunks they don't recognize, including the `parse`
+/// function in this crate.
+///
+/// Errors with an invalid input error of the writer if an extra chunk uses the `MThd` or `MTrk`
+/// identifiers, which would corrupt the file, and with `WriteError::OutOfRange` if its data is 4GB
+/// or larger.
+///
+/// This function is always available, even in `no_std` environments.
+pub fn write_with_chunks<'a, T, E, W>(
+    header: &Header,
+    tracks: T,
+    extra_chunks: &[([u8; 4], &[u8])],
+    position: ChunkPosition,
+    out: &mut W,
+) -> StdResult<(), WriteError<W::Error>>
+where
+    T: IntoIterator<Item = E>,
+    T::IntoIter: ExactSizeIterator + Clone + Send,
+    E: IntoIterator<Item = &'a TrackEvent<'a>>,
+    E::IntoIter: Clone + Send,
+    W: Write,
+{
+    for (id, data) in extra_chunks {
+        if id == b"MThd" || id == b"MTrk" {
+            return Err(WriteError::Sink(W::invalid_input(
+                "extra chunk uses a standard chunk id",
+            )));
+        }
+        if u32::try_from(data.len()).is_err() {
+            return Err(WriteError::OutOfRange);
+        }
+    }
+    let write_extra = |out: &mut W| {
+        for (id, data) in extra_chunks {
+            out.write(&id[..])?;
+            out.write(&(data.len() as u32).to_be_bytes()[..])?;
+            out.write(data)?;
+        }
+        Ok(())
+    };
+
+    let tracks = tracks.into_iter();
+    Chunk::write_header(header, tracks.len(), out)?;
+    if position == ChunkPosition::BeforeTracks {
+        write_extra(out).map_err(WriteError::Sink)?;
+    }
+    write_tracks(tracks, out)?;
+    if position == ChunkPosition::AfterTracks {
+        write_extra(out).map_err(WriteError::Sink)?;
+    }
+    Ok(())
+}
+
+/// Write the track chunks of a file, after its header has been written.
+fn write_tracks<'a, T, E, W>(tracks: T, out: &mut W) -> StdResult<(), WriteError<W::Error>>
+where
+    T: Iterator<Item = E> + Clone + Send,
+    E: IntoIterator<Item = &'a TrackEvent<'a>>,
+    E::IntoIter: Clone + Send,
+    W: Write,
+{
+    let tracks = tracks.map(|events| events.into_iter());
 
     //Try to write the file in parallel
     #[cfg(feature = "parallel")]
@@ -324,7 +3344,7 @@ where
             use rayon::prelude::*;
 
             //Write out the tracks in parallel into several different buffers
-            let mut track_chunks = Vec::new();
+            let mut track_chunks: Vec<StdResult<_, WriteError<&'static str>>> = Vec::new();
             tracks
                 .collect::<Vec<_>>()
                 .into_par_iter()
@@ -337,8 +3357,8 @@ where
 
             //Write down the tracks sequentially and in order
             for result in track_chunks {
-                let track_chunk = result.map_err(W::invalid_input)?;
-                out.write(&track_chunk)?;
+                let track_chunk = result.map_err(|err| err.map_sink(W::invalid_input))?;
+                out.write(&track_chunk).map_err(WriteError::Sink)?;
             }
             return Ok(());
         }
@@ -349,8 +3369,8 @@ where
         //Write the tracks into a buffer before writing out to the file
         let mut buf = Vec::new();
         for track in tracks {
-            Chunk::write_to_vec(track, &mut buf).map_err(|msg| W::invalid_input(msg))?;
-            out.write(&buf)?;
+            Chunk::write_to_vec(track, &mut buf).map_err(|err| err.map_sink(W::invalid_input))?;
+            out.write(&buf).map_err(WriteError::Sink)?;
         }
         return Ok(());
     }
@@ -378,6 +3398,9 @@ where
 /// Similar to [`write`](fn.write.html), but writes to a `std::io::Write` writer instead of a
 /// `midly::io::Write` writer.
 ///
+/// The file is written in several small pieces, so unbuffered writers such as `File` should be
+/// wrapped in a `std::io::BufWriter`.
+///
 /// This function is only available with the `std` feature enabled.
 #[cfg(feature = "std")]
 #[inline]
@@ -389,25 +3412,31 @@ where
     E::IntoIter: Clone + Send,
     W: io::Write,
 {
-    write(header, tracks, &mut IoWrap(out))
+    Ok(write(header, tracks, &mut IoWrap(out))?)
 }
 
 #[derive(Clone, Debug)]
 struct ChunkIter<'a> {
     /// Starts at the current index, ends at EOF.
     raw: &'a [u8],
+    /// What to do with a chunk that extends past EOF.
+    overrun: ChunkOverrun,
 }
 impl<'a> ChunkIter<'a> {
     #[inline]
-    fn new(raw: &'a [u8]) -> ChunkIter {
-        ChunkIter { raw }
+    fn new(raw: &'a [u8]) -> ChunkIter<'a> {
+        ChunkIter {
+            raw,
+            overrun: ChunkOverrun::default(),
+        }
     }
 
     #[inline]
-    fn as_tracks(self, track_count_hint: u16) -> TrackIter<'a> {
+    fn into_tracks(self, track_count_hint: u16) -> TrackIter<'a> {
         TrackIter {
             chunks: self,
             track_count_hint,
+            options: ParseOptions::default(),
         }
     }
 }
@@ -416,7 +3445,7 @@ impl<'a> Iterator for ChunkIter<'a> {
     #[inline]
     fn next(&mut self) -> Option<Result<Chunk<'a>>> {
         //Flip around option and result
-        match Chunk::read(&mut self.raw) {
+        match Chunk::read(&mut self.raw, self.overrun) {
             Ok(Some(chunk)) => Some(Ok(chunk)),
             Ok(None) => None,
             Err(err) => {
@@ -439,7 +3468,7 @@ impl<'a> Chunk<'a> {
     /// Should be called with a byte slice at least as large as the chunk (ideally until EOF).
     /// The slice will be modified to point to the next chunk.
     /// If we're *exactly* at EOF (slice length 0), returns a None signalling no more chunks.
-    fn read(raw: &mut &'a [u8]) -> Result<Option<Chunk<'a>>> {
+    fn read(raw: &mut &'a [u8], overrun: ChunkOverrun) -> Result<Option<Chunk<'a>>> {
         Ok(loop {
             if raw.is_empty() {
                 break None;
@@ -450,14 +3479,14 @@ impl<'a> Chunk<'a> {
             let len = u32::read(raw).context(err_invalid!("failed to read chunklen"))?;
             let chunkdata = match raw.split_checked(len as usize) {
                 Some(chunkdata) => chunkdata,
-                None => {
-                    if cfg!(feature = "strict") {
-                        bail!(err_malformed!("reached eof before chunk ended"));
-                    } else {
-                        //Just use the remainder of the file
-                        mem::replace(raw, &[])
+                None => match overrun {
+                    ChunkOverrun::UseRemainder => mem::take(raw),
+                    ChunkOverrun::Error => bail!(err_malformed!("reached eof before chunk ended")),
+                    ChunkOverrun::TruncateToDeclared => {
+                        *raw = &[];
+                        break None;
                     }
-                }
+                },
             };
             match id {
                 b"MThd" => {
@@ -474,15 +3503,18 @@ impl<'a> Chunk<'a> {
     }
 
     /// Write a header chunk into a writer.
-    fn write_header<W: Write>(header: &Header, track_count: usize, out: &mut W) -> WriteResult<W> {
+    fn write_header<W: Write>(
+        header: &Header,
+        track_count: usize,
+        out: &mut W,
+    ) -> StdResult<(), WriteError<W::Error>> {
         let mut header_chunk = [0; 4 + 4 + 6];
-        let track_count = u16::try_from(track_count)
-            .map_err(|_| W::invalid_input("track count exceeds 16 bit range"))?;
+        let track_count = u16::try_from(track_count).map_err(|_| WriteError::OutOfRange)?;
         let header = header.encode(track_count);
         header_chunk[0..4].copy_from_slice(&b"MThd"[..]);
         header_chunk[4..8].copy_from_slice(&(header.len() as u32).to_be_bytes()[..]);
         header_chunk[8..].copy_from_slice(&header[..]);
-        out.write(&header_chunk[..])?;
+        out.write(&header_chunk[..]).map_err(WriteError::Sink)?;
         Ok(())
     }
 
@@ -493,14 +3525,15 @@ impl<'a> Chunk<'a> {
     fn write_probe<W: Write>(
         track: impl Iterator<Item = &'a TrackEvent<'a>> + Clone,
         out: &mut W,
-    ) -> WriteResult<W> {
+    ) -> StdResult<(), WriteError<W::Error>> {
         let mut counter = WriteCounter(0);
-        Self::write_raw(track.clone(), &mut counter).map_err(W::invalid_input)?;
-        let len = Self::check_len::<W, _>(counter.0)?;
+        Self::write_raw(track.clone(), &mut counter)
+            .map_err(|msg| WriteError::Sink(W::invalid_input(msg)))?;
+        let len = Self::check_len(counter.0)?;
         let mut head = [b'M', b'T', b'r', b'k', 0, 0, 0, 0];
         head[4..8].copy_from_slice(&len);
-        out.write(&head)?;
-        Self::write_raw(track, out)?;
+        out.write(&head).map_err(WriteError::Sink)?;
+        Self::write_raw(track, out).map_err(WriteError::Sink)?;
         Ok(())
     }
 
@@ -511,12 +3544,13 @@ impl<'a> Chunk<'a> {
     fn write_seek<W: Write + Seek>(
         track: impl Iterator<Item = &'a TrackEvent<'a>>,
         out: &mut W,
-    ) -> WriteResult<W> {
-        out.write(b"MTrk\0\0\0\0")?;
-        let start = out.tell()?;
-        Self::write_raw(track, out)?;
-        let len = Self::check_len::<W, _>(out.tell()? - start)?;
-        out.write_at(&len, start - 4)?;
+    ) -> StdResult<(), WriteError<W::Error>> {
+        out.write(b"MTrk\0\0\0\0").map_err(WriteError::Sink)?;
+        let start = out.tell().map_err(WriteError::Sink)?;
+        Self::write_raw(track, out).map_err(WriteError::Sink)?;
+        let end = out.tell().map_err(WriteError::Sink)?;
+        let len = Self::check_len(end - start)?;
+        out.write_at(&len, start - 4).map_err(WriteError::Sink)?;
         Ok(())
     }
 
@@ -528,13 +3562,13 @@ impl<'a> Chunk<'a> {
     fn write_to_vec(
         track: impl Iterator<Item = &'a TrackEvent<'a>>,
         out: &mut Vec<u8>,
-    ) -> WriteResult<Vec<u8>> {
+    ) -> StdResult<(), WriteError<&'static str>> {
         let cap = (track.size_hint().0 as f32 * EVENTS_TO_BYTES) as usize;
         out.clear();
         out.reserve(8 + cap);
         out.extend_from_slice(b"MTrk\0\0\0\0");
-        Self::write_raw(track, out)?;
-        let len = Self::check_len::<Vec<u8>, _>(out.len() - 8)?;
+        Self::write_raw(track, out).map_err(WriteError::Sink)?;
+        let len = Self::check_len(out.len() - 8)?;
         out[4..8].copy_from_slice(&len);
         Ok(())
     }
@@ -553,13 +3587,11 @@ impl<'a> Chunk<'a> {
 
     /// Auxiliary method. Given an arbitrary-width length, fit it into a 32-bit big-endian integer,
     /// reporting an error if it does not fit.
-    fn check_len<W, T>(len: T) -> StdResult<[u8; 4], W::Error>
+    fn check_len<E, T>(len: T) -> StdResult<[u8; 4], WriteError<E>>
     where
         u32: TryFrom<T>,
-        W: Write,
     {
-        let len = u32::try_from(len)
-            .map_err(|_| W::invalid_input("midi chunk size exceeds 32 bit range"))?;
+        let len = u32::try_from(len).map_err(|_| WriteError::OutOfRange)?;
         Ok(len.to_be_bytes())
     }
 }
@@ -576,13 +3608,63 @@ pub struct Header {
     /// file might have changing tempos along the song.
     pub timing: Timing,
 }
+/// The default header is a `Format::Parallel` file with the default `Timing`.
+impl Default for Header {
+    #[inline]
+    fn default() -> Header {
+        Header::new(Format::Parallel, Timing::default())
+    }
+}
 impl Header {
     /// Create a new header from its raw parts.
+    ///
+    /// This function is `const`, so headers can be built at compile time:
+    ///
+    /// ```rust
+    /// use midly::{num::u15, Format, Header, Timing};
+    ///
+    /// const HEADER: Header = Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480)));
+    /// ```
     #[inline]
-    pub fn new(format: Format, timing: Timing) -> Header {
+    pub const fn new(format: Format, timing: Timing) -> Header {
         Header { format, timing }
     }
 
+    /// Replace the timing of this header with metrical timing at the given amount of ticks per
+    /// beat (also known as PPQ, pulses per quarter note).
+    ///
+    /// Useful along with the default constructors, which use
+    /// [`Timing::DEFAULT_TICKS_PER_BEAT`](enum.Timing.html#associatedconstant.DEFAULT_TICKS_PER_BEAT):
+    ///
+    /// ```rust
+    /// use midly::{Header, Timing};
+    ///
+    /// let header = Header::default().with_ppq(960.into());
+    /// assert_eq!(header.timing, Timing::Metrical(960.into()));
+    /// ```
+    #[inline]
+    pub const fn with_ppq(self, ppq: u15) -> Header {
+        Header {
+            format: self.format,
+            timing: Timing::Metrical(ppq),
+        }
+    }
+
+    /// Set the timing of this header, making sure that it describes a usable tick length.
+    ///
+    /// Metrical timing with zero ticks per beat and timecode timing with zero ticks per frame are
+    /// rejected with an invalid input error, leaving the header untouched.
+    pub fn set_timing(&mut self, timing: Timing) -> Result<()> {
+        match timing {
+            Timing::Metrical(ppq) => ensure!(ppq > 0, err_invalid!("zero ticks per beat")),
+            Timing::Timecode(_, subframes) => {
+                ensure!(subframes > 0, err_invalid!("zero ticks per frame"))
+            }
+        }
+        self.timing = timing;
+        Ok(())
+    }
+
     /// Read the contents of a header chunk, including the `Header` and the track count.
     fn read(mut raw: &[u8]) -> Result<(Header, u16)> {
         let format = Format::read(&mut raw)?;
@@ -599,6 +3681,95 @@ impl Header {
     }
 }
 
+/// Options that tune how the tracks of a file are parsed.
+///
+/// Use them through [`Smf::parse_with_options`](struct.Smf.html#method.parse_with_options),
+/// [`Smf::parse_preserving`](struct.Smf.html#method.parse_preserving) or
+/// [`TrackIter::with_options`](struct.TrackIter.html#method.with_options).
+/// Apart from [`on_chunk_overrun`](#structfield.on_chunk_overrun) and
+/// [`max_payload_len`](#structfield.max_payload_len), the resulting tracks never depend on these
+/// options, only the way they are obtained does.
+///
+/// This type is always available, even in `no_std` environments.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ParseOptions {
+    /// Parse tracks using multiple threads if the track data is at least this many bytes long.
+    ///
+    /// Only has an effect if the `parallel` feature is enabled.
+    pub parallel_bytes: usize,
+    /// Parse tracks using multiple threads if the header declares at least this many tracks,
+    /// even if the track data is shorter than `parallel_bytes`.
+    ///
+    /// Tracks are parsed in parallel with each other, so files with many short but dense tracks
+    /// can benefit from multithreading even when they are small.
+    ///
+    /// Only has an effect if the `parallel` feature is enabled.
+    pub parallel_tracks: u16,
+    /// The most bytes of unknown chunk data to keep in total when parsing with
+    /// [`Smf::parse_preserving`](struct.Smf.html#method.parse_preserving).
+    ///
+    /// Unknown chunks that would push the total over this cap are dropped instead.
+    pub max_unknown_chunk_bytes: usize,
+    /// What to do with a chunk that declares a length longer than the rest of the file.
+    ///
+    /// Defaults to [`ChunkOverrun::UseRemainder`](enum.ChunkOverrun.html#variant.UseRemainder), or
+    /// to [`ChunkOverrun::Error`](enum.ChunkOverrun.html#variant.Error) if the `strict` feature is
+    /// enabled.
+    pub on_chunk_overrun: ChunkOverrun,
+    /// The longest payload, in bytes, that a sysex, escape or meta event may declare.
+    ///
+    /// Events declaring a longer payload are rejected with an error before their payload is
+    /// sliced out of the input, regardless of whether the `strict` feature is enabled.
+    /// This protects programs that copy events out of untrusted files (eg. through
+    /// [`Smf::to_static`](struct.Smf.html#method.to_static)) from allocating huge buffers
+    /// because of a single malicious event.
+    ///
+    /// By default there is no limit.
+    pub max_payload_len: usize,
+}
+impl Default for ParseOptions {
+    #[inline]
+    fn default() -> ParseOptions {
+        ParseOptions {
+            parallel_bytes: PARALLEL_ENABLE_THRESHOLD,
+            parallel_tracks: 64,
+            max_unknown_chunk_bytes: 1024 * 1024,
+            on_chunk_overrun: ChunkOverrun::default(),
+            max_payload_len: usize::MAX,
+        }
+    }
+}
+
+/// How to handle a chunk that declares a length longer than the rest of the file, which usually
+/// happens when a file is cut short.
+///
+/// Used by [`ParseOptions::on_chunk_overrun`](struct.ParseOptions.html#structfield.on_chunk_overrun).
+///
+/// This type is always available, even in `no_std` environments.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChunkOverrun {
+    /// Use the rest of the file as the chunk data, recovering as many events as possible.
+    UseRemainder,
+    /// Fail with an [`ErrorKind::Malformed`](enum.ErrorKind.html#variant.Malformed) error.
+    ///
+    /// With this policy, chunks that cannot be read at all (such as chunk headers cut short by
+    /// the end of the file) are errors too, instead of being skipped.
+    Error,
+    /// Drop the overrunning chunk, keeping only the chunks that fit within the file as declared.
+    TruncateToDeclared,
+}
+/// The default policy is `UseRemainder`, or `Error` if the `strict` feature is enabled.
+impl Default for ChunkOverrun {
+    #[inline]
+    fn default() -> ChunkOverrun {
+        if cfg!(feature = "strict") {
+            ChunkOverrun::Error
+        } else {
+            ChunkOverrun::UseRemainder
+        }
+    }
+}
+
 /// An iterator over all *tracks* in a Standard Midi File.
 /// Created by the [`parse`](fn.parse.html) function.
 ///
@@ -607,25 +3778,53 @@ impl Header {
 pub struct TrackIter<'a> {
     chunks: ChunkIter<'a>,
     track_count_hint: u16,
+    options: ParseOptions,
 }
 impl<'a> TrackIter<'a> {
     /// Create an event iterator from raw SMF bytes, excluding the header.
     ///
     /// The main way to obtain raw SMF without a header is the [`unread`](#method.unread) method.
     #[inline]
-    pub fn new(raw: &[u8]) -> TrackIter {
+    pub fn new(raw: &[u8]) -> TrackIter<'_> {
         TrackIter {
             chunks: ChunkIter::new(raw),
             track_count_hint: 0,
+            options: ParseOptions::default(),
         }
     }
 
+    /// Use the given options when collecting the remaining tracks.
+    #[inline]
+    pub fn with_options(mut self, options: ParseOptions) -> TrackIter<'a> {
+        self.options = options;
+        self.chunks.overrun = options.on_chunk_overrun;
+        self
+    }
+
+    /// Get the options used when collecting the remaining tracks.
+    #[inline]
+    pub fn options(&self) -> ParseOptions {
+        self.options
+    }
+
     /// Peek at the remaining unparsed bytes in the file.
     #[inline]
     pub fn unread(&self) -> &'a [u8] {
         self.chunks.raw
     }
 
+    /// Get the amount of remaining tracks, according to the track count declared in the file
+    /// header.
+    ///
+    /// This is only a hint: non-compliant files may hold more or fewer tracks than declared (and
+    /// in non-strict mode, invalid chunks are skipped), so `TrackIter` does not implement
+    /// `ExactSizeIterator`.
+    /// Iterators created through [`TrackIter::new`](#method.new) always declare zero tracks.
+    #[inline]
+    pub fn declared_count(&self) -> u16 {
+        self.track_count_hint
+    }
+
     /// Parse and collect the remaining unparsed tracks into a `Vec` of tracks.
     ///
     /// This function is only available with the `alloc` feature enabled.
@@ -643,6 +3842,21 @@ impl<'a> TrackIter<'a> {
         self.generic_collect(|events| events.bytemapped().into_vec())
     }
 
+    /// Whether collecting the remaining tracks would use multiple threads.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub(crate) fn use_parallel(&self) -> bool {
+        #[cfg(feature = "parallel")]
+        {
+            self.unread().len() >= self.options.parallel_bytes
+                || self.track_count_hint >= self.options.parallel_tracks
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            false
+        }
+    }
+
     #[cfg(feature = "alloc")]
     #[inline]
     fn generic_collect<T: Send + 'a>(
@@ -652,7 +3866,7 @@ impl<'a> TrackIter<'a> {
         //Attempt to use multiple threads if possible and advantageous
         #[cfg(feature = "parallel")]
         {
-            if self.unread().len() >= PARALLEL_ENABLE_THRESHOLD {
+            if self.use_parallel() {
                 use rayon::prelude::*;
 
                 let chunk_vec = self.collect::<Result<Vec<_>>>()?;
@@ -672,10 +3886,9 @@ impl<'a> Iterator for TrackIter<'a> {
 
     #[inline]
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (
-            self.track_count_hint as usize,
-            Some(self.track_count_hint as usize),
-        )
+        //The declared track count is only a hint: files may contain more tracks than declared (eg.
+        //if the header declares zero tracks), so never report an upper bound
+        (self.track_count_hint as usize, None)
     }
 
     #[inline]
@@ -684,7 +3897,11 @@ impl<'a> Iterator for TrackIter<'a> {
             if let Some(chunk) = self.chunks.next() {
                 self.track_count_hint = self.track_count_hint.saturating_sub(1);
                 match chunk {
-                    Ok(Chunk::Track(track)) => break Some(Ok(EventIter::new(track))),
+                    Ok(Chunk::Track(track)) => {
+                        let mut events = EventIter::new(track);
+                        events.inner.max_payload_len = self.options.max_payload_len;
+                        break Some(Ok(events));
+                    }
                     //Read another header (?)
                     Ok(Chunk::Header(..)) => {
                         if cfg!(feature = "strict") {
@@ -695,7 +3912,9 @@ impl<'a> Iterator for TrackIter<'a> {
                     }
                     //Failed to read chunk
                     Err(err) => {
-                        if cfg!(feature = "strict") {
+                        if cfg!(feature = "strict")
+                            || self.options.on_chunk_overrun == ChunkOverrun::Error
+                        {
                             break Some(Err(err).context(err_malformed!("invalid chunk")));
                         } else {
                             //Ignore invalid chunk
@@ -717,25 +3936,63 @@ trait EventKind<'a> {
 #[derive(Clone, Debug)]
 struct EventIterGeneric<'a, T> {
     raw: &'a [u8],
+    /// The length of the track data when the iterator was created.
+    len: usize,
     running_status: Option<u8>,
+    /// The longest payload that an event may declare.
+    max_payload_len: usize,
     _kind: PhantomData<T>,
 }
 impl<'a, T: EventKind<'a>> EventIterGeneric<'a, T> {
     #[inline]
-    fn new(raw: &[u8]) -> EventIterGeneric<T> {
+    fn new(raw: &[u8]) -> EventIterGeneric<'_, T> {
         EventIterGeneric {
             raw,
+            len: raw.len(),
             running_status: None,
+            max_payload_len: usize::MAX,
             _kind: PhantomData,
         }
     }
 
+    /// Make sure that the next event does not declare a payload longer than the maximum, without
+    /// reading the event.
+    #[inline]
+    fn check_payload_len(&mut self) -> Result<()> {
+        if self.max_payload_len == usize::MAX {
+            return Ok(());
+        }
+        let mut raw = self.raw;
+        let declared = (|| {
+            u28::read_u7(&mut raw).ok()?;
+            match raw.split_checked(1)? {
+                [0xF0] | [0xF7] => {}
+                [0xFF] => {
+                    raw.split_checked(1)?;
+                }
+                _ => return None,
+            }
+            Some(u28::read_u7(&mut raw).ok()?.as_int() as usize)
+        })();
+        if declared.is_some_and(|len| len > self.max_payload_len) {
+            self.raw = &[];
+            bail!(err_invalid!("event payload exceeds the maximum length"));
+        }
+        Ok(())
+    }
+
     /// Get the remaining unread bytes.
     #[inline]
     fn unread(&self) -> &'a [u8] {
         self.raw
     }
 
+    /// Get the amount of bytes consumed since the start of the track.
+    #[inline]
+    fn offset(&self) -> usize {
+        self.len - self.raw.len()
+    }
+
     /// Get the current running status of the track.
     #[inline]
     fn running_status(&self) -> Option<u8> {
@@ -756,9 +4013,19 @@ impl<'a, T: EventKind<'a>> EventIterGeneric<'a, T> {
 
     #[cfg(feature = "alloc")]
     #[inline]
-    fn into_vec(mut self) -> Result<Vec<T::Event>> {
-        let mut events = Vec::with_capacity(self.estimate_events());
+    fn into_vec(self) -> Result<Vec<T::Event>> {
+        let mut events = Vec::new();
+        self.collect_into(&mut events)?;
+        Ok(events)
+    }
+
+    #[cfg(feature = "alloc")]
+    #[inline]
+    fn collect_into(mut self, events: &mut Vec<T::Event>) -> Result<()> {
+        events.clear();
+        events.reserve(self.estimate_events());
         while !self.raw.is_empty() {
+            self.check_payload_len()?;
             match T::read_ev(&mut self.raw, &mut self.running_status) {
                 Ok(ev) => events.push(ev),
                 Err(err) => {
@@ -772,7 +4039,7 @@ impl<'a, T: EventKind<'a>> EventIterGeneric<'a, T> {
                 }
             }
         }
-        Ok(events)
+        Ok(())
     }
 }
 impl<'a, T: EventKind<'a>> Iterator for EventIterGeneric<'a, T> {
@@ -780,6 +4047,9 @@ impl<'a, T: EventKind<'a>> Iterator for EventIterGeneric<'a, T> {
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
         if !self.raw.is_empty() {
+            if let Err(err) = self.check_payload_len() {
+                return Some(Err(err));
+            }
             match T::read_ev(&mut self.raw, &mut self.running_status) {
                 Ok(ev) => Some(Ok(ev)),
                 Err(err) => {
@@ -822,7 +4092,7 @@ impl<'a> EventIter<'a> {
     /// Usually these raw track bytes are obtained from the [`unread`](#method.unread) method on an
     /// event iterator.
     #[inline]
-    pub fn new(raw: &[u8]) -> EventIter {
+    pub fn new(raw: &[u8]) -> EventIter<'_> {
         EventIter {
             inner: EventIterGeneric::new(raw),
         }
@@ -834,6 +4104,16 @@ impl<'a> EventIter<'a> {
         self.inner.unread()
     }
 
+    /// Get the byte offset of the next unparsed event, relative to the start of the track data
+    /// this iterator was created with.
+    ///
+    /// For iterators yielded by [`TrackIter`](struct.TrackIter.html), this is the offset from the
+    /// start of the track chunk data (ie. right after the `MTrk` chunk header).
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.inner.offset()
+    }
+
     /// Get the current running status of the track.
     #[inline]
     pub fn running_status(&self) -> Option<u8> {
@@ -846,18 +4126,66 @@ impl<'a> EventIter<'a> {
         self.inner.running_status_mut()
     }
 
+    /// Look for a `MetaMessage::TrackName` event among the leading events of the track, without
+    /// advancing this iterator.
+    ///
+    /// Only the meta events right at the position of this iterator (ie. before any other event,
+    /// and with a delta time of 0) are inspected.
+    /// For a fresh iterator this is the start of the track, where track names are conventionally
+    /// placed.
+    /// This makes it cheap to label tracks before deciding whether to parse them fully.
+    /// The name is decoded as with [`MetaMessage::decode_text`](enum.MetaMessage.html#method.decode_text).
+    ///
+    /// Returns `None` if there is no track name there, or if an error is found before reaching it.
+    ///
+    /// This method is only available with the `alloc` feature enabled.
+    #[cfg(feature = "alloc")]
+    pub fn peek_track_name(&self) -> Option<Cow<'a, str>> {
+        for ev in self.clone() {
+            match ev.ok()? {
+                TrackEvent {
+                    delta,
+                    kind: TrackEventKind::Meta(meta),
+                } if delta == 0 => {
+                    if let MetaMessage::TrackName(_) = meta {
+                        return meta.decode_text(false);
+                    }
+                }
+                _ => break,
+            }
+        }
+        None
+    }
+
     /// Make this event iterator keep track of the raw bytes that make up each event.
     #[inline]
     pub fn bytemapped(self) -> EventBytemapIter<'a> {
         EventBytemapIter {
             inner: EventIterGeneric {
                 raw: self.inner.raw,
+                len: self.inner.len,
                 running_status: self.inner.running_status,
+                max_payload_len: self.inner.max_payload_len,
                 _kind: PhantomData,
             },
         }
     }
 
+    /// Make this event iterator yield the position of each event in beats, relative to the
+    /// position of this iterator, along with the event itself.
+    ///
+    /// `ppq` is the amount of ticks per beat, usually taken from the
+    /// [`Timing::Metrical`](enum.Timing.html#variant.Metrical) field of the file header.
+    /// Since a beat cannot be zero ticks long, a `ppq` of `0` is treated as `1`.
+    #[inline]
+    pub fn beats(self, ppq: u16) -> EventBeatIter<'a> {
+        EventBeatIter {
+            events: self,
+            ticks: 0,
+            ppq: ppq.max(1) as f64,
+        }
+    }
+
     /// Collects the remaining unparsed events into a `Track`.
     ///
     /// This function is a smarter version of `Iterator::collect`, as it guesses allocations and
@@ -869,6 +4197,23 @@ impl<'a> EventIter<'a> {
     pub fn into_vec(self) -> Result<Track<'a>> {
         self.inner.into_vec()
     }
+
+    /// Collects the remaining unparsed events into an existing buffer, replacing its previous
+    /// contents.
+    ///
+    /// This is similar to [`into_vec`](#method.into_vec), but reuses the allocation of the given
+    /// buffer.
+    /// When processing a file one track at a time, a single buffer can be reused for all of its
+    /// tracks, keeping memory usage bounded by the largest track.
+    ///
+    /// On error, the buffer holds the events that were parsed before the error.
+    ///
+    /// This function is only available with the `alloc` feature enabled.
+    #[cfg(feature = "alloc")]
+    #[inline]
+    pub fn collect_into(self, buf: &mut Track<'a>) -> Result<()> {
+        self.inner.collect_into(buf)
+    }
 }
 impl<'a> Iterator for EventIter<'a> {
     type Item = Result<TrackEvent<'a>>;
@@ -878,6 +4223,31 @@ impl<'a> Iterator for EventIter<'a> {
     }
 }
 
+/// An iterator over the events of a single track that yields the position of each event in
+/// beats.
+/// Created by the [`EventIter::beats`](struct.EventIter.html#method.beats) method.
+///
+/// This iterator is lazy, it parses events as it goes, and therefore produces
+/// `Result<(f64, TrackEvent)>` rather than just `(f64, TrackEvent)`.
+///
+/// This type is always available, even in `no_std` environments.
+#[derive(Clone, Debug)]
+pub struct EventBeatIter<'a> {
+    events: EventIter<'a>,
+    ticks: u64,
+    ppq: f64,
+}
+impl<'a> Iterator for EventBeatIter<'a> {
+    type Item = Result<(f64, TrackEvent<'a>)>;
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.events.next()?.map(|ev| {
+            self.ticks += ev.delta.as_int() as u64;
+            (self.ticks as f64 / self.ppq, ev)
+        }))
+    }
+}
+
 /// An iterator over the events of a single track that keeps track of the raw bytes that make up
 /// each event.
 /// Created by the [`EventIter::bytemapped`](struct.EventIter.html#method.bytemapped) method.
@@ -904,7 +4274,7 @@ impl<'a> EventBytemapIter<'a> {
     /// Usually these raw track bytes are obtained from the [`unread`](#method.unread) method on an
     /// event iterator.
     #[inline]
-    pub fn new(raw: &[u8]) -> EventBytemapIter {
+    pub fn new(raw: &[u8]) -> EventBytemapIter<'_> {
         EventBytemapIter {
             inner: EventIterGeneric::new(raw),
         }
@@ -916,6 +4286,16 @@ impl<'a> EventBytemapIter<'a> {
         self.inner.unread()
     }
 
+    /// Get the byte offset of the next unparsed event, relative to the start of the track data
+    /// this iterator was created with.
+    ///
+    /// For iterators yielded by [`TrackIter`](struct.TrackIter.html), this is the offset from the
+    /// start of the track chunk data (ie. right after the `MTrk` chunk header).
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.inner.offset()
+    }
+
     /// Get the current running status of the track.
     #[inline]
     pub fn running_status(&self) -> Option<u8> {
@@ -934,7 +4314,9 @@ impl<'a> EventBytemapIter<'a> {
         EventIter {
             inner: EventIterGeneric {
                 raw: self.inner.raw,
+                len: self.inner.len,
                 running_status: self.inner.running_status,
+                max_payload_len: self.inner.max_payload_len,
                 _kind: PhantomData,
             },
         }
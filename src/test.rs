@@ -75,7 +75,7 @@ mod parse_collect {
     }
     #[cfg(not(feature = "alloc"))]
     impl<'a> Smf<'a> {
-        pub fn parse(raw: &[u8]) -> MidlyResult<Smf> {
+        pub fn parse(raw: &[u8]) -> MidlyResult<Smf<'_>> {
             let (header, tracks) = crate::parse(raw)?;
             Ok(Smf {
                 header,
@@ -84,7 +84,10 @@ mod parse_collect {
                     .collect::<MidlyResult<Vec<_>>>()?,
             })
         }
-        pub fn write<W: crate::io::Write>(&self, out: &mut W) -> Result<(), W::Error> {
+        pub fn write<W: crate::io::Write>(
+            &self,
+            out: &mut W,
+        ) -> Result<(), crate::io::WriteError<W::Error>> {
             crate::write(&self.header, self.tracks.iter(), out)
         }
     }
@@ -104,7 +107,7 @@ mod parse_bytemap {
     }
     #[cfg(not(feature = "alloc"))]
     impl<'a> Smf<'a> {
-        pub fn parse(raw: &[u8]) -> MidlyResult<Smf> {
+        pub fn parse(raw: &[u8]) -> MidlyResult<Smf<'_>> {
             let (header, tracks) = crate::parse(raw)?;
             Ok(Smf {
                 header,
@@ -121,7 +124,7 @@ mod parse_bytemap {
         //NOT consecutive (because delta times must interrupt every single event)
         for (bytes, _ev) in track.iter() {
             let mut advanced = false;
-            while !raw.starts_with(*bytes) {
+            while !raw.starts_with(bytes) {
                 advanced = true;
                 match raw.get(1..) {
                     Some(new_raw) => raw = new_raw,
@@ -136,12 +139,13 @@ mod parse_bytemap {
 }
 mod parse_lazy {
     use super::*;
+    #[allow(dead_code)]
     pub struct Smf<'a> {
         pub header: crate::Header,
         pub tracks: crate::TrackIter<'a>,
     }
     impl Smf<'_> {
-        pub fn parse(raw: &[u8]) -> MidlyResult<Smf> {
+        pub fn parse(raw: &[u8]) -> MidlyResult<Smf<'_>> {
             let (header, tracks) = crate::parse(raw)?;
             Ok(Smf { header, tracks })
         }
@@ -607,3 +611,3916 @@ mod parse {
         assert_send::<crate::Arena>();
     }
 }
+
+#[cfg(feature = "alloc")]
+fn note(delta: u32, channel: u8, key: u8, vel: u8) -> TrackEvent<'static> {
+    use crate::{MidiMessage, TrackEventKind};
+    TrackEvent {
+        delta: delta.into(),
+        kind: TrackEventKind::Midi {
+            channel: channel.into(),
+            message: MidiMessage::NoteOn {
+                key: key.into(),
+                vel: vel.into(),
+            },
+        },
+    }
+}
+
+#[cfg(feature = "alloc")]
+fn note_off(delta: u32, channel: u8, key: u8, vel: u8) -> TrackEvent<'static> {
+    use crate::{MidiMessage, TrackEventKind};
+    TrackEvent {
+        delta: delta.into(),
+        kind: TrackEventKind::Midi {
+            channel: channel.into(),
+            message: MidiMessage::NoteOff {
+                key: key.into(),
+                vel: vel.into(),
+            },
+        },
+    }
+}
+
+#[cfg(feature = "alloc")]
+fn meta(delta: u32, msg: crate::MetaMessage<'_>) -> TrackEvent<'_> {
+    TrackEvent {
+        delta: delta.into(),
+        kind: crate::TrackEventKind::Meta(msg),
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn split_polyphonic_channel() {
+    use crate::{Format, Header, Smf, Timing, TrackEventKind};
+
+    let mut smf = Smf::new(Header::new(
+        Format::SingleTrack,
+        Timing::Metrical(96.into()),
+    ));
+    smf.tracks.push(vec![
+        // A three-note chord on channel 0, plus a melody on channel 1
+        note(0, 0, 60, 100),
+        note(0, 0, 64, 100),
+        note(0, 0, 67, 100),
+        note(0, 1, 72, 100),
+        note(48, 1, 72, 0),
+        note(0, 1, 74, 100),
+        note(48, 0, 60, 0),
+        note(0, 0, 64, 0),
+        note(0, 0, 67, 0),
+        note(0, 1, 74, 0),
+        note(0, 0, 62, 100),
+        note(96, 0, 62, 0),
+    ]);
+
+    let unsplit = smf.split_by_channel(None);
+    assert_eq!(unsplit.header.format, Format::Parallel);
+    assert_eq!(unsplit.tracks.len(), 3);
+
+    let split = smf.split_by_channel(Some(1));
+    // Conductor, three voices for channel 0 and a single track for channel 1
+    assert_eq!(split.tracks.len(), 5);
+    for track in split.tracks[1..].iter() {
+        let mut sounding = 0;
+        for ev in track.iter() {
+            if let TrackEventKind::Midi { message, .. } = ev.kind {
+                match message {
+                    crate::MidiMessage::NoteOn { vel, .. } if vel > 0 => sounding += 1,
+                    _ => sounding -= 1,
+                }
+                assert!(sounding <= 1, "voice track is not monophonic");
+            }
+        }
+        assert_eq!(sounding, 0);
+    }
+    let note_count = |smf: &Smf| -> usize { smf.tracks.iter().map(|t| t.len() - 1).sum() };
+    assert_eq!(note_count(&split), note_count(&unsplit));
+}
+
+#[test]
+fn const_header() {
+    use crate::{
+        num::{u15, u28},
+        Format, Fps, Header, Timing,
+    };
+
+    const HEADER: Header = Header::new(Format::Parallel, Timing::Metrical(u15::new(480)));
+    const SMPTE: Header = Header::new(Format::SingleTrack, Timing::Timecode(Fps::Fps25, 40));
+    const TICKS: u16 = u15::new(480).as_int();
+    const FPS: u8 = Fps::Fps30.as_int();
+    const MAX_DELTA: u32 = u28::max_value().as_int();
+
+    assert_eq!(HEADER.timing, Timing::Metrical(480.into()));
+    assert_eq!(SMPTE.format, Format::SingleTrack);
+    assert_eq!(TICKS, 480);
+    assert_eq!(FPS, 30);
+    assert_eq!(MAX_DELTA, 0x0FFF_FFFF);
+}
+
+#[test]
+fn timeline_order() {
+    use crate::{compare_timeline, MetaMessage, MidiMessage, TrackEventKind};
+    use core::cmp::Ordering;
+
+    let tempo = TrackEventKind::Meta(MetaMessage::Tempo(500_000.into()));
+    let end = TrackEventKind::Meta(MetaMessage::EndOfTrack);
+    let midi = |message| TrackEventKind::Midi {
+        channel: 0.into(),
+        message,
+    };
+    let on = midi(MidiMessage::NoteOn {
+        key: 60.into(),
+        vel: 64.into(),
+    });
+    let off = midi(MidiMessage::NoteOn {
+        key: 60.into(),
+        vel: 0.into(),
+    });
+    let program = midi(MidiMessage::ProgramChange { program: 3.into() });
+
+    assert_eq!(compare_timeline((0, &tempo), (0, &on)), Ordering::Less);
+    assert_eq!(compare_timeline((10, &tempo), (0, &on)), Ordering::Greater);
+    assert_eq!(compare_timeline((0, &off), (0, &on)), Ordering::Equal);
+    assert_eq!(compare_timeline((0, &program), (0, &on)), Ordering::Less);
+    assert_eq!(compare_timeline((0, &end), (0, &on)), Ordering::Greater);
+    assert_eq!(compare_timeline((5, &on), (5, &on)), Ordering::Equal);
+
+    let mut events = vec![(0, on), (0, end), (0, off), (0, tempo), (0, program)];
+    events.sort_by(|a, b| compare_timeline((a.0, &a.1), (b.0, &b.1)));
+    assert_eq!(
+        events,
+        vec![(0, tempo), (0, program), (0, on), (0, off), (0, end)]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn sort_timeline_zero_length_notes() {
+    use crate::{sort_timeline, MidiMessage, TrackEventKind};
+
+    let key = |key: u8, vel: u8| TrackEventKind::Midi {
+        channel: 0.into(),
+        message: MidiMessage::NoteOn {
+            key: key.into(),
+            vel: vel.into(),
+        },
+    };
+    //A zero-length note keeps its note off after its note on
+    let mut events = vec![(0, key(60, 100)), (0, key(60, 0))];
+    sort_timeline(&mut events);
+    assert_eq!(events, [(0, key(60, 100)), (0, key(60, 0))]);
+
+    //Notes struck earlier are released before new notes start
+    let mut events = vec![
+        (0, key(62, 100)),
+        (10, key(64, 100)),
+        (10, key(60, 100)),
+        (10, key(62, 0)),
+        (10, key(60, 0)),
+    ];
+    sort_timeline(&mut events);
+    assert_eq!(
+        events,
+        [
+            (0, key(62, 100)),
+            (10, key(62, 0)),
+            (10, key(64, 100)),
+            (10, key(60, 100)),
+            (10, key(60, 0)),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn text_newlines() {
+    use crate::MetaMessage;
+
+    let lyric = MetaMessage::Lyric(b"first line\r\nsecond line\r\n");
+    assert_eq!(
+        lyric.decode_text(true).unwrap(),
+        "first line\nsecond line\n"
+    );
+    assert_eq!(
+        lyric.decode_text(false).unwrap(),
+        "first line\r\nsecond line\r\n"
+    );
+    assert_eq!(
+        MetaMessage::Text(b"no\rchange").decode_text(true).unwrap(),
+        "no\rchange"
+    );
+    assert_eq!(MetaMessage::EndOfTrack.decode_text(true), None);
+}
+
+#[test]
+fn event_offsets() {
+    use crate::EventIter;
+
+    let track = [
+        0x00, 0x90, 0x3C, 0x40, // Note on
+        0x10, 0x3C, 0x00, // Note off through running status
+        0x00, 0xFF, 0x01, 0x02, b'h', b'i', // Text meta
+        0x00, 0xFF, 0x2F, 0x00, // End of track
+    ];
+    let mut events = EventIter::new(&track);
+    let mut offsets = vec![events.offset()];
+    while let Some(ev) = events.next() {
+        ev.unwrap();
+        offsets.push(events.offset());
+        assert_eq!(events.offset(), track.len() - events.unread().len());
+    }
+    assert_eq!(offsets, [0, 4, 7, 13, 17]);
+
+    let mut bytemapped = EventIter::new(&track).bytemapped();
+    bytemapped.next().unwrap().unwrap();
+    assert_eq!(bytemapped.offset(), 4);
+    assert_eq!(bytemapped.not_bytemapped().offset(), 4);
+}
+
+#[cfg(feature = "alloc")]
+fn cc(delta: u32, channel: u8, controller: u8, value: u8) -> TrackEvent<'static> {
+    use crate::{MidiMessage, TrackEventKind};
+    TrackEvent {
+        delta: delta.into(),
+        kind: TrackEventKind::Midi {
+            channel: channel.into(),
+            message: MidiMessage::Controller {
+                controller: controller.into(),
+                value: value.into(),
+            },
+        },
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn sustained_notes() {
+    use crate::{notes, Note};
+
+    let track = vec![
+        note(0, 0, 60, 90),
+        cc(10, 0, 64, 127),
+        note(10, 0, 60, 0),
+        note(0, 0, 64, 80),
+        note(20, 0, 64, 0),
+        cc(30, 0, 64, 0),
+        note(10, 0, 67, 70),
+        note(10, 0, 67, 0),
+    ];
+    let note_at = |channel: u8, key: u8, vel: u8, start, end| Note {
+        channel: channel.into(),
+        key: key.into(),
+        vel: vel.into(),
+        start,
+        end,
+    };
+
+    assert_eq!(
+        notes(&track, false),
+        vec![
+            note_at(0, 60, 90, 0, 20),
+            note_at(0, 64, 80, 20, 40),
+            note_at(0, 67, 70, 80, 90),
+        ]
+    );
+    let sustained = notes(&track, true);
+    assert_eq!(
+        sustained,
+        vec![
+            note_at(0, 60, 90, 0, 70),
+            note_at(0, 64, 80, 20, 70),
+            note_at(0, 67, 70, 80, 90),
+        ]
+    );
+    assert_eq!(sustained[0].duration(), 70);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn parse_single_track() {
+    use crate::{Arena, Smf};
+
+    open! {file: "Clementi.mid"};
+    let smf = Smf::parse(&file).unwrap();
+    assert!(smf.tracks.len() >= 3);
+    let arena = Arena::new();
+    for idx in 0..smf.tracks.len() {
+        let track = Smf::parse_track(std::io::Cursor::new(&file), idx, &arena).unwrap();
+        assert_eq!(track, smf.tracks[idx]);
+    }
+    let err = Smf::parse_track(std::io::Cursor::new(&file), smf.tracks.len(), &arena);
+    assert_eq!(err.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn drop_events_under_budget() {
+    use crate::{drop_under_budget, event_priority, MidiMessage, TrackEventKind};
+
+    let aftertouch = TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::Aftertouch {
+                key: 60.into(),
+                vel: 50.into(),
+            },
+        },
+    };
+    assert!(event_priority(&aftertouch.kind) < event_priority(&note(0, 0, 60, 1).kind));
+
+    let mut track = vec![
+        note(0, 0, 60, 100),
+        aftertouch,
+        cc(0, 0, 7, 100),
+        note(0, 0, 64, 100),
+        aftertouch,
+        cc(10, 0, 7, 100),
+        note(0, 0, 60, 0),
+        note(5, 0, 64, 0),
+    ];
+    drop_under_budget(&mut track, 3);
+    assert_eq!(
+        track,
+        vec![
+            note(0, 0, 60, 100),
+            cc(0, 0, 7, 100),
+            note(0, 0, 64, 100),
+            cc(10, 0, 7, 100),
+            note(0, 0, 60, 0),
+            note(5, 0, 64, 0),
+        ]
+    );
+
+    // Redundant controllers are dropped first, and notes are never dropped
+    drop_under_budget(&mut track, 1);
+    assert_eq!(
+        track,
+        vec![
+            note(0, 0, 60, 100),
+            note(0, 0, 64, 100),
+            note(10, 0, 60, 0),
+            note(5, 0, 64, 0),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn file_layout() {
+    use crate::{EventIter, Smf};
+
+    for name in ["Clementi.mid", "Sandstorm.mid", "Beethoven.rmi"].iter() {
+        open! {file: name};
+        let smf = Smf::parse(&file).unwrap();
+        let layout = smf.layout(&file).unwrap();
+        assert_eq!(layout.tracks.len(), smf.tracks.len());
+        assert_eq!(&file[layout.header.start..layout.header.start + 4], b"MThd");
+        for (range, track) in layout.tracks.iter().zip(smf.tracks.iter()) {
+            assert_eq!(&file[range.start..range.start + 4], b"MTrk");
+            let events = EventIter::new(&file[range.start + 8..range.end])
+                .into_vec()
+                .unwrap();
+            assert_eq!(&events, track);
+        }
+        let covered = layout
+            .tracks
+            .iter()
+            .chain(layout.unknown.iter())
+            .map(|range| range.len())
+            .sum::<usize>()
+            + layout.header.len();
+        if name.ends_with(".mid") {
+            assert_eq!(covered, file.len());
+        } else {
+            assert!(covered < file.len());
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn flat_map_track() {
+    use crate::{flat_map_events, MidiMessage, TrackEventKind};
+
+    let track = vec![
+        note(10, 0, 60, 100),
+        cc(5, 0, 1, 20),
+        note(20, 0, 60, 0),
+        note(0, 0, 62, 90),
+    ];
+    let mapped = flat_map_events(track, |ev| match ev.kind {
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOn { vel, .. },
+        } if vel > 0 => vec![ev, cc(99, channel.as_int(), 11, vel.as_int())],
+        TrackEventKind::Midi {
+            message: MidiMessage::Controller { .. },
+            ..
+        } => vec![],
+        _ => vec![ev],
+    });
+    assert_eq!(
+        mapped,
+        vec![
+            note(10, 0, 60, 100),
+            cc(0, 0, 11, 100),
+            note(25, 0, 60, 0),
+            note(0, 0, 62, 90),
+            cc(0, 0, 11, 90),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn double_triggers() {
+    use crate::{Format, Header, NoteOverlap, Smf, Timing};
+
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(96.into())));
+    smf.tracks
+        .push(vec![note(0, 0, 60, 100), note(96, 0, 60, 0)]);
+    smf.tracks.push(vec![
+        note(0, 2, 60, 100),
+        note(0, 3, 60, 100),
+        note(48, 2, 60, 100),
+        note(48, 2, 60, 0),
+        note(0, 3, 60, 0),
+        note(0, 2, 60, 0),
+    ]);
+    assert_eq!(
+        smf.overlapping_notes(),
+        vec![NoteOverlap {
+            track: 1,
+            channel: 2.into(),
+            key: 60.into(),
+            first: 0,
+            second: 48,
+        }]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn channel_mask() {
+    use crate::{Format, Header, Smf, Timing};
+
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(96.into())));
+    assert_eq!(smf.channels_used(), 0);
+    smf.tracks.push(vec![note(0, 0, 60, 100), cc(0, 3, 7, 100)]);
+    smf.tracks
+        .push(vec![note(0, 9, 36, 100), note(10, 9, 36, 0)]);
+    assert_eq!(smf.channels_used(), 0b0000_0010_0000_1001);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn escape_roundtrip() {
+    use crate::{EventIter, Format, Header, Smf, Timing};
+
+    let raw = [0xF0, 0x90, 0x3C, 0xF7, 0x00, 0xFF];
+    let mut smf = Smf::new(Header::new(
+        Format::SingleTrack,
+        Timing::Metrical(96.into()),
+    ));
+    smf.tracks.push(vec![
+        note(0, 0, 60, 100),
+        TrackEvent::escape(5.into(), &raw),
+        note(5, 0, 60, 0),
+    ]);
+    let mut bytes = Vec::new();
+    smf.write(&mut bytes).unwrap();
+
+    // The note after the escape must not rely on running status
+    let track_data = &bytes[14 + 8..];
+    assert_eq!(
+        track_data,
+        &[
+            0x00, 0x90, 0x3C, 0x64, // Note on
+            0x05, 0xF7, 0x06, 0xF0, 0x90, 0x3C, 0xF7, 0x00, 0xFF, // Escape
+            0x05, 0x90, 0x3C, 0x00, // Note off, with explicit status
+        ][..]
+    );
+    let reparsed = EventIter::new(track_data).into_vec().unwrap();
+    assert_eq!(reparsed, smf.tracks[0]);
+    assert_eq!(Smf::parse(&bytes).unwrap(), smf);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn sort_tracks() {
+    use crate::{Format, Header, MetaMessage, Smf, Timing, TrackEventKind};
+
+    let tempo = meta(0, MetaMessage::Tempo(400_000.into()));
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(96.into())));
+    smf.tracks.push(vec![note(0, 9, 36, 100)]);
+    smf.tracks.push(vec![note(0, 1, 60, 100)]);
+    smf.tracks.push(vec![tempo]);
+    smf.tracks.push(vec![note(0, 0, 60, 100)]);
+    smf.sort_tracks_by(|track| {
+        !track
+            .iter()
+            .any(|ev| matches!(ev.kind, TrackEventKind::Meta(MetaMessage::Tempo(_))))
+    });
+    assert_eq!(
+        smf.tracks,
+        vec![
+            vec![tempo],
+            vec![note(0, 9, 36, 100)],
+            vec![note(0, 1, 60, 100)],
+            vec![note(0, 0, 60, 100)],
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn smf_collection() {
+    use crate::Smf;
+
+    open! {file: "Clementi.mid"};
+    let mut smf = Smf::parse(&file).unwrap();
+    assert!(!smf.is_empty());
+    assert_eq!(smf.len(), smf.tracks.len());
+    assert_eq!(smf[1], smf.tracks[1]);
+    assert_eq!(smf.iter().count(), smf.len());
+    let total = smf.tracks.iter().map(|track| track.len()).sum::<usize>();
+    assert_eq!(
+        (&smf).into_iter().map(|track| track.len()).sum::<usize>(),
+        total
+    );
+    for track in &mut smf {
+        track.pop();
+    }
+    smf[0].clear();
+    assert!(smf[0].is_empty());
+    let tracks = smf.clone().into_iter().collect::<Vec<_>>();
+    assert_eq!(tracks, smf.tracks);
+    smf.tracks.clear();
+    assert!(smf.is_empty());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn click_track_compound_meter() {
+    use crate::{Format, Fps, Header, MetaMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+    let mut smf = Smf::new(Header::new(
+        Format::SingleTrack,
+        Timing::Metrical(96.into()),
+    ));
+    //Two bars of 6/8, clicking on every dotted quarter
+    smf.tracks.push(vec![
+        meta(0, MetaMessage::TimeSignature(6, 3, 36, 8)),
+        note(0, 0, 60, 100),
+        note(576, 0, 60, 0),
+    ]);
+    let count_clicks = |track: &[TrackEvent]| {
+        track
+            .iter()
+            .filter(|ev| match ev.kind {
+                TrackEventKind::Midi {
+                    message: crate::MidiMessage::NoteOn { vel, .. },
+                    ..
+                } => vel > 0,
+                _ => false,
+            })
+            .count()
+    };
+    let clicks = smf.click_track(true).unwrap();
+    assert_eq!(count_clicks(&clicks), 4);
+    assert_eq!(clicks[0], note(0, 9, 76, 127));
+    assert_eq!(clicks[2], note(72, 9, 77, 100));
+    assert_eq!(clicks[4], note(72, 9, 76, 127));
+    let clicks = smf.click_track(false).unwrap();
+    assert_eq!(count_clicks(&clicks), 12);
+
+    smf.header.timing = Timing::Timecode(Fps::Fps25, 40);
+    assert_eq!(smf.click_track(true), None);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn click_track_partial_clicks() {
+    use crate::{Header, MetaMessage, Smf};
+
+    let signature = |delta: u32, num: u8| meta(delta, MetaMessage::TimeSignature(num, 3, 36, 8));
+    //Two bars of 7/8, clicking every dotted quarter, so the last click of each bar is cut short
+    let mut smf = Smf::new(Header::default()).with_ppq(480.into());
+    smf.tracks.push(vec![
+        signature(0, 7),
+        note(0, 0, 60, 100),
+        note(3360, 0, 60, 0),
+    ]);
+    let clicks = smf.click_track(true).unwrap();
+    let mut time = 0;
+    let timed = clicks
+        .iter()
+        .map(|ev| {
+            time += ev.delta.as_int();
+            (time, ev.kind)
+        })
+        .collect::<Vec<_>>();
+    let click = |time: u32, key: u8, vel: u8| (time, note(0, 9, key, vel).kind);
+    assert_eq!(
+        timed[..8],
+        [
+            click(0, 76, 127),
+            click(360, 76, 0),
+            click(720, 77, 100),
+            click(1080, 77, 0),
+            click(1440, 77, 100),
+            click(1680, 77, 0),
+            click(1680, 76, 127),
+            click(2040, 76, 0),
+        ]
+    );
+    assert_eq!(time, 3360);
+
+    //A time signature change in the middle of a bar cuts the click short as well
+    smf.tracks[0].insert(1, signature(1560, 2));
+    let clicks = smf.click_track(true).unwrap();
+    assert_eq!(clicks[5], note(120, 9, 77, 0));
+    assert_eq!(clicks[6], note(0, 9, 76, 127));
+
+    smf.tracks[0][0] = signature(0, 0);
+    assert_eq!(smf.click_track(true), None);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn insert_remove_tracks() {
+    use crate::{Format, Header, Smf, Timing};
+
+    open! {file: "Clementi.mid"};
+    let mut smf = Smf::parse(&file).unwrap();
+    assert_eq!(smf.header.format, Format::Parallel);
+    let count = smf.tracks.len();
+    let removed = smf.remove_track(1).unwrap();
+    assert_eq!(removed, Smf::parse(&file).unwrap().tracks[1]);
+    assert_eq!(smf.tracks.len(), count - 1);
+    let mut out = Vec::new();
+    smf.write(&mut out).unwrap();
+    assert_eq!(Smf::parse(&out).unwrap(), smf);
+
+    let mut smf = Smf::new(Header::new(
+        Format::SingleTrack,
+        Timing::Metrical(96.into()),
+    ));
+    smf.tracks.push(vec![note(0, 0, 60, 100)]);
+    assert!(smf.remove_track(0).is_err());
+    assert_eq!(smf.tracks.len(), 1);
+    smf.insert_track(0, vec![note(0, 1, 60, 100)]);
+    assert_eq!(smf.header.format, Format::Parallel);
+    assert_eq!(smf.tracks[0], vec![note(0, 1, 60, 100)]);
+    smf.remove_track(0).unwrap();
+    assert_eq!(smf.header.format, Format::Parallel);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn parse_metrics() {
+    use crate::{ParseOptions, Smf};
+
+    open! {file: "Clementi.mid"};
+    let (smf, metrics) = Smf::parse_with_metrics(&file).unwrap();
+    assert_eq!(smf, Smf::parse(&file).unwrap());
+    assert_eq!(metrics.bytes, file.len());
+    assert_eq!(
+        metrics.parallel_tracks + metrics.serial_tracks,
+        smf.tracks.len()
+    );
+    assert!(file.len() > ParseOptions::default().parallel_bytes);
+    if cfg!(feature = "parallel") {
+        assert_eq!(metrics.parallel_tracks, smf.tracks.len());
+        assert!(metrics.was_parallel());
+    } else {
+        assert_eq!(metrics.parallel_tracks, 0);
+        assert!(!metrics.was_parallel());
+    }
+
+    open! {file: "SysExTest.mid"};
+    assert!(file.len() < ParseOptions::default().parallel_bytes);
+    let (smf, metrics) = Smf::parse_with_metrics(&file).unwrap();
+    assert_eq!(metrics.parallel_tracks, 0);
+    assert_eq!(metrics.serial_tracks, smf.tracks.len());
+    assert!(!metrics.was_parallel());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn zero_declared_tracks() {
+    use crate::Smf;
+
+    let mut raw = Vec::new();
+    raw.extend_from_slice(b"MThd\x00\x00\x00\x06\x00\x01\x00\x00\x00\x60");
+    for _ in 0..2 {
+        raw.extend_from_slice(b"MTrk\x00\x00\x00\x04\x00\xFF\x2F\x00");
+    }
+    let (_header, tracks) = crate::parse(&raw).unwrap();
+    assert_eq!(tracks.size_hint(), (0, None));
+    let result = Smf::parse(&raw);
+    if cfg!(feature = "strict") {
+        let err = result.unwrap_err();
+        assert!(matches!(err.kind(), crate::ErrorKind::Malformed(_)));
+    } else {
+        assert_eq!(result.unwrap().tracks.len(), 2);
+    }
+}
+
+#[cfg(feature = "base64")]
+#[test]
+fn base64_roundtrip() {
+    use crate::{Arena, Smf};
+
+    open! {file: "Clementi.mid"};
+    let smf = Smf::parse(&file).unwrap();
+    let text = smf.to_base64().unwrap();
+    assert!(text.starts_with("TVRoZA"));
+    let arena = Arena::new();
+    assert_eq!(Smf::from_base64(&text, &arena).unwrap(), smf);
+
+    let wrapped = text
+        .as_bytes()
+        .chunks(76)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert_eq!(Smf::from_base64(&wrapped, &arena).unwrap(), smf);
+    assert!(Smf::from_base64("TVRo!A==", &arena).is_err());
+    assert!(Smf::from_base64("TVR", &arena).is_err());
+
+    let mut invalid = Smf::new(Default::default());
+    invalid.tracks.resize(usize::from(u16::MAX) + 1, Vec::new());
+    assert!(invalid.to_base64().is_err());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn annotated_smf() {
+    use crate::{Smf, SmfAnnotated};
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    struct Selection {
+        selected: bool,
+        color: u32,
+    }
+
+    open! {file: "Clementi.mid"};
+    let smf = Smf::parse(&file).unwrap();
+    let mut annotated = SmfAnnotated::<Selection>::from(smf.clone());
+    assert_eq!(annotated.tracks.len(), smf.tracks.len());
+    assert!(annotated.tracks[1]
+        .iter()
+        .all(|(_ev, sel)| *sel == Selection::default()));
+    for (_ev, sel) in annotated.tracks[1].iter_mut().step_by(2) {
+        sel.selected = true;
+        sel.color = 0xFF0000;
+    }
+    assert!(annotated.tracks[1][0].1.selected);
+    assert!(!annotated.tracks[1][1].1.selected);
+
+    let mut expected = Vec::new();
+    smf.write(&mut expected).unwrap();
+    let mut out = Vec::new();
+    annotated.write(&mut out).unwrap();
+    assert_eq!(out, expected);
+    assert_eq!(annotated.into_smf(), smf);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn gm_reset_preamble() {
+    use crate::{MidiMessage, Smf, TrackEventKind};
+
+    open! {file: "Clementi.mid"};
+    let original = Smf::parse(&file).unwrap();
+    let mut smf = original.clone();
+    smf.prepend_gm_reset();
+    let added = smf.tracks[0].len() - original.tracks[0].len();
+    assert_eq!(added, 1 + 16 * 3);
+    let preamble = &smf.tracks[0][..added];
+    assert!(preamble.iter().all(|ev| ev.delta == 0));
+    assert_eq!(
+        preamble[0].kind,
+        TrackEventKind::SysEx(&[0x7E, 0x7F, 0x09, 0x01, 0xF7])
+    );
+    assert!(preamble[1..].iter().any(|ev| ev.kind
+        == TrackEventKind::Midi {
+            channel: 15.into(),
+            message: MidiMessage::Controller {
+                controller: 7.into(),
+                value: 100.into(),
+            },
+        }));
+    //Everything after the preamble keeps its original timing
+    assert_eq!(&smf.tracks[0][added..], &original.tracks[0][..]);
+    assert_eq!(&smf.tracks[1..], &original.tracks[1..]);
+
+    let mut out = Vec::new();
+    smf.write(&mut out).unwrap();
+    assert_eq!(Smf::parse(&out).unwrap(), smf);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn deep_clone_outlives_source() {
+    use crate::{Arena, Smf};
+
+    let arena = Arena::new();
+    let (copy, original) = {
+        open! {file: "SysExTest.mid"};
+        let smf = Smf::parse(&file).unwrap();
+        let mut original = Vec::new();
+        smf.write(&mut original).unwrap();
+        (smf.deep_clone(&arena), original)
+    };
+    let mut out = Vec::new();
+    copy.write(&mut out).unwrap();
+    assert_eq!(out, original);
+    assert_eq!(copy, Smf::parse(&original).unwrap());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn merge_conflicting_conductors() {
+    use crate::{merge_conductors, ConflictPolicy, MetaMessage};
+
+    let tempo = |delta: u32, tempo: u32| meta(delta, MetaMessage::Tempo(tempo.into()));
+    let eot = meta(0, MetaMessage::EndOfTrack);
+    let a = vec![
+        tempo(0, 500_000),
+        meta(0, MetaMessage::TimeSignature(4, 2, 24, 8)),
+        tempo(960, 400_000),
+        eot,
+    ];
+    let b = vec![tempo(480, 600_000), tempo(480, 600_000), eot];
+
+    let merged = merge_conductors(&a, &b, ConflictPolicy::PreferA);
+    assert_eq!(
+        merged,
+        vec![a[0], a[1], tempo(480, 600_000), tempo(480, 400_000), eot]
+    );
+    let merged = merge_conductors(&a, &b, ConflictPolicy::PreferB);
+    assert_eq!(
+        merged,
+        vec![a[0], a[1], tempo(480, 600_000), tempo(480, 600_000), eot]
+    );
+    let merged = merge_conductors(&a, &b, ConflictPolicy::Average);
+    assert_eq!(
+        merged,
+        vec![a[0], a[1], tempo(480, 600_000), tempo(480, 500_000), eot]
+    );
+
+    //Only the last tempo on a tick takes effect, on either side
+    let a = vec![tempo(0, 500_000), tempo(0, 450_000), eot];
+    let b = vec![tempo(0, 600_000), tempo(0, 550_000), eot];
+    let merged = merge_conductors(&a, &b, ConflictPolicy::PreferA);
+    assert_eq!(merged, vec![tempo(0, 450_000), eot]);
+    let merged = merge_conductors(&a, &b, ConflictPolicy::PreferB);
+    assert_eq!(merged, vec![tempo(0, 550_000), eot]);
+    let merged = merge_conductors(&a, &b, ConflictPolicy::Average);
+    assert_eq!(merged, vec![tempo(0, 500_000), eot]);
+    let merged = merge_conductors(&a, &b[2..], ConflictPolicy::PreferB);
+    assert_eq!(merged, vec![tempo(0, 450_000), eot]);
+}
+
+#[test]
+fn header_then_lazy_tracks() {
+    use crate::{Format, Timing};
+
+    open! {file: "Clementi.mid"};
+    let (header, mut tracks) = crate::parse(&file).unwrap();
+    assert_eq!(header.format, Format::Parallel);
+    assert!(matches!(header.timing, Timing::Metrical(_)));
+    let declared = tracks.declared_count();
+    assert!(declared > 0);
+    let first = tracks.next().unwrap().unwrap();
+    assert_eq!(tracks.declared_count(), declared - 1);
+    assert!(first.count() > 0);
+    assert_eq!(tracks.count(), declared as usize - 1);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn normalize_mixed_note_offs() {
+    use crate::{normalize_note_offs, NoteOffStyle};
+
+    let mixed = vec![
+        note(0, 0, 60, 100),
+        note(0, 0, 64, 100),
+        note(10, 0, 60, 0),
+        note_off(0, 0, 64, 30),
+        cc(5, 0, 64, 0),
+    ];
+
+    let mut track = mixed.clone();
+    normalize_note_offs(&mut track, NoteOffStyle::NoteOff);
+    assert_eq!(
+        track,
+        vec![
+            mixed[0],
+            mixed[1],
+            note_off(10, 0, 60, 64),
+            note_off(0, 0, 64, 30),
+            mixed[4],
+        ]
+    );
+
+    let mut track = mixed.clone();
+    normalize_note_offs(&mut track, NoteOffStyle::ZeroVelocity);
+    assert_eq!(
+        track,
+        vec![mixed[0], mixed[1], mixed[2], note(0, 0, 64, 0), mixed[4]]
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn append_to_saved_file() {
+    use crate::{Format, Header, MetaMessage, Smf, Timing, TrackEvent};
+
+    let eot = meta(0, MetaMessage::EndOfTrack);
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(96.into())));
+    smf.tracks
+        .push(vec![note(0, 0, 60, 100), note(10, 0, 60, 0), eot]);
+    smf.tracks.push(vec![
+        note(0, 1, 48, 100),
+        TrackEvent {
+            delta: 20.into(),
+            kind: eot.kind,
+        },
+    ]);
+    let path = std::env::temp_dir().join(format!("midly-append-{}.mid", std::process::id()));
+    smf.save(&path).unwrap();
+
+    Smf::append_to_file(
+        &[
+            vec![note(5, 0, 62, 100), note(10, 0, 62, 0)],
+            vec![note(0, 1, 48, 0)],
+        ],
+        &path,
+    )
+    .unwrap();
+    Smf::append_to_file(&[vec![], vec![note(4, 1, 50, 90), eot]], &path).unwrap();
+    assert!(Smf::append_to_file(&[vec![], vec![], vec![]], &path).is_err());
+
+    let raw = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let appended = Smf::parse(&raw).unwrap();
+    assert_eq!(appended.header, smf.header);
+    assert_eq!(
+        appended.tracks,
+        vec![
+            vec![
+                note(0, 0, 60, 100),
+                note(10, 0, 60, 0),
+                note(5, 0, 62, 100),
+                note(10, 0, 62, 0),
+                eot,
+            ],
+            vec![
+                note(0, 1, 48, 100),
+                note(20, 1, 48, 0),
+                note(4, 1, 50, 90),
+                eot
+            ],
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn c_major_histograms() {
+    use crate::{Format, Header, Smf, Timing};
+
+    let mut smf = Smf::new(Header::new(
+        Format::SingleTrack,
+        Timing::Metrical(96.into()),
+    ));
+    let mut track = Vec::new();
+    for (i, &key) in [60, 62, 64, 65, 67, 69, 71, 72].iter().enumerate() {
+        //The final C is held twice as long
+        let len = if i == 7 { 96 } else { 48 };
+        track.push(note(0, 0, key, 100));
+        track.push(note(len, 0, key, 0));
+    }
+    //Percussion is ignored
+    track.push(note(0, 9, 37, 100));
+    track.push(note(10, 9, 37, 0));
+    smf.tracks.push(track);
+
+    assert_eq!(
+        smf.pitch_class_histogram(false),
+        [2, 0, 1, 0, 1, 1, 0, 1, 0, 1, 0, 1]
+    );
+    assert_eq!(
+        smf.pitch_class_histogram(true),
+        [144, 0, 48, 0, 48, 48, 0, 48, 0, 48, 0, 48]
+    );
+    //Five whole steps and two half steps
+    assert_eq!(
+        smf.interval_histogram(),
+        [0, 2, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn default_ppq() {
+    use crate::{Format, Header, Smf, Timing};
+
+    assert_eq!(
+        Smf::default().header,
+        Header::new(Format::Parallel, Timing::Metrical(480.into()))
+    );
+    let mut smf = Smf::default().with_ppq(96.into());
+    smf.tracks
+        .push(vec![note(0, 0, 60, 100), note(96, 0, 60, 0)]);
+    let mut out = Vec::new();
+    smf.write(&mut out).unwrap();
+    let parsed = Smf::parse(&out).unwrap();
+    assert_eq!(parsed.header.timing, Timing::Metrical(96.into()));
+    assert_eq!(parsed.header.format, Format::Parallel);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn extract_single_channel() {
+    use crate::{Format, MetaMessage, Smf};
+
+    let mut smf = Smf::default();
+    smf.tracks.push(vec![
+        meta(0, MetaMessage::TrackName(b"Conductor")),
+        meta(0, MetaMessage::Tempo(500_000.into())),
+        meta(100, MetaMessage::Tempo(400_000.into())),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    smf.tracks.push(vec![
+        note(0, 1, 60, 100),
+        note(50, 2, 64, 100),
+        note(10, 1, 60, 0),
+        cc(20, 2, 7, 90),
+        note(40, 2, 64, 0),
+    ]);
+    smf.tracks
+        .push(vec![note(30, 2, 67, 100), note(90, 2, 67, 0)]);
+
+    let solo = smf.extract_channel(2.into());
+    assert_eq!(solo.header.format, Format::SingleTrack);
+    assert_eq!(solo.header.timing, smf.header.timing);
+    assert_eq!(
+        solo.tracks,
+        vec![vec![
+            meta(0, MetaMessage::Tempo(500_000.into())),
+            note(30, 2, 67, 100),
+            note(20, 2, 64, 100),
+            cc(30, 2, 7, 90),
+            meta(20, MetaMessage::Tempo(400_000.into())),
+            note(20, 2, 64, 0),
+            note(0, 2, 67, 0),
+            meta(0, MetaMessage::EndOfTrack),
+        ]]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn event_kind_eq() {
+    let a = note(0, 3, 60, 100);
+    let b = note(120, 3, 60, 100);
+    assert_ne!(a, b);
+    assert!(a.kind_eq(&b));
+    assert!(!a.kind_eq(&note(0, 3, 60, 99)));
+    assert!(!a.kind_eq(&note(0, 4, 60, 100)));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn channel_aftertouch_to_cc() {
+    use crate::{aftertouch_to_cc, MidiMessage, TrackEvent, TrackEventKind};
+
+    let pressure = |delta: u32, channel: u8, vel: u8| TrackEvent {
+        delta: delta.into(),
+        kind: TrackEventKind::Midi {
+            channel: channel.into(),
+            message: MidiMessage::ChannelAftertouch { vel: vel.into() },
+        },
+    };
+    let mut track = vec![
+        note(0, 0, 60, 100),
+        pressure(10, 0, 40),
+        pressure(10, 5, 127),
+        note(5, 0, 60, 0),
+    ];
+    aftertouch_to_cc(&mut track, 11.into());
+    assert_eq!(
+        track,
+        vec![
+            note(0, 0, 60, 100),
+            cc(10, 0, 11, 40),
+            cc(10, 5, 11, 127),
+            note(5, 0, 60, 0),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn format_consistency() {
+    use crate::{Format, FormatWarning, MetaMessage, Smf};
+
+    let tempo = meta(0, MetaMessage::Tempo(500_000.into()));
+    let mut smf = Smf::default();
+    assert_eq!(
+        smf.check_format_consistency(),
+        vec![FormatWarning::NoTracks]
+    );
+    smf.tracks
+        .push(vec![tempo, note(0, 0, 60, 100), note(0, 1, 60, 100)]);
+    assert_eq!(
+        smf.check_format_consistency(),
+        vec![FormatWarning::ParallelSingleTrack]
+    );
+    smf.header.format = Format::SingleTrack;
+    assert_eq!(smf.check_format_consistency(), vec![]);
+
+    smf.header.format = Format::Parallel;
+    smf.tracks.push(vec![note(0, 2, 60, 100)]);
+    smf.tracks.push(vec![tempo]);
+    assert_eq!(
+        smf.check_format_consistency(),
+        vec![FormatWarning::TempoOutsideFirstTrack { track: 2 }]
+    );
+    smf.header.format = Format::Sequential;
+    assert_eq!(smf.check_format_consistency(), vec![]);
+    smf.header.format = Format::SingleTrack;
+    assert_eq!(
+        smf.check_format_consistency(),
+        vec![FormatWarning::SingleTrackMultipleTracks]
+    );
+}
+
+#[test]
+fn event_beat_positions() {
+    use crate::EventIter;
+
+    //Note on at tick 0, note off at tick 240, end of track at tick 720
+    let raw = [
+        0x00, 0x90, 0x3C, 0x40, 0x81, 0x70, 0x3C, 0x00, 0x83, 0x60, 0xFF, 0x2F, 0x00,
+    ];
+    let beats = EventIter::new(&raw)
+        .beats(480)
+        .map(|ev| ev.unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(beats, [0.0, 0.5, 1.5]);
+    let (beat, ev) = EventIter::new(&raw).beats(0).nth(1).unwrap().unwrap();
+    assert_eq!(beat, 240.0);
+    assert_eq!(ev.delta, 240);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn flatten_bends() {
+    use crate::{flatten_pitch_bend, MidiMessage, PitchBend, TrackEvent, TrackEventKind};
+
+    let bend = |delta: u32, channel: u8, bend: PitchBend| TrackEvent {
+        delta: delta.into(),
+        kind: TrackEventKind::Midi {
+            channel: channel.into(),
+            message: MidiMessage::PitchBend { bend },
+        },
+    };
+    let track = vec![
+        note(0, 2, 60, 100),
+        bend(10, 2, PitchBend::from_int(1000)),
+        bend(10, 2, PitchBend::from_int(2000)),
+        bend(5, 0, PitchBend::min_raw_value()),
+        note(5, 2, 60, 0),
+    ];
+
+    let mut flat = track.clone();
+    flatten_pitch_bend(&mut flat, false);
+    assert_eq!(flat, vec![note(0, 2, 60, 100), note(30, 2, 60, 0)]);
+
+    let mut flat = track;
+    flatten_pitch_bend(&mut flat, true);
+    assert_eq!(
+        flat,
+        vec![
+            bend(0, 0, PitchBend::mid_raw_value()),
+            bend(0, 2, PitchBend::mid_raw_value()),
+            note(0, 2, 60, 100),
+            note(30, 2, 60, 0),
+        ]
+    );
+    assert_eq!(PitchBend::mid_raw_value().0, 0x2000);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn channel_state_seek() {
+    use crate::{ChannelState, MidiMessage, PitchBend, Smf, TrackEvent, TrackEventKind};
+
+    let midi = |delta: u32, channel: u8, message| TrackEvent {
+        delta: delta.into(),
+        kind: TrackEventKind::Midi {
+            channel: channel.into(),
+            message,
+        },
+    };
+    let program = |delta: u32, channel: u8, program: u8| {
+        midi(
+            delta,
+            channel,
+            MidiMessage::ProgramChange {
+                program: program.into(),
+            },
+        )
+    };
+    let mut smf = Smf::default();
+    smf.tracks.push(vec![
+        cc(0, 1, 0, 1),
+        cc(0, 1, 32, 2),
+        program(0, 1, 40),
+        cc(0, 1, 7, 80),
+        note(0, 1, 60, 100),
+        cc(50, 1, 64, 127),
+        midi(
+            0,
+            1,
+            MidiMessage::PitchBend {
+                bend: PitchBend::from_int(500),
+            },
+        ),
+        program(50, 1, 41),
+        cc(0, 3, 10, 0),
+    ]);
+    smf.tracks.push(vec![cc(75, 1, 10, 20), cc(50, 1, 121, 0)]);
+
+    assert_eq!(smf.state_at(1.into(), 0), ChannelState::default());
+    let state = smf.state_at(1.into(), 10);
+    assert_eq!(state.program, 40);
+    assert_eq!(state.bank, 1 << 7 | 2);
+    assert_eq!(state.volume, 80);
+    assert!(!state.sustain);
+    let state = smf.state_at(1.into(), 101);
+    assert_eq!(state.program, 41);
+    assert_eq!(state.pan, 20);
+    assert!(state.sustain);
+    assert_eq!(state.pitch_bend, PitchBend::from_int(500));
+    let state = smf.state_at(1.into(), 200);
+    assert!(!state.sustain);
+    assert_eq!(state.pitch_bend, PitchBend::mid_raw_value());
+    assert_eq!(state.volume, 80);
+    assert_eq!(smf.state_at(3.into(), 200).pan, 0);
+}
+
+#[test]
+fn big_endian_fields() {
+    use crate::{
+        num::{u14, u24},
+        prelude::{IntRead, IntReadBottom7},
+    };
+
+    assert_eq!(u16::read(&mut &[0x12, 0x34][..]).unwrap(), 0x1234);
+    assert_eq!(
+        u32::read(&mut &[0x00, 0x00, 0x01, 0x02][..]).unwrap(),
+        0x0102
+    );
+    assert_eq!(u24::read(&mut &[0x07, 0xA1, 0x20][..]).unwrap(), 500_000);
+    assert_eq!(u14::read_u7(&mut &[0x01, 0x02][..]).unwrap(), 0x0082);
+
+    open! {file: "Clementi.mid"};
+    let (_header, tracks) = crate::parse(&file).unwrap();
+    assert_eq!(&file[4..8], &[0, 0, 0, 6]);
+    assert_eq!(
+        u16::from_be_bytes([file[10], file[11]]),
+        tracks.declared_count()
+    );
+    let first_len = u32::from_be_bytes([file[18], file[19], file[20], file[21]]) as usize;
+    let first = tracks.clone().next().unwrap().unwrap();
+    assert_eq!(first.unread().len(), first_len);
+    assert_eq!(&file[22..22 + first_len], first.unread());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn big_endian_writes() {
+    use crate::{Format, Header, Smf, Timing};
+
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(0x1E0.into()),
+    ));
+    smf.tracks.push(vec![note(0x80, 0, 60, 100)]);
+    smf.tracks.push(vec![]);
+    let mut out = Vec::new();
+    smf.write(&mut out).unwrap();
+    assert_eq!(
+        &out[..22],
+        &[
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 2, 0x01, 0xE0, b'M', b'T', b'r', b'k', 0,
+            0, 0, 5,
+        ]
+    );
+    //Delta times are big-endian variable-length integers
+    assert_eq!(&out[22..27], &[0x81, 0x00, 0x90, 60, 100]);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn overlay_bass_onto_melody() {
+    use crate::{overlay_track, MetaMessage};
+
+    let mut melody = vec![
+        note(0, 0, 72, 100),
+        note(48, 0, 72, 0),
+        note(0, 0, 74, 100),
+        note(48, 0, 74, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ];
+    let bass = vec![
+        note(0, 1, 36, 100),
+        note(96, 1, 36, 0),
+        meta(96, MetaMessage::EndOfTrack),
+    ];
+    overlay_track(&mut melody, &bass);
+    assert_eq!(
+        melody,
+        vec![
+            note(0, 0, 72, 100),
+            note(0, 1, 36, 100),
+            note(48, 0, 72, 0),
+            note(0, 0, 74, 100),
+            note(48, 0, 74, 0),
+            note(0, 1, 36, 0),
+            meta(96, MetaMessage::EndOfTrack),
+        ]
+    );
+}
+
+#[test]
+fn cursor_write_errors() {
+    use crate::{
+        io::{Cursor, CursorError, WriteError},
+        Format, Header, Timing,
+    };
+
+    let header = Header::new(Format::Parallel, Timing::Metrical(96.into()));
+    let no_events: [&[crate::TrackEvent]; 0] = [];
+    let mut buf = [0; 8];
+    let err = crate::write(
+        &header,
+        no_events.iter().copied(),
+        &mut Cursor::new(&mut buf),
+    )
+    .unwrap_err();
+    assert!(matches!(err, WriteError::Sink(CursorError::OutOfSpace)));
+
+    //Track counts must fit in 16 bits
+    let too_many = [&[][..]; 0x10000];
+    let mut buf = [0; 64];
+    let err = crate::write(
+        &header,
+        too_many.iter().copied(),
+        &mut Cursor::new(&mut buf),
+    )
+    .unwrap_err();
+    assert!(matches!(err, WriteError::OutOfRange));
+
+    //Errors that are not about ranges are still reported by the writer
+    let chunks = [(*b"MThd", &[][..])];
+    let position = crate::ChunkPosition::AfterTracks;
+    let mut buf = [0; 64];
+    let err = crate::write_with_chunks(
+        &header,
+        no_events.iter().copied(),
+        &chunks,
+        position,
+        &mut Cursor::new(&mut buf),
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        WriteError::Sink(CursorError::InvalidInput(_))
+    ));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn unbuffered_write_errors() {
+    use crate::{
+        io::{CursorError, WriteError},
+        write_track_chunk_unbuffered, TrackEvent, TrackEventKind,
+    };
+
+    //Probing does not touch the data, so this does not actually write gigabytes
+    let data = vec![0; 255 << 20];
+    let sysex = TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::SysEx(&data),
+    };
+    let track = vec![sysex; 17];
+    let mut buf = [0; 64];
+    let err = write_track_chunk_unbuffered(&track, &mut &mut buf[..]).unwrap_err();
+    assert!(matches!(err, WriteError::OutOfRange));
+
+    let mut buf = [0; 8];
+    let err = write_track_chunk_unbuffered(&track[..1], &mut &mut buf[..]).unwrap_err();
+    assert!(matches!(err, WriteError::Sink(CursorError::OutOfSpace)));
+
+    #[cfg(feature = "std")]
+    {
+        let err = std::io::Error::from(WriteError::<std::io::Error>::OutOfRange);
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let err = WriteError::Sink(std::io::Error::from(std::io::ErrorKind::WriteZero));
+        assert_eq!(
+            std::io::Error::from(err).kind(),
+            std::io::ErrorKind::WriteZero
+        );
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn guess_track_ppq() {
+    use crate::guess_ppq;
+
+    let track = vec![
+        note(0, 0, 60, 100),
+        note(120, 0, 60, 0),
+        note(0, 0, 62, 100),
+        note(360, 0, 62, 0),
+        note(240, 0, 64, 100),
+        note(480, 0, 64, 0),
+    ];
+    assert_eq!(guess_ppq(&track), 120);
+    assert_eq!(guess_ppq(&[note(0, 0, 60, 100)]), 480);
+    assert_eq!(guess_ppq(&[note(7, 0, 60, 100), note(14, 0, 60, 0)]), 28);
+    assert_eq!(guess_ppq(&[note(3840, 0, 60, 100)]), 960);
+    assert_eq!(guess_ppq(&[note(2000, 0, 60, 100)]), 500);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn record_live_messages() {
+    use crate::{live::Recorder, MidiMessage};
+    use std::time::Duration;
+
+    let start = Instant::now();
+    let mut recorder = Recorder::new(500_000.into(), 96.into());
+    let on = MidiMessage::NoteOn {
+        key: 60.into(),
+        vel: 100.into(),
+    };
+    //At 120 BPM and 96 PPQ a tick lasts 1/192 of a second, so record every 1/3 of a tick
+    //for a long time to accumulate any fractional error
+    let step = Duration::from_nanos(1_000_000_000 / 192 / 3);
+    for i in 0..3000u32 {
+        recorder.record(start + step * i, 0.into(), on);
+    }
+    recorder.record(start + Duration::from_secs(60), 1.into(), on);
+    let events = recorder.events();
+    let mut tick = 0;
+    let ticks = events[1..]
+        .iter()
+        .map(|ev| {
+            tick += ev.delta.as_int();
+            tick
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ticks[0], 0);
+    assert_eq!(ticks[3], 1);
+    assert_eq!(ticks[2999], 1000);
+    //One minute at 120 BPM is 120 beats
+    assert_eq!(ticks[3000], 120 * 96);
+
+    let smf = recorder.into_smf();
+    assert_eq!(smf.header.timing, crate::Timing::Metrical(96.into()));
+    assert_eq!(smf.tracks[0].len(), 3003);
+
+    //A zero tempo is recorded as the tempo actually used
+    let recorder = Recorder::new(0.into(), 96.into());
+    assert_eq!(
+        recorder.events()[0].kind,
+        crate::TrackEventKind::Meta(crate::MetaMessage::Tempo(1.into()))
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn write_extra_chunks() {
+    use crate::{write_with_chunks, ChunkPosition, FileLayout, Smf};
+
+    open! {file: "Clementi.mid"};
+    let smf = Smf::parse(&file).unwrap();
+    let extra: &[([u8; 4], &[u8])] = &[(*b"XVND", b"vendor data"), (*b"XEMP", b"")];
+    for &position in &[ChunkPosition::BeforeTracks, ChunkPosition::AfterTracks] {
+        let mut out = Vec::new();
+        write_with_chunks(&smf.header, &smf.tracks, extra, position, &mut out).unwrap();
+        assert_eq!(Smf::parse(&out).unwrap(), smf);
+        let layout = FileLayout::read(&out).unwrap();
+        assert_eq!(layout.tracks.len(), smf.tracks.len());
+        assert_eq!(layout.unknown.len(), 2);
+        let vendor = &out[layout.unknown[0].clone()];
+        assert_eq!(&vendor[..8], b"XVND\x00\x00\x00\x0B");
+        assert_eq!(&vendor[8..], b"vendor data");
+        assert_eq!(out[layout.unknown[1].clone()].len(), 8);
+        match position {
+            ChunkPosition::BeforeTracks => {
+                assert_eq!(layout.unknown[0].start, layout.header.end);
+                assert_eq!(layout.tracks[0].start, layout.unknown[1].end);
+            }
+            ChunkPosition::AfterTracks => {
+                assert_eq!(layout.unknown[0].start, layout.tracks.last().unwrap().end);
+                assert_eq!(layout.unknown[1].end, out.len());
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    let bad: &[([u8; 4], &[u8])] = &[(*b"MTrk", b"")];
+    assert!(write_with_chunks(
+        &smf.header,
+        &smf.tracks,
+        bad,
+        ChunkPosition::AfterTracks,
+        &mut out
+    )
+    .is_err());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn unknown_meta_roundtrip() {
+    use crate::{MetaMessage, Smf, TrackEventKind};
+
+    let mut raw = Vec::new();
+    raw.extend_from_slice(b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60");
+    raw.extend_from_slice(b"MTrk\x00\x00\x00\x10");
+    //Made-up meta type 0x42
+    raw.extend_from_slice(&[0x00, 0xFF, 0x42, 0x03, 0x01, 0x02, 0x03]);
+    //Truncated key signature
+    raw.extend_from_slice(&[0x00, 0xFF, 0x59, 0x01, 0x02]);
+    raw.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+    let smf = Smf::parse(&raw).unwrap();
+    assert_eq!(
+        smf.tracks[0][0].kind,
+        TrackEventKind::Meta(MetaMessage::Unknown(0x42, &[1, 2, 3]))
+    );
+    assert_eq!(
+        smf.tracks[0][1].kind,
+        TrackEventKind::Meta(MetaMessage::Unknown(0x59, &[2]))
+    );
+    let mut out = Vec::new();
+    smf.write(&mut out).unwrap();
+    assert_eq!(out, raw);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn event_counts() {
+    use crate::{MetaMessage, Smf};
+
+    let eot = meta(0, MetaMessage::EndOfTrack);
+    let mut smf = Smf::default();
+    assert_eq!(smf.total_event_count(), 0);
+    assert!(!smf.has_events());
+    smf.tracks = vec![vec![eot], vec![eot]];
+    assert!(!smf.is_empty());
+    assert_eq!(smf.total_event_count(), 2);
+    assert!(!smf.has_events());
+    smf.tracks[1].insert(0, note(0, 0, 60, 100));
+    assert_eq!(smf.total_event_count(), 3);
+    assert!(smf.has_events());
+}
+
+#[test]
+fn channel_mode_decoding() {
+    use crate::{as_channel_mode, ChannelMode, MidiMessage};
+
+    let ctrl = |controller: u8, value: u8| MidiMessage::Controller {
+        controller: controller.into(),
+        value: value.into(),
+    };
+    assert_eq!(
+        as_channel_mode(&ctrl(120, 0)),
+        Some(ChannelMode::AllSoundOff)
+    );
+    assert_eq!(
+        as_channel_mode(&ctrl(126, 4)),
+        Some(ChannelMode::MonoOn { channels: 4.into() })
+    );
+    assert_eq!(
+        as_channel_mode(&ctrl(122, 127)),
+        Some(ChannelMode::LocalControl(true))
+    );
+    assert_eq!(as_channel_mode(&ctrl(7, 100)), None);
+    assert_eq!(
+        as_channel_mode(&MidiMessage::ProgramChange {
+            program: 120.into()
+        }),
+        None
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn number_tracks() {
+    use crate::{MetaMessage, Smf, TrackEventKind};
+
+    let mut smf = Smf {
+        header: Default::default(),
+        tracks: vec![
+            vec![note(0, 0, 60, 100)],
+            vec![
+                meta(0, MetaMessage::TrackNumber(Some(7))),
+                note(10, 1, 62, 100),
+            ],
+            vec![],
+        ],
+    };
+    smf.number_tracks();
+    for (idx, track) in smf.tracks.iter().enumerate() {
+        assert_eq!(
+            track[0].kind,
+            TrackEventKind::Meta(MetaMessage::TrackNumber(Some(idx as u16)))
+        );
+    }
+    assert_eq!(smf.tracks[0].len(), 2);
+    assert_eq!(smf.tracks[1].len(), 2);
+    assert_eq!(smf.tracks[1][1], note(10, 1, 62, 100));
+    assert_eq!(smf.tracks[2].len(), 1);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn write_crc32() {
+    use crate::{
+        io::{Crc32Writer, Write},
+        Smf,
+    };
+
+    let mut check = Crc32Writer::new(Vec::new());
+    check.write(b"123456789").unwrap();
+    assert_eq!(check.crc(), 0xCBF4_3926);
+    assert_eq!(check.into_inner(), b"123456789");
+
+    open! {file: "Clementi.mid"};
+    let smf = Smf::parse(&file).unwrap();
+    let mut out = Vec::new();
+    let crc = smf.write_crc32(&mut out).unwrap();
+    let mut independent = Vec::new();
+    smf.write(&mut independent).unwrap();
+    assert_eq!(out, independent);
+    let mut check = Crc32Writer::new(Vec::new());
+    check.write(&independent).unwrap();
+    assert_eq!(crc, check.crc());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn collect_into_reused_buffer() {
+    use crate::{parse, Smf};
+
+    open! {file: "Clementi.mid"};
+    let smf = Smf::parse(&file).unwrap();
+    let (_, tracks) = parse(&file).unwrap();
+    let mut buf = Vec::new();
+    let mut count = 0;
+    for (track, expected) in tracks.zip(smf.tracks.iter()) {
+        track.unwrap().collect_into(&mut buf).unwrap();
+        assert_eq!(&buf, expected);
+        count += 1;
+    }
+    assert_eq!(count, smf.tracks.len());
+    assert!(buf.capacity() >= smf.tracks.iter().map(|t| t.len()).max().unwrap());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn smf_try_from() -> MidlyResult<()> {
+    use crate::{Smf, SmfBytemap};
+    use std::convert::{TryFrom, TryInto};
+
+    open! {file: "Clementi.mid"};
+    let bytes: &[u8] = &file;
+    let smf: Smf = bytes.try_into()?;
+    assert_eq!(smf, Smf::parse(bytes)?);
+    let bytemap: SmfBytemap = bytes.try_into()?;
+    assert_eq!(bytemap, SmfBytemap::parse(bytes)?);
+    assert!(Smf::try_from(&bytes[..10]).is_err());
+    Ok(())
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn thin_pitch_bend() {
+    use crate::{MidiMessage, PitchBend, TrackEventKind};
+
+    let bend = |delta: u32, value: i16| TrackEvent {
+        delta: delta.into(),
+        kind: TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::PitchBend {
+                bend: PitchBend::from_int(value),
+            },
+        },
+    };
+    //A linear sweep up, a sharp drop, and a linear sweep back up
+    let mut track = Vec::new();
+    track.push(bend(0, 0));
+    for _ in 0..10 {
+        track.push(bend(10, track.len() as i16 * 100));
+    }
+    track.push(note(5, 0, 60, 100));
+    track.push(bend(5, -4000));
+    for i in 1..=5 {
+        track.push(bend(10, -4000 + i * 200));
+    }
+    track.push(cc(7, 0, 7, 100));
+    let original = track.clone();
+
+    crate::thin_pitch_bend(&mut track, 10);
+    assert_eq!(
+        track,
+        vec![
+            bend(0, 0),
+            bend(100, 1000),
+            note(5, 0, 60, 100),
+            bend(5, -4000),
+            bend(50, -3000),
+            cc(7, 0, 7, 100),
+        ]
+    );
+    let total = |t: &[TrackEvent]| t.iter().map(|ev| ev.delta.as_int()).sum::<u32>();
+    assert_eq!(total(&track), total(&original));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn running_status_reset() {
+    use crate::{EventIter, MetaMessage, Smf, TrackEventKind};
+
+    let tempo = meta(0, MetaMessage::Tempo(500_000.into()));
+    let sysex = TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::SysEx(&[0x7E, 0x7F, 0x09, 0x01, 0xF7]),
+    };
+    let track = vec![
+        note(0, 0, 60, 100),
+        note(0, 0, 62, 100),
+        tempo,
+        note(0, 0, 64, 100),
+        sysex,
+        note(0, 0, 65, 100),
+        meta(0, MetaMessage::EndOfTrack),
+    ];
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(track.clone());
+    let mut out = Vec::new();
+    smf.write(&mut out).unwrap();
+    let expected: &[u8] = &[
+        0x00, 0x90, 60, 100, //Full status
+        0x00, 62, 100, //Running status
+        0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, //Meta cancels running status
+        0x00, 0x90, 64, 100, //Full status again
+        0x00, 0xF0, 0x05, 0x7E, 0x7F, 0x09, 0x01, 0xF7, //SysEx cancels running status
+        0x00, 0x90, 65, 100, //Full status again
+        0x00, 0xFF, 0x2F, 0x00,
+    ];
+    assert_eq!(&out[22..], expected);
+
+    let parsed = EventIter::new(expected)
+        .collect::<MidlyResult<Vec<_>>>()
+        .unwrap();
+    assert_eq!(parsed, track);
+
+    //A status-less note after a meta message must not reuse the previous status
+    let broken: &[u8] = &[0x00, 0x90, 60, 100, 0x00, 0xFF, 0x2F, 0x00, 0x00, 62, 100];
+    let mut iter = EventIter::new(broken);
+    assert_eq!(iter.next().unwrap().unwrap(), note(0, 0, 60, 100));
+    assert!(iter.next().unwrap().is_ok());
+    assert!(!matches!(iter.next(), Some(Ok(_))));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn escape_running_status_reset() {
+    use crate::{EventIter, Smf, TrackEventKind};
+
+    //An MTC quarter frame, a system common message, embedded in an escape
+    let quarter_frame = TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Escape(&[0xF1, 0x20]),
+    };
+    let track = vec![note(0, 0, 60, 100), quarter_frame, note(0, 0, 62, 100)];
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(track.clone());
+    let mut out = Vec::new();
+    smf.write(&mut out).unwrap();
+    let expected: &[u8] = &[
+        0x00, 0x90, 60, 100, //Full status
+        0x00, 0xF7, 0x02, 0xF1, 0x20, //Escape cancels running status
+        0x00, 0x90, 62, 100, //Fresh status byte
+    ];
+    assert_eq!(&out[22..], expected);
+    assert_eq!(EventIter::new(expected).into_vec().unwrap(), track);
+
+    //A status-less note after the escape must not reuse the previous status
+    let broken: &[u8] = &[
+        0x00, 0x90, 60, 100, 0x00, 0xF7, 0x02, 0xF1, 0x20, 0x00, 62, 100,
+    ];
+    let mut iter = EventIter::new(broken);
+    assert_eq!(iter.next().unwrap().unwrap(), note(0, 0, 60, 100));
+    assert_eq!(iter.next().unwrap().unwrap(), quarter_frame);
+    assert_eq!(iter.running_status(), None);
+    assert!(!matches!(iter.next(), Some(Ok(_))));
+}
+
+#[test]
+fn smpte_converter() {
+    use crate::{Fps, SmpteConverter, SmpteTime, Timing};
+
+    let tc = |h, m, s, f, sf, fps| SmpteTime::new(h, m, s, f, sf, fps).unwrap();
+
+    let pal = SmpteConverter::new(Fps::Fps25, 40);
+    assert_eq!(pal.to_timecode(0), Some(tc(0, 0, 0, 0, 0, Fps::Fps25)));
+    let tick = (((3600 + 2 * 60 + 3) * 25 + 4) * 40) + 20;
+    assert_eq!(pal.to_timecode(tick), Some(tc(1, 2, 3, 4, 50, Fps::Fps25)));
+    assert_eq!(pal.from_timecode(tc(1, 2, 3, 4, 50, Fps::Fps25)), tick);
+    assert_eq!(pal.to_timecode(24 * 3600 * 25 * 40), None);
+
+    let ntsc = SmpteConverter::from_timing(Timing::Timecode(Fps::Fps29, 80)).unwrap();
+    //The first minute holds 1800 frames, labels 00:01:00;00 and ;01 are dropped
+    assert_eq!(
+        ntsc.to_timecode(1799 * 80),
+        Some(tc(0, 0, 59, 29, 0, Fps::Fps29))
+    );
+    assert_eq!(
+        ntsc.to_timecode(1800 * 80),
+        Some(tc(0, 1, 0, 2, 0, Fps::Fps29))
+    );
+    //Every tenth minute keeps its labels
+    assert_eq!(
+        ntsc.to_timecode(17982 * 80),
+        Some(tc(0, 10, 0, 0, 0, Fps::Fps29))
+    );
+    //A known timecode: 01:00:00;00 is frame 107892
+    assert_eq!(
+        ntsc.from_timecode(tc(1, 0, 0, 0, 0, Fps::Fps29)),
+        107892 * 80
+    );
+    for &frame in &[0, 1, 1799, 1800, 3597, 3598, 17981, 17982, 107892, 123456] {
+        let tick = frame * 80 + 40;
+        assert_eq!(ntsc.from_timecode(ntsc.to_timecode(tick).unwrap()), tick);
+    }
+
+    //With more than 100 ticks per frame, ticks round down to the start of their hundredth
+    let fine = SmpteConverter::new(Fps::Fps30, 120);
+    for tick in 3000 * 120..3001 * 120 {
+        let time = fine.to_timecode(tick).unwrap();
+        let back = fine.from_timecode(time);
+        assert!(back <= tick && tick - back < 2);
+        assert_eq!(fine.to_timecode(back), Some(time));
+    }
+    assert_eq!(fine.from_timecode(fine.to_timecode(121).unwrap()), 120);
+    assert_eq!(fine.from_timecode(fine.to_timecode(122).unwrap()), 122);
+    assert_eq!(SmpteConverter::from_timing(Timing::default()), None);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn parse_observed() {
+    use crate::{ParseObserver, Smf};
+
+    #[derive(Default)]
+    struct Report {
+        skipped: Vec<([u8; 4], usize)>,
+        truncated: Vec<(usize, usize)>,
+        headers: Vec<usize>,
+    }
+    impl ParseObserver for Report {
+        fn on_skipped_chunk(&mut self, id: [u8; 4], offset: usize) {
+            self.skipped.push((id, offset));
+        }
+        fn on_truncated_track(&mut self, index: usize, offset: usize) {
+            self.truncated.push((index, offset));
+        }
+        fn on_duplicate_header(&mut self, offset: usize) {
+            self.headers.push(offset);
+        }
+    }
+
+    let mut raw = Vec::new();
+    raw.extend_from_slice(b"MThd\x00\x00\x00\x06\x00\x01\x00\x02\x00\x60");
+    raw.extend_from_slice(b"MTrk\x00\x00\x00\x04\x00\xFF\x2F\x00");
+    //Unknown chunk at offset 26
+    raw.extend_from_slice(b"XFIH\x00\x00\x00\x02\xAB\xCD");
+    raw.extend_from_slice(b"MTrk\x00\x00\x00\x04\x00\xFF\x2F\x00");
+    let mut report = Report::default();
+    let smf = Smf::parse_observed(&raw, &mut report).unwrap();
+    assert_eq!(smf, Smf::parse(&raw).unwrap());
+    assert_eq!(report.skipped, vec![(*b"XFIH", 26)]);
+    assert!(report.truncated.is_empty());
+    assert!(report.headers.is_empty());
+
+    if !cfg!(feature = "strict") {
+        //A track chunk that extends past the end of the file
+        raw.extend_from_slice(b"MTrk\x00\x00\x00\x10\x00\x90\x3C\x40");
+        let mut report = Report::default();
+        let smf = Smf::parse_observed(&raw, &mut report).unwrap();
+        assert_eq!(smf, Smf::parse(&raw).unwrap());
+        assert_eq!(report.truncated, vec![(2, raw.len())]);
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn sysex_channel_prefix() {
+    use crate::{sysex_channels, MetaMessage, TrackEventKind};
+
+    let ev = |kind| TrackEvent {
+        delta: 0.into(),
+        kind,
+    };
+    let sysex = ev(TrackEventKind::SysEx(&[0x41, 0x10, 0xF7]));
+    let track = vec![
+        sysex,
+        meta(0, MetaMessage::MidiChannel(3.into())),
+        meta(0, MetaMessage::TrackName(b"Strings")),
+        sysex,
+        ev(TrackEventKind::Escape(&[0xF8])),
+        note(0, 3, 60, 100),
+        sysex,
+        ev(TrackEventKind::Meta(MetaMessage::MidiChannel(9.into()))),
+        sysex,
+    ];
+    assert_eq!(
+        sysex_channels(&track),
+        vec![
+            (0, None),
+            (3, Some(3.into())),
+            (4, Some(3.into())),
+            (6, None),
+            (8, Some(9.into())),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn map_channels() {
+    use crate::MetaMessage;
+
+    let prefix = meta(0, MetaMessage::MidiChannel(15.into()));
+    let mut track = vec![
+        prefix,
+        note(0, 0, 60, 100),
+        cc(10, 9, 7, 100),
+        note(10, 15, 60, 0),
+    ];
+    crate::map_channels(&mut track, |ch| ((ch.as_int() + 1) % 16).into());
+    assert_eq!(
+        track,
+        vec![
+            prefix,
+            note(0, 1, 60, 100),
+            cc(10, 10, 7, 100),
+            note(10, 0, 60, 0),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn split_songs() {
+    use crate::{Format, MetaMessage, Smf};
+
+    let tempo = MetaMessage::Tempo(400_000.into());
+    let mut smf = Smf::new(Default::default());
+    smf.header.format = Format::SingleTrack;
+    smf.tracks.push(vec![
+        meta(0, tempo),
+        note(0, 0, 60, 100),
+        //A long note is not a gap
+        note(5000, 0, 60, 0),
+        note(10, 0, 62, 100),
+        note(10, 0, 62, 0),
+        //Big silent gap
+        note(3000, 0, 64, 100),
+        note(10, 0, 64, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    let boundaries = smf.detect_song_boundaries(1000);
+    assert_eq!(boundaries, vec![8020]);
+
+    smf.split_at_ticks(&boundaries);
+    assert_eq!(smf.header.format, Format::Sequential);
+    assert_eq!(
+        smf.tracks,
+        vec![
+            vec![
+                meta(0, tempo),
+                note(0, 0, 60, 100),
+                note(5000, 0, 60, 0),
+                note(10, 0, 62, 100),
+                note(10, 0, 62, 0),
+                meta(0, MetaMessage::EndOfTrack),
+            ],
+            vec![
+                meta(0, tempo),
+                note(0, 0, 64, 100),
+                note(10, 0, 64, 0),
+                meta(0, MetaMessage::EndOfTrack),
+            ],
+        ]
+    );
+
+    //Songs separated by a premature end of track
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![
+        note(0, 0, 60, 100),
+        note(10, 0, 60, 0),
+        meta(0, MetaMessage::EndOfTrack),
+        note(20, 0, 62, 100),
+        note(10, 0, 62, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    assert_eq!(smf.detect_song_boundaries(1000), vec![30]);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn shared_smf_threads() {
+    use crate::{SharedSmf, Smf};
+    use std::{sync::Arc, thread};
+
+    open! {file: "Clementi.mid"};
+    let expected = Smf::parse(&file).unwrap().to_static();
+    let raw: Arc<[u8]> = file.into();
+    let shared = SharedSmf::parse(raw.clone()).unwrap();
+    let handles = (0..2)
+        .map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || shared.smf().tracks.iter().map(|t| t.len()).sum::<usize>())
+        })
+        .collect::<Vec<_>>();
+    drop(raw);
+    let counts = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Vec<_>>();
+    let total = expected.tracks.iter().map(|t| t.len()).sum::<usize>();
+    assert_eq!(counts, vec![total, total]);
+    assert_eq!(shared.smf().header, expected.header);
+    assert_eq!(shared.raw().len(), shared.clone().raw().len());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn programs_used() {
+    use crate::{MidiMessage, Smf, TrackEventKind};
+
+    let program = |ch: u8, program: u8| TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Midi {
+            channel: ch.into(),
+            message: MidiMessage::ProgramChange {
+                program: program.into(),
+            },
+        },
+    };
+    let mut smf = Smf::new(Default::default());
+    smf.tracks
+        .push(vec![program(0, 0), note(0, 0, 60, 100), program(0, 40)]);
+    smf.tracks.push(vec![program(0, 40), program(9, 16)]);
+    let programs = smf.programs_used();
+    assert_eq!(
+        programs[0].iter().map(|p| p.as_int()).collect::<Vec<_>>(),
+        vec![0, 40]
+    );
+    assert_eq!(programs[9].len(), 1);
+    assert!(programs[1].is_empty());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn controllers_used() {
+    use crate::Smf;
+
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![
+        cc(0, 0, 7, 100),
+        note(0, 0, 60, 100),
+        cc(0, 0, 10, 64),
+    ]);
+    smf.tracks.push(vec![cc(0, 0, 7, 90), cc(0, 3, 11, 127)]);
+    let controllers = smf.controllers_used();
+    assert_eq!(
+        controllers[0]
+            .iter()
+            .map(|c| c.as_int())
+            .collect::<Vec<_>>(),
+        vec![7, 10]
+    );
+    assert_eq!(controllers[3].len(), 1);
+    assert!(controllers[1].is_empty());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn append_with_overlap() {
+    use crate::{MetaMessage, MidiMessage, Smf, TrackEventKind};
+
+    let mut a = Smf::new(Default::default());
+    a.tracks.push(vec![
+        note(0, 0, 60, 100),
+        note(960, 0, 60, 0),
+        note(0, 0, 64, 100),
+        note(960, 0, 64, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    let mut b = Smf::new(Default::default());
+    b.tracks.push(vec![
+        note(0, 0, 64, 90),
+        note(480, 0, 64, 0),
+        note(0, 0, 67, 90),
+        note(480, 0, 67, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+
+    let mut plain = a.clone();
+    plain.append(&b, 0);
+    let mut time = 0;
+    let times = plain.tracks[0]
+        .iter()
+        .map(|ev| {
+            time += ev.delta.as_int();
+            time
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(times, vec![0, 960, 960, 1920, 1920, 2400, 2400, 2880, 2880]);
+
+    a.append(&b, 480);
+    let track = &a.tracks[0];
+    //B starts at 1440, while A is still holding key 64
+    let mut time = 0;
+    let mut held = [0i32; 128];
+    for ev in track {
+        time += ev.delta.as_int();
+        if let TrackEventKind::Midi { message, .. } = ev.kind {
+            match message {
+                MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                    assert_eq!(held[key.as_int() as usize], 0, "double trigger at {}", time);
+                    held[key.as_int() as usize] += 1;
+                }
+                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                    held[key.as_int() as usize] -= 1;
+                }
+                _ => {}
+            }
+        }
+    }
+    assert!(held.iter().all(|&h| h == 0));
+    assert_eq!(time, 2400);
+    assert_eq!(track.last(), Some(&meta(0, MetaMessage::EndOfTrack)));
+    let release = TrackEvent {
+        delta: 480.into(),
+        kind: TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOff {
+                key: 64.into(),
+                vel: 64.into(),
+            },
+        },
+    };
+    assert_eq!(
+        track,
+        &vec![
+            note(0, 0, 60, 100),
+            note(960, 0, 60, 0),
+            note(0, 0, 64, 100),
+            release,
+            note(0, 0, 64, 90),
+            note(480, 0, 64, 0),
+            note(0, 0, 67, 90),
+            note(480, 0, 67, 0),
+            meta(0, MetaMessage::EndOfTrack),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn retime_to_seconds() {
+    use crate::{MetaMessage, Smf, Timing};
+
+    let mut smf = Smf::new(Default::default()).with_ppq(480.into());
+    smf.tracks.push(vec![
+        meta(0, MetaMessage::Tempo(500_000.into())),
+        meta(960, MetaMessage::Tempo(250_000.into())),
+        meta(480, MetaMessage::EndOfTrack),
+    ]);
+    smf.tracks.push(vec![
+        note(0, 0, 60, 100),
+        note(960, 0, 60, 0),
+        //Lands at 1s + 0.25s
+        note(480, 0, 62, 100),
+        note(1, 0, 62, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    smf.retime_to_seconds();
+    assert_eq!(
+        smf.header.timing,
+        Timing::Metrical(Smf::RETIMED_TICKS_PER_BEAT.into())
+    );
+    assert_eq!(smf.tracks[0], vec![meta(12500, MetaMessage::EndOfTrack)]);
+    //One tick at 250000us/beat and 480ppq is ~520us, rounded to 5 grid ticks
+    assert_eq!(
+        smf.tracks[1],
+        vec![
+            note(0, 0, 60, 100),
+            note(10000, 0, 60, 0),
+            note(2500, 0, 62, 100),
+            note(5, 0, 62, 0),
+            meta(0, MetaMessage::EndOfTrack),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn order_chords() {
+    use crate::{order_chords, ChordOrder};
+
+    let track = vec![
+        note(0, 0, 60, 100),
+        note(0, 0, 64, 100),
+        //Interleaved chord change
+        note(480, 0, 60, 0),
+        note(0, 0, 62, 100),
+        cc(0, 0, 64, 127),
+        note(0, 0, 64, 0),
+        note(0, 0, 65, 100),
+        note(480, 0, 62, 0),
+        note(0, 0, 65, 0),
+    ];
+    let mut offs_first = track.clone();
+    order_chords(&mut offs_first, ChordOrder::OffsFirst);
+    assert_eq!(
+        offs_first,
+        vec![
+            note(0, 0, 60, 100),
+            note(0, 0, 64, 100),
+            note(480, 0, 60, 0),
+            note(0, 0, 64, 0),
+            cc(0, 0, 64, 127),
+            note(0, 0, 62, 100),
+            note(0, 0, 65, 100),
+            note(480, 0, 62, 0),
+            note(0, 0, 65, 0),
+        ]
+    );
+    let mut ons_first = track;
+    order_chords(&mut ons_first, ChordOrder::OnsFirst);
+    assert_eq!(
+        ons_first[2..7],
+        [
+            note(480, 0, 62, 100),
+            note(0, 0, 65, 100),
+            cc(0, 0, 64, 127),
+            note(0, 0, 60, 0),
+            note(0, 0, 64, 0),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn validated_header_setters() {
+    use crate::{Format, Fps, Header, Smf, Timing};
+
+    let mut header = Header::default();
+    assert!(header.set_timing(Timing::Metrical(0.into())).is_err());
+    assert!(header.set_timing(Timing::Timecode(Fps::Fps25, 0)).is_err());
+    assert_eq!(header, Header::default());
+    header.set_timing(Timing::Metrical(96.into())).unwrap();
+    assert_eq!(header.timing, Timing::Metrical(96.into()));
+
+    let mut smf = Smf::new(header);
+    smf.tracks = vec![vec![], vec![]];
+    assert!(smf.set_format(Format::SingleTrack).is_err());
+    assert_eq!(smf.header.format, Format::Parallel);
+    smf.set_format(Format::Sequential).unwrap();
+    assert_eq!(smf.header.format, Format::Sequential);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn xf_info() {
+    use crate::{write_with_chunks, ChunkPosition, Header, MetaMessage, XfInfo};
+
+    let track = vec![
+        meta(
+            0,
+            MetaMessage::SequencerSpecific(&[0x43, 0x7B, 0x00, b'X', b'F', b'0', b'2', 0x00, 0x11]),
+        ),
+        meta(0, MetaMessage::EndOfTrack),
+    ];
+    let info = XfInfo::from_events(&track);
+    assert_eq!(info.version, Some(*b"XF02"));
+    assert!(info.song_info.is_empty());
+
+    //Song information stored in an XFIH chunk
+    let mut xfih = Vec::new();
+    let song = b"XFhd:1999:US:Pop:::F1:Jane Doe:John Roe::The Band:::";
+    xfih.extend_from_slice(&[0x00, 0xFF, 0x01, song.len() as u8]);
+    xfih.extend_from_slice(song);
+    xfih.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+    let mut raw = Vec::new();
+    write_with_chunks(
+        &Header::default(),
+        &[track],
+        &[(*b"XFIH", &xfih[..])],
+        ChunkPosition::BeforeTracks,
+        &mut raw,
+    )
+    .unwrap();
+    let info = XfInfo::read(&raw).unwrap();
+    assert!(info.is_xf());
+    assert_eq!(info.version, Some(*b"XF02"));
+    assert_eq!(info.date(), Some(&b"1999"[..]));
+    assert_eq!(info.category(), Some(&b"Pop"[..]));
+    assert_eq!(info.composer(), Some(&b"Jane Doe"[..]));
+    assert_eq!(info.lyricist(), Some(&b"John Roe"[..]));
+    assert_eq!(info.performer(), Some(&b"The Band"[..]));
+    assert_eq!(info.song_name(), None);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn empty_track_roundtrip() {
+    use crate::{EventIter, Smf};
+
+    assert!(EventIter::new(&[]).into_vec().unwrap().is_empty());
+
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![note(0, 0, 60, 100)]);
+    smf.tracks.push(vec![]);
+    let mut raw = Vec::new();
+    smf.write(&mut raw).unwrap();
+    assert_eq!(&raw[raw.len() - 8..], b"MTrk\0\0\0\0");
+    let parsed = Smf::parse(&raw).unwrap();
+    assert_eq!(parsed, smf);
+    assert!(parsed.tracks[1].is_empty());
+    let mut rewritten = Vec::new();
+    parsed.write(&mut rewritten).unwrap();
+    assert_eq!(rewritten, raw);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn scheduled_events() {
+    use crate::{Format, MetaMessage, Smf};
+    use std::time::Duration;
+
+    let mut smf = Smf::new(Default::default()).with_ppq(480.into());
+    smf.tracks.push(vec![
+        meta(0, MetaMessage::Tempo(500_000.into())),
+        meta(960, MetaMessage::Tempo(250_000.into())),
+        meta(960, MetaMessage::EndOfTrack),
+    ]);
+    smf.tracks.push(vec![
+        note(480, 0, 60, 100),
+        note(960, 0, 60, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    let delays = smf
+        .scheduled()
+        .map(|(delay, _)| delay.as_millis())
+        .collect::<Vec<_>>();
+    //Tempo, note on, tempo change, note off and end of track at 1.25s, end of track at 1.5s
+    assert_eq!(delays, [0, 500, 500, 250, 0, 250]);
+    assert_eq!(smf.duration(), Duration::from_millis(1500));
+
+    //Sequential tracks play back to back, each with their own tempo map
+    smf.header.format = Format::Sequential;
+    assert_eq!(smf.duration(), Duration::from_millis(1500 + 1500));
+    let total: Duration = smf.scheduled().map(|(delay, _)| delay).sum();
+    assert_eq!(total, smf.duration());
+
+    open! {file: "Clementi.mid"};
+    let smf = Smf::parse(&file).unwrap();
+    let total: Duration = smf.scheduled().map(|(delay, _)| delay).sum();
+    assert_eq!(smf.scheduled().count(), smf.total_event_count());
+    assert_eq!(total, smf.duration());
+    assert!(total > Duration::from_secs(1));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn malformed_rmid() {
+    use crate::Smf;
+
+    open! {file: "Clementi.mid"};
+    let expected = Smf::parse(&file).unwrap();
+    let rmid = |riff_len: u32, extra: &[u8], data_len: u32, trailer: &[u8]| {
+        let mut raw = b"RIFF".to_vec();
+        raw.extend_from_slice(&riff_len.to_le_bytes());
+        raw.extend_from_slice(b"RMID");
+        raw.extend_from_slice(extra);
+        raw.extend_from_slice(b"data");
+        raw.extend_from_slice(&data_len.to_le_bytes());
+        raw.extend_from_slice(&file);
+        raw.extend_from_slice(trailer);
+        raw
+    };
+    let len = file.len() as u32;
+    let info = b"LIST\x04\0\0\0INFO";
+    let cases = [
+        //Wrong main chunk size
+        rmid(0, &[], len, &[]),
+        //Data chunk longer than the file
+        rmid(u32::MAX, &[], len + 1000, &[]),
+        //Data chunk too short, and followed by trailing chunks
+        rmid(0, &[], 14, info),
+        rmid(0, &[], len / 2, info),
+        //Missing padding after an odd-sized chunk
+        rmid(0, b"DISP\x03\0\0\0abc", len, info),
+        //No data chunk at all
+        rmid(0, &[], len, &[])
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (12..16).contains(&i) { b'x' } else { b })
+            .collect(),
+    ];
+    for raw in cases.iter() {
+        assert_eq!(Smf::parse(raw).unwrap(), expected);
+    }
+    //A trailing chunk right after the SMF is left out
+    let mut no_data = rmid(0, &[], len, info);
+    no_data[12..16].copy_from_slice(b"xxxx");
+    assert_eq!(Smf::parse(&no_data).unwrap(), expected);
+    assert!(Smf::parse(b"RIFF\0\0\0\0RMIDdata").is_err());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn transpose_to_key() {
+    use crate::{Key, MetaMessage, MidiMessage, Smf, TrackEventKind};
+
+    let key_sig = |sharps, minor| meta(0, MetaMessage::KeySignature(sharps, minor));
+    //A D major scale, with a kick drum on every beat
+    let scale = |offset: u8| {
+        let mut track = Vec::new();
+        for &key in [62, 64, 66, 67, 69, 71, 73, 74].iter() {
+            track.push(note(0, 0, key + offset, 100));
+            track.push(note(0, 9, 36, 100));
+            track.push(note(480, 0, key + offset, 0));
+        }
+        track
+    };
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(scale(0));
+    assert_eq!(smf.detect_key(), Some(Key::new(2, false)));
+    smf.tracks[0].insert(0, key_sig(2, false));
+    assert_eq!(smf.transpose_to_key(Key::C_MAJOR), Some(-2));
+    let mut expected = scale(0);
+    for ev in expected.iter_mut() {
+        if let TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOn { key, .. },
+        } = &mut ev.kind
+        {
+            if *channel != 9 {
+                *key = (key.as_int() - 2).into();
+            }
+        }
+    }
+    expected.insert(0, key_sig(0, false));
+    assert_eq!(smf.tracks[0], expected);
+
+    //E minor into C major lands on its relative, A minor
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![key_sig(1, true), note(0, 0, 64, 100)]);
+    assert_eq!(smf.transpose_to_key(Key::C_MAJOR), Some(5));
+    assert_eq!(smf.tracks[0], vec![key_sig(0, true), note(0, 0, 69, 100)]);
+
+    //Notes are clamped to the MIDI range
+    let mut smf = Smf::new(Default::default());
+    smf.tracks
+        .push(vec![key_sig(-5, false), note(0, 0, 126, 100)]);
+    assert_eq!(smf.transpose_to_key(Key::new(4, false)), Some(3));
+    assert_eq!(smf.tracks[0], vec![key_sig(4, false), note(0, 0, 127, 100)]);
+
+    assert_eq!(
+        Smf::new(Default::default()).transpose_to_key(Key::C_MAJOR),
+        None
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn assign_channels() {
+    use crate::{num::u4, Smf};
+
+    let file = |drums: bool| {
+        let mut track = vec![note(0, 0, 60, 100)];
+        if drums {
+            track.push(note(0, 9, 36, 100));
+        }
+        track.push(note(480, 0, 60, 0));
+        let mut smf = Smf::new(Default::default());
+        smf.tracks.push(track);
+        smf
+    };
+    let mut files = vec![file(false), file(true), file(false)];
+    let mappings = Smf::assign_channels(&mut files).unwrap();
+    let moved = |to: u8| {
+        let mut mapping = [u4::from(0); 16];
+        for (channel, mapped) in mapping.iter_mut().enumerate() {
+            *mapped = (channel as u8).into();
+        }
+        mapping[0] = to.into();
+        mapping
+    };
+    assert_eq!(mappings, vec![moved(0), moved(1), moved(2)]);
+
+    let mut merged = Smf::new(Default::default());
+    for smf in files {
+        merged.tracks.extend(smf.tracks);
+    }
+    assert_eq!(merged.channels_used(), 0b10_0000_0111);
+    assert_eq!(merged.tracks[1][1], note(0, 9, 36, 100));
+
+    //Channel 9 is skipped when handing out melodic channels
+    let mut files = (0..16).map(|_| file(false)).collect::<Vec<_>>();
+    assert!(Smf::assign_channels(&mut files).is_err());
+    assert_eq!(files[15].channels_used(), 1);
+    files.pop();
+    let mappings = Smf::assign_channels(&mut files).unwrap();
+    assert_eq!(mappings[9][0], 10);
+    assert_eq!(mappings[14][0], 15);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn ensure_initial_tempo() {
+    use crate::{Format, Header, MetaMessage, Smf, Timing};
+
+    let tempo = |delta: u32| meta(delta, MetaMessage::Tempo(500_000.into()));
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(480.into())));
+    smf.tracks
+        .push(vec![meta(0, MetaMessage::TrackName(b"Conductor"))]);
+    smf.tracks
+        .push(vec![note(240, 0, 60, 100), note(480, 0, 60, 0)]);
+    let original = smf.clone();
+    assert!(smf.ensure_initial_tempo());
+    assert_eq!(smf.tracks[0][0], tempo(0));
+    assert_eq!(smf.tracks[0][1..], original.tracks[0][..]);
+    assert_eq!(smf.tracks[1], original.tracks[1]);
+    assert!(!smf.ensure_initial_tempo());
+
+    //A tempo in any track before the first note is enough
+    let mut smf = original.clone();
+    smf.tracks[1].insert(0, tempo(100));
+    assert!(!smf.ensure_initial_tempo());
+    let mut smf = original.clone();
+    smf.tracks[0].push(tempo(300));
+    assert!(smf.ensure_initial_tempo());
+
+    //Sequential tracks are handled separately
+    let mut smf = original;
+    smf.header.format = Format::Sequential;
+    smf.tracks[1].insert(0, tempo(0));
+    assert!(smf.ensure_initial_tempo());
+    assert_eq!(smf.tracks[0][0], tempo(0));
+    assert_eq!(smf.tracks[1].len(), 3);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn peek_track_name() {
+    use crate::{MetaMessage, Smf};
+
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![
+        meta(0, MetaMessage::Tempo(500_000.into())),
+        meta(0, MetaMessage::TrackName("Piano \u{e9}".as_bytes())),
+        note(0, 0, 60, 100),
+        note(480, 0, 60, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    //Names after the first non-meta event are not considered leading
+    smf.tracks.push(vec![
+        note(0, 0, 60, 100),
+        meta(0, MetaMessage::TrackName(b"Late")),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    smf.tracks.push(vec![
+        meta(10, MetaMessage::TrackName(b"Delayed")),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    let mut raw = Vec::new();
+    smf.write(&mut raw).unwrap();
+
+    let (_, tracks) = crate::parse(&raw).unwrap();
+    let mut names = Vec::new();
+    let mut parsed = Vec::new();
+    for track in tracks {
+        let track = track.unwrap();
+        let offset = track.offset();
+        names.push(track.peek_track_name().map(|name| name.into_owned()));
+        assert_eq!(track.offset(), offset);
+        parsed.push(track.into_vec().unwrap());
+    }
+    assert_eq!(names, [Some("Piano \u{e9}".to_string()), None, None]);
+    assert_eq!(parsed, smf.tracks);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_track_threshold() {
+    use crate::{MetaMessage, ParseOptions, Smf};
+
+    let mut smf = Smf::new(Default::default());
+    for key in 0..100 {
+        smf.tracks.push(vec![
+            note(0, 0, key, 100),
+            note(10, 0, key, 0),
+            meta(0, MetaMessage::EndOfTrack),
+        ]);
+    }
+    let mut raw = Vec::new();
+    smf.write(&mut raw).unwrap();
+    let defaults = ParseOptions::default();
+    assert!(raw.len() < defaults.parallel_bytes);
+
+    //Many small tracks engage the parallel path through the track count alone
+    let (_, tracks) = crate::parse(&raw).unwrap();
+    assert!(tracks.use_parallel());
+    let (parsed, metrics) = Smf::parse_with_metrics(&raw).unwrap();
+    assert_eq!(metrics.parallel_tracks, 100);
+    assert_eq!(parsed, smf);
+
+    let serial = ParseOptions {
+        parallel_tracks: u16::MAX,
+        ..defaults
+    };
+    let (_, tracks) = crate::parse(&raw).unwrap();
+    assert!(!tracks.with_options(serial).use_parallel());
+    assert_eq!(Smf::parse_with_options(&raw, serial).unwrap(), smf);
+    let few_tracks = ParseOptions {
+        parallel_bytes: 0,
+        ..serial
+    };
+    let (_, tracks) = crate::parse(&raw).unwrap();
+    assert!(tracks.with_options(few_tracks).use_parallel());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn write_counted() {
+    use crate::{io::CountingWriter, Smf};
+
+    open! {file: "Clementi.mid"};
+    let smf = Smf::parse(&file).unwrap();
+    let mut out = Vec::new();
+    let written = smf.write_counted(&mut out).unwrap();
+    assert_eq!(written, smf.encoded_len().unwrap());
+    assert_eq!(written, out.len() as u64);
+
+    let mut counting = CountingWriter::new(Vec::new());
+    smf.write(&mut counting).unwrap();
+    assert_eq!(counting.count(), written);
+    assert_eq!(counting.into_inner(), out);
+
+    //Errors are forwarded from the inner writer
+    let mut tiny = [0; 16];
+    assert!(smf.write_counted(&mut &mut tiny[..]).is_err());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn unknown_format() {
+    use crate::{Format, ParseObserver, Smf};
+
+    #[derive(Default)]
+    struct Report(Vec<u16>);
+    impl ParseObserver for Report {
+        fn on_unknown_format(&mut self, format: u16) {
+            self.0.push(format);
+        }
+    }
+
+    open! {file: "Clementi.mid"};
+    let mut raw = file.to_vec();
+    assert_eq!(&raw[8..10], [0, 1]);
+    raw[9] = 3;
+    let mut report = Report::default();
+    let result = Smf::parse_observed(&raw, &mut report);
+    if cfg!(feature = "strict") {
+        assert!(result.is_err());
+        assert!(Smf::parse(&raw).is_err());
+    } else {
+        let smf = result.unwrap();
+        assert_eq!(smf.header.format, Format::Parallel);
+        assert_eq!(smf, Smf::parse(&file).unwrap());
+        assert_eq!(report.0, [3]);
+    }
+
+    let mut report = Report::default();
+    Smf::parse_observed(&file, &mut report).unwrap();
+    assert!(report.0.is_empty());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn canonicalize() {
+    use crate::{MetaMessage, Smf};
+
+    let mut a = Smf::new(Default::default());
+    a.tracks.push(vec![
+        meta(0, MetaMessage::Tempo(500_000.into())),
+        meta(0, MetaMessage::Tempo(400_000.into())),
+        meta(960, MetaMessage::Tempo(300_000.into())),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    a.tracks.push(vec![
+        note(0, 0, 60, 100),
+        note(480, 0, 60, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    a.tracks.push(vec![
+        note(0, 1, 64, 100),
+        note_off(480, 1, 64, 30),
+        meta(960, MetaMessage::EndOfTrack),
+    ]);
+
+    //The same music, laid out differently
+    let mut b = Smf::new(Default::default());
+    b.tracks.push(vec![
+        meta(0, MetaMessage::Tempo(400_000.into())),
+        meta(480, MetaMessage::Tempo(400_000.into())),
+        meta(480, MetaMessage::Tempo(300_000.into())),
+        meta(100, MetaMessage::EndOfTrack),
+    ]);
+    b.tracks.push(vec![
+        note(0, 1, 64, 100),
+        note(480, 1, 64, 0),
+        meta(0, MetaMessage::EndOfTrack),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    //Missing end of track
+    b.tracks
+        .push(vec![note(0, 0, 60, 100), note(480, 0, 60, 0)]);
+
+    let encode = |smf: &Smf| {
+        let mut raw = Vec::new();
+        smf.write(&mut raw).unwrap();
+        raw
+    };
+    assert_ne!(encode(&a), encode(&b));
+    a.canonicalize();
+    b.canonicalize();
+    assert_eq!(encode(&a), encode(&b));
+    assert_eq!(
+        a.tracks[0],
+        vec![
+            meta(0, MetaMessage::Tempo(400_000.into())),
+            meta(960, MetaMessage::Tempo(300_000.into())),
+            meta(0, MetaMessage::EndOfTrack),
+        ]
+    );
+    assert_eq!(a.tracks[1][0], note(0, 0, 60, 100));
+
+    //Canonicalizing is idempotent
+    let once = a.clone();
+    a.canonicalize();
+    assert_eq!(a, once);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn canonicalize_zero_length_note() {
+    use crate::{MetaMessage, Smf};
+
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![
+        note(0, 0, 62, 100),
+        note(240, 0, 60, 100),
+        note_off(0, 0, 60, 64),
+        note_off(0, 0, 62, 64),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    smf.canonicalize();
+    //The zero-length note stays released, and the held note is released first
+    assert_eq!(
+        smf.tracks[0],
+        vec![
+            note(0, 0, 62, 100),
+            note(240, 0, 62, 0),
+            note(0, 0, 60, 100),
+            note(0, 0, 60, 0),
+            meta(0, MetaMessage::EndOfTrack),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn reassemble_split_sysex() {
+    use crate::{sysex_messages, MetaMessage, SysExMessage, TrackEventKind};
+    use std::borrow::Cow;
+
+    let ev = |delta: u32, kind| TrackEvent {
+        delta: delta.into(),
+        kind,
+    };
+    let track = vec![
+        note(0, 0, 60, 100),
+        ev(10, TrackEventKind::SysEx(&[0x7E, 0x7F, 0xF7])),
+        //Split message, with time between packets
+        ev(20, TrackEventKind::SysEx(&[0x43, 0x12, 0x00])),
+        ev(100, TrackEventKind::Escape(&[0x07, 0x01])),
+        ev(50, TrackEventKind::Escape(&[0x01, 0xF7])),
+        //Not part of any message
+        ev(0, TrackEventKind::Escape(&[0xF3, 0x01])),
+        //Split message with an event in between, cut short by the end of the track
+        ev(0, TrackEventKind::SysEx(&[0x41, 0x10])),
+        note(5, 0, 60, 0),
+        ev(5, TrackEventKind::Escape(&[0x42])),
+        meta(0, MetaMessage::EndOfTrack),
+    ];
+    let messages = sysex_messages(&track);
+    assert_eq!(
+        messages,
+        vec![
+            SysExMessage {
+                time: 10,
+                data: Cow::Borrowed(&[0x7E, 0x7F, 0xF7][..]),
+                packets: vec![1],
+                interleaved: vec![],
+                complete: true,
+            },
+            SysExMessage {
+                time: 30,
+                data: Cow::Owned(vec![0x43, 0x12, 0x00, 0x07, 0x01, 0x01, 0xF7]),
+                packets: vec![2, 3, 4],
+                interleaved: vec![],
+                complete: true,
+            },
+            SysExMessage {
+                time: 180,
+                data: Cow::Owned(vec![0x41, 0x10, 0x42]),
+                packets: vec![6, 8],
+                interleaved: vec![7, 9],
+                complete: false,
+            },
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn limit_polyphony() {
+    use crate::{limit_polyphony, MidiMessage, TrackEventKind, VoiceSteal};
+
+    let stolen = |key: u8| TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOff {
+                key: key.into(),
+                vel: 64.into(),
+            },
+        },
+    };
+    let keys = [60, 62, 64, 65, 67, 69];
+    let mut chord = Vec::new();
+    for (i, &key) in keys.iter().enumerate() {
+        chord.push(note(0, 0, key, 100 - i as u8));
+    }
+    //Another channel has its own voices
+    chord.push(note(0, 1, 40, 100));
+    for (i, &key) in keys.iter().enumerate() {
+        chord.push(note(if i == 0 { 480 } else { 0 }, 0, key, 0));
+    }
+
+    let mut oldest = chord.clone();
+    limit_polyphony(&mut oldest, 4, VoiceSteal::Oldest);
+    assert_eq!(
+        oldest,
+        vec![
+            note(0, 0, 60, 100),
+            note(0, 0, 62, 99),
+            note(0, 0, 64, 98),
+            note(0, 0, 65, 97),
+            stolen(60),
+            note(0, 0, 67, 96),
+            stolen(62),
+            note(0, 0, 69, 95),
+            note(0, 1, 40, 100),
+            note(480, 0, 64, 0),
+            note(0, 0, 65, 0),
+            note(0, 0, 67, 0),
+            note(0, 0, 69, 0),
+        ]
+    );
+
+    //Later notes are quieter, so they are dropped instead
+    let mut quietest = chord.clone();
+    limit_polyphony(&mut quietest, 4, VoiceSteal::Quietest);
+    assert_eq!(
+        quietest,
+        vec![
+            note(0, 0, 60, 100),
+            note(0, 0, 62, 99),
+            note(0, 0, 64, 98),
+            note(0, 0, 65, 97),
+            note(0, 1, 40, 100),
+            note(480, 0, 60, 0),
+            note(0, 0, 62, 0),
+            note(0, 0, 64, 0),
+            note(0, 0, 65, 0),
+        ]
+    );
+
+    let mut silent = chord;
+    limit_polyphony(&mut silent, 0, VoiceSteal::Oldest);
+    assert!(silent.is_empty());
+}
+
+#[test]
+fn timing_division() {
+    use crate::{Fps, Timing};
+
+    let metrical = Timing::Metrical(480.into());
+    assert_eq!(metrical.to_division_u16(), 0x01E0);
+    assert_eq!(Timing::from_division_u16(0x01E0).unwrap(), metrical);
+
+    //-25 in two's complement is 0xE7
+    let timecode = Timing::Timecode(Fps::Fps25, 40);
+    assert_eq!(timecode.to_division_u16(), 0xE728);
+    assert_eq!(Timing::from_division_u16(0xE728).unwrap(), timecode);
+    let drop_frame = Timing::Timecode(Fps::Fps29, 80);
+    assert_eq!(
+        Timing::from_division_u16(drop_frame.to_division_u16()).unwrap(),
+        drop_frame
+    );
+
+    //-26 fps is not a valid frame rate
+    assert!(Timing::from_division_u16(0xE628).is_err());
+}
+
+#[test]
+fn validate_structure() {
+    use crate::{validate, ErrorKind};
+
+    open! {file: "Clementi.mid"};
+    validate(&file).unwrap();
+    open! {rmid: "Beethoven.rmi"};
+    validate(&rmid).unwrap();
+
+    let truncated = &file[..file.len() - 10];
+    assert_eq!(
+        validate(truncated).unwrap_err().kind().message(),
+        "reached eof before chunk ended"
+    );
+
+    //A track with a dangling note and no end of track, with the chunk length patched up
+    let mut raw = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60".to_vec();
+    raw.extend_from_slice(b"MTrk\x00\x00\x00\x04\x00\x90\x3C\x40");
+    assert!(matches!(
+        validate(&raw).unwrap_err().kind(),
+        ErrorKind::Malformed("malformed event")
+    ));
+    raw[21] = 8;
+    raw.extend_from_slice(b"\x00\xFF\x2F\x00");
+    validate(&raw).unwrap();
+
+    //Header and track count disagree
+    raw[11] = 2;
+    assert!(validate(&raw).is_err());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn write_chunks_manually() {
+    use crate::{write_header_chunk, write_track_chunk, Smf};
+
+    open! {file: "Clementi.mid"};
+    let mut smf = Smf::parse(&file).unwrap();
+    smf.tracks.truncate(2);
+    assert_eq!(smf.tracks.len(), 2);
+    let mut expected = Vec::new();
+    smf.write(&mut expected).unwrap();
+
+    let mut raw = Vec::new();
+    write_header_chunk(&smf.header, 2, &mut raw).unwrap();
+    for track in smf.tracks.iter() {
+        write_track_chunk(track, &mut raw).unwrap();
+    }
+    assert_eq!(raw, expected);
+
+    //Into a fixed-size buffer
+    let mut buf = vec![0; raw.len()];
+    let mut out = &mut buf[..];
+    write_header_chunk(&smf.header, 2, &mut out).unwrap();
+    for track in smf.tracks.iter() {
+        write_track_chunk(track, &mut out).unwrap();
+    }
+    assert!(out.is_empty());
+    assert_eq!(buf, expected);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn patch_roundtrip() {
+    use crate::{
+        num::u15, Format, Header, MetaMessage, MidiMessage, PatchOp, Smf, SmfPatch, Timing,
+        TrackEventKind,
+    };
+
+    open! {file: "Clementi.mid"};
+    let base = Smf::parse(&file).unwrap();
+    let mut target = base.clone();
+    //Change a note velocity in place
+    let idx = target.tracks[1]
+        .iter()
+        .position(|ev| matches!(ev.kind, TrackEventKind::Midi { .. }))
+        .unwrap();
+    if let TrackEventKind::Midi {
+        message: MidiMessage::NoteOn { vel, .. },
+        ..
+    } = &mut target.tracks[1][idx].kind
+    {
+        *vel = 1.into();
+    }
+    //Remove an event, carrying its delta over
+    let removed = target.tracks[1].remove(idx + 3);
+    target.tracks[1][idx + 3].delta += removed.delta;
+    //Insert a marker and a new track
+    target.tracks[0].insert(0, meta(0, MetaMessage::Marker(b"Coda")));
+    target
+        .tracks
+        .push(vec![note(0, 0, 60, 100), note(96, 0, 60, 0)]);
+    target.header.timing = Timing::Metrical(u15::from(960));
+
+    let patch = SmfPatch::diff(&base, &target);
+    assert_eq!(patch.header.unwrap().timing, target.header.timing);
+    assert!(patch.ops.contains(&PatchOp::InsertTrack {
+        track: base.tracks.len()
+    }));
+    assert!(patch
+        .ops
+        .iter()
+        .any(|op| matches!(op, PatchOp::ChangeEvent { track: 1, .. })));
+    let mut encoded = Vec::new();
+    patch.write(&mut encoded).unwrap();
+    assert!(encoded.len() < 64);
+    let decoded = SmfPatch::parse(&encoded).unwrap();
+    assert_eq!(decoded, patch);
+
+    let mut synced = base.clone();
+    decoded.apply(&mut synced).unwrap();
+    assert_eq!(synced, target);
+    assert!(SmfPatch::diff(&synced, &target).is_empty());
+
+    //The reverse patch removes the extra track
+    let mut reverted = target.clone();
+    SmfPatch::diff(&target, &base).apply(&mut reverted).unwrap();
+    assert_eq!(reverted, base);
+
+    //Patches refuse to apply over a different file
+    let mut other = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::from(96)),
+    ));
+    other.tracks.push(vec![note(0, 0, 60, 100)]);
+    other.tracks.push(vec![note(0, 0, 60, 100)]);
+    let before = other.clone();
+    assert!(decoded.apply(&mut other).is_err());
+    assert_eq!(other, before);
+    assert!(SmfPatch::parse(&encoded[..encoded.len() - 1]).is_err());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn channels_per_track() {
+    use crate::Smf;
+
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![]);
+    smf.tracks
+        .push(vec![note(0, 0, 60, 100), note(10, 0, 60, 0)]);
+    smf.tracks
+        .push(vec![note(0, 1, 64, 100), note(0, 9, 36, 100)]);
+    assert_eq!(smf.channels_per_track(), vec![0, 0b1, 0b10_0000_0010]);
+    assert_eq!(smf.channels_used(), 0b10_0000_0011);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn preserve_unknown_chunks() {
+    use crate::{write_with_chunks, ChunkPosition, ParseObserver, ParseOptions, Smf};
+
+    #[derive(Default)]
+    struct Report {
+        dropped: Vec<([u8; 4], usize, usize)>,
+    }
+    impl ParseObserver for Report {
+        fn on_dropped_chunk(&mut self, id: [u8; 4], offset: usize, len: usize) {
+            self.dropped.push((id, offset, len));
+        }
+    }
+
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![note(0, 0, 60, 100)]);
+    let big = vec![0xAB; 100];
+    let extra: &[([u8; 4], &[u8])] = &[(*b"abcd", b"tiny"), (*b"huge", &big)];
+    let mut raw = Vec::new();
+    write_with_chunks(
+        &smf.header,
+        &smf.tracks,
+        extra,
+        ChunkPosition::AfterTracks,
+        &mut raw,
+    )
+    .unwrap();
+    let huge_offset = raw.len() - 108;
+
+    //Everything fits under the default cap
+    let mut report = Report::default();
+    let (parsed, chunks) =
+        Smf::parse_preserving(&raw, ParseOptions::default(), &mut report).unwrap();
+    assert_eq!(parsed, smf);
+    assert_eq!(chunks, extra);
+    assert!(report.dropped.is_empty());
+
+    //The large chunk is dropped under a small cap
+    let capped = ParseOptions {
+        max_unknown_chunk_bytes: 16,
+        ..Default::default()
+    };
+    let (parsed, chunks) = Smf::parse_preserving(&raw, capped, &mut report).unwrap();
+    assert_eq!(parsed, smf);
+    assert_eq!(chunks, &extra[..1]);
+    assert_eq!(report.dropped, vec![(*b"huge", huge_offset, 100)]);
+
+    //A chunk declaring an enormous length is cut short at the end of the file, and then dropped
+    if !cfg!(feature = "strict") {
+        let mut report = Report::default();
+        let end = raw.len();
+        raw.extend_from_slice(b"evil\xFF\xFF\xFF\xFF");
+        raw.extend_from_slice(&big);
+        let (parsed, chunks) = Smf::parse_preserving(&raw, capped, &mut report).unwrap();
+        assert_eq!(parsed, smf);
+        assert_eq!(chunks, &extra[..1]);
+        assert_eq!(
+            report.dropped,
+            vec![(*b"huge", huge_offset, 100), (*b"evil", end, 100)]
+        );
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn recognize_chords() {
+    use crate::{chord_at, ChordName, ChordQuality};
+
+    let track = vec![
+        //C major, voiced E-G-C
+        note(0, 0, 64, 100),
+        note(0, 0, 67, 100),
+        note(0, 0, 72, 100),
+        note(0, 9, 36, 100),
+        note(96, 0, 64, 0),
+        note(0, 0, 67, 0),
+        note(0, 0, 72, 0),
+        //G dominant seventh
+        note(0, 0, 55, 100),
+        note(0, 0, 59, 100),
+        note(0, 0, 62, 100),
+        note(0, 0, 65, 100),
+        note(96, 0, 55, 0),
+        note(0, 0, 59, 0),
+        note(0, 0, 62, 0),
+        note(0, 0, 65, 0),
+        //A two-note interval, then an augmented triad over E
+        note(96, 0, 64, 100),
+        note(0, 0, 68, 100),
+        note(96, 0, 72, 100),
+        note(96, 0, 64, 0),
+        note(0, 0, 68, 0),
+        note(0, 0, 72, 0),
+    ];
+    let c_major = chord_at(&track, 0).unwrap();
+    assert_eq!(
+        c_major,
+        ChordName {
+            root: 0,
+            quality: ChordQuality::Major
+        }
+    );
+    assert_eq!(c_major.to_string(), "C");
+    let g7 = chord_at(&track, 150).unwrap();
+    assert_eq!(g7.to_string(), "G7");
+    assert_eq!(chord_at(&track, 200), None);
+    assert_eq!(chord_at(&track, 300), None);
+    assert_eq!(chord_at(&track, 1000), None);
+    assert_eq!(chord_at(&track, 384).unwrap().to_string(), "Eaug");
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn split_long_gaps() {
+    use crate::{num::u28, smf::from_absolute, MetaMessage, TrackEventKind};
+
+    let max = u28::max_value().as_int();
+    let gap = (1 << 30) + 5;
+    let track = from_absolute(vec![
+        (10, note(0, 0, 60, 100).kind),
+        (10 + gap, note(0, 0, 60, 0).kind),
+    ]);
+    let filler = TrackEventKind::Meta(MetaMessage::Text(b""));
+    assert_eq!(track.len(), 7);
+    assert_eq!(track[0], note(10, 0, 60, 100));
+    for ev in &track[1..5] {
+        assert_eq!(ev.delta.as_int(), max);
+        assert_eq!(ev.kind, filler);
+    }
+    assert_eq!(track[5], note(gap - 4 * max, 0, 60, 0));
+    let total: u64 = track.iter().map(|ev| u64::from(ev.delta.as_int())).sum();
+    assert_eq!(total, 10 + u64::from(gap));
+    assert_eq!(track[6].kind, TrackEventKind::Meta(MetaMessage::EndOfTrack));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn retain_long_gaps() {
+    use crate::{flatten_pitch_bend, num::u28, MidiMessage, PitchBend, TrackEventKind};
+
+    //Removing 16 events at the longest delta used to overflow the carried delta
+    let max = u28::max_value().as_int();
+    let mut track = (0..16)
+        .map(|_| TrackEvent {
+            delta: max.into(),
+            kind: TrackEventKind::Midi {
+                channel: 0.into(),
+                message: MidiMessage::PitchBend {
+                    bend: PitchBend::from_int(100),
+                },
+            },
+        })
+        .collect::<Vec<_>>();
+    track.push(note(10, 0, 60, 100));
+    let total =
+        |track: &[TrackEvent]| -> u64 { track.iter().map(|ev| u64::from(ev.delta.as_int())).sum() };
+    let expected = total(&track);
+    flatten_pitch_bend(&mut track, false);
+    assert_eq!(track.len(), 17);
+    assert_eq!(total(&track), expected);
+    assert_eq!(track[16], note(10, 0, 60, 100));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn edits_split_long_gaps() {
+    use crate::{enforce_min_duration, flat_map_events, num::u28, MetaMessage, TrackEventKind};
+
+    let filler = TrackEventKind::Meta(MetaMessage::Text(b""));
+    let total =
+        |track: &[TrackEvent]| -> u64 { track.iter().map(|ev| u64::from(ev.delta.as_int())).sum() };
+    let max = u28::max_value().as_int();
+
+    //Notes stretched past the longest delta
+    let mut track = vec![note(0, 0, 60, 100), note(0, 0, 60, 0)];
+    enforce_min_duration(&mut track, max + 100);
+    assert_eq!(track.len(), 3);
+    assert_eq!(track[1].kind, filler);
+    assert_eq!(track[2], note(100, 0, 60, 0));
+    assert_eq!(total(&track), u64::from(max) + 100);
+
+    //Deltas of dropped events carried past the longest delta
+    let track = vec![
+        note(max, 0, 60, 100),
+        note(max, 0, 60, 0),
+        note(5, 0, 62, 100),
+    ];
+    let mapped = flat_map_events(track, |ev| Some(ev).filter(|ev| ev.delta != max));
+    assert_eq!(mapped.len(), 3);
+    assert!(mapped[..2].iter().all(|ev| ev.kind == filler));
+    assert_eq!(mapped[2], note(5, 0, 62, 100));
+    assert_eq!(total(&mapped), 2 * u64::from(max) + 5);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn save_buffered() {
+    use crate::{Smf, SmfBytemap};
+
+    //Large enough to go through several buffer flushes
+    open! {file: "Clementi.mid"};
+    let mut smf = Smf::parse(&file).unwrap();
+    for _ in 0..4 {
+        let tracks = smf.tracks.clone();
+        smf.tracks.extend(tracks);
+    }
+    let mut expected = Vec::new();
+    smf.write_std(&mut expected).unwrap();
+    assert!(expected.len() > 64 * 1024);
+
+    let path = std::env::temp_dir().join(format!("midly-save-{}.mid", std::process::id()));
+    smf.save(&path).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), expected);
+
+    let bytemap = SmfBytemap::parse(&file).unwrap();
+    bytemap.save(&path).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), file);
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn iter_timeline() {
+    use crate::Smf;
+
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![
+        note(0, 0, 60, 100),
+        note(10, 0, 60, 0),
+        note(20, 0, 62, 100),
+    ]);
+    smf.tracks.push(vec![
+        note(5, 1, 48, 100),
+        note(5, 1, 48, 0),
+        note(30, 1, 50, 100),
+    ]);
+    smf.tracks.push(vec![]);
+    let timeline = smf
+        .iter_timeline()
+        .map(|(track, tick, ev)| (track, tick, ev.kind))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        timeline
+            .iter()
+            .map(|&(track, tick, _)| (track, tick))
+            .collect::<Vec<_>>(),
+        vec![(0, 0), (1, 5), (0, 10), (1, 10), (0, 30), (1, 40)]
+    );
+    assert!(timeline.windows(2).all(|w| w[0].1 <= w[1].1));
+    assert_eq!(timeline[3].2, note(0, 1, 48, 0).kind);
+
+    open! {file: "Clementi.mid"};
+    let smf = Smf::parse(&file).unwrap();
+    let ticks = smf
+        .iter_timeline()
+        .map(|(_, tick, _)| tick)
+        .collect::<Vec<_>>();
+    assert_eq!(ticks.len(), smf.total_event_count());
+    assert!(ticks.windows(2).all(|w| w[0] <= w[1]));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn release_velocity_roundtrip() {
+    use crate::{num::u7, EventIter, MidiMessage, Smf, TrackEventKind};
+
+    let off = TrackEvent {
+        delta: 96.into(),
+        kind: TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOff {
+                key: 60.into(),
+                vel: 64.into(),
+            },
+        },
+    };
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![note(0, 0, 60, 100), off]);
+    let mut raw = Vec::new();
+    smf.write(&mut raw).unwrap();
+    assert!(raw.ends_with(&[0x60, 0x80, 60, 64]));
+    let parsed = Smf::parse(&raw).unwrap();
+    assert_eq!(parsed, smf);
+    match parsed.tracks[0][1].kind {
+        TrackEventKind::Midi { message, .. } => {
+            assert_eq!(message.release_velocity(), Some(u7::new(64)))
+        }
+        _ => panic!("expected a midi event"),
+    }
+
+    let raw: &[u8] = &[0x00, 0x90, 60, 100, 0x10, 0x80, 60, 127, 0x00, 0x90, 60, 0];
+    let messages = EventIter::new(raw)
+        .map(|ev| match ev.unwrap().kind {
+            TrackEventKind::Midi { message, .. } => message.release_velocity(),
+            _ => panic!("expected a midi event"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(messages, vec![None, Some(u7::new(127)), None]);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn split_at_tick() {
+    use crate::{num::u24, validate, MetaMessage, MidiMessage, Smf, TrackEventKind};
+
+    let program = TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::ProgramChange { program: 40.into() },
+        },
+    };
+    let tempo = MetaMessage::Tempo(u24::new(400_000));
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![
+        meta(0, tempo),
+        meta(0, MetaMessage::TimeSignature(3, 2, 24, 8)),
+        meta(384, MetaMessage::EndOfTrack),
+    ]);
+    smf.tracks.push(vec![
+        program,
+        cc(0, 0, 7, 90),
+        note(0, 0, 60, 100),
+        note(96, 0, 64, 80),
+        note(4, 0, 64, 0),
+        note(92, 0, 60, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+
+    let (before, after) = smf.split_at_tick(100);
+    assert_eq!(before.header, smf.header);
+    assert_eq!(after.header, smf.header);
+    assert_eq!(
+        before.tracks[0],
+        smf.tracks[0][..2]
+            .iter()
+            .copied()
+            .chain(Some(meta(100, MetaMessage::EndOfTrack)))
+            .collect::<Vec<_>>()
+    );
+    let off = |delta: u32, key: u8| TrackEvent {
+        delta: delta.into(),
+        kind: TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOff {
+                key: key.into(),
+                vel: 64.into(),
+            },
+        },
+    };
+    assert_eq!(
+        before.tracks[1],
+        vec![
+            program,
+            cc(0, 0, 7, 90),
+            note(0, 0, 60, 100),
+            note(96, 0, 64, 80),
+            off(4, 60),
+            off(0, 64),
+            meta(0, MetaMessage::EndOfTrack),
+        ]
+    );
+    assert_eq!(
+        after.tracks[0],
+        vec![
+            meta(0, tempo),
+            meta(0, MetaMessage::TimeSignature(3, 2, 24, 8)),
+            meta(284, MetaMessage::EndOfTrack),
+        ]
+    );
+    //The note released at the split is not struck again
+    assert_eq!(
+        after.tracks[1],
+        vec![
+            cc(0, 0, 7, 90),
+            program,
+            note(0, 0, 60, 100),
+            note(92, 0, 60, 0),
+            meta(0, MetaMessage::EndOfTrack),
+        ]
+    );
+
+    //Both halves are valid files on their own
+    for half in [&before, &after].iter() {
+        let mut raw = Vec::new();
+        half.write(&mut raw).unwrap();
+        validate(&raw).unwrap();
+        assert_eq!(&Smf::parse(&raw).unwrap(), *half);
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn dedupe_files() {
+    use crate::{transpose, Duplicates, MidiMessage, Smf, TrackEventKind};
+
+    open! {file: "Clementi.mid"};
+    let original = Smf::parse(&file).unwrap();
+    //The same music with note offs spelled as explicit `NoteOff` messages
+    let mut reencoded = original.clone();
+    for track in reencoded.tracks.iter_mut() {
+        for ev in track.iter_mut() {
+            if let TrackEventKind::Midi { message, .. } = &mut ev.kind {
+                if let MidiMessage::NoteOn { key, vel } = *message {
+                    if vel == 0 {
+                        *message = MidiMessage::NoteOff { key, vel };
+                    }
+                }
+            }
+        }
+    }
+    assert_ne!(reencoded, original);
+    let mut transposed = original.clone();
+    for track in transposed.tracks.iter_mut() {
+        transpose(track, 2);
+    }
+    let mut transposed_reencoded = reencoded.clone();
+    for track in transposed_reencoded.tracks.iter_mut() {
+        transpose(track, 2);
+    }
+
+    let files = [
+        original.clone(),
+        transposed,
+        reencoded,
+        original,
+        transposed_reencoded,
+    ];
+    assert_eq!(
+        Smf::dedupe(&files),
+        Duplicates {
+            identical: vec![vec![0, 3]],
+            equivalent: vec![vec![0, 2, 3], vec![1, 4]],
+        }
+    );
+    assert_eq!(Smf::dedupe(&files[..2]), Duplicates::default());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn chunk_overrun_policies() {
+    use crate::{ChunkOverrun, ErrorKind, ParseObserver, ParseOptions, Smf};
+
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![note(0, 0, 60, 100)]);
+    smf.tracks.push(vec![note(0, 1, 64, 100)]);
+    let mut raw = Vec::new();
+    smf.write(&mut raw).unwrap();
+    //Make the last track declare 100 more bytes than there are
+    let len_at = raw.len() - 4 - 4;
+    raw[len_at + 3] += 100;
+    let options = |on_chunk_overrun| ParseOptions {
+        on_chunk_overrun,
+        ..Default::default()
+    };
+
+    let parsed = Smf::parse_with_options(&raw, options(ChunkOverrun::UseRemainder)).unwrap();
+    assert_eq!(parsed, smf);
+    let err = Smf::parse_with_options(&raw, options(ChunkOverrun::Error)).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::Malformed(_)));
+    let truncated = Smf::parse_with_options(&raw, options(ChunkOverrun::TruncateToDeclared));
+    if cfg!(feature = "strict") {
+        //The header declares more tracks than are left
+        assert!(truncated.is_err());
+    } else {
+        assert_eq!(truncated.unwrap().tracks, &smf.tracks[..1]);
+        assert_eq!(Smf::parse(&raw).unwrap(), smf);
+    }
+
+    struct Quiet;
+    impl ParseObserver for Quiet {}
+    let mut report = Quiet;
+    let preserved = Smf::parse_preserving(&raw, options(ChunkOverrun::Error), &mut report);
+    assert!(preserved.is_err());
+    let (parsed, _) =
+        Smf::parse_preserving(&raw, options(ChunkOverrun::UseRemainder), &mut report).unwrap();
+    assert_eq!(parsed, smf);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn compact_tracks() {
+    use crate::{Format, MetaMessage, Smf};
+
+    let eot = meta(0, MetaMessage::EndOfTrack);
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![
+        meta(0, MetaMessage::TrackNumber(Some(0))),
+        note(0, 0, 60, 100),
+        eot,
+    ]);
+    smf.tracks
+        .push(vec![meta(0, MetaMessage::TrackNumber(Some(1))), eot]);
+    smf.tracks.push(vec![
+        meta(0, MetaMessage::TrackNumber(Some(2))),
+        note(0, 1, 60, 100),
+        eot,
+    ]);
+    smf.compact();
+    assert_eq!(smf.header.format, Format::Parallel);
+    assert_eq!(
+        smf.tracks,
+        vec![
+            vec![
+                meta(0, MetaMessage::TrackNumber(Some(0))),
+                note(0, 0, 60, 100),
+                eot
+            ],
+            vec![
+                meta(0, MetaMessage::TrackNumber(Some(1))),
+                note(0, 1, 60, 100),
+                eot
+            ],
+        ]
+    );
+
+    //A single remaining track turns the file into a single-track file
+    smf.tracks[1].retain(|ev| ev.kind == eot.kind);
+    smf.compact();
+    assert_eq!(smf.header.format, Format::SingleTrack);
+    assert_eq!(smf.tracks.len(), 1);
+
+    //At least one track is kept
+    let mut empty = Smf::new(Default::default());
+    empty.tracks.push(vec![eot]);
+    empty.tracks.push(vec![]);
+    empty.compact();
+    assert_eq!(empty.tracks, vec![vec![eot]]);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn device_names() {
+    use crate::{EventIter, MetaMessage, Smf, TrackEventKind};
+
+    let raw: &[u8] = &[
+        0x00, 0xFF, 0x09, 0x06, b'S', b'y', b'n', b't', b'h', b'A', //Device name
+        0x00, 0x90, 60, 100, //
+        0x00, 0xFF, 0x2F, 0x00,
+    ];
+    let track = EventIter::new(raw).into_vec().unwrap();
+    assert_eq!(
+        track[0].kind,
+        TrackEventKind::Meta(MetaMessage::DeviceName(b"SynthA"))
+    );
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(track);
+    smf.tracks.push(vec![note(0, 1, 60, 100)]);
+    assert_eq!(smf.device_names(), vec![Some("SynthA".into()), None]);
+
+    let mut out = Vec::new();
+    smf.write(&mut out).unwrap();
+    assert_eq!(&out[22..22 + raw.len()], raw);
+    assert_eq!(Smf::parse(&out).unwrap(), smf);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn enforce_min_duration() {
+    use crate::MetaMessage;
+
+    //A zero-duration note, a long note, and a short note struck again before the minimum
+    let mut track = vec![
+        note(0, 0, 60, 100),
+        note(0, 0, 60, 0),
+        cc(10, 0, 7, 100),
+        note(0, 0, 62, 100),
+        note(50, 0, 62, 0),
+        note(0, 0, 64, 100),
+        note(5, 0, 64, 0),
+        note(10, 0, 64, 100),
+        note(20, 0, 64, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ];
+    crate::enforce_min_duration(&mut track, 30);
+    assert_eq!(
+        track,
+        vec![
+            note(0, 0, 60, 100),
+            cc(10, 0, 7, 100),
+            note(0, 0, 62, 100),
+            note(20, 0, 60, 0),
+            note(30, 0, 62, 0),
+            note(0, 0, 64, 100),
+            note(15, 0, 64, 0),
+            note(0, 0, 64, 100),
+            note(30, 0, 64, 0),
+            meta(0, MetaMessage::EndOfTrack),
+        ]
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn sequential_songs() {
+    use crate::{Format, MetaMessage, Smf};
+    use std::time::Duration;
+
+    let mut smf = Smf::new(Default::default()).with_ppq(480.into());
+    //A song at 60 bpm, and a song at the default 120 bpm
+    smf.tracks.push(vec![
+        meta(0, MetaMessage::Tempo(1_000_000.into())),
+        note(0, 0, 60, 100),
+        note(960, 0, 60, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    smf.tracks.push(vec![
+        note(0, 0, 64, 100),
+        note(480, 0, 64, 0),
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    assert_eq!(smf.sequences().count(), 1);
+    assert_eq!(smf.sequence_durations(), [Duration::from_secs(2)]);
+
+    smf.header.format = Format::Sequential;
+    let sequences = smf.sequences().collect::<Vec<_>>();
+    assert_eq!(sequences, [&smf.tracks[..1], &smf.tracks[1..]]);
+    assert_eq!(
+        smf.sequence_durations(),
+        [Duration::from_secs(2), Duration::from_millis(500)]
+    );
+    assert_eq!(smf.duration(), Duration::from_millis(2500));
+
+    //Appending to a sequential file adds new songs instead of merging them
+    let other = smf.clone();
+    smf.append(&other, 0);
+    assert_eq!(smf.tracks.len(), 4);
+    assert_eq!(smf.tracks[2..], other.tracks[..]);
+    assert_eq!(smf.duration(), Duration::from_millis(5000));
+
+    assert_eq!(Smf::new(Default::default()).sequences().count(), 0);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn max_payload_len() {
+    use crate::{ErrorKind, MetaMessage, ParseObserver, ParseOptions, Smf};
+
+    //A text meta event declaring a 100MB payload, followed by a few bytes
+    let mut raw = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60".to_vec();
+    let track = b"\x00\x90\x3C\x40\x00\xFF\x01\xAF\xD7\xC2\x00hello";
+    raw.extend_from_slice(b"MTrk");
+    raw.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    raw.extend_from_slice(track);
+    let limited = ParseOptions {
+        max_payload_len: 1024,
+        ..Default::default()
+    };
+
+    let err = Smf::parse_with_options(&raw, limited).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::Invalid(_)));
+    let mut tracks = crate::parse(&raw).unwrap().1.with_options(limited);
+    let mut events = tracks.next().unwrap().unwrap();
+    assert!(events.next().unwrap().is_ok());
+    assert!(events.next().unwrap().is_err());
+    assert!(events.next().is_none());
+    struct Quiet;
+    impl ParseObserver for Quiet {}
+    assert!(Smf::parse_preserving(&raw, limited, &mut Quiet).is_err());
+
+    //Without a limit, the payload is cut short, which is only rejected in strict mode
+    assert_eq!(Smf::parse(&raw).is_err(), cfg!(feature = "strict"));
+
+    //Payloads within the limit are fine
+    let text = [b'x'; 1024];
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![meta(0, MetaMessage::Text(&text))]);
+    let mut raw = Vec::new();
+    smf.write(&mut raw).unwrap();
+    assert_eq!(Smf::parse_with_options(&raw, limited).unwrap(), smf);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn midi_bytes() {
+    use crate::{MetaMessage, Smf, TrackEventKind};
+
+    let wire = |smf: &Smf| {
+        smf.midi_bytes()
+            .map(|(tick, bytes)| (tick, bytes.to_vec()))
+            .collect::<Vec<_>>()
+    };
+    let mut smf = Smf::new(Default::default());
+    smf.tracks.push(vec![
+        meta(0, MetaMessage::Tempo(500_000.into())),
+        TrackEvent {
+            delta: 240.into(),
+            kind: TrackEventKind::SysEx(b"\x7E\x7F\x09\x01\xF7"),
+        },
+        meta(0, MetaMessage::EndOfTrack),
+    ]);
+    smf.tracks.push(vec![
+        note(480, 3, 60, 100),
+        note(0, 3, 64, 100),
+        TrackEvent {
+            delta: 10.into(),
+            kind: TrackEventKind::Escape(b"\xF8"),
+        },
+        note(470, 3, 60, 0),
+    ]);
+    let bytes = wire(&smf);
+    assert_eq!(
+        bytes,
+        [
+            (240, vec![0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]),
+            (480, vec![0x93, 60, 100]),
+            (480, vec![0x93, 64, 100]),
+            (490, vec![0xF8]),
+            (960, vec![0x93, 60, 0]),
+        ]
+    );
+
+    //Simultaneous events are ordered like in `scheduled`, regardless of their track
+    let eot = meta(0, MetaMessage::EndOfTrack);
+    smf.tracks = vec![
+        vec![note(960, 3, 60, 100), note(480, 3, 60, 0), eot],
+        vec![
+            note(480, 3, 62, 100),
+            cc(480, 3, 7, 90),
+            note(0, 3, 62, 0),
+            eot,
+        ],
+    ];
+    let bytes = wire(&smf);
+    assert_eq!(
+        bytes,
+        [
+            (480, vec![0x93, 62, 100]),
+            (960, vec![0xB3, 7, 90]),
+            (960, vec![0x93, 62, 0]),
+            (960, vec![0x93, 60, 100]),
+            (1440, vec![0x93, 60, 0]),
+        ]
+    );
+
+    //Sequential tracks play one after the other
+    smf.header.format = crate::Format::Sequential;
+    let bytes = wire(&smf);
+    assert_eq!(
+        bytes,
+        [
+            (960, vec![0x93, 60, 100]),
+            (1440, vec![0x93, 60, 0]),
+            (1920, vec![0x93, 62, 100]),
+            (2400, vec![0xB3, 7, 90]),
+            (2400, vec![0x93, 62, 0]),
+        ]
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn from_timeline() {
+    use crate::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+    let on = |ch: u8, key: u8| TrackEventKind::Midi {
+        channel: ch.into(),
+        message: MidiMessage::NoteOn {
+            key: key.into(),
+            vel: 100.into(),
+        },
+    };
+    let off = |ch: u8, key: u8| TrackEventKind::Midi {
+        channel: ch.into(),
+        message: MidiMessage::NoteOff {
+            key: key.into(),
+            vel: 64.into(),
+        },
+    };
+    let timeline = vec![
+        (0, TrackEventKind::Meta(MetaMessage::Tempo(400_000.into()))),
+        (0, on(0, 60)),
+        (480, off(0, 60)),
+        (240, on(9, 36)),
+        (300, off(9, 36)),
+        (480, on(0, 62)),
+        (960, off(0, 62)),
+    ];
+    let timing = Timing::Metrical(480.into());
+
+    let single = Smf::from_timeline(Header::new(Format::SingleTrack, timing), timeline.clone());
+    assert_eq!(single.header.format, Format::SingleTrack);
+    assert_eq!(single.tracks.len(), 1);
+    assert_eq!(single.total_event_count(), timeline.len() + 1);
+    let parallel = Smf::from_timeline(Header::new(Format::Parallel, timing), timeline);
+    assert_eq!(parallel.header.format, Format::Parallel);
+    //A conductor track, plus one track for each of the two channels
+    assert_eq!(parallel.tracks.len(), 3);
+
+    //Both files play the same events at the same time
+    fn played<'a>(smf: &Smf<'a>) -> Vec<(u32, TrackEventKind<'a>)> {
+        smf.iter_timeline()
+            .map(|(_, tick, ev)| (tick, ev.kind))
+            .filter(|(_, kind)| *kind != TrackEventKind::Meta(MetaMessage::EndOfTrack))
+            .collect()
+    }
+    assert_eq!(played(&single), played(&parallel));
+    assert_eq!(played(&single)[2], (240, on(9, 36)));
+}
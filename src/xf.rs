@@ -0,0 +1,137 @@
+//! Support for the Yamaha XF extensions to Standard Midi Files.
+
+#![cfg(feature = "alloc")]
+
+use crate::{
+    event::{MetaMessage, TrackEvent, TrackEventKind},
+    prelude::*,
+    smf::{EventIter, FileLayout},
+};
+
+/// Song information embedded in a Yamaha XF file.
+///
+/// XF files identify themselves with a sequencer-specific meta message holding Yamaha's
+/// manufacturer ID (`43 7B 00`) followed by the XF version (eg. `XF02`).
+/// Song information is stored as text meta messages starting with `XFhd:` (song information) and
+/// `XFln:` (karaoke information), which may appear either in regular tracks or in the
+/// non-standard `XFIH` (information header) and `XFKM` (karaoke message) chunks.
+/// These texts are lists of fields separated by colons, which are kept here as raw bytes, since
+/// their encoding depends on the language of the song.
+///
+/// This type is only available with the `alloc` feature enabled.
+#[derive(Clone, Default, PartialEq, Eq, Debug, Hash)]
+pub struct XfInfo<'a> {
+    /// The XF version, such as `b"XF02"`, or `None` if no XF version meta message was found.
+    pub version: Option<[u8; 4]>,
+    /// The colon-separated fields of the `XFhd` song information text, in order: date, country,
+    /// category, beat, melody instrument, vocal type, composer, lyricist, arranger, performer,
+    /// programmer and keywords.
+    pub song_info: Vec<&'a [u8]>,
+    /// The colon-separated fields of the `XFln` karaoke information text, in order: language,
+    /// song name, composer, lyricist, arranger, performer and programmer.
+    pub lyrics_info: Vec<&'a [u8]>,
+}
+impl<'a> XfInfo<'a> {
+    /// Gather XF information out of the raw bytes of a file, looking into its tracks as well as
+    /// into any `XFIH` and `XFKM` chunks.
+    pub fn read(raw: &'a [u8]) -> Result<XfInfo<'a>> {
+        let layout = FileLayout::read(raw)?;
+        let mut info = XfInfo::default();
+        let mut chunks = layout
+            .tracks
+            .iter()
+            .chain(layout.unknown.iter())
+            .collect::<Vec<_>>();
+        chunks.sort_by_key(|range| range.start);
+        for range in chunks {
+            let chunk = &raw[range.clone()];
+            if let b"MTrk" | b"XFIH" | b"XFKM" = &chunk[..4] {
+                for ev in EventIter::new(&chunk[8..]) {
+                    info.add(&ev?.kind);
+                }
+            }
+        }
+        Ok(info)
+    }
+
+    /// Gather XF information out of the events of a parsed track.
+    pub fn from_events(events: &[TrackEvent<'a>]) -> XfInfo<'a> {
+        let mut info = XfInfo::default();
+        for ev in events {
+            info.add(&ev.kind);
+        }
+        info
+    }
+
+    /// Whether any XF information was found.
+    pub fn is_xf(&self) -> bool {
+        self.version.is_some() || !self.song_info.is_empty() || !self.lyrics_info.is_empty()
+    }
+
+    /// The release date of the song, from the song information.
+    pub fn date(&self) -> Option<&'a [u8]> {
+        Self::field(&self.song_info, 0)
+    }
+
+    /// The country of the song, from the song information.
+    pub fn country(&self) -> Option<&'a [u8]> {
+        Self::field(&self.song_info, 1)
+    }
+
+    /// The category (genre) of the song, from the song information.
+    pub fn category(&self) -> Option<&'a [u8]> {
+        Self::field(&self.song_info, 2)
+    }
+
+    /// The composer of the song, from the song information.
+    pub fn composer(&self) -> Option<&'a [u8]> {
+        Self::field(&self.song_info, 6)
+    }
+
+    /// The lyricist of the song, from the song information.
+    pub fn lyricist(&self) -> Option<&'a [u8]> {
+        Self::field(&self.song_info, 7)
+    }
+
+    /// The performer of the song, from the song information.
+    pub fn performer(&self) -> Option<&'a [u8]> {
+        Self::field(&self.song_info, 9)
+    }
+
+    /// The language of the lyrics, from the karaoke information.
+    pub fn language(&self) -> Option<&'a [u8]> {
+        Self::field(&self.lyrics_info, 0)
+    }
+
+    /// The name of the song, from the karaoke information.
+    pub fn song_name(&self) -> Option<&'a [u8]> {
+        Self::field(&self.lyrics_info, 1)
+    }
+
+    /// Get a field by index, treating empty fields as missing.
+    fn field(fields: &[&'a [u8]], idx: usize) -> Option<&'a [u8]> {
+        fields.get(idx).copied().filter(|field| !field.is_empty())
+    }
+
+    /// Take the XF information out of a single event, if any.
+    fn add(&mut self, kind: &TrackEventKind<'a>) {
+        match *kind {
+            TrackEventKind::Meta(MetaMessage::SequencerSpecific(data))
+                if data.len() >= 7 && data.starts_with(&[0x43, 0x7B, 0x00, b'X', b'F']) =>
+            {
+                let mut version = [0; 4];
+                version.copy_from_slice(&data[3..7]);
+                self.version = Some(version);
+            }
+            TrackEventKind::Meta(MetaMessage::Text(text)) => {
+                let fields = |text: &'a [u8]| text.split(|&b| b == b':').collect();
+                if let Some(rest) = text.strip_prefix(b"XFhd:") {
+                    self.song_info = fields(rest);
+                } else if let Some(rest) = text.strip_prefix(b"XFln:") {
+                    self.lyrics_info = fields(rest);
+                }
+            }
+            _ => {}
+        }
+    }
+}